@@ -0,0 +1,382 @@
+//! ## ACPI
+//!
+//! Advanced Configuration and Power Interface (ACPI) は、ファームウェアが
+//! OS にハードウェアの構成を伝えるための仕組みである。
+//! 情報は物理メモリ上のいくつかのテーブルに書かれている。
+//!
+//! ### RSDP
+//!
+//! テーブルを探す起点となるのが Root System Description Pointer (RSDP) である。
+//! BIOS の場合、RSDP は以下のどちらかの領域の 16 バイト境界に置かれており、
+//! `"RSD PTR "` というシグネチャで見つけられる。
+//!
+//! - Extended BIOS Data Area (EBDA) の先頭 1 KiB
+//! - `0xE0000`〜`0xFFFFF`
+//!
+//! bootloader によっては RSDP のアドレスを教えてくれるものもあるが、
+//! 今使っている bootloader の `BootInfo` には含まれていないので、自分で探す。
+//! RSDP のアドレスがわかっている場合は `init_from_rsdp` を使う。
+//!
+//! RSDP には RSDT（ACPI 1.0）または XSDT（ACPI 2.0 以降）のアドレスが書かれている。
+//! RSDT/XSDT は、他のテーブルへのポインタの配列である。
+//! 違いはポインタの大きさだけで、RSDT は 32 bit、XSDT は 64 bit 。
+//!
+//! ### テーブル
+//!
+//! 全てのテーブルは共通のヘッダ（`SdtHeader`）から始まる。
+//! ヘッダの 4 バイトのシグネチャでテーブルの種類を判別する。
+//! テーブル全体のバイトの和は 0 になるようになっており（checksum）、
+//! これでテーブルが壊れていないかを確認できる。
+//!
+//! | シグネチャ | テーブル | モジュール |
+//! |------------|----------|------------|
+//! | `"APIC"`   | MADT     | `madt`     |
+//! | `"FACP"`   | FADT     | `fadt`     |
+//! | `"HPET"`   | HPET     | `hpet`     |
+//! | `"MCFG"`   | MCFG     | `mcfg`     |
+//! | `"DSDT"`   | DSDT     | `aml`（`\_S5` のみ） |
+//!
+//! `dump` で、見つけたテーブルの内容を serial に出力できる。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/RSDP
+//! - https://wiki.osdev.org/RSDT
+//! - https://uefi.org/specifications
+
+pub mod aml;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+pub use self::{fadt::Fadt, hpet::Hpet, madt::Madt, mcfg::Mcfg};
+
+use crate::{memory, serial_println};
+use alloc::vec::Vec;
+use core::{mem, ptr, slice};
+use spin::Once;
+use x86_64::PhysAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// RSDP が見つからなかった。
+    RsdpNotFound,
+    /// checksum が一致しなかった。
+    InvalidChecksum([u8; 4]),
+    /// テーブルが見つからなかった。
+    TableNotFound([u8; 4]),
+    /// `init` がまだ呼び出されていない。
+    NotInitialized,
+    /// AML のオブジェクトが見つからなかった。
+    AmlObjectNotFound([u8; 4]),
+    /// テーブルの `length` がヘッダより短いか、`MAX_SDT_SIZE` より長い。
+    /// RSDP の場合のシグネチャは `RSDP` 。
+    InvalidLength([u8; 4]),
+}
+
+/// RSDP 。`revision` が 2 以上の場合は `length` 以降も有効。
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// ACPI 1.0 の RSDP の大きさ。checksum はこの範囲で計算する。
+const RSDP_V1_SIZE: usize = 20;
+/// ACPI 2.0 の RSDP の大きさ。`length` はこれ以上でなければならない。
+const RSDP_V2_SIZE: usize = 36;
+/// RSDP の `length` として受け付ける最大値。
+const MAX_RSDP_SIZE: usize = 1024;
+/// テーブルの `length` として受け付ける最大値。(1 MiB)
+///
+/// firmware が壊れた値を書いていると、物理メモリのマップの外まで読んでしまうので制限する。
+const MAX_SDT_SIZE: usize = 1024 * 1024;
+
+/// 全てのテーブルに共通のヘッダ。
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Generic Address Structure 。
+///
+/// レジスタの場所を、アドレス空間（メモリか I/O ポートかなど）とともに表す。
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// `GenericAddress::address_space` の値。
+pub mod address_space {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+    pub const PCI_CONFIGURATION: u8 = 2;
+}
+
+/// 見つけたテーブルの一覧。
+#[derive(Debug)]
+pub struct Tables {
+    /// RSDP の revision 。0 なら ACPI 1.0 。
+    pub revision: u8,
+    /// 各テーブルの物理アドレス。
+    pub addresses: Vec<PhysAddr>,
+}
+
+static TABLES: Once<Tables> = Once::new();
+
+/// RSDP を探し、RSDT/XSDT からテーブルの一覧を作る。
+///
+/// `memory::init` と `allocator::init_heap` の後に呼び出すこと。
+pub fn init() -> Result<(), AcpiError> {
+    let rsdp = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    init_from_rsdp(rsdp)
+}
+
+/// `rsdp_addr` の RSDP から、RSDT/XSDT のテーブルの一覧を作る。
+///
+/// bootloader などから RSDP のアドレスがわかっている場合に使う。
+pub fn init_from_rsdp(rsdp_addr: PhysAddr) -> Result<(), AcpiError> {
+    if !is_valid_rsdp(rsdp_addr) {
+        return Err(AcpiError::InvalidChecksum(*b"RSDP"));
+    }
+    let rsdp: Rsdp = unsafe { read(rsdp_addr) };
+
+    let (root, entry_size) = if has_valid_xsdt(rsdp_addr, &rsdp)? {
+        (PhysAddr::new(rsdp.xsdt_address), mem::size_of::<u64>())
+    } else {
+        (
+            PhysAddr::new(u64::from(rsdp.rsdt_address)),
+            mem::size_of::<u32>(),
+        )
+    };
+    // `validate` で `length` がヘッダより短くないことを確認している
+    let header = validate(root)?;
+
+    let count = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+    let entries = root + mem::size_of::<SdtHeader>();
+    let addresses = (0..count)
+        .map(|i| {
+            let entry = entries + i * entry_size;
+            if entry_size == mem::size_of::<u64>() {
+                PhysAddr::new(unsafe { read::<u64>(entry) })
+            } else {
+                PhysAddr::new(u64::from(unsafe { read::<u32>(entry) }))
+            }
+        })
+        .collect();
+
+    TABLES.call_once(|| Tables {
+        revision: rsdp.revision,
+        addresses,
+    });
+    Ok(())
+}
+
+/// `init` で見つけたテーブルの一覧を返す。
+pub fn tables() -> Result<&'static Tables, AcpiError> {
+    TABLES.r#try().ok_or(AcpiError::NotInitialized)
+}
+
+/// `signature` のテーブルを探し、checksum を確認してからその物理アドレスを返す。
+pub fn find_table(signature: &[u8; 4]) -> Result<PhysAddr, AcpiError> {
+    for &addr in &tables()?.addresses {
+        let header: SdtHeader = unsafe { read(addr) };
+        if &header.signature == signature {
+            validate(addr)?;
+            return Ok(addr);
+        }
+    }
+    Err(AcpiError::TableNotFound(*signature))
+}
+
+/// MADT を読み込む。
+pub fn madt() -> Result<Madt, AcpiError> {
+    let addr = find_table(b"APIC")?;
+    Ok(unsafe { Madt::parse(addr) })
+}
+
+/// FADT を読み込む。
+pub fn fadt() -> Result<Fadt, AcpiError> {
+    let addr = find_table(b"FACP")?;
+    Ok(unsafe { Fadt::parse(addr) })
+}
+
+/// HPET テーブルを読み込む。
+pub fn hpet() -> Result<Hpet, AcpiError> {
+    let addr = find_table(b"HPET")?;
+    Ok(unsafe { Hpet::parse(addr) })
+}
+
+/// MCFG を読み込む。
+pub fn mcfg() -> Result<Mcfg, AcpiError> {
+    let addr = find_table(b"MCFG")?;
+    Ok(unsafe { Mcfg::parse(addr) })
+}
+
+/// DSDT から `\_S5`（soft off）の sleep type を読み取る。
+pub fn s5_sleep_type() -> Result<aml::SleepType, AcpiError> {
+    let dsdt = fadt()?.dsdt;
+    // `validate` で `length` がヘッダより短くないことを確認している
+    let header = validate(dsdt)?;
+    let header_size = mem::size_of::<SdtHeader>();
+    let aml = unsafe { bytes(dsdt + header_size, header.length as usize - header_size) };
+    aml::find_s5(aml).ok_or(AcpiError::AmlObjectNotFound(*b"_S5_"))
+}
+
+/// `addr` のテーブルのヘッダを返す。
+pub fn header(addr: PhysAddr) -> SdtHeader {
+    unsafe { read(addr) }
+}
+
+/// 見つけたテーブルの内容を serial に出力する。
+pub fn dump() {
+    let tables = match tables() {
+        Ok(tables) => tables,
+        Err(err) => {
+            serial_println!("ACPI : {:?}", err);
+            return;
+        }
+    };
+
+    serial_println!("ACPI (revision {})", tables.revision);
+    for &addr in &tables.addresses {
+        let header = header(addr);
+        // packed な構造体のフィールドへの参照は作れないので、コピーしておく
+        let length = header.length;
+        let revision = header.revision;
+        serial_println!(
+            "  {} at {:#x} : length {}, revision {}, OEM {}",
+            ascii(&header.signature),
+            addr.as_u64(),
+            length,
+            revision,
+            ascii(&header.oem_id),
+        );
+    }
+
+    if let Ok(madt) = madt() {
+        serial_println!("MADT");
+        serial_println!("  local APIC : {:#x}", madt.local_apic_address.as_u64());
+        for processor in &madt.processors {
+            serial_println!("  {:?}", processor);
+        }
+        for io_apic in &madt.io_apics {
+            serial_println!("  {:?}", io_apic);
+        }
+        for o in &madt.overrides {
+            serial_println!("  {:?}", o);
+        }
+        for nmi in &madt.local_apic_nmis {
+            serial_println!("  {:?}", nmi);
+        }
+    }
+    if let Ok(fadt) = fadt() {
+        serial_println!("{:#x?}", fadt);
+    }
+    if let Ok(hpet) = hpet() {
+        serial_println!("{:#x?}", hpet);
+    }
+    if let Ok(mcfg) = mcfg() {
+        serial_println!("{:#x?}", mcfg);
+    }
+}
+
+/// シグネチャなどの ASCII の文字列を `&str` として扱う。
+fn ascii(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("?")
+}
+
+/// RSDP を探す。
+fn find_rsdp() -> Option<PhysAddr> {
+    // EBDA のセグメントは BIOS Data Area の 0x40E に書かれている
+    let ebda = u64::from(unsafe { read::<u16>(PhysAddr::new(0x40e)) }) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe_0000, 0x10_0000)];
+
+    areas
+        .iter()
+        .filter(|&&(start, _)| start != 0)
+        .flat_map(|&(start, end)| (start..end).step_by(16))
+        .map(PhysAddr::new)
+        .find(|&addr| is_valid_rsdp(addr))
+}
+
+/// `addr` にシグネチャと checksum が正しい RSDP があるか。
+fn is_valid_rsdp(addr: PhysAddr) -> bool {
+    let signature: [u8; 8] = unsafe { read(addr) };
+    &signature == b"RSD PTR " && checksum(unsafe { bytes(addr, RSDP_V1_SIZE) }) == 0
+}
+
+/// RSDP の ACPI 2.0 のフィールドが使えるか。
+///
+/// revision が 2 以上でも拡張 checksum が正しくなければ使わず、RSDT を使う。
+/// `length` が `RSDP_V2_SIZE..=MAX_RSDP_SIZE` になければ、読まずにエラーにする。
+fn has_valid_xsdt(addr: PhysAddr, rsdp: &Rsdp) -> Result<bool, AcpiError> {
+    if rsdp.revision < 2 {
+        return Ok(false);
+    }
+    let length = rsdp.length as usize;
+    if !(RSDP_V2_SIZE..=MAX_RSDP_SIZE).contains(&length) {
+        return Err(AcpiError::InvalidLength(*b"RSDP"));
+    }
+    Ok(checksum(unsafe { bytes(addr, length) }) == 0 && rsdp.xsdt_address != 0)
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+/// `addr` のテーブルの checksum を確認し、ヘッダを返す。
+fn validate(addr: PhysAddr) -> Result<SdtHeader, AcpiError> {
+    let header: SdtHeader = unsafe { read(addr) };
+    // 短すぎる `length` は、ヘッダの大きさを引く時に underflow する
+    let length = header.length as usize;
+    if length < mem::size_of::<SdtHeader>() || length > MAX_SDT_SIZE {
+        return Err(AcpiError::InvalidLength(header.signature));
+    }
+    if checksum(unsafe { bytes(addr, length) }) == 0 {
+        Ok(header)
+    } else {
+        Err(AcpiError::InvalidChecksum(header.signature))
+    }
+}
+
+/// 物理アドレス `addr` から `T` を読み込む。
+///
+/// ACPI のテーブルはアラインメントされているとは限らないので、`read_unaligned` を使う。
+///
+/// ## Safety
+/// `addr` から `T` の大きさの領域が読み込み可能である必要がある。
+unsafe fn read<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(memory::phys_to_virt(addr).as_ptr())
+}
+
+/// 物理アドレス `addr` から `len` バイトの領域を返す。
+///
+/// ## Safety
+/// `read` と同じ。
+unsafe fn bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(memory::phys_to_virt(addr).as_ptr(), len)
+}
@@ -0,0 +1,136 @@
+//! ## AML
+//!
+//! DSDT などのテーブルには、デバイスの構成や電源管理の方法が
+//! ACPI Machine Language (AML) というバイトコードで書かれている。
+//! AML を完全に解釈するにはインタプリタが必要だが、
+//! 電源を切るのに必要な `\_S5` オブジェクトは単純な定数のパッケージなので、
+//! バイト列から直接探して読み取る。
+//!
+//! `\_S5` は次のようにエンコードされている。
+//!
+//! ```text
+//! 08                NameOp
+//! (5C)              RootChar `\`（省略されることもある）
+//! 5F 53 35 5F       NameSeg "_S5_"
+//! 12                PackageOp
+//! xx (xx ...)       PkgLength
+//! xx                NumElements
+//! (0A) xx           SLP_TYPa（BytePrefix がつかない場合は ZeroOp / OneOp）
+//! (0A) xx           SLP_TYPb
+//! ...
+//! ```
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/Shutdown
+//! - ACPI Specification, Chapter 20 "ACPI Machine Language (AML) Specification"
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const PACKAGE_OP: u8 = 0x12;
+const ROOT_CHAR: u8 = b'\\';
+
+/// スリープ状態に入る時に PM1a/PM1b control register の SLP_TYP に書き込む値。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub a: u8,
+    pub b: u8,
+}
+
+/// `aml` から `\_S5` を探し、その SLP_TYPa と SLP_TYPb を返す。
+pub fn find_s5(aml: &[u8]) -> Option<SleepType> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|&(i, name)| name == b"_S5_" && is_name_definition(aml, i))
+        .find_map(|(i, _)| parse_sleep_package(&aml[i + 4..]))
+}
+
+/// `aml[i..]` の NameSeg が `Name(...)` で定義されているものか。
+/// メソッドの中などで参照されているだけの場合は除く。
+fn is_name_definition(aml: &[u8], i: usize) -> bool {
+    match i {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => aml[i - 1] == NAME_OP || (aml[i - 1] == ROOT_CHAR && aml[i - 2] == NAME_OP),
+    }
+}
+
+fn parse_sleep_package(aml: &[u8]) -> Option<SleepType> {
+    if *aml.first()? != PACKAGE_OP {
+        return None;
+    }
+    // PkgLength は先頭バイトの上位 2 bit が後に続くバイト数を表す
+    let pkg_length_bytes = usize::from(*aml.get(1)? >> 6) + 1;
+    // NumElements を読み飛ばす
+    let rest = aml.get(1 + pkg_length_bytes + 1..)?;
+
+    let (a, rest) = parse_integer(rest)?;
+    let (b, _) = parse_integer(rest)?;
+    Some(SleepType { a, b })
+}
+
+/// 整数を読み、SLP_TYP として使う下位 8 bit と残りのバイト列を返す。
+fn parse_integer(aml: &[u8]) -> Option<(u8, &[u8])> {
+    match *aml.first()? {
+        ZERO_OP => Some((0, &aml[1..])),
+        ONE_OP => Some((1, &aml[1..])),
+        BYTE_PREFIX => Some((*aml.get(1)?, aml.get(2..)?)),
+        WORD_PREFIX => Some((*aml.get(1)?, aml.get(3..)?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_find_s5() {
+        serial_print!("test_find_s5... ");
+        // Name (\_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })
+        let aml = [
+            0x10,
+            0x08,
+            NAME_OP,
+            ROOT_CHAR,
+            b'_',
+            b'S',
+            b'5',
+            b'_',
+            PACKAGE_OP,
+            0x0a,
+            0x04,
+            BYTE_PREFIX,
+            0x05,
+            BYTE_PREFIX,
+            0x05,
+            ZERO_OP,
+            ZERO_OP,
+        ];
+        assert_eq!(find_s5(&aml), Some(SleepType { a: 5, b: 5 }));
+
+        // Name (_S5, Package (0x04) { Zero, Zero, Zero, Zero })
+        let aml = [
+            NAME_OP, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x06, 0x04, ZERO_OP, ZERO_OP, ZERO_OP,
+            ZERO_OP,
+        ];
+        assert_eq!(find_s5(&aml), Some(SleepType { a: 0, b: 0 }));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_find_s5_skips_references() {
+        serial_print!("test_find_s5_skips_references... ");
+        // メソッドの中での参照（NameOp がない）は無視する
+        let aml = [
+            0x70, b'_', b'S', b'5', b'_', 0x60, NAME_OP, b'_', b'S', b'5', b'_', PACKAGE_OP, 0x06,
+            0x04, ONE_OP, ONE_OP, ZERO_OP, ZERO_OP,
+        ];
+        assert_eq!(find_s5(&aml), Some(SleepType { a: 1, b: 1 }));
+        assert_eq!(find_s5(b"_S5_"), None);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,104 @@
+//! ## FADT
+//!
+//! Fixed ACPI Description Table (FADT) には、電源管理のためのレジスタの場所などが
+//! 書かれている。シグネチャは `"FACP"` 。
+//! DSDT（AML で書かれた、デバイスの構成を表すテーブル）のアドレスもここに書かれている。
+//!
+//! FADT は ACPI のバージョンによって長さが異なる。
+//! ACPI 1.0 の FADT は `flags` までの 116 バイトで、
+//! それ以降のフィールド（reset register や 64 bit のアドレス）は ACPI 2.0 で追加された。
+//! テーブルの長さを確認してから読む。
+
+use super::{read, GenericAddress, SdtHeader};
+use x86_64::PhysAddr;
+
+/// `flags` の bit 10 。reset register がサポートされている。
+pub const RESET_REG_SUPPORTED: u32 = 1 << 10;
+/// `boot_architecture_flags` の bit 1 。8042 キーボードコントローラがある。
+pub const BOOT_ARCH_8042: u16 = 1 << 1;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub revision: u8,
+    pub firmware_control: PhysAddr,
+    pub dsdt: PhysAddr,
+    /// SCI（ACPI のイベントの割り込み）の IRQ 。
+    pub sci_interrupt: u16,
+    /// ACPI モードに切り替えるためのポート。0 なら最初から ACPI モード。
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub pm1_control_length: u8,
+    /// RTC の「世紀」のレジスタの番号。0 ならない。
+    pub century: u8,
+    pub boot_architecture_flags: u16,
+    pub flags: u32,
+    /// ACPI 2.0 以降で、`flags` に `RESET_REG_SUPPORTED` が立っている場合のみ。
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+/// ACPI 1.0 の FADT の長さ。
+const FADT_V1_LENGTH: u32 = 116;
+/// `reset_value` までの長さ。
+const FADT_RESET_LENGTH: u32 = 129;
+/// `x_dsdt` までの長さ。
+const FADT_X_DSDT_LENGTH: u32 = 148;
+
+impl Fadt {
+    /// `addr` の FADT を読み込む。
+    ///
+    /// ## Safety
+    /// `addr` は checksum を確認した FADT を指している必要がある。
+    pub(super) unsafe fn parse(addr: PhysAddr) -> Fadt {
+        let header: SdtHeader = read(addr);
+        let field = |offset: u64| addr + offset;
+
+        let mut fadt = Fadt {
+            revision: header.revision,
+            firmware_control: PhysAddr::new(u64::from(read::<u32>(field(36)))),
+            dsdt: PhysAddr::new(u64::from(read::<u32>(field(40)))),
+            sci_interrupt: read(field(46)),
+            smi_command_port: read(field(48)),
+            acpi_enable: read(field(52)),
+            acpi_disable: read(field(53)),
+            pm1a_event_block: read(field(56)),
+            pm1b_event_block: read(field(60)),
+            pm1a_control_block: read(field(64)),
+            pm1b_control_block: read(field(68)),
+            pm_timer_block: read(field(76)),
+            pm1_control_length: read(field(89)),
+            century: read(field(108)),
+            boot_architecture_flags: 0,
+            flags: 0,
+            reset_register: None,
+            reset_value: 0,
+        };
+        if header.length < FADT_V1_LENGTH {
+            return fadt;
+        }
+
+        // ACPI 1.0 ではこのフィールドは予約されている
+        if header.revision >= 2 {
+            fadt.boot_architecture_flags = read(field(109));
+        }
+        fadt.flags = read(field(112));
+
+        if header.length >= FADT_RESET_LENGTH && fadt.flags & RESET_REG_SUPPORTED != 0 {
+            fadt.reset_register = Some(read(field(116)));
+            fadt.reset_value = read(field(128));
+        }
+        if header.length >= FADT_X_DSDT_LENGTH {
+            let x_dsdt: u64 = read(field(140));
+            if x_dsdt != 0 {
+                fadt.dsdt = PhysAddr::new(x_dsdt);
+            }
+        }
+        fadt
+    }
+}
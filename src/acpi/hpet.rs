@@ -0,0 +1,43 @@
+//! ## HPET
+//!
+//! High Precision Event Timer (HPET) のテーブル。シグネチャは `"HPET"` 。
+//! HPET のレジスタのアドレスと、タイマーの構成が書かれている。
+
+use super::{read, GenericAddress};
+use x86_64::PhysAddr;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    /// コンパレータ（タイマー）の数。
+    pub comparators: u8,
+    /// メインカウンタが 64 bit か。
+    pub counter_64bit: bool,
+    /// legacy replacement（PIT と RTC の IRQ を置き換える）に対応しているか。
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// periodic mode で設定できる最小の周期（メインカウンタのクロック数）。
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    /// `addr` の HPET テーブルを読み込む。
+    ///
+    /// ## Safety
+    /// `addr` は checksum を確認した HPET テーブルを指している必要がある。
+    pub(super) unsafe fn parse(addr: PhysAddr) -> Hpet {
+        let id: u32 = read(addr + 36u64);
+        Hpet {
+            hardware_revision: id as u8,
+            comparators: ((id >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: id & (1 << 13) != 0,
+            legacy_replacement: id & (1 << 15) != 0,
+            pci_vendor_id: (id >> 16) as u16,
+            base_address: read(addr + 40u64),
+            hpet_number: read(addr + 52u64),
+            minimum_tick: read(addr + 53u64),
+        }
+    }
+}
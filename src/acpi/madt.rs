@@ -0,0 +1,207 @@
+//! ## MADT
+//!
+//! Multiple APIC Description Table (MADT) には、割り込みコントローラの構成が書かれている。
+//! シグネチャは `"APIC"` 。
+//!
+//! ヘッダの後に local APIC のアドレスとフラグが続き、
+//! その後に可変長のエントリーが並ぶ。
+//! 各エントリーは種類（1 バイト）と長さ（1 バイト）から始まる。
+//!
+//! | 種類 | 内容                                  |
+//! |------|---------------------------------------|
+//! | 0    | Processor Local APIC（CPU）            |
+//! | 1    | I/O APIC                              |
+//! | 2    | Interrupt Source Override             |
+//! | 4    | Local APIC NMI                        |
+//! | 5    | Local APIC Address Override           |
+//! | 9    | Processor Local x2APIC（CPU）          |
+//!
+//! ### Interrupt Source Override
+//!
+//! ISA の IRQ は、通常は同じ番号の I/O APIC の入力（Global System Interrupt, GSI）に
+//! 接続されているが、そうでない場合がある。
+//! 例えば QEMU では、PIT の IRQ 0 は GSI 2 に接続されている。
+//! Interrupt Source Override はそのような例外を表す。
+
+use super::{read, SdtHeader};
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::PhysAddr;
+
+/// MADT のフラグ。8259 PIC も搭載されている。
+pub const PCAT_COMPAT: u32 = 1;
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub flags: u32,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptSourceOverride>,
+    pub local_apic_nmis: Vec<LocalApicNmi>,
+}
+
+/// CPU 。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    /// `false` なら使えない CPU 。
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// この I/O APIC の最初の入力の GSI 。
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptSourceOverride {
+    pub bus: u8,
+    /// ISA の IRQ 番号。
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// バスの規定に従う。ISA なら active high 。
+    Conforming,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// バスの規定に従う。ISA なら edge 。
+    Conforming,
+    Edge,
+    Level,
+}
+
+impl InterruptSourceOverride {
+    pub fn polarity(&self) -> Polarity {
+        polarity(self.flags)
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        trigger_mode(self.flags)
+    }
+}
+
+/// local APIC の LINT ピンに接続された NMI 。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    /// `0xff` なら全ての CPU 。
+    pub processor_uid: u8,
+    pub flags: u16,
+    /// LINT0 か LINT1 か。
+    pub lint: u8,
+}
+
+fn polarity(flags: u16) -> Polarity {
+    match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::Conforming,
+    }
+}
+
+fn trigger_mode(flags: u16) -> TriggerMode {
+    match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::Conforming,
+    }
+}
+
+impl Madt {
+    /// `addr` の MADT を読み込む。
+    ///
+    /// ## Safety
+    /// `addr` は checksum を確認した MADT を指している必要がある。
+    pub(super) unsafe fn parse(addr: PhysAddr) -> Madt {
+        let header: SdtHeader = read(addr);
+        let end = addr + u64::from(header.length);
+
+        let fields = addr + mem::size_of::<SdtHeader>();
+        let mut madt = Madt {
+            local_apic_address: PhysAddr::new(u64::from(read::<u32>(fields))),
+            flags: read(fields + 4u64),
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            local_apic_nmis: Vec::new(),
+        };
+
+        let mut entry = fields + 8u64;
+        while entry + 2u64 <= end {
+            let kind: u8 = read(entry);
+            let length: u8 = read(entry + 1u64);
+            if length < 2 {
+                // 壊れたエントリー。これ以上読み進められない
+                break;
+            }
+            madt.parse_entry(kind, entry);
+            entry += u64::from(length);
+        }
+        madt
+    }
+
+    unsafe fn parse_entry(&mut self, kind: u8, entry: PhysAddr) {
+        match kind {
+            0 => {
+                let flags: u32 = read(entry + 4u64);
+                self.processors.push(Processor {
+                    processor_uid: u32::from(read::<u8>(entry + 2u64)),
+                    apic_id: u32::from(read::<u8>(entry + 3u64)),
+                    enabled: flags & 1 != 0,
+                });
+            }
+            1 => self.io_apics.push(IoApic {
+                id: read(entry + 2u64),
+                address: PhysAddr::new(u64::from(read::<u32>(entry + 4u64))),
+                gsi_base: read(entry + 8u64),
+            }),
+            2 => self.overrides.push(InterruptSourceOverride {
+                bus: read(entry + 2u64),
+                source: read(entry + 3u64),
+                gsi: read(entry + 4u64),
+                flags: read(entry + 8u64),
+            }),
+            4 => self.local_apic_nmis.push(LocalApicNmi {
+                processor_uid: read(entry + 2u64),
+                flags: read(entry + 3u64),
+                lint: read(entry + 5u64),
+            }),
+            5 => self.local_apic_address = PhysAddr::new(read(entry + 4u64)),
+            9 => {
+                let flags: u32 = read(entry + 8u64);
+                self.processors.push(Processor {
+                    processor_uid: read(entry + 12u64),
+                    apic_id: read(entry + 4u64),
+                    enabled: flags & 1 != 0,
+                });
+            }
+            // 使わないエントリーは無視する
+            _ => {}
+        }
+    }
+
+    /// ISA の IRQ が接続されている GSI と、そのフラグを返す。
+    pub fn isa_irq(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        self.overrides
+            .iter()
+            .find(|o| o.bus == 0 && o.source == irq)
+            .map(|o| (o.gsi, o.polarity(), o.trigger_mode()))
+            .unwrap_or((
+                u32::from(irq),
+                Polarity::Conforming,
+                TriggerMode::Conforming,
+            ))
+    }
+}
@@ -0,0 +1,54 @@
+//! ## MCFG
+//!
+//! PCI Express の configuration space を memory-mapped I/O でアクセスするための
+//! 領域（ECAM）が書かれたテーブル。シグネチャは `"MCFG"` 。
+//!
+//! QEMU のデフォルトのマシン（i440FX）は PCI Express をサポートしていないので、
+//! このテーブルはない。`-machine q35` を指定すると作られる。
+
+use super::{read, SdtHeader};
+use alloc::vec::Vec;
+use core::mem;
+use x86_64::PhysAddr;
+
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+/// PCI segment group ごとの configuration space の領域。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    pub base_address: PhysAddr,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// エントリーの大きさ。
+const ENTRY_SIZE: u64 = 16;
+
+impl Mcfg {
+    /// `addr` の MCFG を読み込む。
+    ///
+    /// ## Safety
+    /// `addr` は checksum を確認した MCFG を指している必要がある。
+    pub(super) unsafe fn parse(addr: PhysAddr) -> Mcfg {
+        let header: SdtHeader = read(addr);
+        let end = addr + u64::from(header.length);
+        // ヘッダの後に 8 バイトの予約領域がある
+        let mut entry = addr + mem::size_of::<SdtHeader>() + 8u64;
+
+        let mut entries = Vec::new();
+        while entry + ENTRY_SIZE <= end {
+            entries.push(McfgEntry {
+                base_address: PhysAddr::new(read(entry)),
+                segment_group: read(entry + 8u64),
+                start_bus: read(entry + 10u64),
+                end_bus: read(entry + 11u64),
+            });
+            entry += ENTRY_SIZE;
+        }
+        Mcfg { entries }
+    }
+}
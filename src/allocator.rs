@@ -0,0 +1,264 @@
+//! ## Heap
+//!
+//! `Box` や `Vec` などの `alloc` クレートの型を使うには、
+//! `#[global_allocator]` 属性をつけたメモリアロケータが必要になる。
+//! アロケータは `GlobalAlloc` トレイトを実装し、
+//! 要求されたサイズとアラインメントのメモリ領域を heap から切り出して返す。
+//!
+//! ### Heap 領域
+//!
+//! kernel の heap は、仮想アドレス `HEAP_START` から `HEAP_SIZE` バイトの
+//! 固定された領域とする。
+//! `init_heap` でこの領域のページを新しいフレームにマップしてから、
+//! アロケータに渡す。
+//! `HEAP_START` は他の領域（kernel のコードや物理メモリのマップなど）と
+//! 重ならない適当なアドレスを選んでいる。
+//!
+//! ### アロケータの選択
+//!
+//! アロケータの実装は cargo feature でビルド時に選択する。
+//!
+//! | feature                      | アロケータ                      |
+//! |------------------------------|---------------------------------|
+//! | （なし）                     | `linked_list::LinkedListAllocator` |
+//! | `bump_allocator`             | `bump::BumpAllocator`           |
+//! | `fixed_size_block_allocator` | `fixed_size_block::FixedSizeBlockAllocator` |
+//! | `buddy_allocator`            | `buddy::BuddyAllocator`         |
+//!
+//! どのアロケータも `HeapAllocator` トレイトを実装しており、
+//! `Locked` が `GlobalAlloc` への橋渡しと使用量の統計をまとめて行う。
+//! 統計は `stats` で取得でき、`print_stats` で serial に出力できるので、
+//! 同じワークロードでアロケータを比較することができる。
+//!
+//! ### 参照
+//! - https://os.phil-opp.com/heap-allocation/
+//! - https://os.phil-opp.com/allocator-designs/
+
+pub mod buddy;
+pub mod bump;
+pub mod fixed_size_block;
+pub mod linked_list;
+
+use crate::{
+    memory, serial_println,
+    sync::{IrqSafeMutex, IrqSafeMutexGuard},
+};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageTableFlags},
+    VirtAddr,
+};
+
+#[cfg(any(
+    all(feature = "bump_allocator", feature = "fixed_size_block_allocator"),
+    all(feature = "bump_allocator", feature = "buddy_allocator"),
+    all(feature = "fixed_size_block_allocator", feature = "buddy_allocator"),
+))]
+compile_error!("only one allocator feature can be enabled at a time");
+
+#[cfg(feature = "bump_allocator")]
+type SelectedAllocator = bump::BumpAllocator;
+#[cfg(feature = "fixed_size_block_allocator")]
+type SelectedAllocator = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "buddy_allocator")]
+type SelectedAllocator = buddy::BuddyAllocator;
+#[cfg(not(any(
+    feature = "bump_allocator",
+    feature = "fixed_size_block_allocator",
+    feature = "buddy_allocator",
+)))]
+type SelectedAllocator = linked_list::LinkedListAllocator;
+
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+
+#[global_allocator]
+static ALLOCATOR: Locked<SelectedAllocator> = Locked::new(SelectedAllocator::new());
+
+/// heap 領域をマップし、アロケータを初期化する。
+///
+/// `memory::init` の後に、一度だけ呼び出すこと。
+pub fn init_heap() -> Result<(), MapToError> {
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    let heap_end = heap_start + HEAP_SIZE - 1u64;
+    let pages = Page::range_inclusive(
+        Page::containing_address(heap_start),
+        Page::containing_address(heap_end),
+    );
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    for page in pages {
+        memory::map_new(page, flags)?;
+    }
+
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+/// heap のアロケータが実装するトレイト。
+///
+/// `GlobalAlloc` と違って `&mut self` を受け取るので、
+/// 実装側は排他制御を気にしなくてよい。
+pub trait HeapAllocator {
+    /// 統計の表示に使う名前。
+    const NAME: &'static str;
+
+    /// ## Safety
+    /// 与えられた領域が未使用であることを呼び出し側が保証する必要がある。
+    /// また、一度だけ呼び出すこと。
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+
+    /// ## Safety
+    /// `GlobalAlloc::alloc` と同じ。
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8;
+
+    /// ## Safety
+    /// `GlobalAlloc::dealloc` と同じ。
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout);
+
+    /// 割り当てに使える空き領域の合計バイト数。
+    fn free_bytes(&self) -> usize;
+
+    /// 一度に割り当てられる最大のバイト数。
+    fn largest_free_block(&self) -> usize;
+}
+
+/// `GlobalAlloc` のメソッドは `&self` を受け取るので、
+/// アロケータの状態を変更するには内部可変性が必要になる。
+/// `IrqSafeMutex` でラップすることでそれを実現する。
+///
+/// `thread` のスレッドは lock を保持している間に切り替えられることがあるので、
+/// 割り込みを無効にする `IrqSafeMutex` を使う。`spin::Mutex` だと、割り込みを無効にして
+/// heap を使う scheduler が、切り替えられたスレッドの lock を待ち続けてしまう。
+///
+/// 使用量の統計もここで記録する。
+/// 統計の更新はアロケータの lock を保持している間に行うので、
+/// 各値は互いに矛盾しない。
+pub struct Locked<A> {
+    inner: IrqSafeMutex<A>,
+    in_use: AtomicUsize,
+    high_water_mark: AtomicUsize,
+    live_allocations: AtomicUsize,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: IrqSafeMutex::new(inner),
+            in_use: AtomicUsize::new(0),
+            high_water_mark: AtomicUsize::new(0),
+            live_allocations: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSafeMutexGuard<A> {
+        self.inner.lock()
+    }
+}
+
+impl<A: HeapAllocator> Locked<A> {
+    pub fn stats(&self) -> Stats {
+        let allocator = self.lock();
+        let free = allocator.free_bytes();
+        let largest_free_block = allocator.largest_free_block();
+        Stats {
+            allocator: A::NAME,
+            heap_size: HEAP_SIZE,
+            in_use: self.in_use.load(Ordering::Relaxed),
+            high_water_mark: self.high_water_mark.load(Ordering::Relaxed),
+            live_allocations: self.live_allocations.load(Ordering::Relaxed),
+            free,
+            largest_free_block,
+        }
+    }
+}
+
+unsafe impl<A: HeapAllocator> GlobalAlloc for Locked<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        let ptr = allocator.alloc(layout);
+        if !ptr.is_null() {
+            let in_use = self.in_use.load(Ordering::Relaxed) + layout.size();
+            self.in_use.store(in_use, Ordering::Relaxed);
+            if in_use > self.high_water_mark.load(Ordering::Relaxed) {
+                self.high_water_mark.store(in_use, Ordering::Relaxed);
+            }
+            self.live_allocations.fetch_add(1, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        allocator.dealloc(ptr, layout);
+        self.in_use.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.live_allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// heap の使用状況。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub allocator: &'static str,
+    pub heap_size: usize,
+    /// 割り当てられているバイト数（要求されたサイズの合計）。
+    pub in_use: usize,
+    /// 起動してからの `in_use` の最大値。
+    pub high_water_mark: usize,
+    /// 解放されていない割り当ての数。
+    pub live_allocations: usize,
+    /// 割り当てに使える空き領域の合計バイト数。
+    pub free: usize,
+    /// 一度に割り当てられる最大のバイト数。
+    pub largest_free_block: usize,
+}
+
+impl Stats {
+    /// 外部断片化の割合（パーセント）。
+    ///
+    /// 空き領域のうち、最大の空きブロックに含まれない部分の割合。
+    /// 空き領域が1つにまとまっていれば 0 になる。
+    pub fn fragmentation(&self) -> usize {
+        if self.free == 0 {
+            return 0;
+        }
+        (self.free - self.largest_free_block) * 100 / self.free
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap ({} allocator)", self.allocator)?;
+        writeln!(f, "  size          : {} bytes", self.heap_size)?;
+        writeln!(f, "  in use        : {} bytes", self.in_use)?;
+        writeln!(f, "  high water    : {} bytes", self.high_water_mark)?;
+        writeln!(f, "  allocations   : {}", self.live_allocations)?;
+        writeln!(f, "  free          : {} bytes", self.free)?;
+        writeln!(f, "  largest block : {} bytes", self.largest_free_block)?;
+        write!(f, "  fragmentation : {}%", self.fragmentation())
+    }
+}
+
+/// heap の使用状況を返す。
+pub fn stats() -> Stats {
+    ALLOCATOR.stats()
+}
+
+/// heap の使用状況を serial に出力する。
+pub fn print_stats() {
+    serial_println!("{}", stats());
+}
+
+/// `addr` を `align` の倍数に切り上げる。
+///
+/// `align` は 2 の累乗である必要がある。
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,241 @@
+//! ## Buddy Allocator
+//!
+//! heap を 2 の累乗のサイズのブロックに分割して管理するアロケータ。
+//! サイズ `2^order` のブロックを order のブロックと呼ぶ。
+//!
+//! 割り当て時は、要求を満たす最小の order を求め、
+//! その order の空きブロックがなければ、より大きい order のブロックを
+//! 半分ずつに分割（split）して作る。
+//! 分割してできた2つのブロックは互いに「buddy」と呼ばれる。
+//!
+//! 解放時は、解放したブロックの buddy も空いていれば、
+//! 2つを結合（merge）して1つ大きい order のブロックに戻す。
+//! これを繰り返すことで、解放された領域が自動的に大きなブロックにまとまり、
+//! 外部断片化が起きにくい。
+//!
+//! heap の先頭からのオフセットを `offset` とすると、
+//! order のブロックの buddy のオフセットは `offset ^ (1 << order)` で求められる。
+//!
+//! ### 空きブロックの管理
+//!
+//! order ごとに空きブロックの連結リストを持つ。
+//! リストの「次へのポインタ」は空きブロック自身の先頭に書き込む。
+//! 終端は 0 で表す。
+//!
+//! ### 参照
+//! - https://en.wikipedia.org/wiki/Buddy_memory_allocation
+
+use super::HeapAllocator;
+use core::{alloc::Layout, mem, ptr};
+
+/// 最小のブロックの order。
+/// 空きブロックに次へのポインタを書き込むので、`usize` 以上である必要がある。
+const MIN_ORDER: usize = 4;
+/// 最大のブロックの order。(`2^MAX_ORDER` = 64 MiB)
+const MAX_ORDER: usize = 26;
+const ORDERS: usize = MAX_ORDER - MIN_ORDER + 1;
+
+/// リストの終端。
+const NULL: usize = 0;
+
+pub struct BuddyAllocator {
+    heap_start: usize,
+    heap_size: usize,
+    /// order ごとの空きブロックのリストの先頭。インデックスは `order - MIN_ORDER` 。
+    free_lists: [usize; ORDERS],
+}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        BuddyAllocator {
+            heap_start: 0,
+            heap_size: 0,
+            free_lists: [NULL; ORDERS],
+        }
+    }
+
+    /// `layout` を満たすブロックの order を返す。
+    ///
+    /// ブロックはサイズと同じアラインメントで配置されるので、
+    /// アラインメントもサイズとして考えればよい。
+    fn order_for(layout: &Layout) -> Option<usize> {
+        let size = layout
+            .size()
+            .max(layout.align())
+            .max(1 << MIN_ORDER)
+            .checked_next_power_of_two()?;
+        let order = size.trailing_zeros() as usize;
+        if order > MAX_ORDER {
+            None
+        } else {
+            Some(order)
+        }
+    }
+
+    unsafe fn push(&mut self, order: usize, addr: usize) {
+        let head = &mut self.free_lists[order - MIN_ORDER];
+        (addr as *mut usize).write(*head);
+        *head = addr;
+    }
+
+    unsafe fn pop(&mut self, order: usize) -> Option<usize> {
+        let head = &mut self.free_lists[order - MIN_ORDER];
+        if *head == NULL {
+            return None;
+        }
+        let addr = *head;
+        *head = (addr as *const usize).read();
+        Some(addr)
+    }
+
+    /// order のリストから `addr` を取り除く。見つからなければ `false` を返す。
+    unsafe fn remove(&mut self, order: usize, addr: usize) -> bool {
+        let mut link: *mut usize = &mut self.free_lists[order - MIN_ORDER];
+        while *link != NULL {
+            if *link == addr {
+                *link = (addr as *const usize).read();
+                return true;
+            }
+            link = *link as *mut usize;
+        }
+        false
+    }
+
+    /// order ごとの空きブロックの数を列挙する。
+    fn free_blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.free_lists.iter().enumerate().map(|(i, &head)| {
+            let mut count = 0;
+            let mut current = head;
+            while current != NULL {
+                count += 1;
+                current = unsafe { (current as *const usize).read() };
+            }
+            (i + MIN_ORDER, count)
+        })
+    }
+}
+
+impl HeapAllocator for BuddyAllocator {
+    const NAME: &'static str = "buddy";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        assert_eq!(heap_start % (1 << MIN_ORDER), 0);
+        assert!(mem::size_of::<usize>() <= 1 << MIN_ORDER);
+
+        self.heap_start = heap_start;
+        self.heap_size = heap_size;
+
+        // heap を、先頭から大きい順に 2 の累乗のブロックに分割する。
+        // こうすると、各ブロックのオフセットは必ずそのサイズの倍数になる。
+        let mut offset = 0;
+        for order in (MIN_ORDER..=MAX_ORDER).rev() {
+            while offset + (1 << order) <= heap_size {
+                self.push(order, heap_start + offset);
+                offset += 1 << order;
+            }
+        }
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let order = match Self::order_for(&layout) {
+            Some(order) => order,
+            None => return ptr::null_mut(),
+        };
+
+        // 空きブロックがある最小の order を探す
+        let mut current = order;
+        let addr = loop {
+            if current > MAX_ORDER {
+                return ptr::null_mut();
+            }
+            if let Some(addr) = self.pop(current) {
+                break addr;
+            }
+            current += 1;
+        };
+
+        // 必要な order になるまで分割し、後ろ半分を空きブロックとして戻す
+        while current > order {
+            current -= 1;
+            self.push(current, addr + (1 << current));
+        }
+
+        // ブロックのオフセットはサイズの倍数だが、heap_start 自体のアラインメントが
+        // 足りない場合は要求を満たせない
+        if addr % layout.align() != 0 {
+            self.dealloc(addr as *mut u8, layout);
+            return ptr::null_mut();
+        }
+
+        addr as *mut u8
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let mut order = Self::order_for(&layout).expect("invalid layout");
+        let mut offset = ptr as usize - self.heap_start;
+
+        // buddy が空いている限り結合していく
+        while order < MAX_ORDER {
+            let buddy_offset = offset ^ (1 << order);
+            if buddy_offset + (1 << order) > self.heap_size
+                || !self.remove(order, self.heap_start + buddy_offset)
+            {
+                break;
+            }
+            offset = offset.min(buddy_offset);
+            order += 1;
+        }
+
+        self.push(order, self.heap_start + offset);
+    }
+
+    fn free_bytes(&self) -> usize {
+        self.free_blocks()
+            .map(|(order, count)| count << order)
+            .sum()
+    }
+
+    fn largest_free_block(&self) -> usize {
+        self.free_blocks()
+            .filter(|&(_, count)| count > 0)
+            .map(|(order, _)| 1 << order)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+    use alloc::alloc::{alloc, dealloc};
+
+    #[test_case]
+    fn test_buddy_split_and_merge() {
+        serial_print!("test_buddy_split_and_merge... ");
+        let region_layout = Layout::from_size_align(4096, 4096).unwrap();
+        unsafe {
+            let region = alloc(region_layout);
+            let mut allocator = BuddyAllocator::new();
+            allocator.init(region as usize, 4096);
+            assert_eq!(allocator.largest_free_block(), 4096);
+
+            let layout = Layout::from_size_align(100, 8).unwrap();
+            let a = allocator.alloc(layout);
+            let b = allocator.alloc(layout);
+            // 128 バイトのブロックに切り上げられ、buddy 同士になる
+            assert_eq!(b as usize - a as usize, 128);
+            assert_eq!(allocator.free_bytes(), 4096 - 256);
+            assert_eq!(allocator.largest_free_block(), 2048);
+
+            // 両方解放すると元の 1つのブロックに戻る
+            allocator.dealloc(a, layout);
+            allocator.dealloc(b, layout);
+            assert_eq!(allocator.free_bytes(), 4096);
+            assert_eq!(allocator.largest_free_block(), 4096);
+
+            dealloc(region, region_layout);
+        }
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,78 @@
+//! ## Bump Allocator
+//!
+//! 最も単純なアロケータ。
+//! heap の先頭から順番に領域を切り出していき（`next` を増やしていき）、
+//! 解放された領域は再利用しない。
+//! ただし、全ての割り当てが解放された時点で `next` を heap の先頭に戻す。
+//!
+//! 割り当てが非常に高速（ポインタを1つ進めるだけ）なので、
+//! 他のアロケータと比較するためのベンチマークの基準として使う。
+//! 長く生きるオブジェクトが1つでもあると解放された領域が再利用されないので、
+//! 実用には向かない。
+//! そのため `bump_allocator` feature を指定した場合は、
+//! `tests/heap_allocation.rs` の `many_boxes_long_lived` を実行しない。
+//!
+//! ### 参照
+//! - https://os.phil-opp.com/allocator-designs/#bump-allocator
+
+use super::{align_up, HeapAllocator};
+use core::{alloc::Layout, ptr};
+
+pub struct BumpAllocator {
+    heap_start: usize,
+    heap_end: usize,
+    next: usize,
+    allocations: usize,
+}
+
+impl BumpAllocator {
+    pub const fn new() -> Self {
+        BumpAllocator {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+}
+
+impl HeapAllocator for BumpAllocator {
+    const NAME: &'static str = "bump";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let alloc_start = align_up(self.next, layout.align());
+        let alloc_end = match alloc_start.checked_add(layout.size()) {
+            Some(end) => end,
+            None => return ptr::null_mut(),
+        };
+
+        if alloc_end > self.heap_end {
+            ptr::null_mut()
+        } else {
+            self.next = alloc_end;
+            self.allocations += 1;
+            alloc_start as *mut u8
+        }
+    }
+
+    unsafe fn dealloc(&mut self, _ptr: *mut u8, _layout: Layout) {
+        self.allocations -= 1;
+        if self.allocations == 0 {
+            self.next = self.heap_start;
+        }
+    }
+
+    fn free_bytes(&self) -> usize {
+        self.heap_end - self.next
+    }
+
+    fn largest_free_block(&self) -> usize {
+        self.heap_end - self.next
+    }
+}
@@ -0,0 +1,158 @@
+//! ## Fixed-Size Block Allocator
+//!
+//! 割り当てのサイズをいくつかのクラス（`BLOCK_SIZES`）に切り上げ、
+//! クラスごとに空きブロックのリストを持つアロケータ。
+//!
+//! 割り当て時は、要求に合うクラスのリストの先頭からブロックを取り出すだけなので、
+//! linked list allocator のようにリストを走査する必要がなく高速である。
+//! リストが空の場合は、fallback のアロケータから新しいブロックを切り出す。
+//! 解放されたブロックは fallback には返さず、クラスのリストに戻して再利用する。
+//!
+//! 最大のクラスより大きい割り当ては、fallback のアロケータに直接任せる。
+//!
+//! サイズをクラスに切り上げる分だけメモリを無駄にする（内部断片化）が、
+//! kernel の割り当てはほとんどが小さいので、全体としては問題にならない。
+//!
+//! ### 参照
+//! - https://os.phil-opp.com/allocator-designs/#fixed-size-block-allocator
+
+use super::{linked_list::LinkedListAllocator, HeapAllocator};
+use core::{alloc::Layout, mem, ptr};
+
+/// ブロックのサイズのクラス。
+///
+/// ブロックのアラインメントとしても使うので、2 の累乗である必要がある。
+/// また、空きブロックに `ListNode` を格納するので 8 以上である必要がある。
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        FixedSizeBlockAllocator {
+            list_heads: [None, None, None, None, None, None, None, None, None],
+            fallback_allocator: LinkedListAllocator::new(),
+        }
+    }
+
+    /// `layout` に合うクラスのインデックスを返す。
+    fn list_index(layout: &Layout) -> Option<usize> {
+        let required_block_size = layout.size().max(layout.align());
+        BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+    }
+
+    /// 空きブロックの数をクラスごとに列挙する。
+    fn free_blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.list_heads
+            .iter()
+            .zip(BLOCK_SIZES.iter())
+            .map(|(head, &size)| {
+                let mut count = 0;
+                let mut current = head.as_ref();
+                while let Some(node) = current {
+                    count += 1;
+                    current = node.next.as_ref();
+                }
+                (size, count)
+            })
+    }
+}
+
+impl HeapAllocator for FixedSizeBlockAllocator {
+    const NAME: &'static str = "fixed size block";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        match Self::list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // リストが空なので、fallback から新しいブロックを切り出す
+                    let block_size = BLOCK_SIZES[index];
+                    let layout = Layout::from_size_align_unchecked(block_size, block_size);
+                    self.fallback_allocator.alloc(layout)
+                }
+            },
+            None => self.fallback_allocator.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        match Self::list_index(&layout) {
+            Some(index) => {
+                // ブロックのサイズとアラインメントはクラスのサイズなので、
+                // ListNode を格納できる
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                self.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => self.fallback_allocator.dealloc(ptr, layout),
+        }
+    }
+
+    fn free_bytes(&self) -> usize {
+        let blocks: usize = self.free_blocks().map(|(size, count)| size * count).sum();
+        blocks + self.fallback_allocator.free_bytes()
+    }
+
+    fn largest_free_block(&self) -> usize {
+        let largest_block = self
+            .free_blocks()
+            .filter(|&(_, count)| count > 0)
+            .map(|(size, _)| size)
+            .max()
+            .unwrap_or(0);
+        largest_block.max(self.fallback_allocator.largest_free_block())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+    use alloc::alloc::{alloc, dealloc};
+
+    #[test_case]
+    fn test_fixed_size_block_reuse() {
+        serial_print!("test_fixed_size_block_reuse... ");
+        let region_layout = Layout::from_size_align(4096, 4096).unwrap();
+        unsafe {
+            let region = alloc(region_layout);
+            let mut allocator = FixedSizeBlockAllocator::new();
+            allocator.init(region as usize, 4096);
+
+            let layout = Layout::from_size_align(24, 8).unwrap();
+            let a = allocator.alloc(layout);
+            assert!(!a.is_null());
+            assert_eq!(a as usize % 32, 0);
+
+            // 解放したブロックはクラスのリストから再利用される
+            allocator.dealloc(a, layout);
+            assert_eq!(allocator.free_blocks().nth(2), Some((32, 1)));
+            assert_eq!(allocator.alloc(layout), a);
+            assert_eq!(allocator.free_blocks().nth(2), Some((32, 0)));
+
+            dealloc(region, region_layout);
+        }
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,194 @@
+//! ## Linked List Allocator
+//!
+//! 空き領域を連結リストで管理するアロケータ。
+//! リストのノード（領域のサイズと次のノードへのポインタ）は、
+//! 空き領域自身の先頭に書き込むので、管理のための追加のメモリが不要になる。
+//!
+//! 割り当て時はリストを先頭から走査し、要求を満たす最初の領域を使う（first fit）。
+//! 領域の残りが `ListNode` を格納できる大きさなら、新しい空き領域としてリストに戻す。
+//!
+//! 解放時は、領域をアドレス順に並んだリストに挿入し、
+//! 前後の空き領域と隣接していれば結合する。
+//! 結合しないと、小さい領域の割り当てと解放を繰り返すうちに heap が細切れになり、
+//! 大きな領域を割り当てられなくなってしまう。
+//!
+//! ### 参照
+//! - https://os.phil-opp.com/allocator-designs/#linked-list-allocator
+
+use super::{align_up, HeapAllocator};
+use core::{alloc::Layout, mem, ptr};
+
+struct ListNode {
+    size: usize,
+    next: Option<&'static mut ListNode>,
+}
+
+impl ListNode {
+    const fn new(size: usize) -> Self {
+        ListNode { size, next: None }
+    }
+
+    fn start_addr(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end_addr(&self) -> usize {
+        self.start_addr() + self.size
+    }
+}
+
+pub struct LinkedListAllocator {
+    /// 番兵ノード。`head.next` がリストの最初のノード。
+    head: ListNode,
+}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        LinkedListAllocator {
+            head: ListNode::new(0),
+        }
+    }
+
+    /// 空き領域をアドレス順を保ったままリストに追加し、隣接する領域と結合する。
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        // 挿入位置の直前のノードを探す
+        let mut prev = &mut self.head;
+        while prev
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            prev = prev.next.as_mut().unwrap();
+        }
+
+        let mut node = ListNode::new(size);
+        node.next = prev.next.take();
+
+        // 後ろの領域と結合する
+        if let Some(next) = node.next.take() {
+            if addr + size == next.start_addr() {
+                node.size += next.size;
+                node.next = next.next.take();
+            } else {
+                node.next = Some(next);
+            }
+        }
+
+        // 前の領域と結合する（番兵ノードとは結合しない）
+        if prev.size != 0 && prev.end_addr() == addr {
+            prev.size += node.size;
+            prev.next = node.next.take();
+            return;
+        }
+
+        let node_ptr = addr as *mut ListNode;
+        node_ptr.write(node);
+        prev.next = Some(&mut *node_ptr);
+    }
+
+    /// 要求を満たす空き領域をリストから取り除いて返す。
+    ///
+    /// 戻り値は、取り除いた領域と、その中で割り当てに使う開始アドレス。
+    fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if let Ok(alloc_start) = Self::alloc_from_region(region, size, align) {
+                let next = region.next.take();
+                let ret = Some((current.next.take().unwrap(), alloc_start));
+                current.next = next;
+                return ret;
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+        None
+    }
+
+    /// `region` から要求を満たす領域を切り出せるか調べ、切り出す場合の開始アドレスを返す。
+    fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
+        let mut alloc_start = align_up(region.start_addr(), align);
+        // アラインメントのために先頭に生じた隙間も、ListNode を格納できなければ失われてしまう
+        let gap = alloc_start - region.start_addr();
+        if gap > 0 && gap < mem::size_of::<ListNode>() {
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
+        let alloc_end = alloc_start.checked_add(size).ok_or(())?;
+
+        if alloc_end > region.end_addr() {
+            return Err(());
+        }
+
+        // 残りの領域に ListNode を格納できなければ、その領域は失われてしまう
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+            return Err(());
+        }
+
+        Ok(alloc_start)
+    }
+
+    /// 割り当てる領域に `ListNode` を格納できるよう、`layout` を調整する。
+    ///
+    /// 解放された領域はリストに戻されるので、`ListNode` 以上のサイズと
+    /// アラインメントが必要になる。
+    fn size_align(layout: Layout) -> (usize, usize) {
+        let align = layout.align().max(mem::align_of::<ListNode>());
+        let size = align_up(layout.size(), align).max(mem::size_of::<ListNode>());
+        (size, align)
+    }
+
+    /// 空き領域の (開始アドレス, サイズ) を列挙する。
+    fn regions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let mut current = self.head.next.as_ref();
+        core::iter::from_fn(move || {
+            let node = current.take()?;
+            current = node.next.as_ref();
+            Some((node.start_addr(), node.size))
+        })
+    }
+}
+
+impl HeapAllocator for LinkedListAllocator {
+    const NAME: &'static str = "linked list";
+
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.add_free_region(heap_start, heap_size);
+    }
+
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::size_align(layout);
+
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
+            let alloc_end = alloc_start + size;
+
+            // アラインメントのために先頭に生じた隙間と、末尾の残りをリストに戻す
+            if alloc_start > region_start {
+                self.add_free_region(region_start, alloc_start - region_start);
+            }
+            if region_end > alloc_end {
+                self.add_free_region(alloc_end, region_end - alloc_end);
+            }
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size)
+    }
+
+    fn free_bytes(&self) -> usize {
+        self.regions().map(|(_, size)| size).sum()
+    }
+
+    fn largest_free_block(&self) -> usize {
+        self.regions().map(|(_, size)| size).max().unwrap_or(0)
+    }
+}
@@ -0,0 +1,231 @@
+//! ## Backtrace
+//!
+//! panic や例外が発生した時に、そこに至るまでの関数呼び出しの履歴を出力する。
+//!
+//! ### Frame Pointer
+//!
+//! target の設定（`x86_64-atomix.json` の `eliminate-frame-pointer`）で
+//! 全ての関数が frame pointer を使うようにしている。
+//! 関数の先頭では次のように RBP を積むので、
+//!
+//! ```text
+//! push rbp
+//! mov rbp, rsp
+//! ```
+//!
+//! RBP が指すアドレスには呼び出し元の RBP が、その 8 バイト上には戻りアドレスが置かれている。
+//! これを RBP が 0 になるまで辿ると、戻りアドレスの一覧が得られる。
+//! 新しいスレッドは RBP を 0 にして始まる（`thread::context` を参照）。
+//!
+//! 壊れたスタックを辿って Page Fault を起こさないように、
+//! 読む前にアドレスがマップされているかを lock を取らずに確かめる（`memory::is_mapped`）。
+//! user の領域に入ったところや、戻りアドレスが kernel のコードを指していないところで止める。
+//!
+//! ### 例外
+//!
+//! 例外ハンドラから `capture` すると、ハンドラの frame の「戻りアドレス」の位置には
+//! CPU が積んだ error code か RIP があり、例外が発生した場所の RIP は出てこない。
+//! そこで `from_interrupt` は、`InterruptStackFrame` の RIP を最初の要素にし、
+//! ハンドラの frame に保存された（例外が発生した関数の）RBP から辿る。
+//!
+//! ### シンボル
+//!
+//! 戻りアドレスは `symbols` モジュールの表で関数名に変換する。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/Stack_Trace
+
+pub mod symbols;
+
+use crate::{
+    memory::{
+        self,
+        address_space::{USER_END, USER_START},
+    },
+    println, serial_println,
+};
+use core::fmt;
+use x86_64::{structures::idt::InterruptStackFrame, VirtAddr};
+
+/// 記録する戻りアドレスの最大数。VGA の画面に収まるように少なめにしている。
+pub const MAX_DEPTH: usize = 16;
+
+/// 戻りアドレスの一覧。
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    addresses: [u64; MAX_DEPTH],
+    len: usize,
+    /// 最初の要素が戻りアドレスではなく、例外が発生した場所の RIP か。
+    from_interrupt: bool,
+}
+
+impl Backtrace {
+    /// 現在のスタックを辿る。
+    ///
+    /// 最初の要素は `capture` を呼び出した関数の戻りアドレス（呼び出し元の中のアドレス）になる。
+    #[inline(never)]
+    pub fn capture() -> Backtrace {
+        let rbp: u64;
+        unsafe { asm!("mov %rbp, $0" : "=r"(rbp)) };
+        unsafe { Backtrace::walk(rbp) }
+    }
+
+    /// `rbp` から frame pointer を辿る。
+    ///
+    /// ## Safety
+    /// `rbp` はマップされていなくてもよいが、マップされているなら
+    /// frame pointer の連鎖の一部である必要がある。
+    pub unsafe fn walk(rbp: u64) -> Backtrace {
+        let mut backtrace = Backtrace {
+            addresses: [0; MAX_DEPTH],
+            len: 0,
+            from_interrupt: false,
+        };
+        backtrace.extend(rbp);
+        backtrace
+    }
+
+    /// 例外が発生した場所から辿る。
+    ///
+    /// 最初の要素は `stack_frame` の RIP になる。
+    /// `stack_frame` を受け取った割り込みハンドラ（`extern "x86-interrupt" fn`）の中から呼び出すこと。
+    /// ハンドラの frame が見つからなければ、RIP だけを返す。
+    #[inline(never)]
+    pub fn from_interrupt(stack_frame: &InterruptStackFrame) -> Backtrace {
+        let mut backtrace = Backtrace {
+            addresses: [0; MAX_DEPTH],
+            len: 0,
+            from_interrupt: true,
+        };
+        let rip = stack_frame.instruction_pointer.as_u64();
+        if !is_kernel_text(rip) {
+            return backtrace;
+        }
+        backtrace.addresses[0] = rip;
+        backtrace.len = 1;
+
+        let rbp: u64;
+        unsafe { asm!("mov %rbp, $0" : "=r"(rbp)) };
+        if let Some(rbp) = unsafe { find_interrupted_rbp(rbp, stack_frame) } {
+            unsafe { backtrace.extend(rbp) };
+        }
+        backtrace
+    }
+
+    /// `rbp` から辿った戻りアドレスを追加する。
+    ///
+    /// ## Safety
+    /// `walk` と同じ。
+    unsafe fn extend(&mut self, mut rbp: u64) {
+        while self.len < MAX_DEPTH && is_frame(rbp) {
+            let return_address = *((rbp + 8) as *const u64);
+            // 戻りアドレスは call 命令の次を指しているので、1 引いて call 命令の場所で調べる
+            if return_address == 0 || !is_kernel_text(return_address - 1) {
+                break;
+            }
+            self.addresses[self.len] = return_address;
+            self.len += 1;
+            rbp = *(rbp as *const u64);
+        }
+    }
+
+    pub fn addresses(&self) -> &[u64] {
+        &self.addresses[..self.len]
+    }
+}
+
+/// `rbp` から辿って `stack_frame` を受け取った割り込みハンドラの frame を探し、
+/// そこに保存された、割り込まれた関数の RBP を返す。
+///
+/// ハンドラの先頭で積んだ RBP のすぐ上には、CPU が積んだ error code（あれば）と
+/// `stack_frame` がある。
+///
+/// ## Safety
+/// `walk` と同じ。
+unsafe fn find_interrupted_rbp(mut rbp: u64, stack_frame: &InterruptStackFrame) -> Option<u64> {
+    let frame = stack_frame as *const InterruptStackFrame as u64;
+    for _ in 0..MAX_DEPTH {
+        if !is_frame(rbp) {
+            return None;
+        }
+        if rbp + 8 == frame || rbp + 16 == frame {
+            return Some(*(rbp as *const u64));
+        }
+        rbp = *(rbp as *const u64);
+    }
+    None
+}
+
+/// `address` が kernel のコードの中にあるか。
+///
+/// シンボルの表があれば、いずれかの関数に含まれているかで判断する。
+/// なければ、マップされた kernel の領域にあるかだけを確かめる。
+fn is_kernel_text(address: u64) -> bool {
+    if (USER_START..USER_END).contains(&address) {
+        return false;
+    }
+    match symbols::kernel() {
+        Some(table) => table.lookup(address).is_some(),
+        None => VirtAddr::try_new(address).map_or(false, memory::is_mapped),
+    }
+}
+
+/// `rbp` が読める stack frame を指しているか。
+fn is_frame(rbp: u64) -> bool {
+    if rbp == 0 || rbp % 8 != 0 || rbp > u64::max_value() - 16 {
+        return false;
+    }
+    if (USER_START..USER_END).contains(&rbp) {
+        return false;
+    }
+    // RBP と戻りアドレスの両方が読めること
+    [rbp, rbp + 8]
+        .iter()
+        .all(|&addr| match VirtAddr::try_new(addr) {
+            Ok(addr) => memory::is_mapped(addr),
+            Err(_) => false,
+        })
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let table = symbols::kernel();
+        write!(f, "BACKTRACE :")?;
+        if self.len == 0 {
+            write!(f, " (empty)")?;
+        }
+        for (i, &address) in self.addresses().iter().enumerate() {
+            write!(f, "\n  #{:<2} {:#018x} ", i, address)?;
+            // 戻りアドレスは call 命令の次を指しているので、1 引いて call 命令の関数を探す。
+            // `from_interrupt` の最初の要素は RIP なので、そのまま探す
+            let call_site = if i == 0 && self.from_interrupt {
+                address
+            } else {
+                address - 1
+            };
+            match table.and_then(|table| table.lookup(call_site)) {
+                Some(symbol) => write!(f, "{}+{:#x}", symbol.name, address - symbol.address)?,
+                None => write!(f, "??")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 例外が発生した場所からの backtrace を VGA と serial の両方に出力する。
+///
+/// `Backtrace::from_interrupt` と同じく、割り込みハンドラの中から呼び出すこと。
+#[inline(never)]
+pub fn print_interrupted(stack_frame: &InterruptStackFrame) {
+    let backtrace = Backtrace::from_interrupt(stack_frame);
+    println!("{}", backtrace);
+    serial_println!("{}", backtrace);
+}
+
+/// 現在のスタックの backtrace を VGA と serial の両方に出力する。
+#[inline(never)]
+pub fn print() {
+    let backtrace = Backtrace::capture();
+    println!("{}", backtrace);
+    serial_println!("{}", backtrace);
+}
@@ -0,0 +1,214 @@
+//! ## Symbol Table
+//!
+//! kernel の関数のアドレスと名前の表。backtrace の戻りアドレスを関数名に変換するのに使う。
+//!
+//! ### 埋め込み
+//!
+//! 表は kernel の ELF から作るが、kernel をリンクするまで関数のアドレスは決まらない。
+//! そこで、`.kernel_symbols` section に大きさを固定した領域（`KERNEL_SYMBOLS`）を確保しておき、
+//! リンクした後で `tools/embed_symbols.py` が ELF の `.symtab` から表を作って、
+//! この領域を上書きする。領域の大きさは変わらないので、他のアドレスはずれない。
+//!
+//! cargo の runner（`tools/runner.sh`）が bootimage を作る前に上書きするので、
+//! `cargo xrun` や `cargo xtest` では自動的に埋め込まれる。
+//! 上書きされていなければ `kernel` は `None` を返し、backtrace はアドレスだけを出力する。
+//!
+//! ### フォーマット
+//!
+//! 全て little endian 。
+//!
+//! | オフセット | 内容                                                    |
+//! |------------|---------------------------------------------------------|
+//! | 0          | `MAGIC`                                                 |
+//! | 8          | シンボルの数 (u32)                                      |
+//! | 12         | 名前の領域の先頭のオフセット (u32)                      |
+//! | 16         | シンボル (アドレス u64, 大きさ u32, 名前のオフセット u32) |
+//! |            | 名前（NUL 終端、demangle 済み）                         |
+//!
+//! シンボルはアドレスの順に並んでいる。
+
+use core::{convert::TryInto, mem, ptr, slice, str};
+
+/// 上書きされた表の先頭の 8 バイト。
+pub const MAGIC: [u8; 8] = *b"SYMTAB01";
+/// 上書きされていない領域の先頭の 8 バイト。
+const EMPTY_MAGIC: [u8; 8] = *b"NOSYMTAB";
+
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+/// 埋め込む表の最大の大きさ。(512 KiB)
+pub const CAPACITY: usize = 512 * 1024;
+
+#[repr(C, align(8))]
+struct RawTable {
+    magic: [u8; 8],
+    data: [u8; CAPACITY - 8],
+}
+
+/// `tools/embed_symbols.py` が上書きする領域。
+///
+/// 中身を 0 だけにすると `.bss` のようにファイル中に領域が確保されないことがあるので、
+/// 先頭に `EMPTY_MAGIC` を置いている。
+#[used]
+#[link_section = ".kernel_symbols"]
+static KERNEL_SYMBOLS: RawTable = RawTable {
+    magic: EMPTY_MAGIC,
+    data: [0; CAPACITY - 8],
+};
+
+/// kernel に埋め込まれた表。埋め込まれていなければ `None` 。
+pub fn kernel() -> Option<SymbolTable<'static>> {
+    // コンパイラは `KERNEL_SYMBOLS` の中身を初期値のまま変わらないとみなして
+    // `MAGIC` の比較を消してしまうかもしれないので、volatile に読んだポインタを通して読む
+    let table = &KERNEL_SYMBOLS as *const RawTable;
+    let table = unsafe { ptr::read_volatile(&table) };
+    let data = unsafe { slice::from_raw_parts(table as *const u8, mem::size_of::<RawTable>()) };
+    SymbolTable::parse(data)
+}
+
+/// アドレスを含む関数。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    /// 関数の先頭のアドレス。
+    pub address: u64,
+    pub size: u64,
+}
+
+/// 検証済みの表。
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolTable<'a> {
+    data: &'a [u8],
+    count: usize,
+    names_offset: usize,
+}
+
+impl<'a> SymbolTable<'a> {
+    /// ヘッダを検証する。`MAGIC` で始まっていなければ `None` 。
+    pub fn parse(data: &'a [u8]) -> Option<SymbolTable<'a>> {
+        if data.len() < HEADER_SIZE || data[0..8] != MAGIC {
+            return None;
+        }
+        let count = read_u32(data, 8) as usize;
+        let names_offset = read_u32(data, 12) as usize;
+        let entries_end = count.checked_mul(ENTRY_SIZE)?.checked_add(HEADER_SIZE)?;
+        if entries_end > names_offset || names_offset > data.len() {
+            return None;
+        }
+        Some(SymbolTable {
+            data,
+            count,
+            names_offset,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// `index` 番目のシンボル。名前が壊れていれば `None` 。
+    pub fn get(&self, index: usize) -> Option<Symbol<'a>> {
+        if index >= self.count {
+            return None;
+        }
+        let base = HEADER_SIZE + index * ENTRY_SIZE;
+        let name_start = self
+            .names_offset
+            .checked_add(read_u32(self.data, base + 12) as usize)?;
+        let names = self.data.get(name_start..)?;
+        let name_len = names.iter().position(|&byte| byte == 0)?;
+        Some(Symbol {
+            name: str::from_utf8(&names[..name_len]).ok()?,
+            address: read_u64(self.data, base),
+            size: u64::from(read_u32(self.data, base + 8)),
+        })
+    }
+
+    /// `address` を含む関数を探す。
+    pub fn lookup(&self, address: u64) -> Option<Symbol<'a>> {
+        // `address` 以下で最後のシンボルを二分探索する
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if read_u64(self.data, HEADER_SIZE + mid * ENTRY_SIZE) <= address {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let symbol = self.get(low.checked_sub(1)?)?;
+        if address < symbol.address + symbol.size {
+            Some(symbol)
+        } else {
+            None
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    /// `foo` (0x1000..0x1010) と `bar` (0x2000..0x2100) の表を作る。
+    fn table() -> [u8; 64] {
+        let mut data = [0; 64];
+        data[0..8].copy_from_slice(&MAGIC);
+        data[8..12].copy_from_slice(&2u32.to_le_bytes());
+        data[12..16].copy_from_slice(&48u32.to_le_bytes());
+        data[16..24].copy_from_slice(&0x1000u64.to_le_bytes());
+        data[24..28].copy_from_slice(&0x10u32.to_le_bytes());
+        data[28..32].copy_from_slice(&0u32.to_le_bytes());
+        data[32..40].copy_from_slice(&0x2000u64.to_le_bytes());
+        data[40..44].copy_from_slice(&0x100u32.to_le_bytes());
+        data[44..48].copy_from_slice(&4u32.to_le_bytes());
+        data[48..56].copy_from_slice(b"foo\0bar\0");
+        data
+    }
+
+    #[test_case]
+    fn test_lookup() {
+        serial_print!("test_lookup... ");
+        let data = table();
+        let table = SymbolTable::parse(&data).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.lookup(0x1000).map(|symbol| symbol.name), Some("foo"));
+        assert_eq!(table.lookup(0x100f).map(|symbol| symbol.name), Some("foo"));
+        assert_eq!(table.lookup(0x20ff).map(|symbol| symbol.name), Some("bar"));
+        // 関数の間や前後のアドレス
+        assert_eq!(table.lookup(0xfff), None);
+        assert_eq!(table.lookup(0x1010), None);
+        assert_eq!(table.lookup(0x2100), None);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_parse_errors() {
+        serial_print!("test_parse_errors... ");
+        let data = table();
+        assert!(SymbolTable::parse(&data[..8]).is_none());
+
+        let mut bad = data;
+        bad[0..8].copy_from_slice(&EMPTY_MAGIC);
+        assert!(SymbolTable::parse(&bad).is_none());
+
+        // シンボルが名前の領域と重なっている
+        let mut bad = data;
+        bad[8] = 3;
+        assert!(SymbolTable::parse(&bad).is_none());
+        serial_println!("[ok]");
+    }
+}
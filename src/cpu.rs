@@ -0,0 +1,322 @@
+//! ## Per-CPU データ
+//!
+//! GDT や TSS のように、CPU ごとに1つずつ必要なものは `PerCpu` にまとめる。
+//! BSP（最初に起動する CPU）の `PerCpu` は static な領域に置き、
+//! 他の CPU（AP）の `PerCpu` は起動時に heap に割り当てる（`smp` モジュールを参照）。
+//! どちらも一度作ったら解放しないので、`&'static` で参照できる。
+//!
+//! ### GS base
+//!
+//! 実行中の CPU の `PerCpu` は、GS セグメントのベースアドレス（`IA32_GS_BASE` MSR）
+//! を使って見つける。
+//! 64-bit モードではセグメンテーションはほぼ無効になっているが、
+//! FS と GS のベースアドレスだけはアドレス計算に使われる。
+//! `PerCpu` の先頭に自分自身のアドレスを置いておけば、
+//! `mov %gs:0, reg` の1命令で `PerCpu` のアドレスが得られる。
+//!
+//! `syscall` 命令の入口ではスタックを切り替える前にレジスタを壊せないので、
+//! user mode のスタックポインタの退避先（`%gs:8`）と
+//! kernel のスタック（`%gs:16`）も `PerCpu` に置いている。
+//!
+//! ### swapgs
+//!
+//! user mode のプログラムは GS セグメントをロードし直せる（GS base は 0 になる）ので、
+//! user mode から kernel に入った時の GS base は信用できない。
+//! そこで user mode で動いている間は、`PerCpu` のアドレスを `IA32_KERNEL_GS_BASE` MSR に置いておく。
+//! `swapgs` 命令は `IA32_GS_BASE` と `IA32_KERNEL_GS_BASE` を入れ替えるので、
+//! user mode から入る全ての入口で `swapgs` し、user mode に戻る直前にもう一度 `swapgs` する。
+//! こうすると、kernel で動いている間は常に GS base が `PerCpu` を指す。
+//!
+//! - `syscall` と `int 0x80` の入口（`syscall::entry`）
+//! - GDB の入口（`gdb::entry`）
+//! - `x86-interrupt` のハンドラ。先頭で `SwapGsGuard` を作る
+//! - 初めて user mode に入る `user::enter_user_mode`
+//!
+//! 割り込みと例外は、CPU が積んだ CS の RPL が 3 の時だけ `swapgs` する。
+//! NMI などは kernel が `swapgs` した直後にも発生しうるので、
+//! `SwapGsGuard::paranoid` で `IA32_GS_BASE` を読んで判断する。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/SWAPGS
+//! - Intel SDM Vol. 3A, 3.4.4 "Segment Loading Instructions in IA-32e Mode"
+
+use crate::{
+    gdt::{
+        tss::{self, Tss},
+        Gdt,
+    },
+    sync::IrqSafeMutex,
+    thread::stack::Stack,
+};
+use alloc::boxed::Box;
+use core::{
+    mem,
+    sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+use lazy_static::lazy_static;
+use x86_64::{
+    instructions::interrupts,
+    registers::model_specific::Msr,
+    structures::{idt::InterruptStackFrame, paging::mapper::MapToError, tss::TaskStateSegment},
+    VirtAddr,
+};
+
+/// サポートする CPU の最大数。
+pub const MAX_CPUS: usize = 64;
+
+/// `IA32_GS_BASE` MSR 。
+const IA32_GS_BASE: u32 = 0xc000_0101;
+/// `IA32_KERNEL_GS_BASE` MSR 。`swapgs` で `IA32_GS_BASE` と入れ替わる。
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// APIC ID がまだわからないことを表す値。
+const UNKNOWN_APIC_ID: u32 = u32::max_value();
+
+/// 起動した CPU の `PerCpu` 。`index` 番目に置く。
+///
+/// BSP は heap を初期化する前に登録するので、`Vec` は使わない。
+static CPUS: IrqSafeMutex<[Option<&'static PerCpu>; MAX_CPUS]> =
+    IrqSafeMutex::new([None; MAX_CPUS]);
+
+/// 起動した CPU の数。
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref BSP_TSS: Tss = Tss::new(
+        tss::bsp_double_fault_stack_top(),
+        tss::bsp_privilege_stack_top(),
+    );
+    static ref BSP_GDT: Gdt = Gdt::new(&BSP_TSS);
+    static ref BSP: PerCpu = PerCpu::new(0, &BSP_GDT, &BSP_TSS);
+}
+
+/// 1つの CPU が持つデータ。
+#[repr(C)]
+pub struct PerCpu {
+    /// 自分自身のアドレス。`%gs:0` で読めるように先頭に置く。
+    self_ptr: AtomicU64,
+    /// `syscall` の入口で user mode のスタックポインタを退避する場所。(`%gs:8`)
+    user_rsp: AtomicU64,
+    /// `syscall` の入口で切り替える kernel のスタック。(`%gs:16`)
+    kernel_rsp: AtomicU64,
+    index: usize,
+    apic_id: AtomicU32,
+    gdt: &'static Gdt,
+    tss: &'static Tss,
+    /// スタックを持たないスレッドが user mode に入った時に使うスタック。
+    default_kernel_stack: VirtAddr,
+}
+
+impl PerCpu {
+    fn new(index: usize, gdt: &'static Gdt, tss: &'static Tss) -> Self {
+        let default_kernel_stack = tss.get().privilege_stack_table[0];
+        PerCpu {
+            self_ptr: AtomicU64::new(0),
+            user_rsp: AtomicU64::new(0),
+            kernel_rsp: AtomicU64::new(default_kernel_stack.as_u64()),
+            index,
+            apic_id: AtomicU32::new(UNKNOWN_APIC_ID),
+            gdt,
+            tss,
+            default_kernel_stack,
+        }
+    }
+
+    /// 起動した順番。BSP は 0 。
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// local APIC の ID 。APIC を有効にする前は `None` 。
+    pub fn apic_id(&self) -> Option<u32> {
+        match self.apic_id.load(Ordering::Relaxed) {
+            UNKNOWN_APIC_ID => None,
+            id => Some(id),
+        }
+    }
+
+    pub(crate) fn set_apic_id(&self, id: u32) {
+        self.apic_id.store(id, Ordering::Relaxed);
+    }
+
+    pub fn gdt(&self) -> &'static Gdt {
+        self.gdt
+    }
+
+    pub fn tss(&self) -> &'static TaskStateSegment {
+        self.tss.get()
+    }
+
+    /// user mode から kernel に入る時に使うスタックを `top` に切り替える。
+    /// `None` ならこの CPU のデフォルトのスタックを使う。
+    ///
+    /// 割り込み（TSS の RSP0）と `syscall` の両方に反映する。
+    ///
+    /// ## Safety
+    /// この CPU で、割り込みを無効にした状態で呼び出すこと。
+    pub(crate) unsafe fn set_kernel_stack(&self, top: Option<VirtAddr>) {
+        let top = top.unwrap_or(self.default_kernel_stack);
+        self.tss.set_privilege_stack(top);
+        self.kernel_rsp.store(top.as_u64(), Ordering::Relaxed);
+    }
+
+    /// 現在の CPU に GDT と TSS をロードし、GS base をこの `PerCpu` に向ける。
+    ///
+    /// 1つの `PerCpu` は1つの CPU でしかロードしてはいけない。
+    pub(crate) fn load(&'static self) {
+        self.gdt.load();
+
+        let addr = self as *const PerCpu as u64;
+        self.self_ptr.store(addr, Ordering::SeqCst);
+        unsafe {
+            Msr::new(IA32_GS_BASE).write(addr);
+            // user mode に入る時に `swapgs` で GS base になる値
+            Msr::new(IA32_KERNEL_GS_BASE).write(0);
+        }
+    }
+}
+
+/// 割り込みハンドラで、user mode から入った時だけ `swapgs` し、drop で元に戻す。
+///
+/// `x86-interrupt` のハンドラの先頭で作り、ハンドラの最後まで保持すること。
+/// それより前に `current` などで GS を使ってはいけない。
+pub(crate) struct SwapGsGuard {
+    swapped: bool,
+}
+
+impl SwapGsGuard {
+    /// `stack_frame` の CS の RPL が 3 なら `swapgs` する。
+    #[inline(always)]
+    pub(crate) fn new(stack_frame: &InterruptStackFrame) -> Self {
+        SwapGsGuard::swap_if(stack_frame.code_segment & 3 == 3)
+    }
+
+    /// GS base が `PerCpu` を指していなければ `swapgs` する。
+    ///
+    /// NMI や Double Fault のように、kernel が user mode に戻る直前の
+    /// `swapgs` と `iretq` の間にも発生しうる例外で使う。
+    /// user mode の GS base は 0 にしかならないので、0 なら user のものとみなす。
+    #[inline(always)]
+    pub(crate) fn paranoid() -> Self {
+        SwapGsGuard::swap_if(unsafe { Msr::new(IA32_GS_BASE).read() } == 0)
+    }
+
+    #[inline(always)]
+    fn swap_if(swap: bool) -> Self {
+        if swap {
+            unsafe { swapgs() };
+        }
+        SwapGsGuard { swapped: swap }
+    }
+}
+
+impl Drop for SwapGsGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if self.swapped {
+            // `iretq` するまで割り込まれないようにする。RFLAGS は `iretq` で戻る
+            interrupts::disable();
+            unsafe { swapgs() };
+        }
+    }
+}
+
+/// `IA32_GS_BASE` と `IA32_KERNEL_GS_BASE` を入れ替える。
+///
+/// ## Safety
+/// kernel と user mode の境界でのみ使うこと。
+#[inline(always)]
+pub(crate) unsafe fn swapgs() {
+    asm!("swapgs" ::: "memory" : "volatile");
+}
+
+/// BSP の `PerCpu` をロードする。
+///
+/// heap を使わないので、起動直後に呼び出せる。
+pub(crate) fn init_bsp() {
+    BSP.load();
+    register(&BSP);
+}
+
+/// AP の `PerCpu` を作る。
+///
+/// TSS の Double Fault 用のスタックと RSP0 のスタックには、
+/// スレッドと同じ guard page 付きのスタックを使う。
+/// AP は停止しないので、作ったものは解放しない。
+/// 起動に成功したら `register` で登録すること。
+pub(crate) fn new_ap() -> Result<&'static PerCpu, MapToError> {
+    let double_fault_stack = Stack::allocate()?;
+    let privilege_stack = Stack::allocate()?;
+    let tss = Tss::new(double_fault_stack.top(), privilege_stack.top());
+    let tss: &'static Tss = Box::leak(Box::new(tss));
+    mem::forget(double_fault_stack);
+    mem::forget(privilege_stack);
+    let gdt: &'static Gdt = Box::leak(Box::new(Gdt::new(tss)));
+    Ok(Box::leak(Box::new(PerCpu::new(count(), gdt, tss))))
+}
+
+/// 起動した CPU として `percpu` を登録する。
+pub(crate) fn register(percpu: &'static PerCpu) {
+    let mut cpus = CPUS.lock();
+    assert_eq!(percpu.index, count(), "CPUs must be registered in order");
+    cpus[percpu.index] = Some(percpu);
+    COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+/// 実行中の CPU の `PerCpu` を返す。
+///
+/// `init` の後でないと使えない。
+pub fn current() -> &'static PerCpu {
+    let ptr: u64;
+    unsafe {
+        asm!("mov %gs:0, $0" : "=r"(ptr) ::: "volatile");
+        &*(ptr as *const PerCpu)
+    }
+}
+
+/// 実行中の CPU の番号。
+pub fn id() -> usize {
+    current().index()
+}
+
+/// 実行中の CPU の番号。GS base を設定する前は `None` 。
+///
+/// ログのように、`init` の前にも呼び出される可能性がある場所で使う。
+pub fn try_id() -> Option<usize> {
+    match unsafe { Msr::new(IA32_GS_BASE).read() } {
+        0 => None,
+        _ => Some(id()),
+    }
+}
+
+/// 起動した CPU の数。
+pub fn count() -> usize {
+    COUNT.load(Ordering::SeqCst)
+}
+
+/// `index` 番目の CPU の `PerCpu` を返す。
+pub fn get(index: usize) -> Option<&'static PerCpu> {
+    CPUS.lock().get(index).and_then(|cpu| *cpu)
+}
+
+/// 起動した全ての CPU の `PerCpu` を返す。
+pub fn iter() -> impl Iterator<Item = &'static PerCpu> {
+    (0..count()).filter_map(get)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_current_is_bsp() {
+        serial_print!("test_current_is_bsp... ");
+        let bsp = current();
+        assert_eq!(bsp.index(), 0);
+        assert_eq!(id(), 0);
+        assert!(core::ptr::eq(get(0).unwrap(), bsp));
+        assert!(core::ptr::eq(bsp, &*BSP));
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,539 @@
+//! ## GDB Stub
+//!
+//! COM2 を通して GDB の remote serial protocol を話し、実行中の kernel を GDB からデバッグできるようにする。
+//!
+//! QEMU の2つめの `-serial` を TCP のポートにつなぎ、GDB から接続する。
+//!
+//! ```text
+//! qemu-system-x86_64 ... -serial stdio -serial tcp::1234,server,nowait
+//! gdb target/x86_64-atomix/debug/atomix -ex 'target remote :1234'
+//! ```
+//!
+//! COM2 がなければ（QEMU に `-serial` が1つしか渡されていなければ）スタブは何もしない。
+//!
+//! ### 止まるタイミング
+//!
+//! - GDB からデータが届いた時。COM2 の受信の割り込み（IRQ 3）で、割り込まれた場所で止まる。
+//!   GDB の最初の接続や Ctrl-C（`0x03`）はこれで受け取る。
+//! - GDB が設定した software breakpoint（`int3`）を実行した時。
+//! - single step（RFLAGS の TF）で1命令実行した時。Debug 例外が発生する。
+//! - panic した時。`stop_on_panic` が `int3` を実行し、GDB が接続するのを待つ。
+//!
+//! GDB が接続していない時の `int3` と Debug 例外は、今までどおりレポートを出力して続行する。
+//!
+//! 止まっている間は、GDB から `c`（continue）か `s`（step）を受け取るまで
+//! 割り込みを無効にしたままパケットを処理する。
+//! 他の CPU は止めないので、他の CPU で動いているスレッドは実行され続ける。
+//!
+//! ### 対応しているパケット
+//!
+//! | パケット            | 意味                                  |
+//! |---------------------|---------------------------------------|
+//! | `?`                 | 止まった理由                          |
+//! | `g` / `G`           | 全てのレジスタの読み書き              |
+//! | `p n` / `P n=v`     | 1つのレジスタの読み書き               |
+//! | `m a,l` / `M a,l:x` | メモリの読み書き                      |
+//! | `c [a]` / `s [a]`   | 実行を再開する / 1命令だけ実行する    |
+//! | `Z0,a,k` / `z0,a,k` | software breakpoint の設定と解除      |
+//! | `D` / `k`           | 切断する。breakpoint は全て解除する   |
+//!
+//! 対応していないパケットには空のパケットを返す。
+//!
+//! ### 参照
+//! - https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+//! - https://wiki.osdev.org/Kernel_Debugging
+
+pub mod entry;
+pub mod packet;
+pub mod uart;
+
+pub use self::entry::{breakpoint_handler, debug_handler, serial_interrupt_handler};
+
+use self::{
+    entry::TrapFrame,
+    packet::{Buffer, Connection},
+    uart::Uart,
+};
+use crate::{
+    interrupts::{self, exceptions, pic, InterruptIndex},
+    memory,
+    sync::IrqSafeMutex,
+};
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags},
+    VirtAddr,
+};
+
+/// RFLAGS の Trap Flag 。立っていると1命令ごとに Debug 例外が発生する。
+const TRAP_FLAG: u64 = 1 << 8;
+/// `int3` 命令。
+const INT3: u8 = 0xcc;
+/// 同時に設定できる software breakpoint の数。
+const MAX_BREAKPOINTS: usize = 32;
+
+/// GDB に伝える止まった理由（シグナルの番号）。
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+
+/// COM2 が存在し、初期化したか。
+static PRESENT: AtomicBool = AtomicBool::new(false);
+/// GDB が接続しているか。パケットを受け取ってから、`D` か `k` を受け取るまで。
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+/// panic して GDB が接続するのを待っているか。
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+static STUB: IrqSafeMutex<Stub> = IrqSafeMutex::new(Stub {
+    uart: unsafe { Uart::new(uart::COM2) },
+    breakpoints: [None; MAX_BREAKPOINTS],
+});
+
+/// スタブに入った原因。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    Breakpoint,
+    Debug,
+    /// COM2 の受信の割り込み。
+    Interrupt,
+}
+
+/// `int3` で置き換えたバイト。
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+    original: u8,
+}
+
+struct Stub {
+    uart: Uart,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+}
+
+/// COM2 があれば初期化し、受信の割り込みを有効にする。
+///
+/// `interrupts::pic::init` の後に呼び出すこと。
+/// APIC に切り替えた時は `interrupts::apic::init` が IRQ 3 を配送する。
+pub fn init() {
+    let mut stub = STUB.lock();
+    if !stub.uart.is_present() {
+        return;
+    }
+    stub.uart.init();
+    pic::unmask(InterruptIndex::Com2.as_u8() - pic::PIC_1_OFFSET);
+    PRESENT.store(true, Ordering::SeqCst);
+}
+
+/// COM2 が使えるか。
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::SeqCst)
+}
+
+/// panic handler から呼び出し、GDB が接続して調べられるように止まる。
+///
+/// COM2 がなければすぐに戻る。GDB から `c` で再開しても、また止まる。
+pub fn stop_on_panic() {
+    if !is_present() {
+        return;
+    }
+    PANICKED.store(true, Ordering::SeqCst);
+    log::warn!("waiting for GDB on COM2");
+    loop {
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+/// 入口（`entry` モジュール）から呼び出される。
+pub(crate) fn handle_trap(trap: Trap, frame: &mut TrapFrame) {
+    let debugging =
+        is_present() && (ATTACHED.load(Ordering::SeqCst) || PANICKED.load(Ordering::SeqCst));
+    match trap {
+        Trap::Interrupt => {
+            interrupts::end_of_interrupt(InterruptIndex::Com2);
+            if is_present() {
+                let mut stub = STUB.lock();
+                match stub.uart.peek_byte() {
+                    // Ctrl-C 。GDB は止まった理由が送られてくるのを待っている
+                    Some(0x03) => {
+                        stub.uart.try_read_byte();
+                        stub.session(frame, SIGINT, true);
+                    }
+                    Some(_) => stub.session(frame, SIGINT, false),
+                    None => {}
+                }
+            }
+        }
+        Trap::Breakpoint => {
+            if !debugging {
+                exceptions::report(exceptions::BREAKPOINT, None, frame.stack_frame());
+                return;
+            }
+            let mut stub = STUB.lock();
+            // GDB が設定した breakpoint なら、`int3` の位置に戻す
+            let address = frame.rip.wrapping_sub(1);
+            if stub.breakpoint(address).is_some() {
+                frame.rip = address;
+            }
+            let signal = if PANICKED.load(Ordering::SeqCst) {
+                SIGABRT
+            } else {
+                SIGTRAP
+            };
+            let attached = ATTACHED.load(Ordering::SeqCst);
+            stub.session(frame, signal, attached);
+        }
+        Trap::Debug => {
+            frame.rflags &= !TRAP_FLAG;
+            if !debugging {
+                exceptions::report(exceptions::DEBUG, None, frame.stack_frame());
+                return;
+            }
+            STUB.lock().session(frame, SIGTRAP, true);
+        }
+    }
+}
+
+impl Stub {
+    /// `c` か `s` を受け取るまでパケットを処理する。
+    ///
+    /// `report` なら、最初に止まった理由を送る。
+    fn session(&mut self, frame: &mut TrapFrame, signal: u8, report: bool) {
+        let mut request = Buffer::new();
+        let mut response = Buffer::new();
+        if report {
+            let _ = write!(response, "S{:02x}", signal);
+            packet::write_packet(&mut self.uart, response.as_bytes());
+        }
+
+        loop {
+            packet::read_packet(&mut self.uart, &mut request);
+            ATTACHED.store(true, Ordering::SeqCst);
+            response.clear();
+
+            let data = request.as_bytes();
+            let (&command, args) = match data.split_first() {
+                Some(split) => split,
+                None => continue,
+            };
+            match command {
+                b'?' => {
+                    let _ = write!(response, "S{:02x}", signal);
+                }
+                b'g' => {
+                    for index in 0..REGISTER_COUNT {
+                        if let Some((value, size)) = register(frame, index) {
+                            response.push_le(value, size);
+                        }
+                    }
+                }
+                b'G' => {
+                    let mut rest = args;
+                    for index in 0..REGISTER_COUNT {
+                        let size = register_size(index) * 2;
+                        if rest.len() < size {
+                            break;
+                        }
+                        if let Some(value) = packet::parse_le(&rest[..size]) {
+                            set_register(frame, index, value);
+                        }
+                        rest = &rest[size..];
+                    }
+                    response.push(b"OK");
+                }
+                b'p' => match packet::parse_hex(args).and_then(|i| register(frame, i as usize)) {
+                    Some((value, size)) => response.push_le(value, size),
+                    None => response.push(b"E01"),
+                },
+                b'P' => {
+                    let done = split(args, b'=').and_then(|(index, value)| {
+                        let index = packet::parse_hex(index)? as usize;
+                        let value = packet::parse_le(value)?;
+                        if set_register(frame, index, value) {
+                            Some(())
+                        } else {
+                            None
+                        }
+                    });
+                    response.push(status(done, b"E01"));
+                }
+                b'm' => match parse_range(args) {
+                    Some((address, len)) => {
+                        let len = len.min(response.remaining() as u64 / 2);
+                        if readable(address, len) {
+                            for i in 0..len {
+                                let byte = unsafe { *((address + i) as *const u8) };
+                                response.push_hex(byte);
+                            }
+                        } else {
+                            response.push(b"E14");
+                        }
+                    }
+                    None => response.push(b"E01"),
+                },
+                b'M' => {
+                    let written = split(args, b':').and_then(|(range, bytes)| {
+                        let (address, len) = parse_range(range)?;
+                        if bytes.len() as u64 != len * 2 || !readable(address, len) {
+                            return None;
+                        }
+                        for (i, pair) in bytes.chunks(2).enumerate() {
+                            let byte = packet::parse_hex(pair)? as u8;
+                            unsafe { write_byte(address + i as u64, byte) };
+                        }
+                        Some(())
+                    });
+                    response.push(status(written, b"E14"));
+                }
+                b'c' | b's' => {
+                    if let Some(address) = packet::parse_hex(args) {
+                        frame.rip = address;
+                    }
+                    if command == b's' {
+                        frame.rflags |= TRAP_FLAG;
+                    } else {
+                        frame.rflags &= !TRAP_FLAG;
+                    }
+                    return;
+                }
+                b'Z' | b'z' => {
+                    // software breakpoint（`0`）だけに対応する
+                    if args.first() == Some(&b'0') {
+                        let address = args
+                            .get(2..)
+                            .and_then(|args| split(args, b',').map(|(address, _)| address))
+                            .and_then(packet::parse_hex);
+                        let done = address.and_then(|address| {
+                            if command == b'Z' {
+                                self.insert_breakpoint(address)
+                            } else {
+                                self.remove_breakpoint(address)
+                            }
+                        });
+                        response.push(status(done, b"E01"));
+                    }
+                }
+                b'D' | b'k' => {
+                    self.remove_all_breakpoints();
+                    frame.rflags &= !TRAP_FLAG;
+                    ATTACHED.store(false, Ordering::SeqCst);
+                    // `k` には返事をしない
+                    if command == b'D' {
+                        packet::write_packet(&mut self.uart, b"OK");
+                    }
+                    return;
+                }
+                b'q' => {
+                    if args.starts_with(b"Supported") {
+                        let _ = write!(response, "PacketSize={:x}", packet::PACKET_SIZE);
+                    } else if args.starts_with(b"Attached") {
+                        // 既に動いている kernel に接続したので、切断しても kernel は終了しない
+                        response.push(b"1");
+                    }
+                }
+                // スレッドは区別しないので、どのスレッドを選んでも OK を返す
+                b'H' | b'T' => response.push(b"OK"),
+                _ => {}
+            }
+            packet::write_packet(&mut self.uart, response.as_bytes());
+        }
+    }
+
+    fn breakpoint(&self, address: u64) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .position(|breakpoint| match breakpoint {
+                Some(breakpoint) => breakpoint.address == address,
+                None => false,
+            })
+    }
+
+    fn insert_breakpoint(&mut self, address: u64) -> Option<()> {
+        if self.breakpoint(address).is_some() {
+            return Some(());
+        }
+        if !readable(address, 1) {
+            return None;
+        }
+        let slot = self.breakpoints.iter().position(Option::is_none)?;
+        let original = unsafe { *(address as *const u8) };
+        unsafe { write_byte(address, INT3) };
+        self.breakpoints[slot] = Some(Breakpoint { address, original });
+        Some(())
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) -> Option<()> {
+        let slot = self.breakpoint(address)?;
+        if let Some(breakpoint) = self.breakpoints[slot].take() {
+            unsafe { write_byte(breakpoint.address, breakpoint.original) };
+        }
+        Some(())
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(breakpoint) = slot.take() {
+                unsafe { write_byte(breakpoint.address, breakpoint.original) };
+            }
+        }
+    }
+}
+
+/// GDB の amd64 のレジスタの数。
+///
+/// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8..r15, rip が 8 バイト、
+/// eflags, cs, ss, ds, es, fs, gs が 4 バイト。
+const REGISTER_COUNT: usize = 24;
+
+fn register_size(index: usize) -> usize {
+    if index <= 16 {
+        8
+    } else {
+        4
+    }
+}
+
+/// `index` 番目のレジスタの値と大きさ。
+fn register(frame: &TrapFrame, index: usize) -> Option<(u64, usize)> {
+    let value = match index {
+        0 => frame.rax,
+        1 => frame.rbx,
+        2 => frame.rcx,
+        3 => frame.rdx,
+        4 => frame.rsi,
+        5 => frame.rdi,
+        6 => frame.rbp,
+        7 => frame.rsp,
+        8 => frame.r8,
+        9 => frame.r9,
+        10 => frame.r10,
+        11 => frame.r11,
+        12 => frame.r12,
+        13 => frame.r13,
+        14 => frame.r14,
+        15 => frame.r15,
+        16 => frame.rip,
+        17 => frame.rflags,
+        18 => frame.cs,
+        19 => frame.ss,
+        20..=23 => u64::from(data_segment(index)),
+        _ => return None,
+    };
+    Some((value, register_size(index)))
+}
+
+/// `index` 番目のレジスタを書き換える。
+///
+/// セグメントレジスタを書き換えると kernel が動かなくなるので、無視して `true` を返す。
+fn set_register(frame: &mut TrapFrame, index: usize, value: u64) -> bool {
+    let register = match index {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => &mut frame.rflags,
+        18..=23 => return true,
+        _ => return false,
+    };
+    *register = value;
+    true
+}
+
+/// DS, ES, FS, GS の値。入口では保存しないので、現在の値を読む。
+fn data_segment(index: usize) -> u16 {
+    let value: u16;
+    unsafe {
+        match index {
+            20 => asm!("mov %ds, $0" : "=r"(value)),
+            21 => asm!("mov %es, $0" : "=r"(value)),
+            22 => asm!("mov %fs, $0" : "=r"(value)),
+            _ => asm!("mov %gs, $0" : "=r"(value)),
+        }
+    }
+    value
+}
+
+/// 成功したら `OK` 、失敗したら `error` 。
+fn status(result: Option<()>, error: &'static [u8]) -> &'static [u8] {
+    match result {
+        Some(()) => b"OK",
+        None => error,
+    }
+}
+
+/// `a,l` の形のアドレスと長さ。
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let (address, len) = split(args, b',')?;
+    Some((packet::parse_hex(address)?, packet::parse_hex(len)?))
+}
+
+/// `s` を最初の `separator` で2つに分ける。
+fn split(s: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let position = s.iter().position(|&c| c == separator)?;
+    Some((&s[..position], &s[position + 1..]))
+}
+
+/// `address` から `len` バイトが全てマップされているか。
+fn readable(address: u64, len: u64) -> bool {
+    let end = match address.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    // ページごとに1回ずつ確かめる
+    let mut page = address & !0xfff;
+    while page < end {
+        match VirtAddr::try_new(page) {
+            Ok(page) if memory::is_mapped(page) => {}
+            _ => return false,
+        }
+        page += 0x1000;
+    }
+    true
+}
+
+/// 読み込み専用のページ（kernel のコードなど）にも書き込めるように、
+/// CR0 の WP を一時的に外して 1 バイト書き込む。
+///
+/// ## Safety
+/// `address` はマップされている必要がある。
+unsafe fn write_byte(address: u64, value: u8) {
+    let cr0 = Cr0::read();
+    Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+    core::ptr::write_volatile(address as *mut u8, value);
+    Cr0::write(cr0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_registers() {
+        serial_print!("test_registers... ");
+        let mut frame: TrapFrame = unsafe { core::mem::zeroed() };
+        frame.rsp = 0x1234;
+        assert_eq!(register(&frame, 7), Some((0x1234, 8)));
+        assert!(set_register(&mut frame, 16, 0x5678));
+        assert_eq!(frame.rip, 0x5678);
+        assert_eq!(register(&frame, 17).map(|(_, size)| size), Some(4));
+        assert_eq!(register(&frame, REGISTER_COUNT), None);
+        assert!(!set_register(&mut frame, REGISTER_COUNT, 0));
+        serial_println!("[ok]");
+    }
+}
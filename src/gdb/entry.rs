@@ -0,0 +1,160 @@
+//! ## Entry
+//!
+//! Breakpoint と Debug 例外、COM2 の割り込みの入口。
+//!
+//! GDB は全ての汎用レジスタを読み書きするので、`x86-interrupt` の関数は使えない。
+//! アセンブリで全ての汎用レジスタを `TrapFrame` の順に push し、そのアドレスを渡して
+//! Rust の関数を呼び出す。戻ってきたら（書き換えられているかもしれない）レジスタを
+//! 元に戻して `iretq` する。RIP, RFLAGS, RSP は CPU が push した値を書き換える。
+//!
+//! user mode から入った時（CPU が push した CS の RPL が 3 の時）は、
+//! 他の入口と同じく `swapgs` してから Rust の関数を呼び出し、`iretq` の前に元に戻す。
+//!
+//! 割り込みの直前に CPU は RSP を 16 バイトに揃えるので、
+//! 5 つの値を push した後の RSP は 8 バイトずれている。
+//! 原因と 15 個のレジスタを push するとまた 8 バイトずれるので、
+//! `call` の前に 8 バイト下げる。
+
+use super::Trap;
+use core::mem;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+
+global_asm!(
+    r#"
+.global atomix_gdb_breakpoint_entry
+atomix_gdb_breakpoint_entry:
+    pushq $0
+    jmp atomix_gdb_common_entry
+
+.global atomix_gdb_debug_entry
+atomix_gdb_debug_entry:
+    pushq $1
+    jmp atomix_gdb_common_entry
+
+.global atomix_gdb_serial_entry
+atomix_gdb_serial_entry:
+    pushq $2
+    jmp atomix_gdb_common_entry
+
+atomix_gdb_common_entry:
+    testb $3, 16(%rsp)
+    jz 1f
+    swapgs
+1:
+    push %r15
+    push %r14
+    push %r13
+    push %r12
+    push %r11
+    push %r10
+    push %r9
+    push %r8
+    push %rbp
+    push %rdi
+    push %rsi
+    push %rdx
+    push %rcx
+    push %rbx
+    push %rax
+    mov %rsp, %rdi
+    sub $8, %rsp
+    call atomix_gdb_trap_handler
+    add $8, %rsp
+    pop %rax
+    pop %rbx
+    pop %rcx
+    pop %rdx
+    pop %rsi
+    pop %rdi
+    pop %rbp
+    pop %r8
+    pop %r9
+    pop %r10
+    pop %r11
+    pop %r12
+    pop %r13
+    pop %r14
+    pop %r15
+    add $8, %rsp
+    testb $3, 8(%rsp)
+    jz 2f
+    cli
+    swapgs
+2:
+    iretq
+"#
+);
+
+extern "C" {
+    fn atomix_gdb_breakpoint_entry();
+    fn atomix_gdb_debug_entry();
+    fn atomix_gdb_serial_entry();
+}
+
+/// 入口で push する値。
+///
+/// RSP 以外の汎用レジスタ、入口の種類、CPU が push した値の順に並んでいる。
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    /// 0: Breakpoint, 1: Debug, 2: COM2 の割り込み。
+    kind: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// CPU が push した部分を `InterruptStackFrame` として返す。
+    pub fn stack_frame(&self) -> &InterruptStackFrame {
+        // `InterruptStackFrame` は CPU が push する値と同じ順に並んでいる
+        unsafe { &*(&self.rip as *const u64 as *const InterruptStackFrame) }
+    }
+}
+
+/// アセンブリで書いた入口を、`x86-interrupt` の関数として IDT に登録できるようにする。
+fn handler(entry: unsafe extern "C" fn()) -> HandlerFunc {
+    unsafe { mem::transmute(entry) }
+}
+
+/// IDT に登録する Breakpoint 例外のハンドラ。
+pub fn breakpoint_handler() -> HandlerFunc {
+    handler(atomix_gdb_breakpoint_entry)
+}
+
+/// IDT に登録する Debug 例外のハンドラ。
+pub fn debug_handler() -> HandlerFunc {
+    handler(atomix_gdb_debug_entry)
+}
+
+/// IDT に登録する COM2 の割り込みのハンドラ。
+pub fn serial_interrupt_handler() -> HandlerFunc {
+    handler(atomix_gdb_serial_entry)
+}
+
+#[no_mangle]
+extern "C" fn atomix_gdb_trap_handler(frame: &mut TrapFrame) {
+    let trap = match frame.kind {
+        0 => Trap::Breakpoint,
+        1 => Trap::Debug,
+        _ => Trap::Interrupt,
+    };
+    super::handle_trap(trap, frame);
+}
@@ -0,0 +1,259 @@
+//! ## Packet
+//!
+//! GDB remote serial protocol のパケットの送受信。
+//!
+//! パケットは `$<データ>#<チェックサム>` の形をしている。
+//! チェックサムはデータの各バイトの和の下位 8 bit を 16 進数の2文字で表したもの。
+//! 受け取った側は、チェックサムが正しければ `+` を、間違っていれば `-` を返し、
+//! `-` を受け取った側は同じパケットを送り直す。
+//!
+//! データに `$`, `#`, `}`, `*` を含めるにはエスケープが必要だが、
+//! このスタブが送るデータ（16 進数と `OK` など）には現れないので扱わない。
+//!
+//! panic 中にも使えるように、heap は使わずに固定長のバッファを使う。
+
+use core::fmt;
+
+/// パケットのデータの最大の長さ。`qSupported` の `PacketSize` で GDB に伝える。
+pub const PACKET_SIZE: usize = 0x1000;
+
+/// GDB との通信路。
+pub trait Connection {
+    /// 1 バイト受け取るまで待つ。
+    fn read_byte(&mut self) -> u8;
+    fn write_byte(&mut self, byte: u8);
+}
+
+/// パケットのデータを組み立てるバッファ。
+pub struct Buffer {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Buffer {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    /// 残りの長さ。
+    pub fn remaining(&self) -> usize {
+        PACKET_SIZE - self.len
+    }
+
+    /// バッファに収まらない分は捨てる。
+    pub fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(self.remaining());
+        self.data[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    /// `byte` を 16 進数の2文字で追加する。
+    pub fn push_hex(&mut self, byte: u8) {
+        self.push(&[
+            HEX_DIGITS[usize::from(byte >> 4)],
+            HEX_DIGITS[usize::from(byte & 0xf)],
+        ]);
+    }
+
+    /// `value` の下位 `size` バイトを、target の順序（little endian）の 16 進数で追加する。
+    pub fn push_le(&mut self, value: u64, size: usize) {
+        for &byte in &value.to_le_bytes()[..size] {
+            self.push_hex(byte);
+        }
+    }
+}
+
+impl Default for Buffer {
+    fn default() -> Self {
+        Buffer::new()
+    }
+}
+
+impl fmt::Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+const HEX_DIGITS: [u8; 16] = *b"0123456789abcdef";
+
+/// データのチェックサム。
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+/// 16 進数の1文字の値。
+pub fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// 16 進数で書かれた数（big endian）を読む。
+pub fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter().try_fold(0, |value, &c| {
+        hex_value(c).map(|digit| (value << 4) | u64::from(digit))
+    })
+}
+
+/// 16 進数で書かれた target の順序（little endian）の値を読む。
+pub fn parse_le(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() % 2 != 0 || s.len() > 16 {
+        return None;
+    }
+    let mut value = 0;
+    for (i, pair) in s.chunks(2).enumerate() {
+        value |= parse_hex(pair)? << (i * 8);
+    }
+    Some(value)
+}
+
+/// パケットを1つ受け取り、`buffer` にデータを入れる。
+///
+/// パケットの外で受け取ったバイト（`+` や Ctrl-C の `0x03`）は無視する。
+/// チェックサムが間違っていれば `-` を返して、送り直されるのを待つ。
+pub fn read_packet<C: Connection>(conn: &mut C, buffer: &mut Buffer) {
+    loop {
+        while conn.read_byte() != b'$' {}
+
+        buffer.clear();
+        let mut overflow = false;
+        loop {
+            match conn.read_byte() {
+                b'#' => break,
+                // 途中で新しいパケットが始まった
+                b'$' => {
+                    buffer.clear();
+                    overflow = false;
+                }
+                byte => {
+                    if buffer.remaining() == 0 {
+                        overflow = true;
+                    }
+                    buffer.push(&[byte]);
+                }
+            }
+        }
+        let high = hex_value(conn.read_byte());
+        let low = hex_value(conn.read_byte());
+        let expected = match (high, low) {
+            (Some(high), Some(low)) => Some((high << 4) | low),
+            _ => None,
+        };
+
+        if !overflow && expected == Some(checksum(buffer.as_bytes())) {
+            conn.write_byte(b'+');
+            return;
+        }
+        conn.write_byte(b'-');
+    }
+}
+
+/// `data` をパケットとして送り、`+` が返ってくるまで送り直す。
+pub fn write_packet<C: Connection>(conn: &mut C, data: &[u8]) {
+    let sum = checksum(data);
+    loop {
+        conn.write_byte(b'$');
+        for &byte in data {
+            conn.write_byte(byte);
+        }
+        conn.write_byte(b'#');
+        conn.write_byte(HEX_DIGITS[usize::from(sum >> 4)]);
+        conn.write_byte(HEX_DIGITS[usize::from(sum & 0xf)]);
+
+        loop {
+            match conn.read_byte() {
+                b'+' => return,
+                b'-' => break,
+                // GDB が応答を待たずに次のパケットを送ってくることはないので、他は無視する
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    /// `input` を順に返し、書き込まれたバイトを記録する通信路。
+    struct Mock<'a> {
+        input: &'a [u8],
+        output: Buffer,
+    }
+
+    impl<'a> Connection for Mock<'a> {
+        fn read_byte(&mut self) -> u8 {
+            let (&byte, rest) = self.input.split_first().expect("no more input");
+            self.input = rest;
+            byte
+        }
+
+        fn write_byte(&mut self, byte: u8) {
+            self.output.push(&[byte]);
+        }
+    }
+
+    #[test_case]
+    fn test_hex() {
+        serial_print!("test_hex... ");
+        assert_eq!(parse_hex(b"ffff8000"), Some(0xffff_8000));
+        assert_eq!(parse_hex(b"1A"), Some(0x1a));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"1g"), None);
+        assert_eq!(parse_le(b"3412"), Some(0x1234));
+        assert_eq!(parse_le(b"341"), None);
+
+        let mut buffer = Buffer::new();
+        buffer.push_le(0x1234, 4);
+        assert_eq!(buffer.as_bytes(), b"34120000");
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_read_packet() {
+        serial_print!("test_read_packet... ");
+        // 1つめはチェックサムが間違っているので送り直される
+        let mut conn = Mock {
+            input: b"+\x03$g#00$g#67",
+            output: Buffer::new(),
+        };
+        let mut buffer = Buffer::new();
+        read_packet(&mut conn, &mut buffer);
+        assert_eq!(buffer.as_bytes(), b"g");
+        assert_eq!(conn.output.as_bytes(), b"-+");
+        assert!(conn.input.is_empty());
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_write_packet() {
+        serial_print!("test_write_packet... ");
+        let mut conn = Mock {
+            input: b"-+",
+            output: Buffer::new(),
+        };
+        write_packet(&mut conn, b"OK");
+        assert_eq!(conn.output.as_bytes(), b"$OK#9a$OK#9a");
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,120 @@
+//! ## UART
+//!
+//! GDB との通信に使う 16550 UART。
+//!
+//! `uart_16550` クレートは送信しかできないので、受信と状態の確認のために
+//! レジスタを直接操作する。
+//!
+//! | オフセット | レジスタ                                      |
+//! |------------|-----------------------------------------------|
+//! | 0          | データ（DLAB = 1 の時は divisor の下位）       |
+//! | 1          | 割り込みの有効化（DLAB = 1 の時は divisor の上位） |
+//! | 2          | FIFO の制御                                   |
+//! | 3          | line control（DLAB は bit 7）                 |
+//! | 4          | modem control                                 |
+//! | 5          | line status                                   |
+//! | 7          | scratch                                       |
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/Serial_Ports
+
+use super::packet::Connection;
+use x86_64::instructions::port::Port;
+
+/// COM2 の I/O ポート。
+pub const COM2: u16 = 0x2f8;
+
+/// line status : 受信したデータがある。
+const LINE_STATUS_DATA_READY: u8 = 1;
+/// line status : 送信できる。
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+pub struct Uart {
+    base: u16,
+    /// `peek_byte` で読んだが、まだ返していないバイト。
+    peeked: Option<u8>,
+}
+
+impl Uart {
+    /// # Safety
+    /// `base` は UART の I/O ポートである必要がある。
+    pub const unsafe fn new(base: u16) -> Self {
+        Uart { base, peeked: None }
+    }
+
+    fn port(&self, offset: u16) -> Port<u8> {
+        Port::new(self.base + offset)
+    }
+
+    /// UART が存在するか。
+    ///
+    /// 存在しないポートを読むと 0xff が返るので、scratch レジスタに書いた値が読めるかで判断する。
+    pub fn is_present(&self) -> bool {
+        let mut scratch = self.port(7);
+        [0x5a, 0xa5].iter().all(|&value| unsafe {
+            scratch.write(value);
+            scratch.read() == value
+        })
+    }
+
+    /// 115200 bps, 8N1 に設定し、受信した時に割り込みを発生させる。
+    pub fn init(&mut self) {
+        unsafe {
+            self.port(1).write(0x00);
+            // DLAB を立てて divisor を 1 (115200 bps) にする
+            self.port(3).write(0x80);
+            self.port(0).write(0x01);
+            self.port(1).write(0x00);
+            // 8 bit, parity なし, stop bit 1 。DLAB を戻す
+            self.port(3).write(0x03);
+            // FIFO を有効にしてクリアする
+            self.port(2).write(0xc7);
+            // DTR, RTS, OUT2 。OUT2 を立てないと割り込みが届かない
+            self.port(4).write(0x0b);
+            // 受信した時の割り込みを有効にする
+            self.port(1).write(0x01);
+        }
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { self.port(5).read() }
+    }
+
+    /// 受信したバイトがあれば返す。
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        if let Some(byte) = self.peeked.take() {
+            return Some(byte);
+        }
+        if self.line_status() & LINE_STATUS_DATA_READY != 0 {
+            Some(unsafe { self.port(0).read() })
+        } else {
+            None
+        }
+    }
+
+    /// 受信したバイトがあれば、読まずに返す。
+    pub fn peek_byte(&mut self) -> Option<u8> {
+        if self.peeked.is_none() {
+            self.peeked = self.try_read_byte();
+        }
+        self.peeked
+    }
+}
+
+impl Connection for Uart {
+    fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return byte;
+            }
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        while self.line_status() & LINE_STATUS_TRANSMIT_EMPTY == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        unsafe { self.port(0).write(byte) };
+    }
+}
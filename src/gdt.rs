@@ -10,39 +10,116 @@
 //! GlobalDescriptorTable 構造体を作成しているコードをみるとよくわかる。
 //!
 //! TSS については tss モジュールを参照
+//!
+//! ### CPU ごとの GDT
+//!
+//! TSS の descriptor は TSS のアドレスを含み、
+//! `ltr` でロードすると busy フラグが立つので、CPU ごとに別の GDT が必要になる。
+//! `Gdt` は CPU ごとに作り、`cpu::PerCpu` に持たせる。
+//!
+//! ### セグメントの並び
+//!
+//! | selector | セグメント        |
+//! |----------|-------------------|
+//! | 0x08     | kernel code       |
+//! | 0x10     | kernel data       |
+//! | 0x1b     | user data (RPL 3) |
+//! | 0x23     | user code (RPL 3) |
+//! | 0x28     | TSS（2 エントリー分） |
+//!
+//! user data が user code より前にあるのは、`sysret` 命令が
+//! STAR MSR に書いたベースから user data を +8、user code を +16 の位置に
+//! あるものとして扱うため。
+//!
+//! 64-bit モードではベースとリミットは無視されるので、
+//! data セグメントは present, writable と DPL だけが意味を持つ。
 
 pub mod tss;
 
-use lazy_static::lazy_static;
-use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
-
-lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors)= {
-        let mut gdt = GlobalDescriptorTable::new();
-        // code_selectorとtss_selectorはそれぞれ、コード領域とTSS領域が
-        // どのセグメントなのかを指す値。(GDTのインデックス)
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&tss::TSS));
-        (gdt, Selectors { code_selector, tss_selector })
-    };
+use self::tss::Tss;
+use x86_64::{
+    instructions::segmentation,
+    structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+    PrivilegeLevel,
+};
+
+/// kernel data セグメント。(present, DPL 0, writable data)
+const KERNEL_DATA: u64 = 0x00cf_9200_0000_ffff;
+/// user data セグメント。(present, DPL 3, writable data)
+const USER_DATA: u64 = 0x00cf_f200_0000_ffff;
+/// user code セグメント。(present, DPL 3, executable, long mode)
+const USER_CODE: u64 = 0x00af_fa00_0000_ffff;
+
+/// 1つの CPU の GDT 。
+pub struct Gdt {
+    table: GlobalDescriptorTable,
+    selectors: Selectors,
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+/// GDT 上のセグメントを指す値。(GDTのインデックス)
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    /// kernel のコード領域のセグメント。
+    pub code_selector: SegmentSelector,
+    /// kernel のデータ領域のセグメント。
+    pub data_selector: SegmentSelector,
+    /// user mode のデータ領域のセグメント。RPL は 3 。
+    pub user_data_selector: SegmentSelector,
+    /// user mode のコード領域のセグメント。RPL は 3 。
+    pub user_code_selector: SegmentSelector,
+    /// TSS 領域のセグメント。
+    pub tss_selector: SegmentSelector,
 }
 
-pub fn init() {
-    // CPU に GDT をロードする。
-    GDT.0.load();
+impl Gdt {
+    /// `tss` をロードするための GDT を作る。
+    pub fn new(tss: &'static Tss) -> Self {
+        let mut table = GlobalDescriptorTable::new();
+        let code_selector = table.add_entry(Descriptor::kernel_code_segment());
+        let data_selector = table.add_entry(Descriptor::UserSegment(KERNEL_DATA));
+        let user_data_selector = table.add_entry(Descriptor::UserSegment(USER_DATA));
+        let user_code_selector = table.add_entry(Descriptor::UserSegment(USER_CODE));
+        let tss_selector = table.add_entry(Descriptor::tss_segment(tss.get()));
+        Gdt {
+            table,
+            selectors: Selectors {
+                code_selector,
+                data_selector,
+                // ring 3 からロードするセレクタは RPL も 3 にする必要がある
+                user_data_selector: SegmentSelector::new(
+                    user_data_selector.index(),
+                    PrivilegeLevel::Ring3,
+                ),
+                user_code_selector: SegmentSelector::new(
+                    user_code_selector.index(),
+                    PrivilegeLevel::Ring3,
+                ),
+                tss_selector,
+            },
+        }
+    }
+
+    pub fn selectors(&self) -> Selectors {
+        self.selectors
+    }
+
+    /// 現在の CPU にこの GDT と TSS をロードする。
+    ///
+    /// 1つの `Gdt` は1つの CPU でしかロードしてはいけない。
+    pub fn load(&'static self) {
+        // CPU に GDT をロードする。
+        self.table.load();
 
-    unsafe {
-        // 新しいGDTをロードした後でも、code segment registerは
-        // 古い値を保持し続けているのでそれを更新してやる必要がある。
-        x86_64::instructions::segmentation::set_cs(GDT.1.code_selector);
+        unsafe {
+            // 新しいGDTをロードした後でも、code segment registerは
+            // 古い値を保持し続けているのでそれを更新してやる必要がある。
+            segmentation::set_cs(self.selectors.code_selector);
+            // stack segment も bootloader の GDT の値を指したままなので更新する。
+            segmentation::load_ss(self.selectors.data_selector);
 
-        // 新しいGDTを作成したので、CPUにそのGDT上のTSSを使うように指示する。
-        // モジュールドキュメントの仮想手順の最後のステップ。
-        x86_64::instructions::tables::load_tss(GDT.1.tss_selector);
+            // 新しいGDTを作成したので、CPUにそのGDT上のTSSを使うように指示する。
+            // モジュールドキュメントの仮想手順の最後のステップ。
+            x86_64::instructions::tables::load_tss(self.selectors.tss_selector);
+        }
     }
 }
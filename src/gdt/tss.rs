@@ -53,37 +53,105 @@
 //! 具体的には、新しい Segment Descriptor を Global Descriptor Table (GDT)
 //! に追加し、その index とともに `ltr` 命令を実行し、 CPU にロードする。
 //!
+//! ### Privilege Stack Table
+//!
+//! user mode (ring 3) で割り込みや例外が発生すると、CPU は
+//! `privilege_stack_table[0]`（RSP0）に書かれたスタックに切り替えてから
+//! stack frame を push する。
+//! user mode のスタックは信用できないので、kernel 用のスタックが必要になる。
+//!
+//! ### CPU ごとの TSS
+//!
+//! TSS は CPU ごとに1つずつ必要になる。
+//! 同じ IST のスタックを複数の CPU で共有すると、同時に Double Fault が
+//! 発生した時にスタックを壊し合ってしまうので、スタックも CPU ごとに用意する。
+//! BSP のスタックは `BSP_DOUBLE_FAULT_STACK` と `BSP_PRIVILEGE_STACK` を使い、
+//! 他の CPU のスタックは起動時に割り当てる（`cpu` モジュールを参照）。
+//!
 //! ### GDT
 //!
 //! gdtモジュールを参照
 
-use lazy_static::lazy_static;
+use crate::thread::stack::STACK_PAGES;
+use core::cell::UnsafeCell;
 use x86_64::{structures::tss::TaskStateSegment, VirtAddr};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    pub static ref TSS: TaskStateSegment = {
+/// BSP の Double Fault 用のスタックのサイズ。
+///
+/// Double Fault のハンドラはレポートと backtrace を出力してから panic し、
+/// panic handler もまた backtrace を出力するので、余裕を持たせておく。
+const BSP_STACK_SIZE: usize = 4096 * STACK_PAGES as usize;
+/// BSP の RSP0 のスタックのサイズ。
+/// スタックを持たない boot スレッドが user mode に入った時に使う。
+const BSP_PRIVILEGE_STACK_SIZE: usize = 4096 * 4;
+
+/// BSP の Double Fault 用のスタック領域。
+///
+/// heap を初期化する前から使えるように、static な領域として確保する。
+/// immutable な領域として宣言してしまうと、
+/// bootloader はそれを read-only なページにマップしてしまう
+/// かもしれないので、mutable な領域として宣言する必要がある。
+///
+/// また、guard page も設定していないので、
+/// double fault handler はスタックを使い切らないように
+/// 注意する必要がある。
+/// もし使い切ると、その先のメモリ領域を侵食してしまう。
+/// そのため、AP のスタック（`thread::stack::Stack`）と同じ大きさにしている。
+static mut BSP_DOUBLE_FAULT_STACK: [u8; BSP_STACK_SIZE] = [0; BSP_STACK_SIZE];
+
+/// BSP の RSP0 のスタック領域。`BSP_DOUBLE_FAULT_STACK` と同じ理由で static mut にする。
+static mut BSP_PRIVILEGE_STACK: [u8; BSP_PRIVILEGE_STACK_SIZE] = [0; BSP_PRIVILEGE_STACK_SIZE];
+
+/// BSP の Double Fault 用のスタックの末尾のアドレスを返す。
+///
+/// BSP の TSS を作る時に一度だけ使うこと。
+pub fn bsp_double_fault_stack_top() -> VirtAddr {
+    let stack_start = VirtAddr::from_ptr(unsafe { &BSP_DOUBLE_FAULT_STACK });
+    // stack 領域は下に伸びていく
+    stack_start + BSP_STACK_SIZE
+}
+
+/// BSP の RSP0 のスタックの末尾のアドレスを返す。
+///
+/// BSP の TSS を作る時に一度だけ使うこと。
+pub fn bsp_privilege_stack_top() -> VirtAddr {
+    let stack_start = VirtAddr::from_ptr(unsafe { &BSP_PRIVILEGE_STACK });
+    stack_start + BSP_PRIVILEGE_STACK_SIZE
+}
+
+/// 1つの CPU の TSS 。
+///
+/// RSP0 はスレッドを切り替えるたびに、切り替え先のスレッドのスタックに書き換える。
+/// （`thread` のスレッドはそれぞれ自分のスタックを持っているので、
+/// user mode から割り込みで戻ってきた時もそのスタックを使う）
+/// CPU は TSS を読むだけなので、書き換えられるように `UnsafeCell` に入れておく。
+pub struct Tss(UnsafeCell<TaskStateSegment>);
+
+// RSP0 を書き換えるのは、この TSS をロードした CPU だけ
+unsafe impl Sync for Tss {}
+
+impl Tss {
+    /// `double_fault_stack_top` を Double Fault 用のスタック、
+    /// `privilege_stack_top` を RSP0 のスタックとする TSS を作る。
+    pub fn new(double_fault_stack_top: VirtAddr, privilege_stack_top: VirtAddr) -> Self {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
-            // スタック領域を確保。
-            //
-            // immutable な領域として宣言してしまうと、
-            // bootloader はそれを read-only なページにマップしてしまう
-            // かもしれないので、mutable な領域として宣言する必要がある。
-            // これは Rust 的にめっちゃ unsafe なので、後で修正する。
-            //
-            // また、guard page も設定していないので、
-            // double fault handler はスタックを使い切らないように
-            // 注意する必要がある。
-            // もし使い切ると、その先のメモリ領域を侵食してしまう。
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            // stack 領域は上に伸びていく
-            stack_end
-        };
-        tss
-    };
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_top;
+        tss.privilege_stack_table[0] = privilege_stack_top;
+        Tss(UnsafeCell::new(tss))
+    }
+
+    pub fn get(&self) -> &TaskStateSegment {
+        unsafe { &*self.0.get() }
+    }
+
+    /// RSP0 を `top` に書き換える。
+    ///
+    /// ## Safety
+    /// この TSS をロードした CPU で、割り込みを無効にした状態で呼び出すこと。
+    /// 割り込みハンドラが使っているスタックを指定してはいけない。
+    pub unsafe fn set_privilege_stack(&self, top: VirtAddr) {
+        (*self.0.get()).privilege_stack_table[0] = top;
+    }
 }
@@ -51,9 +51,57 @@
 //! `x86-interrupt` 呼び出し規約はまだ安定化されていないので、 `#![feature(abi_x86_interrupt)]` を
 //! `lib.rs` フィアルの先頭に追加する必要がある。
 
-use crate::{gdt::tss, println};
+pub mod apic;
+pub mod exceptions;
+pub mod page_fault;
+pub mod pic;
+pub mod timer;
+
+pub use self::timer::ticks;
+
+use crate::{cpu::SwapGsGuard, gdt::tss};
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::{
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+    PrivilegeLevel,
+};
+
+/// ハードウェア割り込みの IDT 上のインデックス。
+///
+/// PIC によって remap された後のベクタ番号を表す。
+/// 詳しくは `pic` モジュールのドキュメントを参照。
+/// APIC を使う場合も同じベクタ番号で配送するように設定する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = pic::PIC_1_OFFSET,
+    Keyboard,
+    /// COM2 （IRQ 3）。GDB のスタブが使う。
+    Com2 = pic::PIC_1_OFFSET + 3,
+    /// primary PIC の IRQ 7 。spurious interrupt にも使われる。
+    PicPrimarySpurious = pic::PIC_1_OFFSET + 7,
+    /// secondary PIC の IRQ 15 。spurious interrupt にも使われる。
+    PicSecondarySpurious = pic::PIC_2_OFFSET + 7,
+    /// 他の CPU から TLB shootdown を要求する IPI 。
+    TlbShootdown = 0xfc,
+    /// 他の CPU から関数の実行を要求する IPI 。
+    CallFunction = 0xfd,
+    /// local APIC がエラーを検出した時の割り込み。
+    ApicError = 0xfe,
+    /// local APIC の spurious interrupt 。
+    /// 下位 4 bit が 1 である必要があるので、最後のベクタを使う。
+    Spurious = 0xff,
+}
+
+impl InterruptIndex {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
 
 lazy_static! {
     /// 当たり前だが、IDT のライフタイムは static である必要がある.
@@ -62,13 +110,33 @@ lazy_static! {
     /// data 領域にメモリを確保して、最初の呼び出し時に初期化処理を行う（たぶん）
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        exceptions::register(&mut idt);
+        // GDB のスタブが全てのレジスタを読み書きできるように、専用の入口を使う
+        idt.breakpoint.set_handler_fn(crate::gdb::breakpoint_handler());
+        idt.debug.set_handler_fn(crate::gdb::debug_handler());
+        idt.page_fault.set_handler_fn(page_fault::page_fault_handler);
         unsafe {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 // double fault handler が呼び出されたときに使用する
                 // スタック領域を設定。
                 .set_stack_index(tss::DOUBLE_FAULT_IST_INDEX);
         }
+        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer::timer_interrupt_handler);
+        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Com2.as_usize()].set_handler_fn(crate::gdb::serial_interrupt_handler());
+        // PIC をマスクした後も spurious interrupt は届くので、常に登録しておく
+        idt[InterruptIndex::PicPrimarySpurious.as_usize()]
+            .set_handler_fn(pic_primary_spurious_handler);
+        idt[InterruptIndex::PicSecondarySpurious.as_usize()]
+            .set_handler_fn(pic_secondary_spurious_handler);
+        idt[InterruptIndex::TlbShootdown.as_usize()].set_handler_fn(tlb_shootdown_handler);
+        idt[InterruptIndex::CallFunction.as_usize()].set_handler_fn(call_function_handler);
+        idt[InterruptIndex::ApicError.as_usize()].set_handler_fn(apic_error_handler);
+        idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_interrupt_handler);
+        // user mode から `int 0x80` で呼び出せるように DPL を 3 にする
+        idt[crate::syscall::INT80_VECTOR]
+            .set_handler_fn(crate::syscall::int80_handler())
+            .set_privilege_level(PrivilegeLevel::Ring3);
         idt
     };
 }
@@ -77,11 +145,13 @@ pub fn init_idt() {
     IDT.load();
 }
 
-/// ### 疑問
-/// breakpoint exception が起きた時はどのように対処するのが適切なんだろう。
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("EXCEPTION : BREAKPOINT");
-    println!("{:#?}", stack_frame);
+/// `index` の割り込みの処理が終わったことを、有効な割り込みコントローラに通知する。
+pub fn end_of_interrupt(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::local::end_of_interrupt();
+    } else {
+        pic::notify_end_of_interrupt(index.as_u8());
+    }
 }
 
 /// ## Double Fault
@@ -135,7 +205,63 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame,
     _err_code: u64,
 ) {
-    panic!("EXCEPTION : DOUBLE FAULT\n{:#?}", stack_frame);
+    let _gs = SwapGsGuard::paranoid();
+    exceptions::report(exceptions::DOUBLE_FAULT, None, stack_frame);
+    panic!("EXCEPTION : DOUBLE FAULT");
+}
+
+/// ## Keyboard
+/// PS/2 キーボードは primary PIC の IRQ 1 に接続されている。
+/// 詳しくは `keyboard` モジュールのドキュメントを参照。
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    crate::keyboard::handle_interrupt();
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+/// ## TLB Shootdown
+/// 他の CPU がマップを解除した時に送られてくる。
+/// 詳しくは `memory::tlb` モジュールのドキュメントを参照。
+extern "x86-interrupt" fn tlb_shootdown_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    crate::memory::tlb::handle_shootdown();
+    end_of_interrupt(InterruptIndex::TlbShootdown);
+}
+
+/// ## Call Function
+/// 他の CPU が `smp::run_on_cpu` を呼び出した時に送られてくる。
+extern "x86-interrupt" fn call_function_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    crate::smp::call::handle_calls();
+    end_of_interrupt(InterruptIndex::CallFunction);
+}
+
+/// ## APIC Error
+/// local APIC が不正なベクタの割り込みなどを検出した時に発生する。
+/// 原因は Error Status Register に書かれている。
+extern "x86-interrupt" fn apic_error_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    let status = apic::local::error_status();
+    log::error!("APIC error : {:#x}", status);
+    end_of_interrupt(InterruptIndex::ApicError);
+}
+
+/// ## Spurious Interrupt
+/// 割り込みを配送しようとした時に、その割り込みが取り下げられていた場合などに発生する。
+/// 実際の割り込みではないので、EOI を送ってはいけない。
+/// GS を使わないので、`SwapGsGuard` も不要。
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {}
+
+/// ## PIC Spurious Interrupt
+/// PIC の IRQ 7 と IRQ 15 は spurious interrupt にも使われるので、ISR を見て EOI を送るか決める。
+/// 詳しくは `pic` モジュールのドキュメントを参照。
+/// GS を使わないので、`SwapGsGuard` も不要。
+extern "x86-interrupt" fn pic_primary_spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+    pic::handle_primary_irq7();
+}
+
+extern "x86-interrupt" fn pic_secondary_spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+    pic::handle_secondary_irq7();
 }
 
 #[cfg(test)]
@@ -150,4 +276,26 @@ mod tests {
         x86_64::instructions::interrupts::int3();
         serial_println!("[ok]");
     }
+
+    #[test_case]
+    fn test_timer_ticks() {
+        serial_print!("test_timer_ticks...");
+        // timer interrupt が有効なら、hlt しているうちに tick が進むはず
+        let start = super::ticks();
+        while super::ticks() < start + 2 {
+            x86_64::instructions::hlt();
+        }
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_pic_spurious_interrupts() {
+        serial_print!("test_pic_spurious_interrupts...");
+        // ISR が立っていないので spurious として扱われ、Double Fault にならずに戻ってくる
+        unsafe {
+            asm!("int $$0x27" :::: "volatile");
+            asm!("int $$0x2f" :::: "volatile");
+        }
+        serial_println!("[ok]");
+    }
 }
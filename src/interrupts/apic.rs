@@ -0,0 +1,119 @@
+//! ## APIC
+//!
+//! Advanced Programmable Interrupt Controller (APIC) は 8259 PIC を置き換える
+//! 割り込みコントローラである。
+//! 2種類のコントローラから構成される。
+//!
+//! - local APIC : CPU ごとに1つずつある。CPU への割り込みの配送と EOI の受付、
+//!   タイマー、他の CPU への割り込み（IPI）などを担当する。
+//! - I/O APIC : キーボードなどのデバイスからの割り込みを受け取り、
+//!   redirection table の設定に従って local APIC に配送する。
+//!
+//! APIC の構成（I/O APIC のアドレスや、ISA の IRQ と I/O APIC の入力の対応）は
+//! ACPI の MADT から取得する。`acpi` モジュールを参照。
+//!
+//! ### 初期化
+//!
+//! 1. 8259 PIC の割り込みを全てマスクする。
+//!    （remap は `pic::init` で済ませておく。マスクする前に割り込みが発生しても、
+//!    CPU 例外と重ならないようにするため）
+//! 2. local APIC を有効にする。
+//! 3. I/O APIC の redirection table を設定し、キーボードと COM2 の IRQ を配送する。
+//! 4. local APIC のタイマーを PIT で較正し、周期的に割り込みを発生させる。
+//!
+//! 割り込みのベクタは 8259 PIC を使っていた時と同じ `InterruptIndex` を使うので、
+//! IDT のハンドラはそのまま使える。
+//! EOI は `interrupts::end_of_interrupt` が、有効な方のコントローラに送る。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/APIC
+//! - https://wiki.osdev.org/IOAPIC
+//! - Intel SDM Vol. 3A, Chapter 10 "Advanced Programmable Interrupt Controller (APIC)"
+
+pub mod io;
+pub mod local;
+
+use super::{pic, InterruptIndex};
+use crate::acpi::{self, AcpiError};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{instructions::interrupts, structures::paging::mapper::MapToError};
+
+/// local APIC のタイマーの割り込みの周波数。
+pub const TIMER_HZ: u32 = 100;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum ApicError {
+    /// CPU が APIC をサポートしていない。
+    NotSupported,
+    Acpi(AcpiError),
+    /// レジスタをマップできなかった。
+    Map(MapToError),
+    /// I/O APIC が見つからなかった。
+    NoIoApic,
+}
+
+impl From<AcpiError> for ApicError {
+    fn from(err: AcpiError) -> Self {
+        ApicError::Acpi(err)
+    }
+}
+
+impl From<MapToError> for ApicError {
+    fn from(err: MapToError) -> Self {
+        ApicError::Map(err)
+    }
+}
+
+/// 8259 PIC から APIC に切り替える。
+///
+/// `acpi::init` の後に呼び出すこと。
+/// 失敗した場合は 8259 PIC を使い続ける。
+pub fn init() -> Result<(), ApicError> {
+    if !local::is_supported() {
+        return Err(ApicError::NotSupported);
+    }
+    let madt = acpi::madt()?;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    interrupts::without_interrupts(|| {
+        local::init(madt.local_apic_address)?;
+        io::init(&madt)?;
+
+        pic::disable();
+        ENABLED.store(true, Ordering::SeqCst);
+
+        io::route_isa_irq(&madt, 1, InterruptIndex::Keyboard.as_u8(), local::id());
+        io::route_isa_irq(&madt, 3, InterruptIndex::Com2.as_u8(), local::id());
+        local::start_timer(TIMER_HZ);
+        Ok(())
+    })
+}
+
+/// APIC が有効になっているか。
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_apic_enabled() {
+        serial_print!("test_apic_enabled... ");
+        // QEMU には APIC があるので、テストの起動時に切り替わっているはず
+        assert!(is_enabled());
+        let madt = acpi::madt().unwrap();
+        let bsp = local::id();
+        assert!(madt
+            .processors
+            .iter()
+            .any(|processor| processor.apic_id == bsp));
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,151 @@
+//! ## I/O APIC
+//!
+//! I/O APIC のレジスタには、2つの 32 bit のレジスタを経由して間接的にアクセスする。
+//!
+//! - `IOREGSEL`（オフセット `0x00`）: アクセスしたいレジスタの番号を書き込む。
+//! - `IOWIN`（オフセット `0x10`）: 選択したレジスタの値を読み書きする。
+//!
+//! ### Redirection Table
+//!
+//! I/O APIC の入力ごとに 64 bit のエントリーがあり、
+//! 割り込みをどのベクタでどの CPU に配送するかを設定する。
+//! エントリー `n` はレジスタ `0x10 + 2n`（下位 32 bit）と `0x11 + 2n`（上位 32 bit）。
+//!
+//! | bit   | 内容                                      |
+//! |-------|-------------------------------------------|
+//! | 0-7   | ベクタ                                    |
+//! | 8-10  | delivery mode（0 なら Fixed）              |
+//! | 11    | destination mode（0 なら APIC ID で指定）  |
+//! | 13    | polarity（1 なら active low）              |
+//! | 15    | trigger mode（1 なら level）               |
+//! | 16    | マスク                                    |
+//! | 56-63 | 配送先の APIC ID                          |
+//!
+//! 複数の I/O APIC がある場合、それぞれが `gsi_base` から連続する GSI を担当する。
+
+use crate::{
+    acpi::madt::{Madt, Polarity, TriggerMode},
+    memory,
+    sync::IrqSafeMutex,
+};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use x86_64::{structures::paging::mapper::MapToError, VirtAddr};
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const REG_ID: u32 = 0x00;
+const REG_VERSION: u32 = 0x01;
+const REG_REDIRECTION_TABLE: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL_TRIGGERED: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+pub struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    /// redirection table のエントリー数。
+    entries: u32,
+}
+
+lazy_static! {
+    static ref IO_APICS: IrqSafeMutex<Vec<IoApic>> = IrqSafeMutex::new(Vec::new());
+}
+
+impl IoApic {
+    unsafe fn read(&self, reg: u32) -> u32 {
+        ((self.base + IOREGSEL).as_mut_ptr::<u32>()).write_volatile(reg);
+        ((self.base + IOWIN).as_ptr::<u32>()).read_volatile()
+    }
+
+    unsafe fn write(&mut self, reg: u32, value: u32) {
+        ((self.base + IOREGSEL).as_mut_ptr::<u32>()).write_volatile(reg);
+        ((self.base + IOWIN).as_mut_ptr::<u32>()).write_volatile(value);
+    }
+
+    pub fn id(&self) -> u8 {
+        (unsafe { self.read(REG_ID) } >> 24) as u8 & 0xf
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.entries
+    }
+
+    /// `gsi` のエントリーを設定する。
+    fn set_entry(&mut self, gsi: u32, entry: u64) {
+        let reg = REG_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        unsafe {
+            // 設定の途中で割り込みが配送されないように、先にマスクしておく
+            self.write(reg, ENTRY_MASKED as u32);
+            self.write(reg + 1, (entry >> 32) as u32);
+            self.write(reg, entry as u32);
+        }
+    }
+}
+
+/// MADT に書かれた全ての I/O APIC のレジスタをマップし、全ての入力をマスクする。
+pub(super) fn init(madt: &Madt) -> Result<(), MapToError> {
+    let mut io_apics = IO_APICS.lock();
+    for info in &madt.io_apics {
+        let base = memory::map_mmio(info.address, 0x20)?;
+        let mut io_apic = IoApic {
+            base,
+            gsi_base: info.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((unsafe { io_apic.read(REG_VERSION) } >> 16) & 0xff) + 1;
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+            io_apic.set_entry(gsi, ENTRY_MASKED);
+        }
+        io_apics.push(io_apic);
+    }
+    Ok(())
+}
+
+/// `gsi` の割り込みを、ベクタ `vector` で `apic_id` の CPU に配送する。
+///
+/// `gsi` を担当する I/O APIC がなければ `false` を返す。
+pub fn route(gsi: u32, vector: u8, apic_id: u32, polarity: Polarity, trigger: TriggerMode) -> bool {
+    let mut entry = u64::from(vector) | (u64::from(apic_id) << 56);
+    if polarity == Polarity::ActiveLow {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+    if trigger == TriggerMode::Level {
+        entry |= ENTRY_LEVEL_TRIGGERED;
+    }
+
+    let mut io_apics = IO_APICS.lock();
+    match io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi)) {
+        Some(io_apic) => {
+            io_apic.set_entry(gsi, entry);
+            true
+        }
+        None => false,
+    }
+}
+
+/// ISA の IRQ `irq` を、Interrupt Source Override を考慮して配送する。
+///
+/// ISA の割り込みは、override で指定されていなければ active high, edge trigger 。
+pub fn route_isa_irq(madt: &Madt, irq: u8, vector: u8, apic_id: u32) -> bool {
+    let (gsi, polarity, trigger) = madt.isa_irq(irq);
+    let polarity = match polarity {
+        Polarity::Conforming => Polarity::ActiveHigh,
+        p => p,
+    };
+    let trigger = match trigger {
+        TriggerMode::Conforming => TriggerMode::Edge,
+        t => t,
+    };
+    route(gsi, vector, apic_id, polarity, trigger)
+}
+
+/// `gsi` の割り込みをマスクする。
+pub fn mask(gsi: u32) {
+    let mut io_apics = IO_APICS.lock();
+    if let Some(io_apic) = io_apics.iter_mut().find(|io_apic| io_apic.handles(gsi)) {
+        io_apic.set_entry(gsi, ENTRY_MASKED);
+    }
+}
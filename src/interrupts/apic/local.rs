@@ -0,0 +1,282 @@
+//! ## Local APIC
+//!
+//! local APIC には2つのモードがある。
+//!
+//! - xAPIC : レジスタは物理アドレス `0xFEE00000`（MADT で変更される場合がある）
+//!   からの 4 KiB の領域にマップされており、メモリアクセスで読み書きする。
+//! - x2APIC : レジスタは MSR `0x800` 以降に割り当てられており、
+//!   `rdmsr` / `wrmsr` 命令で読み書きする。APIC ID が 32 bit に拡張されている。
+//!
+//! CPUID で x2APIC がサポートされていれば x2APIC を使う。
+//! どちらのモードでも、レジスタは xAPIC のオフセットで指定する。
+//! x2APIC の MSR の番号は `0x800 + offset / 16` になる。
+//!
+//! ### タイマー
+//!
+//! local APIC のタイマーは、バスのクロックを分周してカウンタを減らしていき、
+//! 0 になった時に割り込みを発生させる。
+//! バスのクロックの周波数は CPU によって異なるので、
+//! 周波数がわかっている PIT を使って、一定時間にカウンタがどれだけ減るかを測る（較正）。
+
+use crate::{interrupts::InterruptIndex, memory};
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use x86_64::{
+    instructions::port::Port, registers::model_specific::Msr,
+    structures::paging::mapper::MapToError, PhysAddr,
+};
+
+/// レジスタのオフセット。
+pub mod register {
+    pub const ID: u32 = 0x20;
+    pub const VERSION: u32 = 0x30;
+    pub const TASK_PRIORITY: u32 = 0x80;
+    pub const EOI: u32 = 0xb0;
+    pub const SPURIOUS: u32 = 0xf0;
+    pub const ERROR_STATUS: u32 = 0x280;
+    pub const ICR_LOW: u32 = 0x300;
+    pub const ICR_HIGH: u32 = 0x310;
+    pub const LVT_TIMER: u32 = 0x320;
+    pub const LVT_LINT0: u32 = 0x350;
+    pub const LVT_LINT1: u32 = 0x360;
+    pub const LVT_ERROR: u32 = 0x370;
+    pub const TIMER_INITIAL_COUNT: u32 = 0x380;
+    pub const TIMER_CURRENT_COUNT: u32 = 0x390;
+    pub const TIMER_DIVIDE: u32 = 0x3e0;
+}
+
+/// Interrupt Command Register (ICR) の下位 32 bit に書く値。
+pub mod icr {
+    /// delivery mode : INIT
+    pub const INIT: u32 = 0b101 << 8;
+    /// delivery mode : Start Up 。下位 8 bit に開始アドレスのページ番号を書く。
+    pub const STARTUP: u32 = 0b110 << 8;
+    /// level : assert 。INIT 以外では常に立てる。
+    pub const ASSERT: u32 = 1 << 14;
+    /// destination shorthand : 自分自身。
+    pub const SELF: u32 = 0b01 << 18;
+    /// destination shorthand : 自分を含む全ての CPU 。
+    pub const ALL_INCLUDING_SELF: u32 = 0b10 << 18;
+    /// destination shorthand : 自分以外の全ての CPU 。
+    pub const ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+}
+
+/// `IA32_APIC_BASE` MSR 。
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
+
+/// x2APIC のレジスタの MSR の先頭。
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// LVT のマスクビット。
+const LVT_MASKED: u32 = 1 << 16;
+/// LVT の delivery mode : NMI
+const LVT_NMI: u32 = 0b100 << 8;
+/// LVT timer の periodic mode 。
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// タイマーの分周比 16 。
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+/// ICR の delivery status ビット。xAPIC で送信中の間は立っている。
+const ICR_SEND_PENDING: u32 = 1 << 12;
+/// spurious interrupt vector register の APIC software enable ビット。
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+/// PIT の周波数。
+const PIT_HZ: u32 = 1_193_182;
+/// タイマーの較正にかける時間。
+const CALIBRATION_MS: u32 = 10;
+
+static X2APIC: AtomicBool = AtomicBool::new(false);
+/// xAPIC のレジスタがマップされた仮想アドレス。
+static XAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+/// 較正で求めた、1ms あたりのタイマーのカウント数（分周後）。
+static TICKS_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+fn cpuid_features() -> (u32, u32) {
+    let result = unsafe { __cpuid(1) };
+    (result.ecx, result.edx)
+}
+
+/// CPU が local APIC をサポートしているか。
+pub fn is_supported() -> bool {
+    cpuid_features().1 & (1 << 9) != 0
+}
+
+/// CPU が x2APIC をサポートしているか。
+pub fn is_x2apic_supported() -> bool {
+    cpuid_features().0 & (1 << 21) != 0
+}
+
+/// x2APIC モードで動いているか。
+pub fn is_x2apic() -> bool {
+    X2APIC.load(Ordering::Relaxed)
+}
+
+/// レジスタを読み込む。
+///
+/// ## Safety
+/// local APIC が有効になっている必要がある。
+pub unsafe fn read(reg: u32) -> u32 {
+    if is_x2apic() {
+        Msr::new(X2APIC_MSR_BASE + reg / 16).read() as u32
+    } else {
+        let base = XAPIC_BASE.load(Ordering::Relaxed);
+        ((base + u64::from(reg)) as *const u32).read_volatile()
+    }
+}
+
+/// レジスタに書き込む。
+///
+/// ## Safety
+/// local APIC が有効になっている必要がある。
+/// 書き込む値によっては割り込みの配送が壊れる。
+pub unsafe fn write(reg: u32, value: u32) {
+    if is_x2apic() {
+        Msr::new(X2APIC_MSR_BASE + reg / 16).write(u64::from(value));
+    } else {
+        let base = XAPIC_BASE.load(Ordering::Relaxed);
+        ((base + u64::from(reg)) as *mut u32).write_volatile(value);
+    }
+}
+
+/// local APIC を有効にする。
+///
+/// `base` は MADT に書かれた xAPIC のレジスタの物理アドレス。
+pub(super) fn init(base: PhysAddr) -> Result<(), MapToError> {
+    if is_x2apic_supported() {
+        X2APIC.store(true, Ordering::SeqCst);
+    } else {
+        let virt = memory::map_mmio(base, 0x1000)?;
+        XAPIC_BASE.store(virt.as_u64(), Ordering::SeqCst);
+    }
+    enable();
+    Ok(())
+}
+
+/// 現在の CPU の local APIC を有効にする。
+///
+/// モードとレジスタのマップは `init` で決まっているので、
+/// 他の CPU でも同じように呼び出せる。
+pub fn enable() {
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE);
+        let mut value = msr.read() | APIC_BASE_GLOBAL_ENABLE;
+        if is_x2apic() {
+            value |= APIC_BASE_X2APIC_ENABLE;
+        }
+        msr.write(value);
+
+        // 全ての優先度の割り込みを受け付ける
+        write(register::TASK_PRIORITY, 0);
+        // I/O APIC を使うので、8259 PIC からの割り込み（LINT0）は受け付けない
+        write(register::LVT_LINT0, LVT_MASKED);
+        write(register::LVT_LINT1, LVT_NMI);
+        write(
+            register::LVT_ERROR,
+            u32::from(InterruptIndex::ApicError.as_u8()),
+        );
+        // ESR は書き込んでから読むと更新される
+        write(register::ERROR_STATUS, 0);
+        write(register::ERROR_STATUS, 0);
+
+        write(
+            register::SPURIOUS,
+            SPURIOUS_APIC_ENABLE | u32::from(InterruptIndex::Spurious.as_u8()),
+        );
+    }
+}
+
+/// 現在の CPU の APIC ID を返す。
+pub fn id() -> u32 {
+    let id = unsafe { read(register::ID) };
+    if is_x2apic() {
+        id
+    } else {
+        id >> 24
+    }
+}
+
+/// 割り込みの処理が終わったことを local APIC に通知する。
+pub fn end_of_interrupt() {
+    unsafe { write(register::EOI, 0) };
+}
+
+/// APIC ID が `destination` の CPU に IPI を送る。
+///
+/// `command` は ICR の下位 32 bit に書く値（`icr` モジュールの定数とベクタ）。
+/// destination shorthand を指定した場合、`destination` は無視される。
+pub fn send_ipi(destination: u32, command: u32) {
+    unsafe {
+        if is_x2apic() {
+            // x2APIC では ICR は1つの 64-bit の MSR になっている
+            let value = u64::from(destination) << 32 | u64::from(command);
+            Msr::new(X2APIC_MSR_BASE + register::ICR_LOW / 16).write(value);
+        } else {
+            // 下位 32 bit を書き込んだ時に送信されるので、送り先を先に書く
+            write(register::ICR_HIGH, destination << 24);
+            write(register::ICR_LOW, command);
+            while read(register::ICR_LOW) & ICR_SEND_PENDING != 0 {}
+        }
+    }
+}
+
+/// エラーの内容を読み込んでクリアする。
+pub fn error_status() -> u32 {
+    unsafe {
+        write(register::ERROR_STATUS, 0);
+        read(register::ERROR_STATUS)
+    }
+}
+
+/// タイマーを較正し、`hz` の周期で `InterruptIndex::Timer` の割り込みを発生させる。
+pub fn start_timer(hz: u32) {
+    if TICKS_PER_MS.load(Ordering::Relaxed) == 0 {
+        TICKS_PER_MS.store(calibrate_timer(), Ordering::SeqCst);
+    }
+    let initial_count = TICKS_PER_MS.load(Ordering::Relaxed) * 1000 / u64::from(hz);
+
+    unsafe {
+        write(register::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(
+            register::LVT_TIMER,
+            TIMER_PERIODIC | u32::from(InterruptIndex::Timer.as_u8()),
+        );
+        write(register::TIMER_INITIAL_COUNT, initial_count as u32);
+    }
+}
+
+/// PIT のチャネル 2 で `CALIBRATION_MS` だけ待つ間に、
+/// タイマーのカウンタがどれだけ減るかを測り、1ms あたりのカウント数を返す。
+///
+/// チャネル 2 はスピーカー用のチャネルで、割り込みを発生させずに
+/// 出力をポート `0x61` から読むことができる。
+fn calibrate_timer() -> u64 {
+    let mut pit_control = Port::<u8>::new(0x61);
+    let mut pit_command = Port::<u8>::new(0x43);
+    let mut pit_channel2 = Port::<u8>::new(0x42);
+
+    let count = PIT_HZ / 1000 * CALIBRATION_MS;
+    unsafe {
+        // スピーカーを止め、チャネル 2 の gate を有効にする
+        let control = (pit_control.read() & !0b10) | 0b01;
+        pit_control.write(control);
+        // チャネル 2, lobyte/hibyte, mode 0 (interrupt on terminal count)
+        pit_command.write(0b1011_0000);
+        pit_channel2.write(count as u8);
+        pit_channel2.write((count >> 8) as u8);
+
+        write(register::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(register::LVT_TIMER, LVT_MASKED);
+        write(register::TIMER_INITIAL_COUNT, u32::max_value());
+
+        // カウントが 0 になると、チャネル 2 の出力（bit 5）が立つ
+        while pit_control.read() & 0b10_0000 == 0 {}
+
+        let elapsed = u32::max_value() - read(register::TIMER_CURRENT_COUNT);
+        write(register::TIMER_INITIAL_COUNT, 0);
+        u64::from(elapsed) / u64::from(CALIBRATION_MS)
+    }
+}
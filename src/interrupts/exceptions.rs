@@ -0,0 +1,398 @@
+//! ## CPU 例外
+//!
+//! x86_64 では、IDT のエントリー 0..31 が CPU 例外のために予約されている。
+//! ハンドラが登録されていない例外が発生すると Double Fault に化けてしまい、
+//! 元の例外が何だったのか分からなくなるので、全ての例外にハンドラを登録しておく。
+//!
+//! 各ハンドラは、例外の名前、ベクタ番号、（あれば）エラーコード、
+//! そして割り込み時のレジスタ（RIP/CS/RFLAGS/RSP/SS）を
+//! 同じフォーマットで VGA と serial の両方に出力する。
+//! 続けて、例外が発生した場所に至るまでの backtrace（`backtrace` モジュール）も出力する。
+//! serial にも出力するのは、VGA の出力はテスト中に見えないし、
+//! 再起動すると消えてしまうから。
+//!
+//! ### user mode の例外
+//!
+//! user mode (ring 3) のプログラムが起こした例外で kernel 全体を止めてはいけないので、
+//! CPU が積んだ CS の RPL が 3 なら、レポートを出力した後に
+//! 現在のスレッドを終了コード `process::fault_exit_code(vector)` で終了する。
+//! NMI と Machine Check はプログラムと関係なく発生するので、常に panic する。
+//!
+//! ### エラーコード
+//!
+//! 一部の例外は CPU がエラーコードを push する。
+//! Invalid TSS, Segment Not Present, Stack-Segment Fault, General Protection Fault
+//! のエラーコードは、例外の原因となったセグメントセレクタを表す。
+//!
+//! | bit  | 意味                                             |
+//! |------|--------------------------------------------------|
+//! | 0    | 1 なら外部イベント（ハードウェア割り込みなど）が原因 |
+//! | 1..2 | 参照していたテーブル（0: GDT, 1: IDT, 2: LDT, 3: IDT） |
+//! | 3..15| テーブル内のインデックス                           |
+//!
+//! エラーコードが 0 の場合は、セグメントに関係しない原因で発生したことを表す。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/Exceptions
+
+use crate::{cpu::SwapGsGuard, println, serial_println};
+use core::fmt;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+/// 例外ハンドラを IDT に登録する。
+///
+/// Breakpoint, Double Fault, Page Fault は特別な処理が必要なので、
+/// `interrupts` モジュールで別に登録している。
+/// Debug は GDB のスタブ（`gdb` モジュール）が single step に使う。
+pub(super) fn register(idt: &mut InterruptDescriptorTable) {
+    idt.divide_by_zero.set_handler_fn(divide_error_handler);
+    idt.non_maskable_interrupt
+        .set_handler_fn(non_maskable_interrupt_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded
+        .set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.security_exception
+        .set_handler_fn(security_exception_handler);
+}
+
+/// 例外の種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exception {
+    pub name: &'static str,
+    pub vector: u8,
+}
+
+pub const DIVIDE_ERROR: Exception = Exception {
+    name: "DIVIDE ERROR",
+    vector: 0,
+};
+pub const DEBUG: Exception = Exception {
+    name: "DEBUG",
+    vector: 1,
+};
+pub const NON_MASKABLE_INTERRUPT: Exception = Exception {
+    name: "NON MASKABLE INTERRUPT",
+    vector: 2,
+};
+pub const BREAKPOINT: Exception = Exception {
+    name: "BREAKPOINT",
+    vector: 3,
+};
+pub const OVERFLOW: Exception = Exception {
+    name: "OVERFLOW",
+    vector: 4,
+};
+pub const BOUND_RANGE_EXCEEDED: Exception = Exception {
+    name: "BOUND RANGE EXCEEDED",
+    vector: 5,
+};
+pub const INVALID_OPCODE: Exception = Exception {
+    name: "INVALID OPCODE",
+    vector: 6,
+};
+pub const DEVICE_NOT_AVAILABLE: Exception = Exception {
+    name: "DEVICE NOT AVAILABLE",
+    vector: 7,
+};
+pub const DOUBLE_FAULT: Exception = Exception {
+    name: "DOUBLE FAULT",
+    vector: 8,
+};
+pub const INVALID_TSS: Exception = Exception {
+    name: "INVALID TSS",
+    vector: 10,
+};
+pub const SEGMENT_NOT_PRESENT: Exception = Exception {
+    name: "SEGMENT NOT PRESENT",
+    vector: 11,
+};
+pub const STACK_SEGMENT_FAULT: Exception = Exception {
+    name: "STACK SEGMENT FAULT",
+    vector: 12,
+};
+pub const GENERAL_PROTECTION_FAULT: Exception = Exception {
+    name: "GENERAL PROTECTION FAULT",
+    vector: 13,
+};
+pub const PAGE_FAULT: Exception = Exception {
+    name: "PAGE FAULT",
+    vector: 14,
+};
+pub const X87_FLOATING_POINT: Exception = Exception {
+    name: "X87 FLOATING POINT",
+    vector: 16,
+};
+pub const ALIGNMENT_CHECK: Exception = Exception {
+    name: "ALIGNMENT CHECK",
+    vector: 17,
+};
+pub const MACHINE_CHECK: Exception = Exception {
+    name: "MACHINE CHECK",
+    vector: 18,
+};
+pub const SIMD_FLOATING_POINT: Exception = Exception {
+    name: "SIMD FLOATING POINT",
+    vector: 19,
+};
+pub const VIRTUALIZATION: Exception = Exception {
+    name: "VIRTUALIZATION",
+    vector: 20,
+};
+pub const SECURITY_EXCEPTION: Exception = Exception {
+    name: "SECURITY EXCEPTION",
+    vector: 30,
+};
+
+/// CPU が push したエラーコード。
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorCode {
+    /// セグメントセレクタを表すエラーコード。
+    Selector(u64),
+    /// Page Fault のエラーコードと、アクセスしようとしたアドレス。
+    PageFault(PageFaultErrorCode, VirtAddr),
+    /// 特に意味を持たないエラーコード。
+    Raw(u64),
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorCode::Selector(0) => write!(f, "0x0"),
+            ErrorCode::Selector(code) => {
+                let table = match (code >> 1) & 0b11 {
+                    0 => "GDT",
+                    2 => "LDT",
+                    _ => "IDT",
+                };
+                write!(
+                    f,
+                    "{:#x} (selector: table={}, index={}, external={})",
+                    code,
+                    table,
+                    (code >> 3) & 0x1fff,
+                    code & 1 == 1
+                )
+            }
+            ErrorCode::PageFault(code, addr) => write!(
+                f,
+                "{:#x} ({}) accessing {:?}",
+                code.bits(),
+                super::page_fault::describe(code),
+                addr
+            ),
+            ErrorCode::Raw(code) => write!(f, "{:#x}", code),
+        }
+    }
+}
+
+/// 例外発生時のレポート。
+pub struct CrashReport<'a> {
+    pub exception: Exception,
+    pub error_code: Option<ErrorCode>,
+    pub stack_frame: &'a InterruptStackFrame,
+}
+
+impl<'a> fmt::Display for CrashReport<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "EXCEPTION : {} (vector {})",
+            self.exception.name, self.exception.vector
+        )?;
+        if let Some(error_code) = self.error_code {
+            writeln!(f, "  ERROR  : {}", error_code)?;
+        }
+        writeln!(f, "  RIP    : {:?}", self.stack_frame.instruction_pointer)?;
+        writeln!(f, "  CS     : {:#x}", self.stack_frame.code_segment)?;
+        writeln!(f, "  RFLAGS : {:#x}", self.stack_frame.cpu_flags)?;
+        writeln!(f, "  RSP    : {:?}", self.stack_frame.stack_pointer)?;
+        write!(f, "  SS     : {:#x}", self.stack_frame.stack_segment)
+    }
+}
+
+/// レポートを VGA と serial の両方に出力する。
+pub fn report(
+    exception: Exception,
+    error_code: Option<ErrorCode>,
+    stack_frame: &InterruptStackFrame,
+) {
+    let report = CrashReport {
+        exception,
+        error_code,
+        stack_frame,
+    };
+    println!("{}", report);
+    serial_println!("{}", report);
+    crate::logger::persistent::record(format_args!("{}", report));
+    crate::backtrace::print_interrupted(stack_frame);
+}
+
+/// user mode で発生した例外なら、現在のスレッドを終了する。
+///
+/// kernel で発生した例外なら何もしない。
+pub(super) fn exit_if_user(exception: Exception, stack_frame: &InterruptStackFrame) {
+    if stack_frame.code_segment & 3 == 3 {
+        crate::syscall::exit_current(crate::process::fault_exit_code(exception.vector));
+    }
+}
+
+/// レポートを出力し、user mode で発生した例外ならスレッドを終了、そうでなければ panic する。
+fn fault(
+    exception: Exception,
+    error_code: Option<ErrorCode>,
+    stack_frame: &InterruptStackFrame,
+) -> ! {
+    report(exception, error_code, stack_frame);
+    exit_if_user(exception, stack_frame);
+    panic!("EXCEPTION : {}", exception.name);
+}
+
+/// レポートを出力してから panic する。
+fn crash(
+    exception: Exception,
+    error_code: Option<ErrorCode>,
+    stack_frame: &InterruptStackFrame,
+) -> ! {
+    report(exception, error_code, stack_frame);
+    panic!("EXCEPTION : {}", exception.name);
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(DIVIDE_ERROR, None, stack_frame);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::paranoid();
+    crash(NON_MASKABLE_INTERRUPT, None, stack_frame);
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(OVERFLOW, None, stack_frame);
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(BOUND_RANGE_EXCEEDED, None, stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(INVALID_OPCODE, None, stack_frame);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(DEVICE_NOT_AVAILABLE, None, stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(
+        INVALID_TSS,
+        Some(ErrorCode::Selector(error_code)),
+        stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(
+        SEGMENT_NOT_PRESENT,
+        Some(ErrorCode::Selector(error_code)),
+        stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(
+        STACK_SEGMENT_FAULT,
+        Some(ErrorCode::Selector(error_code)),
+        stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(
+        GENERAL_PROTECTION_FAULT,
+        Some(ErrorCode::Selector(error_code)),
+        stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(X87_FLOATING_POINT, None, stack_frame);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(
+        ALIGNMENT_CHECK,
+        Some(ErrorCode::Raw(error_code)),
+        stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::paranoid();
+    crash(MACHINE_CHECK, None, stack_frame);
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(SIMD_FLOATING_POINT, None, stack_frame);
+}
+
+extern "x86-interrupt" fn virtualization_handler(stack_frame: &mut InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(VIRTUALIZATION, None, stack_frame);
+}
+
+extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    fault(
+        SECURITY_EXCEPTION,
+        Some(ErrorCode::Raw(error_code)),
+        stack_frame,
+    );
+}
@@ -0,0 +1,140 @@
+//! ## Page Fault
+//!
+//! Page Fault は、CPU がメモリにアクセスしようとした時に、
+//! そのアクセスが page table の設定によって許可されていない場合に発生する。
+//! 例えば以下のような場合。
+//!
+//! - アクセス先のページが page table にマップされていない
+//! - read-only なページに書き込もうとした
+//! - user mode から kernel のページにアクセスしようとした
+//! - NX bit が立っているページの命令を実行しようとした
+//!
+//! Page Fault が発生すると、CPU はアクセスしようとした仮想アドレスを
+//! CR2 レジスタに保存し、その原因を表すエラーコードとともにハンドラを呼び出す。
+//!
+//! ### エラーコード
+//!
+//! | bit | 名前                 | 意味                                                   |
+//! |-----|----------------------|--------------------------------------------------------|
+//! | 0   | PROTECTION_VIOLATION | 1 ならページは存在したが権限違反。0 ならページが存在しない |
+//! | 1   | CAUSED_BY_WRITE      | 1 なら書き込み、0 なら読み込みによるもの                 |
+//! | 2   | USER_MODE            | 1 なら user mode (ring 3) でのアクセス                   |
+//! | 3   | MALFORMED_TABLE      | 1 なら page table の予約ビットが立っていた               |
+//! | 4   | INSTRUCTION_FETCH    | 1 なら命令フェッチによるもの                             |
+//!
+//! ### Copy-on-Write
+//!
+//! Copy-on-Write のページへの書き込みは、まず `memory::cow` で解決する。
+//! 解決できた場合はハンドラから戻り、元の命令を再実行する。
+//!
+//! ### Hook
+//!
+//! Copy-on-Write 以外の Page Fault を解決する関数（例えば demand paging など）を
+//! `set_hook` で登録できるようにしておく。
+//! 登録された関数が `true` を返した場合、Page Fault は解決されたものとみなし、
+//! ハンドラから戻って元の命令を再実行する。
+//!
+//! ### 参照
+//! - https://os.phil-opp.com/paging-introduction/#page-faults
+//! - https://wiki.osdev.org/Exceptions#Page_Fault
+
+use super::exceptions::{self, ErrorCode};
+use crate::cpu::SwapGsGuard;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+/// Page Fault を解決するための関数。
+///
+/// 1つめの引数はアクセスしようとした仮想アドレス（CR2 の値）。
+/// Page Fault を解決できた場合は `true` を返す。
+pub type Hook = fn(VirtAddr, PageFaultErrorCode) -> bool;
+
+lazy_static! {
+    static ref HOOK: Mutex<Option<Hook>> = Mutex::new(None);
+}
+
+/// Page Fault を解決するための関数を登録する。
+///
+/// すでに登録されている関数は置き換えられる。
+pub fn set_hook(hook: Hook) {
+    *HOOK.lock() = Some(hook);
+}
+
+/// 登録されている関数を解除する。
+pub fn clear_hook() {
+    *HOOK.lock() = None;
+}
+
+pub(super) extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    let addr = Cr2::read();
+
+    if crate::memory::cow::handle_page_fault(addr, error_code) {
+        return;
+    }
+
+    // lock を保持したまま hook を呼び出すと、hook の中で
+    // 再び Page Fault が起きた時にデッドロックするのでコピーしておく。
+    let hook = *HOOK.lock();
+    if let Some(hook) = hook {
+        if hook(addr, error_code) {
+            return;
+        }
+    }
+
+    exceptions::report(
+        exceptions::PAGE_FAULT,
+        Some(ErrorCode::PageFault(error_code, addr)),
+        stack_frame,
+    );
+    exceptions::exit_if_user(exceptions::PAGE_FAULT, stack_frame);
+    panic!("EXCEPTION : PAGE FAULT at {:?}", addr);
+}
+
+/// エラーコードを人間が読める形に変換する。
+pub fn describe(error_code: PageFaultErrorCode) -> Description {
+    Description(error_code)
+}
+
+/// `describe` の戻り値。
+pub struct Description(PageFaultErrorCode);
+
+impl core::fmt::Display for Description {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let code = self.0;
+
+        if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            f.write_str("present")?;
+        } else {
+            f.write_str("not-present")?;
+        }
+
+        if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            f.write_str(", instruction fetch")?;
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            f.write_str(", write")?;
+        } else {
+            f.write_str(", read")?;
+        }
+
+        if code.contains(PageFaultErrorCode::USER_MODE) {
+            f.write_str(", user")?;
+        } else {
+            f.write_str(", kernel")?;
+        }
+
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            f.write_str(", reserved bit set")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+//! ## 8259 PIC
+//!
+//! Programmable Interrupt Controller (PIC) は、タイマーやキーボードなどの
+//! ハードウェアからの割り込みを集約し、CPU に通知するためのチップである。
+//! 現在は APIC に置き換えられているが、後方互換性のために今でもサポートされており、
+//! APIC よりも設定が簡単なのでまずはこちらを使う。
+//!
+//! 典型的な構成では、2つの 8259 PIC（primary と secondary）がチェーンされている。
+//! secondary PIC は primary PIC の IRQ 2 に接続されており、
+//! それぞれが 8本の割り込みラインを持つので、合計 15本のラインが使える。
+//!
+//! ### Remap
+//!
+//! PIC のデフォルト設定では、割り込みベクタ 0..15 が使われる。
+//! しかしこの範囲は CPU 例外（Divide-by-Zero や Double Fault など）と重なってしまうので、
+//! 割り込みベクタを別の範囲に remap する必要がある。
+//! CPU 例外は 0..31 を使うので、その直後の 32..47 を使うのが一般的である。
+//!
+//! ### End of Interrupt
+//!
+//! 割り込みハンドラの最後には、PIC に End of Interrupt (EOI) シグナルを
+//! 送る必要がある。
+//! これを送らないと、PIC はまだ割り込みが処理中だと判断し、
+//! 次の割り込みを送ってこない。
+//!
+//! ### Spurious IRQ
+//!
+//! PIC は CPU に割り込みを通知した後、CPU がベクタを読み取るまでの間に
+//! 割り込みが取り下げられると、代わりにその PIC の IRQ 7 のベクタを送ってくる。
+//! これは全ての IRQ をマスクした後（APIC に切り替えた後）でも起こりうる。
+//! 本物の IRQ 7 かどうかは In-Service Register (ISR) の bit 7 で分かる。
+//! spurious な割り込みには EOI を送ってはいけないが、secondary PIC の spurious IRQ 15 は
+//! primary PIC からは本物の IRQ 2 に見えているので、primary PIC にだけ EOI を送る。
+//!
+//! ### 参照
+//! - https://os.phil-opp.com/hardware-interrupts/
+//! - https://wiki.osdev.org/8259_PIC#Spurious_IRQs
+
+use lazy_static::lazy_static;
+use pic8259_simple::ChainedPics;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+/// 各 PIC の command port 。
+const PRIMARY_COMMAND: u16 = 0x20;
+const SECONDARY_COMMAND: u16 = 0xa0;
+/// 次に command port から ISR を読む、OCW3 のコマンド。
+const READ_ISR: u8 = 0x0b;
+/// non-specific EOI のコマンド。
+const END_OF_INTERRUPT: u8 = 0x20;
+
+lazy_static! {
+    pub static ref PICS: Mutex<ChainedPics> =
+        Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+}
+
+/// PIC を初期化する。
+///
+/// 割り込みを有効にする前に呼び出す必要がある。
+pub fn init() {
+    unsafe { PICS.lock().initialize() };
+}
+
+/// 全ての割り込みをマスクし、PIC を使わないようにする。
+///
+/// APIC に切り替える時に呼び出す。
+/// マスクしていても spurious interrupt が発生することがあるので、
+/// 先に `init` で remap しておく必要がある。
+pub fn disable() {
+    let mut primary_data = Port::<u8>::new(0x21);
+    let mut secondary_data = Port::<u8>::new(0xa1);
+    unsafe {
+        primary_data.write(0xff);
+        secondary_data.write(0xff);
+    }
+}
+
+/// primary PIC の IRQ `irq` のマスクを外す。
+pub fn unmask(irq: u8) {
+    let mut primary_data = Port::<u8>::new(0x21);
+    unsafe {
+        let mask = primary_data.read();
+        primary_data.write(mask & !(1 << irq));
+    }
+}
+
+/// `interrupt_index` に対応する割り込みの処理が終わったことを PIC に通知する。
+pub fn notify_end_of_interrupt(interrupt_index: u8) {
+    unsafe { PICS.lock().notify_end_of_interrupt(interrupt_index) };
+}
+
+/// primary PIC の IRQ 7 を処理する。spurious でなければ EOI を送る。
+pub fn handle_primary_irq7() {
+    let mut pics = PICS.lock();
+    if in_service(PRIMARY_COMMAND) & 0x80 != 0 {
+        unsafe { pics.notify_end_of_interrupt(PIC_1_OFFSET + 7) };
+    }
+}
+
+/// secondary PIC の IRQ 15 を処理する。
+/// spurious でなければ両方の PIC に、spurious なら primary PIC にだけ EOI を送る。
+pub fn handle_secondary_irq7() {
+    let mut pics = PICS.lock();
+    if in_service(SECONDARY_COMMAND) & 0x80 != 0 {
+        unsafe { pics.notify_end_of_interrupt(PIC_2_OFFSET + 7) };
+    } else {
+        unsafe { Port::<u8>::new(PRIMARY_COMMAND).write(END_OF_INTERRUPT) };
+    }
+}
+
+/// `command` の PIC の In-Service Register を読む。
+fn in_service(command: u16) -> u8 {
+    let mut port = Port::<u8>::new(command);
+    unsafe {
+        port.write(READ_ISR);
+        port.read()
+    }
+}
@@ -0,0 +1,111 @@
+//! ## Timer
+//!
+//! Programmable Interval Timer (PIT) は primary PIC の IRQ 0 に接続されている。
+//! PIT はデフォルトで約 18.2Hz で割り込みを発生させる。
+//! APIC に切り替えた後は、local APIC のタイマーが `apic::TIMER_HZ` で
+//! 同じベクタの割り込みを発生させる。
+//! 割り込みのたびに tick をカウントし、割り込みコントローラに EOI を送る。
+//!
+//! その後、`thread` の scheduler に tick を知らせる。
+//! scheduler は別のスレッドに切り替えることがあり、その場合はしばらく
+//! ハンドラから戻らないので、EOI は先に送っておく必要がある。
+//!
+//! ### Hook
+//!
+//! `set_hook` で、割り込みのたびに呼び出される関数を登録できる。
+//! 登録された関数は割り込みハンドラの中で実行されるので、
+//! `IrqSafeMutex` 以外の lock を取得してはいけない。
+//!
+//! ### Stream
+//!
+//! `TickStream` は tick が進むたびに現在の tick を返す async stream 。
+//! tick が進んだことを知らせるための `AtomicWaker` は1つしかないので、
+//! `TickStream` を同時に複数 poll してはいけない。
+//! 最後に poll した stream だけが起こされる。
+
+use super::InterruptIndex;
+use crate::{cpu::SwapGsGuard, sync::IrqSafeMutex};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::{stream::Stream, task::AtomicWaker};
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// 起動してからのタイマー割り込みの回数。
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// タイマー割り込みのたびに呼び出される関数。引数は現在の tick 。
+pub type Hook = fn(u64);
+
+static HOOK: IrqSafeMutex<Option<Hook>> = IrqSafeMutex::new(None);
+
+/// タイマー割り込みのたびに呼び出される関数を登録する。
+///
+/// すでに登録されている関数は置き換えられる。
+pub fn set_hook(hook: Hook) {
+    *HOOK.lock() = Some(hook);
+}
+
+/// 登録されている関数を解除する。
+pub fn clear_hook() {
+    *HOOK.lock() = None;
+}
+
+/// 起動してからのタイマー割り込みの回数を返す。
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub(super) extern "x86-interrupt" fn timer_interrupt_handler(
+    stack_frame: &mut InterruptStackFrame,
+) {
+    let _gs = SwapGsGuard::new(stack_frame);
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    WAKER.wake();
+    // hook の中で lock を取得できるように、コピーしてから呼び出す
+    let hook = *HOOK.lock();
+    if let Some(hook) = hook {
+        hook(now);
+    }
+    super::end_of_interrupt(InterruptIndex::Timer);
+    crate::thread::on_tick(now);
+}
+
+/// tick が進むたびに現在の tick を返す stream 。
+pub struct TickStream {
+    last: u64,
+}
+
+impl TickStream {
+    pub fn new() -> Self {
+        TickStream { last: ticks() }
+    }
+}
+
+impl Stream for TickStream {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        // fast path
+        let now = ticks();
+        if now > self.last {
+            self.last = now;
+            return Poll::Ready(Some(now));
+        }
+
+        WAKER.register(&cx.waker());
+        // register している間に tick が進んだかもしれないので、もう一度確認する
+        let now = ticks();
+        if now > self.last {
+            WAKER.take();
+            self.last = now;
+            Poll::Ready(Some(now))
+        } else {
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,512 @@
+//! ## PS/2 Keyboard
+//!
+//! PS/2 キーボードは PS/2 コントローラ (8042) を通して CPU と通信する。
+//! キーが押されたり離されたりすると、キーボードは scancode を
+//! コントローラに送り、コントローラは primary PIC の IRQ 1 で割り込みを発生させる。
+//! 割り込みハンドラは I/O port 0x60 から scancode を読み出す。
+//! 読み出さないと、次の割り込みが発生しない。
+//!
+//! 読み出した scancode は以下の順に処理される。
+//!
+//! 1. `scancode::Decoder` がバイト列を `KeyCode` に変換する
+//! 2. `Keyboard` が修飾キー（Shift, Ctrl, Alt, Caps Lock など）の状態を更新する
+//! 3. `layout::Layout` が `KeyCode` と修飾キーの状態から文字を求める
+//! 4. `KeyEvent` を `queue::EventQueue` に追加する
+//!
+//! カーネルの他の部分は `pop_event` でイベントを取り出すか、
+//! `KeyEventStream` を使って async にイベントを待つ。
+//!
+//! ### LED
+//!
+//! Caps Lock, Num Lock, Scroll Lock の LED は、キーボードが勝手に点灯させる
+//! わけではなく、OS がコマンド 0xED とともに状態を送る必要がある。
+//! キーボードは1バイト受け取るたびに ACK (0xFA) か Resend (0xFE) を返してくるので、
+//! 次のバイトはその応答を待ってから送る。
+//!
+//! 応答は割り込みで届くので、割り込みハンドラの中では待てない。
+//! 割り込みハンドラは新しい LED の状態を `LEDS` に書いて `update_leds` のタスクを起こすだけにし、
+//! タスクがコマンドを送って応答を待つ。応答は scancode として扱わずに `RESPONSE` に書く。
+//! 応答がないまま次に LED が変わった時は、待つのを諦めて新しい状態を送り直す。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/PS/2_Keyboard
+//! - https://os.phil-opp.com/hardware-interrupts/#keyboard-input
+
+pub mod layout;
+pub mod queue;
+pub mod scancode;
+
+pub use self::{
+    layout::Layout,
+    scancode::{KeyCode, KeyState, ScancodeSet},
+};
+
+use self::{queue::EventQueue, scancode::Decoder};
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+    task::{Context, Poll},
+};
+use futures_util::{future::poll_fn, stream::Stream, task::AtomicWaker};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+
+/// コントローラの input buffer にデータが残っていることを表すステータスビット。
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const COMMAND_SET_LEDS: u8 = 0xED;
+
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+/// キーボードからの応答のうち、scancode ではないもの。
+const RESPONSE_ERROR: u8 = 0x00;
+const RESPONSE_ACK: u8 = 0xFA;
+const RESPONSE_RESEND: u8 = 0xFE;
+const RESPONSE_ERROR_2: u8 = 0xFF;
+
+/// 修飾キーの状態。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub left_shift: bool,
+    pub right_shift: bool,
+    pub left_ctrl: bool,
+    pub right_ctrl: bool,
+    pub left_alt: bool,
+    pub right_alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    const NONE: Modifiers = Modifiers {
+        left_shift: false,
+        right_shift: false,
+        left_ctrl: false,
+        right_ctrl: false,
+        left_alt: false,
+        right_alt: false,
+        caps_lock: false,
+        num_lock: false,
+        scroll_lock: false,
+    };
+
+    pub fn is_shifted(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn is_ctrl(&self) -> bool {
+        self.left_ctrl || self.right_ctrl
+    }
+
+    pub fn is_alt(&self) -> bool {
+        self.left_alt || self.right_alt
+    }
+
+    /// キーボードに送る LED の状態。
+    fn leds(&self) -> u8 {
+        let mut leds = 0;
+        if self.scroll_lock {
+            leds |= LED_SCROLL_LOCK;
+        }
+        if self.num_lock {
+            leds |= LED_NUM_LOCK;
+        }
+        if self.caps_lock {
+            leds |= LED_CAPS_LOCK;
+        }
+        leds
+    }
+}
+
+/// キーが押されたり離されたりしたことを表すイベント。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub state: KeyState,
+    /// イベント発生時の修飾キーの状態。
+    pub modifiers: Modifiers,
+    /// 入力された文字。
+    /// キーが離された時や、文字に対応しないキーの場合は `None` 。
+    pub character: Option<char>,
+}
+
+impl KeyEvent {
+    /// `EventQueue` のバッファを初期化するための値。
+    const EMPTY: KeyEvent = KeyEvent {
+        key: KeyCode::Escape,
+        state: KeyState::Up,
+        modifiers: Modifiers::NONE,
+        character: None,
+    };
+}
+
+/// scancode をキーイベントに変換する。
+///
+/// ハードウェアには触らないので、テストから直接使うことができる。
+pub struct Keyboard {
+    decoder: Decoder,
+    modifiers: Modifiers,
+    layout: Layout,
+}
+
+impl Keyboard {
+    pub const fn new(set: ScancodeSet, layout: Layout) -> Keyboard {
+        Keyboard {
+            decoder: Decoder::new(set),
+            modifiers: Modifiers::NONE,
+            layout,
+        }
+    }
+
+    pub fn modifiers(&self) -> &Modifiers {
+        &self.modifiers
+    }
+
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    pub fn set_scancode_set(&mut self, set: ScancodeSet) {
+        self.decoder = Decoder::new(set);
+    }
+
+    /// キーボードから受け取った1バイトを処理する。
+    ///
+    /// キーイベントが確定した場合はそれを返す。
+    pub fn process(&mut self, byte: u8) -> Option<KeyEvent> {
+        if let RESPONSE_ERROR | RESPONSE_ACK | RESPONSE_RESEND | RESPONSE_ERROR_2 = byte {
+            return None;
+        }
+
+        let (key, state) = self.decoder.add_byte(byte)?;
+        self.update_modifiers(key, state);
+
+        let character = match state {
+            KeyState::Down => self.layout.map(key, &self.modifiers),
+            KeyState::Up => None,
+        };
+
+        Some(KeyEvent {
+            key,
+            state,
+            modifiers: self.modifiers,
+            character,
+        })
+    }
+
+    fn update_modifiers(&mut self, key: KeyCode, state: KeyState) {
+        let down = state == KeyState::Down;
+        let m = &mut self.modifiers;
+        match key {
+            KeyCode::LeftShift => m.left_shift = down,
+            KeyCode::RightShift => m.right_shift = down,
+            KeyCode::LeftControl => m.left_ctrl = down,
+            KeyCode::RightControl => m.right_ctrl = down,
+            KeyCode::LeftAlt => m.left_alt = down,
+            KeyCode::RightAlt => m.right_alt = down,
+            // Lock キーは押すたびに状態が反転する
+            KeyCode::CapsLock if down => m.caps_lock = !m.caps_lock,
+            KeyCode::NumLock if down => m.num_lock = !m.num_lock,
+            KeyCode::ScrollLock if down => m.scroll_lock = !m.scroll_lock,
+            _ => {}
+        }
+    }
+}
+
+lazy_static! {
+    /// QEMU を含むほとんどの環境では、コントローラが set 1 に変換してくれる。
+    static ref KEYBOARD: Mutex<Keyboard> =
+        Mutex::new(Keyboard::new(ScancodeSet::Set1, Layout::Us104));
+    static ref EVENTS: EventQueue = EventQueue::new();
+}
+
+/// キューにイベントが追加されたことを `KeyEventStream` に知らせる。
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+/// キーボードに送る LED の状態。
+static LEDS: AtomicU8 = AtomicU8::new(0);
+/// `LEDS` が変わったが、まだ送っていないか。
+static LEDS_CHANGED: AtomicBool = AtomicBool::new(false);
+/// 最後に受け取った ACK か Resend 。まだ受け取っていなければ 0 。
+static RESPONSE: AtomicU8 = AtomicU8::new(0);
+/// `LEDS` が変わったことや、応答が届いたことを `update_leds` に知らせる。
+static LED_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Resend が返ってきた時に送り直す回数の上限。
+const MAX_RESENDS: usize = 3;
+
+/// キーボードレイアウトを切り替える。
+pub fn set_layout(layout: Layout) {
+    // 割り込みハンドラも KEYBOARD を lock するので、
+    // 割り込みを無効にしておかないとデッドロックする可能性がある。
+    without_interrupts(|| KEYBOARD.lock().set_layout(layout));
+}
+
+/// キーボードの scancode set が変わった時に呼び出す。
+pub fn set_scancode_set(set: ScancodeSet) {
+    without_interrupts(|| KEYBOARD.lock().set_scancode_set(set));
+}
+
+/// キューからキーイベントを1つ取り出す。
+///
+/// キューの consumer は1つだけである必要があるので、
+/// 複数の場所から呼び出さないこと。
+pub fn pop_event() -> Option<KeyEvent> {
+    EVENTS.pop()
+}
+
+/// キーボード割り込みハンドラから呼び出される。
+pub(crate) fn handle_interrupt() {
+    let mut data_port = Port::new(DATA_PORT);
+    let byte: u8 = unsafe { data_port.read() };
+
+    if let RESPONSE_ACK | RESPONSE_RESEND = byte {
+        RESPONSE.store(byte, Ordering::SeqCst);
+        LED_WAKER.wake();
+    }
+
+    let mut keyboard = KEYBOARD.lock();
+    let leds = keyboard.modifiers().leds();
+    if let Some(event) = keyboard.process(byte) {
+        // キューがいっぱいの場合は捨てるしかない
+        let _ = EVENTS.push(event);
+        WAKER.wake();
+    }
+
+    let new_leds = keyboard.modifiers().leds();
+    if new_leds != leds {
+        LEDS.store(new_leds, Ordering::SeqCst);
+        LEDS_CHANGED.store(true, Ordering::SeqCst);
+        LED_WAKER.wake();
+    }
+}
+
+/// LED の状態が変わるたびに、キーボードに送り続ける。
+///
+/// executor のタスクとして1つだけ実行すること。
+pub async fn update_leds() {
+    loop {
+        poll_fn(|cx| {
+            LED_WAKER.register(cx.waker());
+            if LEDS_CHANGED.swap(false, Ordering::SeqCst) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+
+        let leds = LEDS.load(Ordering::SeqCst);
+        if send(COMMAND_SET_LEDS).await {
+            send(leds).await;
+        }
+    }
+}
+
+/// キーボードに `byte` を送り、ACK が返ってきたら `true` を返す。
+///
+/// Resend が返ってきたら送り直す。
+/// 応答がないまま LED の状態が変わった場合は `false` を返す。
+async fn send(byte: u8) -> bool {
+    for _ in 0..=MAX_RESENDS {
+        RESPONSE.store(0, Ordering::SeqCst);
+        write_data(byte);
+        let response = poll_fn(|cx| {
+            LED_WAKER.register(cx.waker());
+            match RESPONSE.swap(0, Ordering::SeqCst) {
+                0 if LEDS_CHANGED.load(Ordering::SeqCst) => Poll::Ready(None),
+                0 => Poll::Pending,
+                response => Poll::Ready(Some(response)),
+            }
+        })
+        .await;
+        match response {
+            Some(RESPONSE_ACK) => return true,
+            Some(_) => continue,
+            None => return false,
+        }
+    }
+    false
+}
+
+/// キーイベントを async に受け取るための stream 。
+///
+/// キューの consumer は1つだけである必要があるので、
+/// 同時に1つしか作ることができない。
+/// また、`pop_event` と併用してはいけない。
+pub struct KeyEventStream {
+    _private: (),
+}
+
+static STREAM_CREATED: AtomicBool = AtomicBool::new(false);
+
+impl KeyEventStream {
+    /// ## Panics
+    /// すでに `KeyEventStream` が存在する場合は panic する。
+    pub fn new() -> Self {
+        if STREAM_CREATED.swap(true, Ordering::AcqRel) {
+            panic!("KeyEventStream::new should only be called once at a time");
+        }
+        KeyEventStream { _private: () }
+    }
+}
+
+impl Drop for KeyEventStream {
+    fn drop(&mut self) {
+        STREAM_CREATED.store(false, Ordering::Release);
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = KeyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<KeyEvent>> {
+        // fast path
+        if let Some(event) = EVENTS.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        WAKER.register(&cx.waker());
+        // register している間にイベントが追加されたかもしれないので、もう一度確認する
+        match EVENTS.pop() {
+            Some(event) => {
+                WAKER.take();
+                Poll::Ready(Some(event))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// コントローラの input buffer が空くのを待ってから、キーボードにデータを送る。
+fn write_data(data: u8) {
+    let mut status_port: Port<u8> = Port::new(STATUS_PORT);
+    let mut data_port = Port::new(DATA_PORT);
+
+    // キーボードが応答しない場合に無限ループしないよう、待つ回数に上限を設ける
+    for _ in 0..100_000 {
+        if unsafe { status_port.read() } & STATUS_INPUT_FULL == 0 {
+            unsafe { data_port.write(data) };
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    fn feed(keyboard: &mut Keyboard, bytes: &[u8]) -> Option<KeyEvent> {
+        bytes.iter().filter_map(|b| keyboard.process(*b)).last()
+    }
+
+    #[test_case]
+    fn test_set1_shift() {
+        serial_print!("test_set1_shift... ");
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, Layout::Us104);
+
+        let event = feed(&mut keyboard, &[0x1E]).unwrap();
+        assert_eq!(event.key, KeyCode::A);
+        assert_eq!(event.character, Some('a'));
+
+        // LeftShift 押下、A 押下
+        let event = feed(&mut keyboard, &[0x2A, 0x1E]).unwrap();
+        assert_eq!(event.character, Some('A'));
+        assert!(event.modifiers.left_shift);
+
+        // LeftShift 解放
+        let event = feed(&mut keyboard, &[0xAA]).unwrap();
+        assert_eq!(event.state, KeyState::Up);
+        assert!(!keyboard.modifiers().is_shifted());
+
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_set1_caps_lock() {
+        serial_print!("test_set1_caps_lock... ");
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, Layout::Us104);
+
+        // CapsLock 押下・解放
+        feed(&mut keyboard, &[0x3A, 0xBA]);
+        assert!(keyboard.modifiers().caps_lock);
+        assert_eq!(keyboard.modifiers().leds(), LED_CAPS_LOCK);
+
+        assert_eq!(feed(&mut keyboard, &[0x1E]).unwrap().character, Some('A'));
+        // Caps Lock は数字には作用しない
+        assert_eq!(feed(&mut keyboard, &[0x03]).unwrap().character, Some('2'));
+        // Caps Lock 中の Shift は小文字になる
+        let event = feed(&mut keyboard, &[0x2A, 0x1E]).unwrap();
+        assert_eq!(event.character, Some('a'));
+
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_set1_extended() {
+        serial_print!("test_set1_extended... ");
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, Layout::Us104);
+
+        let event = feed(&mut keyboard, &[0xE0, 0x48]).unwrap();
+        assert_eq!(event.key, KeyCode::ArrowUp);
+        assert_eq!(event.character, None);
+
+        let event = feed(&mut keyboard, &[0xE0, 0x1D]).unwrap();
+        assert_eq!(event.key, KeyCode::RightControl);
+        assert!(event.modifiers.is_ctrl());
+
+        // ACK は無視される
+        assert_eq!(keyboard.process(RESPONSE_ACK), None);
+
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_set2() {
+        serial_print!("test_set2... ");
+        let mut keyboard = Keyboard::new(ScancodeSet::Set2, Layout::Us104);
+
+        let event = feed(&mut keyboard, &[0x1C]).unwrap();
+        assert_eq!((event.key, event.state), (KeyCode::A, KeyState::Down));
+
+        let event = feed(&mut keyboard, &[0xF0, 0x1C]).unwrap();
+        assert_eq!((event.key, event.state), (KeyCode::A, KeyState::Up));
+
+        let event = feed(&mut keyboard, &[0xE0, 0xF0, 0x75]).unwrap();
+        assert_eq!((event.key, event.state), (KeyCode::ArrowUp, KeyState::Up));
+
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_jis_layout() {
+        serial_print!("test_jis_layout... ");
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, Layout::Jis109);
+
+        // Shift + 2
+        assert_eq!(
+            feed(&mut keyboard, &[0x2A, 0x03]).unwrap().character,
+            Some('"')
+        );
+        // Shift + ろ
+        assert_eq!(feed(&mut keyboard, &[0x73]).unwrap().character, Some('_'));
+        // Shift + ¥
+        assert_eq!(feed(&mut keyboard, &[0x7D]).unwrap().character, Some('|'));
+        feed(&mut keyboard, &[0xAA]);
+        // @
+        assert_eq!(feed(&mut keyboard, &[0x1A]).unwrap().character, Some('@'));
+
+        keyboard.set_layout(Layout::Us104);
+        assert_eq!(feed(&mut keyboard, &[0x1A]).unwrap().character, Some('['));
+
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,174 @@
+//! ## キーボードレイアウト
+//!
+//! 同じ物理キーでも、キーボードの配列によって入力される文字が異なる。
+//! 例えば `Key2` を Shift と一緒に押すと、US 配列では `@` だが JIS 配列では `"` になる。
+//!
+//! ここでは US 104 キー配列と JIS 109 キー配列をサポートする。
+//! JIS 配列の `¥` は ASCII に存在しないので `\` として扱う。
+//! （JIS X 0201 では 0x5C が `¥` なので、歴史的にはこれで正しい）
+
+use super::{scancode::KeyCode, Modifiers};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Jis109,
+}
+
+impl Layout {
+    /// 押されたキーと修飾キーの状態から、入力された文字を求める。
+    ///
+    /// 文字に対応しないキー（矢印キーや修飾キーなど）の場合は `None` を返す。
+    pub fn map(self, key: KeyCode, modifiers: &Modifiers) -> Option<char> {
+        if let Some(c) = map_letter(key) {
+            // Caps Lock はアルファベットにだけ作用する
+            return Some(if modifiers.is_shifted() != modifiers.caps_lock {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            });
+        }
+
+        if let Some(c) = map_common(key, modifiers) {
+            return Some(c);
+        }
+
+        let shifted = modifiers.is_shifted();
+        match self {
+            Layout::Us104 => map_us104(key, shifted),
+            Layout::Jis109 => map_jis109(key, shifted),
+        }
+    }
+}
+
+fn map_letter(key: KeyCode) -> Option<char> {
+    use KeyCode::*;
+
+    let c = match key {
+        A => 'a',
+        B => 'b',
+        C => 'c',
+        D => 'd',
+        E => 'e',
+        F => 'f',
+        G => 'g',
+        H => 'h',
+        I => 'i',
+        J => 'j',
+        K => 'k',
+        L => 'l',
+        M => 'm',
+        N => 'n',
+        O => 'o',
+        P => 'p',
+        Q => 'q',
+        R => 'r',
+        S => 's',
+        T => 't',
+        U => 'u',
+        V => 'v',
+        W => 'w',
+        X => 'x',
+        Y => 'y',
+        Z => 'z',
+        _ => return None,
+    };
+    Some(c)
+}
+
+/// 配列に依存しないキー。
+fn map_common(key: KeyCode, modifiers: &Modifiers) -> Option<char> {
+    use KeyCode::*;
+
+    let c = match key {
+        Space => ' ',
+        Tab => '\t',
+        Enter | NumpadEnter => '\n',
+        Backspace => '\x08',
+        Escape => '\x1B',
+        Delete => '\x7F',
+        NumpadSlash => '/',
+        NumpadStar => '*',
+        NumpadMinus => '-',
+        NumpadPlus => '+',
+        key if modifiers.num_lock => match key {
+            Numpad0 => '0',
+            Numpad1 => '1',
+            Numpad2 => '2',
+            Numpad3 => '3',
+            Numpad4 => '4',
+            Numpad5 => '5',
+            Numpad6 => '6',
+            Numpad7 => '7',
+            Numpad8 => '8',
+            Numpad9 => '9',
+            NumpadPeriod => '.',
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(c)
+}
+
+fn map_us104(key: KeyCode, shifted: bool) -> Option<char> {
+    use KeyCode::*;
+
+    let (normal, shift) = match key {
+        BackTick => ('`', '~'),
+        Key1 => ('1', '!'),
+        Key2 => ('2', '@'),
+        Key3 => ('3', '#'),
+        Key4 => ('4', '$'),
+        Key5 => ('5', '%'),
+        Key6 => ('6', '^'),
+        Key7 => ('7', '&'),
+        Key8 => ('8', '*'),
+        Key9 => ('9', '('),
+        Key0 => ('0', ')'),
+        Minus => ('-', '_'),
+        Equals => ('=', '+'),
+        BracketSquareLeft => ('[', '{'),
+        BracketSquareRight => (']', '}'),
+        BackSlash => ('\\', '|'),
+        SemiColon => (';', ':'),
+        Quote => ('\'', '"'),
+        Comma => (',', '<'),
+        Period => ('.', '>'),
+        Slash => ('/', '?'),
+        _ => return None,
+    };
+    Some(if shifted { shift } else { normal })
+}
+
+fn map_jis109(key: KeyCode, shifted: bool) -> Option<char> {
+    use KeyCode::*;
+
+    let (normal, shift) = match key {
+        Key1 => ('1', '!'),
+        Key2 => ('2', '"'),
+        Key3 => ('3', '#'),
+        Key4 => ('4', '$'),
+        Key5 => ('5', '%'),
+        Key6 => ('6', '&'),
+        Key7 => ('7', '\''),
+        Key8 => ('8', '('),
+        Key9 => ('9', ')'),
+        // JIS 配列では Shift + 0 に文字が割り当てられていない
+        Key0 if shifted => return None,
+        Key0 => ('0', '0'),
+        Minus => ('-', '='),
+        Equals => ('^', '~'),
+        International3 => ('\\', '|'),
+        BracketSquareLeft => ('@', '`'),
+        BracketSquareRight => ('[', '{'),
+        SemiColon => (';', '+'),
+        Quote => (':', '*'),
+        BackSlash => (']', '}'),
+        Comma => (',', '<'),
+        Period => ('.', '>'),
+        Slash => ('/', '?'),
+        International1 => ('\\', '_'),
+        _ => return None,
+    };
+    Some(if shifted { shift } else { normal })
+}
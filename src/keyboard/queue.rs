@@ -0,0 +1,87 @@
+//! ## Lock-free なキーイベントキュー
+//!
+//! キーボード割り込みハンドラとカーネルの他の部分の間でキーイベントを受け渡すための
+//! 固定長のリングバッファ。
+//!
+//! 割り込みハンドラの中で lock を取ると、同じ lock を保持している最中に
+//! 割り込みが発生した場合にデッドロックしてしまう。
+//! そのため、割り込みハンドラ（producer）と読み出し側（consumer）が
+//! lock を取らずにアクセスできるようにする。
+//!
+//! producer と consumer がそれぞれ1つだけであること（SPSC）を前提にしている。
+//! producer は割り込みハンドラだけなので、consumer が1つであることは利用者が保証する。
+//!
+//! `head` は次に読み出す位置、`tail` は次に書き込む位置を表す。
+//! どちらも単調増加させ、バッファのインデックスとして使う時に `QUEUE_SIZE` で割った余りをとる。
+//! `tail - head` がキューに入っている要素数になる。
+
+use super::KeyEvent;
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub const QUEUE_SIZE: usize = 128;
+
+pub struct EventQueue {
+    buf: UnsafeCell<[KeyEvent; QUEUE_SIZE]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+// `buf` の各要素には、`head` と `tail` によって producer と consumer の
+// どちらか一方だけがアクセスするので安全。
+unsafe impl Sync for EventQueue {}
+
+impl EventQueue {
+    pub fn new() -> EventQueue {
+        EventQueue {
+            buf: UnsafeCell::new([KeyEvent::EMPTY; QUEUE_SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// キューの末尾にイベントを追加する。
+    ///
+    /// キューがいっぱいの場合はイベントを捨てて `Err` を返す。
+    /// producer からのみ呼び出すこと。
+    pub fn push(&self, event: KeyEvent) -> Result<(), KeyEvent> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) >= QUEUE_SIZE {
+            return Err(event);
+        }
+
+        unsafe {
+            (*self.buf.get())[tail % QUEUE_SIZE] = event;
+        }
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// キューの先頭からイベントを取り出す。
+    ///
+    /// consumer からのみ呼び出すこと。
+    pub fn pop(&self) -> Option<KeyEvent> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let event = unsafe { (*self.buf.get())[head % QUEUE_SIZE] };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(event)
+    }
+
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,519 @@
+//! ## Scancode
+//!
+//! キーボードはキーが押されたり離されたりするたびに、
+//! そのキーを表す scancode を送ってくる。
+//! scancode の割り当てには複数の種類（scancode set）があり、
+//! 現在よく使われているのは set 1 と set 2 である。
+//!
+//! ### Set 1
+//!
+//! IBM XT で使われていたもの。
+//! キーを押した時の make code に 0x80 を OR したものが、離した時の break code になる。
+//! 後から追加されたキー（矢印キーなど）は 0xE0 が前置される。
+//!
+//! PS/2 コントローラ (8042) はデフォルトで set 2 を set 1 に変換して
+//! CPU に渡してくれるので、通常は set 1 だけを考えればよい。
+//! QEMU もこの変換を行う。
+//!
+//! ### Set 2
+//!
+//! IBM AT で使われていたもの。
+//! break code は make code の前に 0xF0 が前置される。
+//! 拡張キーは set 1 と同様に 0xE0 が前置される。
+//!
+//! ### Pause
+//!
+//! Pause キーは特殊で、押した時だけ 0xE1 から始まる長いシーケンスを送ってくる。
+//! 離した時のシーケンスは無い。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/PS/2_Keyboard
+
+/// キーボード上の物理的なキーの位置。
+///
+/// 名前は US 配列での刻印に従う。
+/// JIS 配列にしか存在しないキーは USB HID の名前に従う。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    BackTick,
+    Key1,
+    Key2,
+    Key3,
+    Key4,
+    Key5,
+    Key6,
+    Key7,
+    Key8,
+    Key9,
+    Key0,
+    Minus,
+    Equals,
+    Backspace,
+    Tab,
+    Q,
+    W,
+    E,
+    R,
+    T,
+    Y,
+    U,
+    I,
+    O,
+    P,
+    BracketSquareLeft,
+    BracketSquareRight,
+    BackSlash,
+    CapsLock,
+    A,
+    S,
+    D,
+    F,
+    G,
+    H,
+    J,
+    K,
+    L,
+    SemiColon,
+    Quote,
+    Enter,
+    LeftShift,
+    Z,
+    X,
+    C,
+    V,
+    B,
+    N,
+    M,
+    Comma,
+    Period,
+    Slash,
+    RightShift,
+    LeftControl,
+    LeftWin,
+    LeftAlt,
+    Space,
+    RightAlt,
+    RightWin,
+    Menu,
+    RightControl,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    ArrowUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+    NumLock,
+    ScrollLock,
+    Pause,
+    NumpadSlash,
+    NumpadStar,
+    NumpadMinus,
+    NumpadPlus,
+    NumpadEnter,
+    NumpadPeriod,
+    Numpad0,
+    Numpad1,
+    Numpad2,
+    Numpad3,
+    Numpad4,
+    Numpad5,
+    Numpad6,
+    Numpad7,
+    Numpad8,
+    Numpad9,
+    /// JIS 配列の「ろ」キー（`\` `_`）
+    International1,
+    /// JIS 配列の「カタカナ/ひらがな」キー
+    International2,
+    /// JIS 配列の「¥」キー（`¥` `|`）
+    International3,
+    /// JIS 配列の「変換」キー
+    International4,
+    /// JIS 配列の「無変換」キー
+    International5,
+}
+
+/// キーが押されたのか離されたのか。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Down,
+    Up,
+}
+
+/// scancode set の種類。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+/// scancode のバイト列を `KeyCode` に変換するステートマシン。
+#[derive(Debug)]
+pub struct Decoder {
+    set: ScancodeSet,
+    extended: bool,
+    release: bool,
+    /// Pause キーのシーケンスのうち、読み飛ばす残りのバイト数。
+    skip: u8,
+}
+
+impl Decoder {
+    pub const fn new(set: ScancodeSet) -> Decoder {
+        Decoder {
+            set,
+            extended: false,
+            release: false,
+            skip: 0,
+        }
+    }
+
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    /// 1バイト読み込む。
+    ///
+    /// シーケンスが完結してキーが確定した場合はそれを返す。
+    pub fn add_byte(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+
+        match self.set {
+            ScancodeSet::Set1 => self.add_byte_set1(byte),
+            ScancodeSet::Set2 => self.add_byte_set2(byte),
+        }
+    }
+
+    fn add_byte_set1(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        match byte {
+            0xE0 => {
+                self.extended = true;
+                None
+            }
+            0xE1 => {
+                // E1 1D 45 E1 9D C5
+                self.skip = 5;
+                Some((KeyCode::Pause, KeyState::Down))
+            }
+            byte => {
+                let extended = core::mem::replace(&mut self.extended, false);
+                let state = if byte & 0x80 == 0 {
+                    KeyState::Down
+                } else {
+                    KeyState::Up
+                };
+                let code = byte & 0x7F;
+                let key = if extended {
+                    set1_extended(code)
+                } else {
+                    set1(code)
+                };
+                key.map(|key| (key, state))
+            }
+        }
+    }
+
+    fn add_byte_set2(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        match byte {
+            0xE0 => {
+                self.extended = true;
+                None
+            }
+            0xF0 => {
+                self.release = true;
+                None
+            }
+            0xE1 => {
+                // E1 14 77 E1 F0 14 F0 77
+                self.skip = 7;
+                Some((KeyCode::Pause, KeyState::Down))
+            }
+            code => {
+                let extended = core::mem::replace(&mut self.extended, false);
+                let release = core::mem::replace(&mut self.release, false);
+                let state = if release {
+                    KeyState::Up
+                } else {
+                    KeyState::Down
+                };
+                let key = if extended {
+                    set2_extended(code)
+                } else {
+                    set2(code)
+                };
+                key.map(|key| (key, state))
+            }
+        }
+    }
+}
+
+fn set1(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    let key = match code {
+        0x01 => Escape,
+        0x02 => Key1,
+        0x03 => Key2,
+        0x04 => Key3,
+        0x05 => Key4,
+        0x06 => Key5,
+        0x07 => Key6,
+        0x08 => Key7,
+        0x09 => Key8,
+        0x0A => Key9,
+        0x0B => Key0,
+        0x0C => Minus,
+        0x0D => Equals,
+        0x0E => Backspace,
+        0x0F => Tab,
+        0x10 => Q,
+        0x11 => W,
+        0x12 => E,
+        0x13 => R,
+        0x14 => T,
+        0x15 => Y,
+        0x16 => U,
+        0x17 => I,
+        0x18 => O,
+        0x19 => P,
+        0x1A => BracketSquareLeft,
+        0x1B => BracketSquareRight,
+        0x1C => Enter,
+        0x1D => LeftControl,
+        0x1E => A,
+        0x1F => S,
+        0x20 => D,
+        0x21 => F,
+        0x22 => G,
+        0x23 => H,
+        0x24 => J,
+        0x25 => K,
+        0x26 => L,
+        0x27 => SemiColon,
+        0x28 => Quote,
+        0x29 => BackTick,
+        0x2A => LeftShift,
+        0x2B => BackSlash,
+        0x2C => Z,
+        0x2D => X,
+        0x2E => C,
+        0x2F => V,
+        0x30 => B,
+        0x31 => N,
+        0x32 => M,
+        0x33 => Comma,
+        0x34 => Period,
+        0x35 => Slash,
+        0x36 => RightShift,
+        0x37 => NumpadStar,
+        0x38 => LeftAlt,
+        0x39 => Space,
+        0x3A => CapsLock,
+        0x3B => F1,
+        0x3C => F2,
+        0x3D => F3,
+        0x3E => F4,
+        0x3F => F5,
+        0x40 => F6,
+        0x41 => F7,
+        0x42 => F8,
+        0x43 => F9,
+        0x44 => F10,
+        0x45 => NumLock,
+        0x46 => ScrollLock,
+        0x47 => Numpad7,
+        0x48 => Numpad8,
+        0x49 => Numpad9,
+        0x4A => NumpadMinus,
+        0x4B => Numpad4,
+        0x4C => Numpad5,
+        0x4D => Numpad6,
+        0x4E => NumpadPlus,
+        0x4F => Numpad1,
+        0x50 => Numpad2,
+        0x51 => Numpad3,
+        0x52 => Numpad0,
+        0x53 => NumpadPeriod,
+        0x57 => F11,
+        0x58 => F12,
+        0x70 => International2,
+        0x73 => International1,
+        0x79 => International4,
+        0x7B => International5,
+        0x7D => International3,
+        _ => return None,
+    };
+    Some(key)
+}
+
+fn set1_extended(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    // 0xE0 0x2A などの「偽の Shift」は PrintScreen などで送られてくるが、
+    // ここでは無視する。
+    let key = match code {
+        0x1C => NumpadEnter,
+        0x1D => RightControl,
+        0x35 => NumpadSlash,
+        0x38 => RightAlt,
+        0x47 => Home,
+        0x48 => ArrowUp,
+        0x49 => PageUp,
+        0x4B => ArrowLeft,
+        0x4D => ArrowRight,
+        0x4F => End,
+        0x50 => ArrowDown,
+        0x51 => PageDown,
+        0x52 => Insert,
+        0x53 => Delete,
+        0x5B => LeftWin,
+        0x5C => RightWin,
+        0x5D => Menu,
+        _ => return None,
+    };
+    Some(key)
+}
+
+fn set2(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    let key = match code {
+        0x01 => F9,
+        0x03 => F5,
+        0x04 => F3,
+        0x05 => F1,
+        0x06 => F2,
+        0x07 => F12,
+        0x09 => F10,
+        0x0A => F8,
+        0x0B => F6,
+        0x0C => F4,
+        0x0D => Tab,
+        0x0E => BackTick,
+        0x11 => LeftAlt,
+        0x12 => LeftShift,
+        0x13 => International2,
+        0x14 => LeftControl,
+        0x15 => Q,
+        0x16 => Key1,
+        0x1A => Z,
+        0x1B => S,
+        0x1C => A,
+        0x1D => W,
+        0x1E => Key2,
+        0x21 => C,
+        0x22 => X,
+        0x23 => D,
+        0x24 => E,
+        0x25 => Key4,
+        0x26 => Key3,
+        0x29 => Space,
+        0x2A => V,
+        0x2B => F,
+        0x2C => T,
+        0x2D => R,
+        0x2E => Key5,
+        0x31 => N,
+        0x32 => B,
+        0x33 => H,
+        0x34 => G,
+        0x35 => Y,
+        0x36 => Key6,
+        0x3A => M,
+        0x3B => J,
+        0x3C => U,
+        0x3D => Key7,
+        0x3E => Key8,
+        0x41 => Comma,
+        0x42 => K,
+        0x43 => I,
+        0x44 => O,
+        0x45 => Key0,
+        0x46 => Key9,
+        0x49 => Period,
+        0x4A => Slash,
+        0x4B => L,
+        0x4C => SemiColon,
+        0x4D => P,
+        0x4E => Minus,
+        0x51 => International1,
+        0x52 => Quote,
+        0x54 => BracketSquareLeft,
+        0x55 => Equals,
+        0x58 => CapsLock,
+        0x59 => RightShift,
+        0x5A => Enter,
+        0x5B => BracketSquareRight,
+        0x5D => BackSlash,
+        0x64 => International4,
+        0x66 => Backspace,
+        0x67 => International5,
+        0x69 => Numpad1,
+        0x6A => International3,
+        0x6B => Numpad4,
+        0x6C => Numpad7,
+        0x70 => Numpad0,
+        0x71 => NumpadPeriod,
+        0x72 => Numpad2,
+        0x73 => Numpad5,
+        0x74 => Numpad6,
+        0x75 => Numpad8,
+        0x76 => Escape,
+        0x77 => NumLock,
+        0x78 => F11,
+        0x79 => NumpadPlus,
+        0x7A => Numpad3,
+        0x7B => NumpadMinus,
+        0x7C => NumpadStar,
+        0x7D => Numpad9,
+        0x7E => ScrollLock,
+        0x83 => F7,
+        _ => return None,
+    };
+    Some(key)
+}
+
+fn set2_extended(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    let key = match code {
+        0x11 => RightAlt,
+        0x14 => RightControl,
+        0x1F => LeftWin,
+        0x27 => RightWin,
+        0x2F => Menu,
+        0x4A => NumpadSlash,
+        0x5A => NumpadEnter,
+        0x69 => End,
+        0x6B => ArrowLeft,
+        0x6C => Home,
+        0x70 => Insert,
+        0x71 => Delete,
+        0x72 => ArrowDown,
+        0x74 => ArrowRight,
+        0x75 => ArrowUp,
+        0x7A => PageDown,
+        0x7D => PageUp,
+        _ => return None,
+    };
+    Some(key)
+}
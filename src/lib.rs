@@ -1,30 +1,126 @@
 #![no_std]
 #![cfg_attr(test, no_main)]
 // `abi_x86_interrupt` は `x86-interrupt` 呼び出し規約の利用を有効にする。
-#![feature(custom_test_frameworks, abi_x86_interrupt)]
+#![feature(
+    custom_test_frameworks,
+    abi_x86_interrupt,
+    panic_info_message,
+    alloc_error_handler,
+    const_fn,
+    asm,
+    global_asm
+)]
 #![test_runner(test_utils::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+extern crate alloc;
+
+pub mod acpi;
+pub mod allocator;
+pub mod backtrace;
+pub mod cpu;
+pub mod gdb;
 pub mod gdt;
 pub mod interrupts;
+pub mod keyboard;
+pub mod logger;
+pub mod memory;
+pub mod power;
+pub mod process;
+pub mod smp;
+pub mod sync;
+pub mod syscall;
+pub mod task;
 pub mod test_utils;
+pub mod thread;
+pub mod user;
 pub mod vga;
 
 pub fn init() {
-    gdt::init();
+    logger::init();
+    // BSP の GDT と TSS をロードし、GS base を設定する。
+    cpu::init_bsp();
     interrupts::init_idt();
+    syscall::init();
+    // IDT にハードウェア割り込みのハンドラを登録し、PIC を初期化してから
+    // 割り込みを有効にする。順番を間違えると、ハンドラが存在しない割り込みが
+    // 発生して Double Fault になる。
+    interrupts::pic::init();
+    gdb::init();
+    x86_64::instructions::interrupts::enable();
+}
+
+/// ACPI のテーブルを読み込み、割り込みコントローラを APIC に切り替える。
+///
+/// `memory::init` と `allocator::init_heap` の後に呼び出すこと。
+/// APIC が使えない場合は、8259 PIC を使い続ける。
+pub fn init_apic() {
+    let result = acpi::init()
+        .map_err(interrupts::apic::ApicError::from)
+        .and_then(|()| interrupts::apic::init());
+    if let Err(err) = result {
+        log::warn!("APIC is not available, using 8259 PIC : {:?}", err);
+    }
+}
+
+/// APIC で他の CPU を起動する。
+///
+/// `init_apic` の後に呼び出すこと。
+/// 起動できなかった場合は BSP だけで動き続ける。
+pub fn init_smp() {
+    match smp::init() {
+        Ok(count) => log::info!("{} CPU(s) online", count),
+        Err(err) => log::warn!("failed to start other CPUs : {:?}", err),
+    }
+}
+
+/// `hlt` 命令で次の割り込みまで CPU を休ませ続ける。
+///
+/// `loop {}` と違って CPU を使い切らない。
+pub fn hlt_loop() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// heap の割り当てに失敗した時に呼び出される。
+#[alloc_error_handler]
+fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    panic!(
+        "allocation error: size = {}, align = {}",
+        layout.size(),
+        layout.align()
+    )
+}
+
+/// 割り込みを有効にし、次の割り込みまで CPU を休ませる。
+///
+/// `sti` 命令は次の命令が完了するまで割り込みの有効化を遅らせるので、
+/// `sti; hlt` と続けて実行すると、有効化と `hlt` の間に割り込みが入ることはない。
+pub fn enable_interrupts_and_hlt() {
+    unsafe {
+        asm!("sti; hlt" :::: "volatile");
+    }
 }
 
+#[cfg(test)]
+use bootloader::{entry_point, BootInfo};
 #[cfg(test)]
 use core::panic::PanicInfo;
 
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
 /// Entry point for `cargo xtest --lib`
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    memory::init(boot_info);
+    allocator::init_heap().expect("heap initialization failed");
+    init_apic();
+    init_smp();
     test_main();
-    loop {}
+    hlt_loop();
 }
 
 #[cfg(test)]
@@ -0,0 +1,327 @@
+//! ## Logger
+//!
+//! `log` クレートの `Log` を実装し、`log::info!` などのマクロで kernel のログを出力できるようにする。
+//!
+//! 各行は次のような形式になる。
+//!
+//! ```text
+//! [     123] cpu0 INFO  atomix::smp: 4 CPU(s) online
+//! ```
+//!
+//! 先頭はタイマー割り込みの tick（`interrupts::ticks`）、次は CPU の番号、
+//! 最後はログの target（デフォルトではモジュールのパス）である。
+//!
+//! ### レベル
+//!
+//! 出力するレベルは起動時に決める。ビルドする時に環境変数 `ATOMIX_LOG` を
+//! `env_logger` と同じ形式で指定すると、それが使われる。指定しなければ `info` 。
+//!
+//! ```text
+//! ATOMIX_LOG=info,atomix::acpi=debug cargo xrun
+//! ```
+//!
+//! target ごとのレベルは、最も長く一致した target の設定が使われる。
+//! 起動した後に `set_level` と `set_target_level` で変更することもできる。
+//!
+//! ### Sink
+//!
+//! 1行に整形したログを `Sink` に渡す。`add_sink` で sink ごとに出力するレベルを決めて追加できる。
+//! `init` は次の sink を追加する。
+//!
+//! - `SERIAL` : 全てのレベル。
+//! - `ring::RING` : 全てのレベル。後から `dmesg` で読める。
+//! - `VGA` : `WARN` 以上。
+//!
+//! `persistent::init` を呼び出すと、再起動しても消えないメモリに書き込む
+//! `persistent::CRASH_LOG` も追加される。
+//!
+//! heap を初期化する前や panic 中にも使えるように、heap は使わない。
+//! sink と target の設定は固定長の配列に入れ、1行は固定長のバッファに整形する。
+
+pub mod persistent;
+pub mod ring;
+
+use crate::{cpu, interrupts, sync::IrqSafeMutex};
+use core::{
+    fmt::{self, Write},
+    str,
+};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// 追加できる sink の数。
+const MAX_SINKS: usize = 8;
+/// 設定できる target ごとのレベルの数。
+const MAX_TARGETS: usize = 16;
+/// 1行の最大の長さ。これより長い部分は捨てる。
+const LINE_SIZE: usize = 256;
+
+/// 整形したログの出力先。
+pub trait Sink: Sync {
+    /// `line` は末尾に改行を含まない。
+    fn write(&self, level: Level, line: &str);
+}
+
+/// VGA に出力する sink 。
+pub struct VgaSink;
+
+impl Sink for VgaSink {
+    fn write(&self, _level: Level, line: &str) {
+        crate::println!("{}", line);
+    }
+}
+
+/// serial（COM1）に出力する sink 。
+pub struct SerialSink;
+
+impl Sink for SerialSink {
+    fn write(&self, _level: Level, line: &str) {
+        crate::serial_println!("{}", line);
+    }
+}
+
+pub static VGA: VgaSink = VgaSink;
+pub static SERIAL: SerialSink = SerialSink;
+
+static LOGGER: Logger = Logger {
+    config: IrqSafeMutex::new(Config {
+        level: LevelFilter::Info,
+        targets: [None; MAX_TARGETS],
+    }),
+    sinks: IrqSafeMutex::new([None; MAX_SINKS]),
+};
+
+struct Logger {
+    config: IrqSafeMutex<Config>,
+    sinks: IrqSafeMutex<[Option<(&'static dyn Sink, LevelFilter)>; MAX_SINKS]>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Config {
+    /// どの target にも一致しなかった時のレベル。
+    level: LevelFilter,
+    targets: [Option<(&'static str, LevelFilter)>; MAX_TARGETS],
+}
+
+impl Config {
+    /// `target` に使われるレベル。
+    fn level_for(&self, target: &str) -> LevelFilter {
+        let mut best: Option<(&str, LevelFilter)> = None;
+        for &(prefix, level) in self.targets.iter().flatten() {
+            let matched = target == prefix
+                || (target.starts_with(prefix) && target[prefix.len()..].starts_with("::"));
+            if matched && best.map_or(true, |(best, _)| prefix.len() > best.len()) {
+                best = Some((prefix, level));
+            }
+        }
+        best.map_or(self.level, |(_, level)| level)
+    }
+
+    /// 最も詳しいレベル。`log` のマクロはこれより詳しいログを呼び出し前に捨てる。
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .flatten()
+            .map(|&(_, level)| level)
+            .fold(self.level, |max, level| max.max(level))
+    }
+
+    fn set_target_level(&mut self, target: &'static str, level: LevelFilter) -> Result<(), Full> {
+        let slot = self
+            .targets
+            .iter()
+            .position(|entry| entry.map_or(false, |(prefix, _)| prefix == target))
+            .or_else(|| self.targets.iter().position(Option::is_none))
+            .ok_or(Full)?;
+        self.targets[slot] = Some((target, level));
+        Ok(())
+    }
+
+    /// `env_logger` と同じ形式（`level,target=level,...`）の設定を読む。
+    /// 読めなかった部分は無視する。
+    fn parse(&mut self, spec: &'static str) {
+        for directive in spec.split(',').map(str::trim) {
+            let mut parts = directive.splitn(2, '=');
+            let (target, level) = match (parts.next(), parts.next()) {
+                (Some(level), None) => (None, level),
+                (Some(target), Some(level)) => (Some(target.trim()), level),
+                _ => continue,
+            };
+            let level = match level.trim().parse() {
+                Ok(level) => level,
+                Err(_) => continue,
+            };
+            match target {
+                None => self.level = level,
+                Some(target) => {
+                    let _ = self.set_target_level(target, level);
+                }
+            }
+        }
+    }
+}
+
+/// sink や target の設定を入れる配列が一杯になっている。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Full;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.config.lock().level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut line = LineBuffer::new();
+        let _ = write!(line, "[{:>8}] ", interrupts::ticks());
+        let _ = match cpu::try_id() {
+            Some(id) => write!(line, "cpu{} ", id),
+            None => write!(line, "cpu? "),
+        };
+        let _ = write!(
+            line,
+            "{:<5} {}: {}",
+            record.level(),
+            record.target(),
+            record.args()
+        );
+
+        // sink の中でログを出力しても deadlock しないように、コピーしてから呼び出す
+        let sinks = *self.sinks.lock();
+        for &(sink, level) in sinks.iter().flatten() {
+            if record.level() <= level {
+                sink.write(record.level(), line.as_str());
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// 1行を整形するための固定長のバッファ。
+struct LineBuffer {
+    data: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl LineBuffer {
+    fn new() -> Self {
+        LineBuffer {
+            data: [0; LINE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // `write_str` は文字の境界でしか切らないので、常に UTF-8 として正しい
+        unsafe { str::from_utf8_unchecked(&self.data[..self.len]) }
+    }
+}
+
+impl fmt::Write for LineBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(LINE_SIZE - self.len);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.data[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+/// logger を登録し、`ATOMIX_LOG` の設定とデフォルトの sink を使って初期化する。
+///
+/// 何もしない限り heap を使わないので、起動直後に呼び出せる。
+/// 2回目以降の呼び出しは何もしない。
+pub fn init() {
+    if log::set_logger(&LOGGER).is_err() {
+        return;
+    }
+    {
+        let mut config = LOGGER.config.lock();
+        if let Some(spec) = option_env!("ATOMIX_LOG") {
+            config.parse(spec);
+        }
+        log::set_max_level(config.max_level());
+    }
+    let _ = add_sink(&SERIAL, LevelFilter::Trace);
+    let _ = add_sink(&ring::RING, LevelFilter::Trace);
+    let _ = add_sink(&VGA, LevelFilter::Warn);
+}
+
+/// `level` より詳しくないログを `sink` に出力する。
+pub fn add_sink(sink: &'static dyn Sink, level: LevelFilter) -> Result<(), Full> {
+    let mut sinks = LOGGER.sinks.lock();
+    let slot = sinks.iter().position(Option::is_none).ok_or(Full)?;
+    sinks[slot] = Some((sink, level));
+    Ok(())
+}
+
+/// target ごとの設定に一致しなかったログのレベルを変更する。
+pub fn set_level(level: LevelFilter) {
+    let mut config = LOGGER.config.lock();
+    config.level = level;
+    log::set_max_level(config.max_level());
+}
+
+/// `target` とその子のモジュールのログのレベルを変更する。
+pub fn set_target_level(target: &'static str, level: LevelFilter) -> Result<(), Full> {
+    let mut config = LOGGER.config.lock();
+    config.set_target_level(target, level)?;
+    log::set_max_level(config.max_level());
+    Ok(())
+}
+
+/// ring buffer に残っているログを VGA と serial に出力する。
+pub fn dmesg() {
+    let _ = ring::RING.dump(&mut DmesgWriter);
+}
+
+struct DmesgWriter;
+
+impl fmt::Write for DmesgWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::print!("{}", s);
+        crate::serial_print!("{}", s);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_target_levels() {
+        serial_print!("test_target_levels... ");
+        let mut config = Config {
+            level: LevelFilter::Off,
+            targets: [None; MAX_TARGETS],
+        };
+        config.parse("warn, atomix::acpi=debug,atomix::acpi::aml=trace,bogus=loud");
+        assert_eq!(config.level, LevelFilter::Warn);
+        assert_eq!(config.level_for("atomix::acpi"), LevelFilter::Debug);
+        assert_eq!(config.level_for("atomix::acpi::madt"), LevelFilter::Debug);
+        assert_eq!(config.level_for("atomix::acpi::aml"), LevelFilter::Trace);
+        // モジュールの名前の途中では一致しない
+        assert_eq!(config.level_for("atomix::acpix"), LevelFilter::Warn);
+        assert_eq!(config.max_level(), LevelFilter::Trace);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_line_truncation() {
+        serial_print!("test_line_truncation... ");
+        let mut line = LineBuffer::new();
+        for _ in 0..LINE_SIZE {
+            let _ = line.write_str("あ");
+        }
+        assert!(line.as_str().len() <= LINE_SIZE);
+        assert!(line.as_str().chars().all(|c| c == 'あ'));
+        serial_println!("[ok]");
+    }
+}
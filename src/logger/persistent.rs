@@ -0,0 +1,348 @@
+//! ## Persistent Log
+//!
+//! 再起動しても消えない物理メモリにログを残す sink 。
+//! kernel が Triple Fault などで再起動してしまった時、次の起動で直前のログを読める。
+//!
+//! ### 仕組み
+//!
+//! 電源を切らずに再起動（warm reboot）した場合、RAM の内容は消えない。
+//! そこで、物理アドレス `ADDRESS` から `SIZE` バイトの領域を毎回同じ場所に確保し、
+//! 先頭に `Header` 、その後ろに ring buffer としてログを書き込む。
+//!
+//! 起動した時に領域を調べ、`Header` の magic と checksum が正しければ
+//! 前回の起動のログとして `PREVIOUS` にコピーしてから、領域を空にして使い始める。
+//! 電源を入れた直後の RAM の内容は不定なので、checksum が合わなければ捨てる。
+//!
+//! 1行を書き込むたびに、データを書いてから `Header` を更新する。
+//! `Header` には書き込んだデータの合計（`sum`）も入れておき、
+//! 行の途中で再起動して古い行が壊れていた場合は「壊れている」として読む。
+//!
+//! 領域は frame allocator で予約し、他の用途に払い出さないようにする。
+//! `Usable` でない場所（bootloader が使っているなど）にあれば、この sink は使わない。
+//!
+//! ### 確認
+//!
+//! `tests/crash_log.rs` は、ログを書いてから Triple Fault を起こし、
+//! QEMU が再起動した後にそのログが読めることを確かめる。
+//! QEMU に `-no-reboot` を渡すと再起動しないので、このテストは失敗する。
+
+use super::Sink;
+use crate::{memory, println, serial_println, sync::IrqSafeMutex};
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::{
+    fmt::{self, Write},
+    mem, str,
+};
+use log::{Level, LevelFilter};
+use x86_64::PhysAddr;
+
+/// ログを残す物理アドレス。(112 MiB)
+///
+/// bootloader や BIOS が使わない、十分に高いアドレスにしている。
+pub const ADDRESS: u64 = 0x0700_0000;
+/// ログを残す領域の大きさ。(32 KiB)
+pub const SIZE: u64 = 32 * 1024;
+
+/// `Header` が正しいことを表す値。("ATMXLOG1")
+const MAGIC: u64 = 0x3147_4f4c_584d_5441;
+const HEADER_SIZE: usize = mem::size_of::<Header>();
+/// ログのデータを置ける大きさ。
+pub const DATA_SIZE: usize = SIZE as usize - HEADER_SIZE;
+
+/// 今回の起動のログを書き込む sink 。`init` が logger に追加する。
+pub static CRASH_LOG: CrashLog = CrashLog;
+
+/// 書き込み中の領域。`init` の前と、領域が使えない場合は `None` 。
+static REGION: IrqSafeMutex<Option<Region>> = IrqSafeMutex::new(None);
+
+/// 前回の起動のログ。
+///
+/// 32 KiB あるので、スタックに置かないように static の中で直接書き換える。
+static PREVIOUS: IrqSafeMutex<PreviousBoot> = IrqSafeMutex::new(PreviousBoot {
+    present: false,
+    boot: 0,
+    intact: false,
+    data: [0; DATA_SIZE],
+    start: 0,
+    len: 0,
+});
+
+/// 領域の先頭に置く情報。
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    magic: u64,
+    /// 何回目の起動か。前回の値に 1 を足していく。
+    boot: u64,
+    /// 次に書き込む位置。
+    head: u64,
+    /// 書き込まれているバイト数。`DATA_SIZE` 以下。
+    len: u64,
+    /// 書き込まれているバイトの合計。
+    sum: u64,
+    /// ここより前のフィールドの checksum 。
+    checksum: u64,
+}
+
+impl Header {
+    fn new(boot: u64) -> Self {
+        let mut header = Header {
+            magic: MAGIC,
+            boot,
+            head: 0,
+            len: 0,
+            sum: 0,
+            checksum: 0,
+        };
+        header.checksum = header.compute_checksum();
+        header
+    }
+
+    /// FNV-1a で `checksum` 以外のフィールドの checksum を計算する。
+    fn compute_checksum(&self) -> u64 {
+        let fields = [self.magic, self.boot, self.head, self.len, self.sum];
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for &field in fields.iter() {
+            for i in 0..8 {
+                hash ^= (field >> (i * 8)) & 0xff;
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        hash
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC
+            && self.checksum == self.compute_checksum()
+            && self.head < DATA_SIZE as u64
+            && self.len <= DATA_SIZE as u64
+    }
+}
+
+/// 物理メモリ上の領域に書き込むためのもの。
+struct Region {
+    /// 領域の先頭の仮想アドレス。
+    base: u64,
+    /// 書き込み中の `Header` 。`commit` で領域に書き込む。
+    header: Header,
+}
+
+impl Region {
+    fn data(&self) -> *mut u8 {
+        (self.base + HEADER_SIZE as u64) as *mut u8
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let slot = unsafe { self.data().add(self.header.head as usize) };
+            if self.header.len == DATA_SIZE as u64 {
+                let old = unsafe { slot.read_volatile() };
+                self.header.sum = self.header.sum.wrapping_sub(u64::from(old));
+            } else {
+                self.header.len += 1;
+            }
+            unsafe { slot.write_volatile(byte) };
+            self.header.sum = self.header.sum.wrapping_add(u64::from(byte));
+            self.header.head = (self.header.head + 1) % DATA_SIZE as u64;
+        }
+    }
+
+    /// 書き込んだデータを `Header` に反映する。
+    fn commit(&mut self) {
+        self.header.checksum = self.header.compute_checksum();
+        unsafe { (self.base as *mut Header).write_volatile(self.header) };
+    }
+}
+
+impl fmt::Write for Region {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// `CRASH_LOG` の型。
+pub struct CrashLog;
+
+impl Sink for CrashLog {
+    fn write(&self, _level: Level, line: &str) {
+        if let Some(region) = REGION.lock().as_mut() {
+            region.push(line.as_bytes());
+            region.push(b"\n");
+            region.commit();
+        }
+    }
+}
+
+/// 前回の起動で残されたログ。
+pub struct PreviousBoot {
+    present: bool,
+    boot: u64,
+    intact: bool,
+    data: [u8; DATA_SIZE],
+    /// 最初の完全な行の位置。
+    start: usize,
+    len: usize,
+}
+
+impl PreviousBoot {
+    /// 何回目の起動のログか。
+    pub fn boot(&self) -> u64 {
+        self.boot
+    }
+
+    /// データの合計が `Header` と一致したか。
+    /// 行を書き込んでいる途中で再起動すると、一致しないことがある。
+    pub fn is_intact(&self) -> bool {
+        self.intact
+    }
+
+    /// 残っているログ。古い順に、改行で区切られている。
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[self.start..self.len]
+    }
+
+    /// 残っているログを行ごとに返す。UTF-8 として正しくない行は飛ばす。
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.as_bytes()
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| str::from_utf8(line).ok())
+    }
+}
+
+/// 前回の起動のログを調べてから、今回の起動のログを書き込み始める。
+///
+/// `memory::init` の直後、他にフレームを割り当てる前に一度だけ呼び出すこと。
+/// 領域が使えなければ何もしない。
+pub fn init(memory_map: &MemoryMap) {
+    let usable = memory_map.iter().any(|region| {
+        region.region_type == MemoryRegionType::Usable
+            && region.range.start_addr() <= ADDRESS
+            && ADDRESS + SIZE <= region.range.end_addr()
+    });
+    if !usable {
+        log::warn!("{:#x} is not usable, crash log is disabled", ADDRESS);
+        return;
+    }
+    memory::with(|memory| memory.frame_allocator.reserve(ADDRESS, ADDRESS + SIZE));
+
+    let base = memory::phys_to_virt(PhysAddr::new(ADDRESS)).as_u64();
+    let header = unsafe { (base as *const Header).read_volatile() };
+    let mut region = Region {
+        base,
+        header: Header::new(1),
+    };
+    if header.is_valid() {
+        load_previous(&region, header);
+        region.header = Header::new(header.boot + 1);
+    }
+    region.commit();
+
+    *REGION.lock() = Some(region);
+    let _ = super::add_sink(&CRASH_LOG, LevelFilter::Trace);
+}
+
+/// 今回の起動のログを書き込んでいるか。
+pub fn is_enabled() -> bool {
+    REGION.lock().is_some()
+}
+
+/// 領域に残っているログを `PREVIOUS` に古い順にコピーする。
+fn load_previous(region: &Region, header: Header) {
+    let mut previous = PREVIOUS.lock();
+    let len = header.len as usize;
+    let start = (header.head as usize + DATA_SIZE - len) % DATA_SIZE;
+    let mut sum: u64 = 0;
+    for i in 0..len {
+        let byte = unsafe { region.data().add((start + i) % DATA_SIZE).read_volatile() };
+        previous.data[i] = byte;
+        sum = sum.wrapping_add(u64::from(byte));
+    }
+
+    previous.present = true;
+    previous.boot = header.boot;
+    previous.intact = sum == header.sum;
+    previous.len = len;
+    // 一周していれば、最初の行は途中から上書きされているので捨てる
+    previous.start = if len == DATA_SIZE {
+        previous.data[..len]
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(len, |i| i + 1)
+    } else {
+        0
+    };
+}
+
+/// 前回の起動のログがあれば、それを渡して `f` を呼び出す。
+pub fn previous_boot<F, R>(f: F) -> R
+where
+    F: FnOnce(Option<&PreviousBoot>) -> R,
+{
+    let previous = PREVIOUS.lock();
+    if previous.present {
+        f(Some(&*previous))
+    } else {
+        f(None)
+    }
+}
+
+/// 前回の起動のログの最後の `max_lines` 行を VGA と serial に出力する。
+pub fn print_previous(max_lines: usize) {
+    previous_boot(|previous| {
+        let previous = match previous {
+            Some(previous) => previous,
+            None => return,
+        };
+        let note = if previous.is_intact() {
+            ""
+        } else {
+            " (corrupted)"
+        };
+        println!("--- log of boot #{}{} ---", previous.boot(), note);
+        serial_println!("--- log of boot #{}{} ---", previous.boot(), note);
+        let skip = previous.lines().count().saturating_sub(max_lines);
+        for line in previous.lines().skip(skip) {
+            println!("{}", line);
+            serial_println!("{}", line);
+        }
+        println!("--- end of previous log ---");
+        serial_println!("--- end of previous log ---");
+    });
+}
+
+/// logger を通さずに、今回の起動のログに直接書き込む。
+///
+/// panic や CPU 例外のレポートのように、logger が使えないかもしれない時に使う。
+/// 複数行でもよい。書き込み中の CPU で panic した場合は何もしない。
+pub fn record(args: fmt::Arguments) {
+    if let Some(mut region) = REGION.try_lock() {
+        if let Some(region) = region.as_mut() {
+            let _ = region.write_fmt(args);
+            region.push(b"\n");
+            region.commit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_header_checksum() {
+        serial_print!("test_header_checksum... ");
+        let mut header = Header::new(3);
+        assert!(header.is_valid());
+        header.len = 1;
+        assert!(!header.is_valid());
+        header.checksum = header.compute_checksum();
+        assert!(header.is_valid());
+        header.head = DATA_SIZE as u64;
+        header.checksum = header.compute_checksum();
+        assert!(!header.is_valid());
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,158 @@
+//! ## Ring Buffer
+//!
+//! ログを固定長のメモリに残しておき、後から `dmesg` のように読めるようにする sink 。
+//!
+//! 各行は改行で区切って書き込む。一杯になったら古いバイトから上書きするので、
+//! 読む時は最初の改行までの（途中から上書きされた）部分を捨てる。
+
+use super::Sink;
+use crate::sync::IrqSafeMutex;
+use core::{fmt, str};
+use log::Level;
+
+/// 残しておくログの大きさ。(16 KiB)
+pub const CAPACITY: usize = 16 * 1024;
+
+/// kernel のログを残しておく ring buffer 。
+pub static RING: Ring = Ring::new();
+
+pub struct Ring {
+    inner: IrqSafeMutex<RingBuffer>,
+}
+
+impl Ring {
+    pub const fn new() -> Self {
+        Ring {
+            inner: IrqSafeMutex::new(RingBuffer::new()),
+        }
+    }
+
+    /// 残っている行を古い順に `out` に書き出す。
+    pub fn dump<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        self.inner.lock().dump(out)
+    }
+
+    /// 残っているログを捨てる。
+    pub fn clear(&self) {
+        self.inner.lock().clear();
+    }
+}
+
+impl Sink for Ring {
+    fn write(&self, _level: Level, line: &str) {
+        let mut buffer = self.inner.lock();
+        buffer.push(line.as_bytes());
+        buffer.push(b"\n");
+    }
+}
+
+pub struct RingBuffer {
+    data: [u8; CAPACITY],
+    /// 次に書き込む位置。
+    head: usize,
+    /// 一度でも一周したか。
+    wrapped: bool,
+}
+
+impl RingBuffer {
+    pub const fn new() -> Self {
+        RingBuffer {
+            data: [0; CAPACITY],
+            head: 0,
+            wrapped: false,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.data[self.head] = byte;
+            self.head += 1;
+            if self.head == CAPACITY {
+                self.head = 0;
+                self.wrapped = true;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.wrapped = false;
+    }
+
+    pub fn dump<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        let mut chunks = if self.wrapped {
+            [&self.data[self.head..], &self.data[..self.head]]
+        } else {
+            [&[][..], &self.data[..self.head]]
+        };
+        // 一周していれば、最初の行は途中から上書きされているので捨てる
+        if self.wrapped {
+            for chunk in chunks.iter_mut() {
+                let bytes: &[u8] = *chunk;
+                match bytes.iter().position(|&byte| byte == b'\n') {
+                    Some(i) => {
+                        *chunk = &bytes[i + 1..];
+                        break;
+                    }
+                    None => *chunk = &[],
+                }
+            }
+        }
+        for chunk in chunks.iter() {
+            write_lossy(out, chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// UTF-8 として正しくない部分（一周の境目で分かれた文字など）は `?` にして書き出す。
+fn write_lossy<W: fmt::Write>(out: &mut W, mut bytes: &[u8]) -> fmt::Result {
+    while !bytes.is_empty() {
+        match str::from_utf8(bytes) {
+            Ok(s) => return out.write_str(s),
+            Err(err) => {
+                let valid = err.valid_up_to();
+                out.write_str(unsafe { str::from_utf8_unchecked(&bytes[..valid]) })?;
+                out.write_char('?')?;
+                bytes = &bytes[valid + err.error_len().unwrap_or(bytes.len() - valid)..];
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+    use alloc::string::String;
+
+    #[test_case]
+    fn test_ring_buffer() {
+        serial_print!("test_ring_buffer... ");
+        let ring = Ring::new();
+        ring.write(Level::Info, "first");
+        ring.write(Level::Info, "second");
+        let mut out = String::new();
+        ring.dump(&mut out).unwrap();
+        assert_eq!(out, "first\nsecond\n");
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_ring_buffer_wrap() {
+        serial_print!("test_ring_buffer_wrap... ");
+        let ring = Ring::new();
+        // 一周させると、途中から上書きされた行は捨てられ、完全な行だけが残る
+        for i in 0..CAPACITY / 7 + 10 {
+            ring.write(Level::Info, if i % 2 == 0 { "abcdef" } else { "ghijkl" });
+        }
+        let mut out = String::new();
+        ring.dump(&mut out).unwrap();
+        assert!(out.len() <= CAPACITY);
+        assert!(out.len() > CAPACITY - 8);
+        assert!(out.lines().all(|line| line == "abcdef" || line == "ghijkl"));
+        assert!(out.ends_with("ghijkl\n"));
+        serial_println!("[ok]");
+    }
+}
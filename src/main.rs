@@ -4,34 +4,76 @@
 #![test_runner(atomix::test_utils::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-use atomix::println;
+use atomix::{
+    keyboard::{self, KeyCode, KeyEventStream, KeyState},
+    print, println, serial_println,
+    task::{executor::Executor, Task},
+    vga,
+};
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+use futures_util::stream::StreamExt;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+// `entry_point` マクロは、bootloader が呼び出す `_start` 関数を
+// 正しいシグネチャで定義してくれる。
+// `extern "C" fn _start()` を自分で書くと、引数の型がチェックされない。
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Hello World!");
 
     atomix::init();
-
-    fn stack_overflow() {
-        stack_overflow();
-    }
-
-    stack_overflow();
+    atomix::memory::init(boot_info);
+    atomix::logger::persistent::init(&boot_info.memory_map);
+    atomix::logger::persistent::print_previous(20);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+    atomix::init_apic();
+    atomix::init_smp();
+    atomix::acpi::dump();
 
     #[cfg(test)]
     test_main();
 
     println!("It did not crash!!");
 
-    loop {}
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(print_keypresses()));
+    executor.spawn(Task::new(keyboard::update_leds()));
+    executor.run();
+}
+
+/// Shift + Page Up / Page Down でスクロールする行数。
+const SCROLL_LINES: usize = 12;
+
+/// 入力された文字を画面に表示し続ける。
+///
+/// Shift + Page Up / Page Down で過去の出力をスクロールして表示する。
+async fn print_keypresses() {
+    let mut events = KeyEventStream::new();
+    while let Some(event) = events.next().await {
+        if event.state == KeyState::Down && event.modifiers.is_shifted() {
+            match event.key {
+                KeyCode::PageUp => vga::scroll_up(SCROLL_LINES),
+                KeyCode::PageDown => vga::scroll_down(SCROLL_LINES),
+                _ => {}
+            }
+        }
+        if let Some(c) = event.character {
+            print!("{}", c);
+        }
+    }
 }
 
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
-    loop {}
+    serial_println!("{}", info);
+    atomix::logger::persistent::record(format_args!("{}", info));
+    atomix::backtrace::print();
+    // COM2 があれば GDB が接続して調べられるように止まる
+    atomix::gdb::stop_on_panic();
+    atomix::hlt_loop();
 }
 
 #[cfg(test)]
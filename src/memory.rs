@@ -0,0 +1,429 @@
+//! ## Paging
+//!
+//! x86_64 では 4-level の page table を使って仮想アドレスを物理アドレスに変換する。
+//! 仮想アドレスの 12..48 bit が 9 bit ずつ 4つのインデックスに分割され、
+//! それぞれ level 4, 3, 2, 1 の page table のエントリーを指す。
+//! level 4 table の物理アドレスは CR3 レジスタに格納されている。
+//!
+//! ### Page table にアクセスする
+//!
+//! page table のエントリーには次の table の **物理** アドレスが書かれているが、
+//! kernel も paging が有効な状態で動いているので、物理アドレスに直接アクセスすることはできない。
+//! そこで、bootloader の `map_physical_memory` feature を使って、
+//! 物理メモリ全体をある仮想アドレス（`physical_memory_offset`）以降にマップしてもらう。
+//! こうすると、物理アドレス `p` には仮想アドレス `p + physical_memory_offset` で
+//! アクセスできるようになる。
+//!
+//! ### TLB
+//!
+//! マップを解除したりフラグを変更したりした時は、`tlb` モジュールで
+//! 全ての CPU の TLB から古い変換を消す。
+//!
+//! ### Lock
+//!
+//! `Memory` は Copy-on-Write の Page Fault ハンドラからも使うので、`IrqSafeMutex` に入れる。
+//! lock を待っている間は割り込みが無効になっているので、
+//! 他の CPU からの TLB shootdown の要求を処理しながら待つ。
+//! そうしないと、lock を持ったまま shootdown の完了を待っている CPU とデッドロックする。
+//!
+//! ### Address Space
+//!
+//! ここでの関数は kernel の page table を操作する。
+//! user mode のプログラムは、kernel の領域を共有した別の page table を使う。
+//! （`address_space` モジュールを参照）
+//!
+//! ### 参照
+//! - https://os.phil-opp.com/paging-introduction/
+//! - https://os.phil-opp.com/paging-implementation/
+
+pub mod address_space;
+pub mod cow;
+pub mod frame_allocator;
+pub mod tlb;
+
+use self::{cow::RefCounts, frame_allocator::BootInfoFrameAllocator};
+use crate::sync::IrqSafeMutex;
+use bootloader::BootInfo;
+use core::sync::atomic::{spin_loop_hint, AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::{FlagUpdateError, MapToError, UnmapError},
+        FrameAllocator, FrameDeallocator, MappedPageTable, Mapper, MapperAllSizes, Page, PageSize,
+        PageTable, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// 物理メモリ全体がマップされている仮想アドレス。
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// kernel の level 4 table の物理アドレス。`init` の前は 0 。
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
+/// memory-mapped I/O の領域をマップする仮想アドレスの先頭。
+/// heap やスレッドのスタックと重ならない適当なアドレスを選んでいる。
+const MMIO_START: u64 = 0x_7777_0000_0000;
+
+/// 次に MMIO の領域をマップする仮想アドレス。
+static NEXT_MMIO: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// kernel が使う page table のマッパー。
+///
+/// クロージャの代わりに関数ポインタを使うことで、型に名前をつけて
+/// static に保存できるようにしている。
+pub type KernelMapper = MappedPageTable<'static, fn(PhysFrame) -> *mut PageTable>;
+
+/// 仮想メモリの管理に必要なものをまとめたもの。
+pub struct Memory {
+    pub mapper: KernelMapper,
+    pub frame_allocator: BootInfoFrameAllocator,
+    /// user の領域で共有しているフレームの参照カウント。
+    pub ref_counts: RefCounts,
+}
+
+lazy_static! {
+    static ref MEMORY: IrqSafeMutex<Option<Memory>> = IrqSafeMutex::new(None);
+}
+
+/// メモリ管理を初期化する。
+///
+/// `bootloader` の `map_physical_memory` feature が有効になっている必要がある。
+/// 一度だけ呼び出すこと。
+pub fn init(boot_info: &'static BootInfo) {
+    PHYSICAL_MEMORY_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let (level_4_table_frame, _) = Cr3::read();
+    KERNEL_LEVEL_4_TABLE.store(
+        level_4_table_frame.start_address().as_u64(),
+        Ordering::Relaxed,
+    );
+
+    let memory = unsafe {
+        Memory {
+            mapper: MappedPageTable::new(
+                active_level_4_table(),
+                frame_to_page_table as fn(PhysFrame) -> *mut PageTable,
+            ),
+            frame_allocator: BootInfoFrameAllocator::new(&boot_info.memory_map),
+            ref_counts: RefCounts::new(),
+        }
+    };
+    *MEMORY.lock() = Some(memory);
+}
+
+/// `Memory` を借用して `f` を実行する。
+///
+/// 割り込みを無効にした状態で `f` を実行する。
+/// `init` より前に呼び出すと panic する。
+pub fn with<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Memory) -> R,
+{
+    let mut memory = loop {
+        if let Some(memory) = MEMORY.try_lock() {
+            break memory;
+        }
+        tlb::handle_shootdown();
+        spin_loop_hint();
+    };
+    f(memory.as_mut().expect("memory is not initialized"))
+}
+
+/// 物理アドレスを、それにアクセスするための仮想アドレスに変換する。
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+fn frame_to_page_table(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// kernel の level 4 table のフレームを返す。`init` の前は `None` 。
+fn kernel_level_4_frame() -> Option<PhysFrame> {
+    match KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed) {
+        0 => None,
+        addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+    }
+}
+
+/// 現在有効な level 4 table への参照を返す。
+///
+/// ## Safety
+/// 物理メモリ全体が `physical_memory_offset` にマップされている必要がある。
+/// また、`&mut` 参照のエイリアスを作らないように、一度だけ呼び出すこと。
+unsafe fn active_level_4_table() -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+    &mut *frame_to_page_table(level_4_table_frame)
+}
+
+/// 仮想アドレスを物理アドレスに変換する。
+///
+/// マップされていない場合は `None` を返す。
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    with(|memory| memory.translate(addr))
+}
+
+/// `addr` が現在のアドレス空間でマップされているか。
+///
+/// `MEMORY` の lock を取らずに page table を読むので、panic handler や
+/// 例外ハンドラからでも呼び出せる。`init` の前は常に `false` を返す。
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    if kernel_level_4_frame().is_none() {
+        return false;
+    }
+    let page: Page = Page::containing_address(addr);
+    let (level_4_frame, _) = Cr3::read();
+    let mut table = frame_to_page_table(level_4_frame);
+    let indexes = [
+        page.p4_index(),
+        page.p3_index(),
+        page.p2_index(),
+        page.p1_index(),
+    ];
+    for (level, &index) in indexes.iter().enumerate() {
+        let flags = unsafe { (*table)[index].flags() };
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return false;
+        }
+        // level 3 と level 2 のエントリーは huge page を指していることがある
+        if level == indexes.len() - 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        let next = unsafe { (*table)[index].addr() };
+        table = frame_to_page_table(PhysFrame::containing_address(next));
+    }
+    false
+}
+
+/// `page` が現在のアドレス空間で user mode からアクセスできるか。
+pub fn is_user_accessible(page: Page) -> bool {
+    with(|memory| memory.is_user_accessible(page))
+}
+
+/// `page` を `frame` にマップする。
+///
+/// ## Safety
+/// 同じフレームを複数のページにマップすると `&mut` 参照のエイリアスを
+/// 作れてしまうので、呼び出し側が安全性を保証する必要がある。
+pub unsafe fn map_to(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapToError> {
+    with(|memory| memory.map_to(page, frame, flags))
+}
+
+/// 新しいフレームを割り当てて `page` にマップする。
+pub fn map_new(page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapToError> {
+    with(|memory| memory.map_new(page, flags))
+}
+
+/// `page` のマップを解除し、対応していたフレームを返す。
+///
+/// フレームは解放されないので、必要なら `deallocate_frame` すること。
+pub fn unmap(page: Page) -> Result<PhysFrame, UnmapError> {
+    with(|memory| memory.unmap(page))
+}
+
+/// 物理アドレス `addr` から `size` バイトの memory-mapped I/O の領域をマップし、
+/// `addr` に対応する仮想アドレスを返す。
+///
+/// APIC のレジスタなどのデバイスの領域は RAM ではないので、
+/// bootloader が物理メモリをマップした範囲に含まれているとは限らない。
+/// また、キャッシュされると書き込みがデバイスに届かないので、
+/// `NO_CACHE` をつけてマップする。
+pub fn map_mmio(addr: PhysAddr, size: u64) -> Result<VirtAddr, MapToError> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(addr);
+    let last_frame = PhysFrame::containing_address(addr + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+
+    let pages = frames.end - frames.start + 1;
+    let start = NEXT_MMIO.fetch_add(pages * Size4KiB::SIZE, Ordering::Relaxed);
+    let start_page = Page::containing_address(VirtAddr::new(start));
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    for (i, frame) in frames.enumerate() {
+        // MMIO の領域は frame allocator が割り当てることはないので、
+        // 他のページと共有されることはない
+        unsafe { map_to(start_page + i as u64, frame, flags)? };
+    }
+
+    Ok(start_page.start_address() + (addr - first_frame.start_address()))
+}
+
+/// `page` を指す level 4, 3, 2 table のエントリーに `USER_ACCESSIBLE` を立てる。
+///
+/// user mode からアクセスできるのは、全ての level のエントリーに
+/// `USER_ACCESSIBLE` が立っているページだけである。
+/// `map_to` が新しく作る table のエントリーには立たないので、ここで立てる。
+/// 権限を広げるだけなので、TLB を消す必要はない。
+fn allow_user_access(level_4_table_frame: PhysFrame, page: Page) {
+    let mut table = frame_to_page_table(level_4_table_frame);
+    for &index in &[page.p4_index(), page.p3_index(), page.p2_index()] {
+        // `MappedPageTable` が持っている参照とエイリアスしないように、生ポインタで辿る
+        let entry = unsafe { &mut (*table)[index] };
+        entry.set_flags(entry.flags() | PageTableFlags::USER_ACCESSIBLE);
+        let frame = entry.frame().expect("page is not mapped");
+        table = frame_to_page_table(frame);
+    }
+}
+
+/// `page` のフラグを変更する。
+pub fn update_flags(page: Page, flags: PageTableFlags) -> Result<(), FlagUpdateError> {
+    with(|memory| memory.update_flags(page, flags))
+}
+
+impl Memory {
+    pub fn translate(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper.translate_addr(addr)
+    }
+
+    /// ## Safety
+    /// `map_to` 関数を参照。
+    pub unsafe fn map_to(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError> {
+        self.mapper
+            .map_to(page, frame, flags, &mut self.frame_allocator)?
+            .flush();
+        if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+            let level_4_table_frame = kernel_level_4_frame().expect("memory is not initialized");
+            allow_user_access(level_4_table_frame, page);
+        }
+        Ok(())
+    }
+
+    /// `page` が現在のアドレス空間で user mode からアクセスできるか。
+    ///
+    /// 全ての level のエントリーが `PRESENT` と `USER_ACCESSIBLE` を持っている必要がある。
+    pub fn is_user_accessible(&self, page: Page) -> bool {
+        let required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let (level_4_table_frame, _) = Cr3::read();
+        let mut table = frame_to_page_table(level_4_table_frame);
+        let indexes = [
+            page.p4_index(),
+            page.p3_index(),
+            page.p2_index(),
+            page.p1_index(),
+        ];
+        for (level, &index) in indexes.iter().enumerate() {
+            let entry = unsafe { &(*table)[index] };
+            if !entry.flags().contains(required) {
+                return false;
+            }
+            // level 3, 2 の huge page はそこで変換が終わる
+            if level == 3 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return true;
+            }
+            match entry.frame() {
+                Ok(frame) => table = frame_to_page_table(frame),
+                Err(_) => return false,
+            }
+        }
+        unreachable!()
+    }
+
+    pub fn map_new(&mut self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapToError> {
+        let frame = self
+            .frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        // 新しく割り当てたフレームなので、他のページにはマップされていない
+        if let Err(e) = unsafe { self.map_to(page, frame, flags) } {
+            self.frame_allocator.deallocate_frame(frame);
+            return Err(e);
+        }
+        Ok(frame)
+    }
+
+    /// 全ての CPU の TLB から `page` を消してから戻るので、
+    /// 戻り値のフレームはすぐに再利用してよい。
+    pub fn unmap(&mut self, page: Page) -> Result<PhysFrame, UnmapError> {
+        let (frame, flush) = self.mapper.unmap(page)?;
+        flush.ignore();
+        tlb::flush(page);
+        Ok(frame)
+    }
+
+    pub fn update_flags(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<(), FlagUpdateError> {
+        // フラグを弱めた場合に備えて、全ての CPU の TLB から消す
+        self.mapper.update_flags(page, flags)?.ignore();
+        tlb::flush(page);
+        Ok(())
+    }
+
+    pub fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        self.frame_allocator.allocate_frame()
+    }
+
+    pub fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.frame_allocator.deallocate_frame(frame);
+    }
+
+    /// 1 MiB 未満のフレームを割り当てる。
+    /// `frame_allocator` のドキュメントを参照。
+    pub fn allocate_low_frame(&mut self) -> Option<PhysFrame> {
+        self.frame_allocator.allocate_low_frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_translate_vga_buffer() {
+        serial_print!("test_translate_vga_buffer... ");
+        // VGA buffer は bootloader によって identity map されている
+        let addr = translate(VirtAddr::new(0xb8000));
+        assert_eq!(addr, Some(PhysAddr::new(0xb8000)));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_map_new_and_unmap() {
+        serial_print!("test_map_new_and_unmap... ");
+        let page = Page::containing_address(VirtAddr::new(0xdead_beaf_0000));
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        let frame = map_new(page, flags).unwrap();
+        assert_eq!(translate(page.start_address()), Some(frame.start_address()));
+
+        let ptr: *mut u64 = page.start_address().as_mut_ptr();
+        unsafe {
+            ptr.write_volatile(0xf021_f077_f065_f04e);
+            assert_eq!(ptr.read_volatile(), 0xf021_f077_f065_f04e);
+        }
+
+        assert_eq!(unmap(page).ok(), Some(frame));
+        assert_eq!(translate(page.start_address()), None);
+        with(|memory| memory.deallocate_frame(frame));
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_frame_reuse() {
+        serial_print!("test_frame_reuse... ");
+        with(|memory| {
+            let allocated = memory.frame_allocator.allocated_frames();
+            let frame = memory.allocate_frame().unwrap();
+            memory.deallocate_frame(frame);
+            assert_eq!(memory.allocate_frame(), Some(frame));
+            memory.deallocate_frame(frame);
+            assert_eq!(memory.frame_allocator.allocated_frames(), allocated);
+        });
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,263 @@
+//! ## Address Space
+//!
+//! user mode のプログラムごとの仮想アドレス空間。
+//!
+//! 新しい level 4 table を作り、`USER_START..USER_END` 以外のエントリーを
+//! kernel の level 4 table からコピーする。
+//! level 3 以下の table は kernel と共有するので、kernel の領域（heap やスタック、MMIO など）は
+//! どのアドレス空間からも同じように見える。
+//! `USER_START..USER_END` の table はアドレス空間ごとに別に作る。
+//!
+//! コピーするのは作った時点の level 4 のエントリーだけなので、
+//! その後で kernel が新しい level 4 のエントリーを使い始めた場合、
+//! 既存のアドレス空間からはそのマップが見えない。
+//!
+//! `clone_cow` で複製したアドレス空間は、user の領域のフレームを Copy-on-Write で共有する。
+//! （`cow` モジュールを参照）
+//!
+//! CR3 はスレッドを切り替える時に scheduler が `activate` で書き換える。
+//! スレッドは BSP でしか実行されないので、他の CPU の TLB を気にする必要はない。
+
+use super::{cow, frame_to_page_table, kernel_level_4_frame, phys_to_virt, Memory};
+use core::ops::Range;
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, MappedPageTable, Mapper, Page, PageSize, PageTable, PageTableEntry,
+        PageTableFlags, PhysFrame, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// user mode のプログラムが使える仮想アドレスの範囲。
+/// level 4 table の 32..64 番目のエントリーに対応する。
+pub const USER_START: u64 = 0x_1000_0000_0000;
+pub const USER_END: u64 = 0x_2000_0000_0000;
+
+/// `USER_START..USER_END` に対応する level 4 table のインデックス。
+const USER_LEVEL_4_INDEXES: Range<usize> = (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+/// 1つの page table のエントリー数。
+const ENTRY_COUNT: usize = 512;
+
+/// user mode のプログラムのアドレス空間。
+///
+/// drop すると `USER_START..USER_END` にマップされたフレームと page table を解放する。
+/// 他のアドレス空間と共有しているフレームは、最後の参照がなくなった時に解放する。
+#[derive(Debug)]
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// kernel の領域だけがマップされたアドレス空間を作る。
+    ///
+    /// `memory::init` の後に呼び出すこと。
+    pub fn new() -> Result<AddressSpace, MapToError> {
+        let kernel_frame = kernel_level_4_frame().expect("memory is not initialized");
+        let level_4_frame = super::with(|memory| memory.allocate_frame())
+            .ok_or(MapToError::FrameAllocationFailed)?;
+
+        let kernel_table = unsafe { &*frame_to_page_table(kernel_frame) };
+        let table = unsafe { &mut *frame_to_page_table(level_4_frame) };
+        table.zero();
+        for index in 0..ENTRY_COUNT {
+            let entry = &kernel_table[index];
+            if USER_LEVEL_4_INDEXES.contains(&index) {
+                debug_assert!(entry.is_unused(), "kernel uses the user address space");
+            } else {
+                table[index].set_addr(entry.addr(), entry.flags());
+            }
+        }
+
+        Ok(AddressSpace { level_4_frame })
+    }
+
+    /// CR3 に書き込む level 4 table のフレーム。
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// `page` が `USER_START..USER_END` に含まれるか。
+    pub fn is_user_page(page: Page) -> bool {
+        let addr = page.start_address().as_u64();
+        USER_START <= addr && addr < USER_END
+    }
+
+    /// 0 で埋めた新しいフレームを割り当てて `page` にマップし、そのフレームを返す。
+    ///
+    /// このアドレス空間が有効でなくても、返したフレームには `frame_to_virt` でアクセスできる。
+    /// `page` が `USER_START..USER_END` に含まれない場合は panic する。
+    pub fn map_new(&mut self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, MapToError> {
+        assert!(
+            Self::is_user_page(page),
+            "{:?} is outside of the user address space",
+            page
+        );
+
+        super::with(|memory| {
+            let frame = memory
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            // 前の持ち主のデータを見せないように、マップする前に 0 で埋める
+            unsafe {
+                frame_to_virt(frame)
+                    .as_mut_ptr::<u8>()
+                    .write_bytes(0, Size4KiB::SIZE as usize);
+            }
+
+            let mut mapper = unsafe {
+                MappedPageTable::new(
+                    &mut *frame_to_page_table(self.level_4_frame),
+                    frame_to_page_table as fn(PhysFrame) -> *mut PageTable,
+                )
+            };
+            // 新しく割り当てたフレームなので、他のページにはマップされていない
+            match unsafe { mapper.map_to(page, frame, flags, &mut memory.frame_allocator) } {
+                // 新しいマップなので、古い変換は TLB にキャッシュされていない
+                Ok(flush) => flush.ignore(),
+                Err(e) => {
+                    memory.deallocate_frame(frame);
+                    return Err(e);
+                }
+            }
+            if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                super::allow_user_access(self.level_4_frame, page);
+            }
+            Ok(frame)
+        })
+    }
+}
+
+impl AddressSpace {
+    /// user の領域を Copy-on-Write で共有する新しいアドレス空間を作る。
+    ///
+    /// page table はコピーするが、ページのフレームはコピーせずに両方からマップする。
+    /// 書き込めるページは、両方のアドレス空間で Copy-on-Write にする。
+    pub fn clone_cow(&self) -> Result<AddressSpace, MapToError> {
+        let clone = AddressSpace::new()?;
+        // 途中で失敗した場合も、それまでに共有したフレームは clone の drop で正しく解放される
+        let result = super::with(|memory| {
+            let table = unsafe { &mut *frame_to_page_table(self.level_4_frame) };
+            let clone_table = unsafe { &mut *frame_to_page_table(clone.level_4_frame) };
+            for index in USER_LEVEL_4_INDEXES {
+                clone_entry(memory, &mut table[index], &mut clone_table[index], 4)?;
+            }
+            Ok(())
+        });
+
+        // 書き込めたページを read-only にしたので、古い変換を消しておく
+        let (active_frame, _) = Cr3::read();
+        if active_frame == self.level_4_frame {
+            tlb::flush_all();
+        }
+        result.map(|()| clone)
+    }
+}
+
+/// level `level` の table の `entry` を `clone` に複製する。
+///
+/// level 2 以上なら新しい table を作ってその先を複製し、
+/// level 1 ならフレームを共有して、両方のエントリーを Copy-on-Write にする。
+fn clone_entry(
+    memory: &mut Memory,
+    entry: &mut PageTableEntry,
+    clone: &mut PageTableEntry,
+    level: usize,
+) -> Result<(), MapToError> {
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return Ok(()),
+    };
+
+    if level == 1 {
+        let flags = cow::write_protect(entry.flags());
+        entry.set_flags(flags);
+        memory.share_frame(frame);
+        clone.set_addr(frame.start_address(), flags);
+        return Ok(());
+    }
+
+    let clone_frame = memory
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let table = unsafe { &mut *frame_to_page_table(frame) };
+    let clone_table = unsafe { &mut *frame_to_page_table(clone_frame) };
+    clone_table.zero();
+    // 先につないでおけば、途中で失敗しても drop で解放される
+    clone.set_addr(clone_frame.start_address(), entry.flags());
+    for index in 0..ENTRY_COUNT {
+        clone_entry(
+            memory,
+            &mut table[index],
+            &mut clone_table[index],
+            level - 1,
+        )?;
+    }
+    Ok(())
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let (active_frame, _) = Cr3::read();
+        assert_ne!(
+            active_frame, self.level_4_frame,
+            "the active address space is dropped"
+        );
+
+        super::with(|memory| {
+            let table = unsafe { &*frame_to_page_table(self.level_4_frame) };
+            for index in USER_LEVEL_4_INDEXES {
+                free_entry(memory, &table[index], 4);
+            }
+            memory.deallocate_frame(self.level_4_frame);
+        });
+    }
+}
+
+/// level `level` の table の `entry` が指すフレームを解放する。
+/// level 2 以上なら、その先の table からマップされているフレームも全て解放する。
+fn free_entry(memory: &mut Memory, entry: &PageTableEntry, level: usize) {
+    // user の領域に huge page はマップしない
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    if level == 1 {
+        // 他のアドレス空間と共有しているかもしれない
+        memory.release_frame(frame);
+        return;
+    }
+
+    let table = unsafe { &*frame_to_page_table(frame) };
+    for index in 0..ENTRY_COUNT {
+        free_entry(memory, &table[index], level - 1);
+    }
+    memory.deallocate_frame(frame);
+}
+
+/// フレームにアクセスするための仮想アドレスを返す。
+pub fn frame_to_virt(frame: PhysFrame) -> VirtAddr {
+    phys_to_virt(frame.start_address())
+}
+
+/// `space` の page table に切り替える。`None` なら kernel の page table に切り替える。
+///
+/// 既に有効な場合は何もしない。`memory::init` の前は何もしない。
+///
+/// ## Safety
+/// 実行中のコードとスタックは kernel の領域にある必要がある。
+pub unsafe fn activate(space: Option<&AddressSpace>) {
+    let frame = match space {
+        Some(space) => space.level_4_frame,
+        None => match kernel_level_4_frame() {
+            Some(frame) => frame,
+            None => return,
+        },
+    };
+    let (active_frame, flags) = Cr3::read();
+    if active_frame != frame {
+        Cr3::write(frame, flags);
+    }
+}
@@ -0,0 +1,165 @@
+//! ## Copy-on-Write
+//!
+//! `AddressSpace::clone_cow` はアドレス空間を複製するが、user の領域のページはコピーせず、
+//! 同じフレームを両方のアドレス空間にマップする。
+//! 書き込めるページは両方で read-only にし、page table のエントリーの
+//! OS が自由に使えるビット（`COPY_ON_WRITE`）を立てておく。
+//!
+//! どちらかがそのページに書き込むと、権限違反の Page Fault が発生する。
+//! `handle_page_fault` はそのページの `COPY_ON_WRITE` を見て、
+//!
+//! - フレームがまだ他のアドレス空間と共有されていれば、新しいフレームにコピーしてマップし直す。
+//! - 他がもうコピーして自分だけのものになっていれば、そのまま書き込めるようにする。
+//!
+//! kernel から user のページに書き込んだ場合も、CR0 の WP ビットが立っているので
+//! Page Fault が発生し、同じように解決される。
+//!
+//! ### 参照カウント
+//!
+//! フレームがいくつのアドレス空間にマップされているかを `RefCounts` で数える。
+//! ほとんどのフレームは共有されないので、2つ以上から参照されているフレームだけを記録し、
+//! 記録されていないフレームの参照は1つとみなす。
+//! アドレス空間を drop した時は `Memory::release_frame` で参照を減らし、最後の参照だった場合だけフレームを解放する。
+//!
+//! ### 参照
+//! - https://en.wikipedia.org/wiki/Copy-on-write
+
+use super::{address_space::AddressSpace, frame_to_page_table, phys_to_virt, Memory};
+use alloc::collections::BTreeMap;
+use x86_64::{
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    },
+    VirtAddr,
+};
+
+/// Copy-on-Write のページを表す、page table のエントリーのフラグ。
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// 2つ以上のアドレス空間から参照されているフレームの参照カウント。
+#[derive(Debug, Default)]
+pub struct RefCounts {
+    counts: BTreeMap<PhysFrame, usize>,
+}
+
+impl RefCounts {
+    pub fn new() -> Self {
+        RefCounts {
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// `frame` を参照しているアドレス空間の数。
+    pub fn get(&self, frame: PhysFrame) -> usize {
+        self.counts.get(&frame).copied().unwrap_or(1)
+    }
+
+    /// 共有されているフレームの数。
+    pub fn shared_frames(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// `frame` の参照を1つ増やす。
+    fn share(&mut self, frame: PhysFrame) {
+        *self.counts.entry(frame).or_insert(1) += 1;
+    }
+
+    /// `frame` の参照を1つ減らす。最後の参照だった場合は `true` を返す。
+    fn release(&mut self, frame: PhysFrame) -> bool {
+        match self.counts.get_mut(&frame) {
+            None => true,
+            Some(count) => {
+                *count -= 1;
+                if *count == 1 {
+                    self.counts.remove(&frame);
+                }
+                false
+            }
+        }
+    }
+}
+
+impl Memory {
+    /// user の領域のフレームを、もう1つのアドレス空間からも参照させる。
+    pub(super) fn share_frame(&mut self, frame: PhysFrame) {
+        self.ref_counts.share(frame);
+    }
+
+    /// user の領域のフレームの参照を1つ減らし、最後の参照ならフレームを解放する。
+    pub(super) fn release_frame(&mut self, frame: PhysFrame) {
+        if self.ref_counts.release(frame) {
+            self.deallocate_frame(frame);
+        }
+    }
+}
+
+/// 書き込める page table のエントリーのフラグを、Copy-on-Write のフラグに変える。
+pub(super) fn write_protect(flags: PageTableFlags) -> PageTableFlags {
+    if flags.contains(PageTableFlags::WRITABLE) {
+        (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+    } else {
+        flags
+    }
+}
+
+/// Copy-on-Write のページへの書き込みによる Page Fault を解決する。
+///
+/// 解決できた場合は `true` を返す。
+/// Copy-on-Write のページでなければ何もせずに `false` を返す。
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_violation =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(write_violation) {
+        return false;
+    }
+    let page: Page = Page::containing_address(addr);
+    if !AddressSpace::is_user_page(page) {
+        return false;
+    }
+
+    super::with(|memory| {
+        // 現在のアドレス空間の level 1 table のエントリーを探す
+        let (level_4_frame, _) = Cr3::read();
+        let mut table = frame_to_page_table(level_4_frame);
+        for &index in &[page.p4_index(), page.p3_index(), page.p2_index()] {
+            let entry = unsafe { &(*table)[index] };
+            match entry.frame() {
+                Ok(frame) => table = frame_to_page_table(frame),
+                Err(_) => return false,
+            }
+        }
+        let entry = unsafe { &mut (*table)[page.p1_index()] };
+        let flags = entry.flags();
+        if !flags.contains(COPY_ON_WRITE) {
+            return false;
+        }
+        let frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(_) => return false,
+        };
+
+        let writable = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+        if memory.ref_counts.get(frame) == 1 {
+            // 他のアドレス空間は既にコピーしたので、このフレームはもう共有されていない
+            entry.set_flags(writable);
+        } else {
+            let new_frame = match memory.allocate_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            unsafe {
+                let src = phys_to_virt(frame.start_address()).as_ptr::<u8>();
+                let dst = phys_to_virt(new_frame.start_address()).as_mut_ptr::<u8>();
+                dst.copy_from_nonoverlapping(src, Size4KiB::SIZE as usize);
+            }
+            entry.set_addr(new_frame.start_address(), writable);
+            memory.release_frame(frame);
+        }
+        // user のスレッドは BSP でしか実行されないので、この CPU の TLB だけを消せばよい
+        tlb::flush(page.start_address());
+        true
+    })
+}
@@ -0,0 +1,206 @@
+//! ## Frame Allocator
+//!
+//! 新しいページをマップするには、そのページに対応する物理フレームが必要になる。
+//! また、page table 自体も物理フレームに置かれるので、
+//! 中間の page table を作る時にもフレームが必要になる。
+//!
+//! bootloader は BIOS (E820) から取得した物理メモリマップを `BootInfo` 経由で
+//! 渡してくれる。
+//! そのうち `MemoryRegionType::Usable` になっている領域は自由に使ってよいので、
+//! そこから順番にフレームを払い出していく。
+//!
+//! ### 1 MiB 未満のフレーム
+//!
+//! 他の CPU を起動する時の trampoline は real mode で実行されるので、
+//! 1 MiB 未満の物理アドレスに置く必要がある。
+//! この領域は貴重なので、通常の割り当てでは使わず、`allocate_low_frame` でのみ払い出す。
+//!
+//! ### 予約された領域
+//!
+//! `reserve` で指定した範囲のフレームは払い出さない。
+//! 再起動しても残しておきたいデータ（`logger::persistent`）を置くのに使う。
+//!
+//! ### 解放されたフレーム
+//!
+//! 解放されたフレームは free list につないでおき、次の割り当てで再利用する。
+//! free list の「次へのポインタ」は解放されたフレーム自身の先頭に書き込む。
+//! 物理メモリ全体がマップされているので、フレームの中身には
+//! `physical_memory_offset` を足した仮想アドレスからアクセスできる。
+//! こうすることで、free list のための追加のメモリが不要になる。
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+/// これより下のフレームは `allocate_low_frame` でのみ払い出す。
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// free list の終端を表す値。
+/// 物理アドレス 0 のフレームは割り当てられることはない。
+const FREE_LIST_END: u64 = 0;
+
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    /// 次にフレームを切り出す `memory_map` のインデックス。
+    region: usize,
+    /// `region` の中で次に切り出すフレームのアドレス。
+    next: u64,
+    /// 解放されたフレームの free list の先頭。
+    free_list: u64,
+    /// `allocate_low_frame` で次に調べるフレームのアドレス。
+    next_low: u64,
+    /// 払い出さない物理アドレスの範囲 (start, end) 。
+    reserved: Option<(u64, u64)>,
+    allocated: usize,
+}
+
+impl BootInfoFrameAllocator {
+    /// ## Safety
+    /// `memory_map` の `Usable` な領域が本当に未使用であること、
+    /// そして物理メモリ全体が `super::phys_to_virt` でアクセスできることを
+    /// 呼び出し側が保証する必要がある。
+    pub unsafe fn new(memory_map: &'static MemoryMap) -> Self {
+        let mut allocator = BootInfoFrameAllocator {
+            memory_map,
+            region: 0,
+            next: 0,
+            free_list: FREE_LIST_END,
+            next_low: 0,
+            reserved: None,
+            allocated: 0,
+        };
+        allocator.seek_region(0);
+        allocator
+    }
+
+    /// 現在割り当てられているフレームの数。
+    pub fn allocated_frames(&self) -> usize {
+        self.allocated
+    }
+
+    /// `Usable` な領域のフレームの総数。
+    pub fn usable_frames(&self) -> usize {
+        self.memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| (r.range.end_frame_number - r.range.start_frame_number) as usize)
+            .sum()
+    }
+
+    /// 物理アドレス `start..end` のフレームを払い出さないようにする。
+    ///
+    /// 1 MiB 以上の範囲で、まだ払い出していないフレームである必要がある。
+    /// 予約できる範囲は1つだけで、もう一度呼び出すと置き換えられる。
+    pub fn reserve(&mut self, start: u64, end: u64) {
+        debug_assert!(start >= LOW_MEMORY_END);
+        self.reserved = Some((start, end));
+    }
+
+    /// 1 MiB 未満のフレームを割り当てる。
+    ///
+    /// 割り当てたフレームは解放しないこと。
+    pub fn allocate_low_frame(&mut self) -> Option<PhysFrame> {
+        let next_low = self.next_low;
+        let addr = self
+            .memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .flat_map(|r| (r.range.start_addr()..r.range.end_addr()).step_by(4096))
+            .find(|&addr| addr != 0 && addr >= next_low && addr < LOW_MEMORY_END)?;
+        self.next_low = addr + 4096;
+        self.allocated += 1;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// `region` 番目以降で最初の `Usable` な領域に移動する。
+    /// 1 MiB 未満の部分は飛ばす。
+    ///
+    /// 物理アドレス 0 から始まる領域も、1 MiB 以上の部分は使う。
+    /// 払い出さないのは 0 のフレームだけである（`allocate_low_frame` も 0 は飛ばす）。
+    fn seek_region(&mut self, region: usize) {
+        self.region = region;
+        while let Some(r) = self.memory_map.get(self.region) {
+            if r.region_type == MemoryRegionType::Usable && r.range.end_addr() > LOW_MEMORY_END {
+                self.next = r.range.start_addr().max(LOW_MEMORY_END);
+                return;
+            }
+            self.region += 1;
+        }
+    }
+
+    fn allocate_from_free_list(&mut self) -> Option<PhysFrame> {
+        if self.free_list == FREE_LIST_END {
+            return None;
+        }
+
+        let frame = PhysFrame::containing_address(PhysAddr::new(self.free_list));
+        let next_ptr = super::phys_to_virt(frame.start_address()).as_ptr::<u64>();
+        self.free_list = unsafe { next_ptr.read() };
+        Some(frame)
+    }
+
+    fn allocate_from_memory_map(&mut self) -> Option<PhysFrame> {
+        loop {
+            let region = self.memory_map.get(self.region)?;
+            if let Some((start, end)) = self.reserved {
+                if start <= self.next && self.next < end {
+                    self.next = end;
+                    continue;
+                }
+            }
+            if self.next < region.range.end_addr() {
+                let frame = PhysFrame::containing_address(PhysAddr::new(self.next));
+                self.next += frame.size();
+                return Some(frame);
+            }
+            self.seek_region(self.region + 1);
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self
+            .allocate_from_free_list()
+            .or_else(|| self.allocate_from_memory_map())?;
+        self.allocated += 1;
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next_ptr = super::phys_to_virt(frame.start_address()).as_mut_ptr::<u64>();
+        unsafe { next_ptr.write(self.free_list) };
+        self.free_list = frame.start_address().as_u64();
+        self.allocated -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+    use alloc::boxed::Box;
+    use bootloader::bootinfo::{FrameRange, MemoryRegion};
+
+    #[test_case]
+    fn test_region_at_zero() {
+        serial_print!("test_region_at_zero... ");
+        let mut memory_map = MemoryMap::new();
+        memory_map.add_region(MemoryRegion {
+            range: FrameRange::new(0, 2 * LOW_MEMORY_END),
+            region_type: MemoryRegionType::Usable,
+        });
+        let memory_map: &'static MemoryMap = Box::leak(Box::new(memory_map));
+        let mut allocator = unsafe { BootInfoFrameAllocator::new(memory_map) };
+
+        let frame = allocator.allocate_frame().unwrap();
+        assert_eq!(frame.start_address().as_u64(), LOW_MEMORY_END);
+        let frame = allocator.allocate_low_frame().unwrap();
+        assert_eq!(frame.start_address().as_u64(), 0x1000);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,131 @@
+//! ## TLB Shootdown
+//!
+//! CPU は page table の変換結果を Translation Lookaside Buffer (TLB) にキャッシュする。
+//! page table を書き換えても TLB は自動的には更新されないので、
+//! マップを解除したりフラグを弱めたりした時は、`invlpg` 命令で TLB から消す必要がある。
+//!
+//! `invlpg` が消すのは実行した CPU の TLB だけである。
+//! kernel の page table は全ての CPU で共有しているので、
+//! 他の CPU にも IPI を送って TLB を消してもらう必要がある（TLB shootdown）。
+//!
+//! 1. 自分の TLB を消す。
+//! 2. `LOCK` を取り、消すアドレスと、まだ消していない CPU のビットマスクを書く。
+//! 3. 自分以外の全ての CPU に `InterruptIndex::TlbShootdown` の IPI を送る。
+//! 4. 各 CPU は TLB を消し、ビットマスクから自分のビットを落とす。
+//! 5. ビットマスクが 0 になったら `LOCK` を離す。
+//!
+//! `LOCK` を待っている間は、自分宛ての要求を処理する。
+//! `LOCK` を待つ間は割り込みが無効になっているかもしれないので、
+//! そうしないと、`LOCK` を持っている CPU が IPI の処理を待ち続けてしまう。
+//!
+//! panic した CPU や、割り込みを無効にしたまま `hlt` している CPU は IPI に応答しない。
+//! 全ての CPU を待ち続けると以後の shootdown が全て止まってしまうので、
+//! `TIMEOUT_MICROS` 待っても応答しない CPU は `UNRESPONSIVE` に入れ、以後は待たない。
+//! その CPU が後で `handle_shootdown` を呼び出したら、TLB を全て消してから元に戻す。
+//!
+//! 新しくマップを追加した時は、古い変換がキャッシュされていないので shootdown は不要である。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/TLB
+//! - Intel SDM Vol. 3A, 4.10.5 "Propagation of Paging-Structure Changes to Multiple Processors"
+
+use crate::{
+    cpu,
+    interrupts::InterruptIndex,
+    power,
+    smp::ipi::{self, Destination},
+    sync::IrqSafeMutex,
+};
+use core::sync::atomic::{spin_loop_hint, AtomicU64, Ordering};
+use x86_64::{instructions::tlb, structures::paging::Page, VirtAddr};
+
+/// 全ての TLB を消すことを表す値。
+const FLUSH_ALL: u64 = u64::max_value();
+
+/// 同時に1つの CPU しか shootdown を行わないようにするための lock 。
+static LOCK: IrqSafeMutex<()> = IrqSafeMutex::new(());
+/// TLB から消すページのアドレス。
+static ADDRESS: AtomicU64 = AtomicU64::new(0);
+/// まだ TLB を消していない CPU のビットマスク。
+static PENDING: AtomicU64 = AtomicU64::new(0);
+/// shootdown に応答しなかった CPU のビットマスク。
+static UNRESPONSIVE: AtomicU64 = AtomicU64::new(0);
+
+/// 他の CPU が TLB を消すのを待つ時間。(100 ms)
+const TIMEOUT_MICROS: u32 = 100_000;
+
+/// 全ての CPU の TLB から `page` を消す。
+pub fn flush(page: Page) {
+    shootdown(page.start_address().as_u64());
+}
+
+/// 全ての CPU の TLB を全て消す。
+pub fn flush_all() {
+    shootdown(FLUSH_ALL);
+}
+
+fn flush_local(addr: u64) {
+    if addr == FLUSH_ALL {
+        tlb::flush_all();
+    } else {
+        tlb::flush(VirtAddr::new(addr));
+    }
+}
+
+fn shootdown(addr: u64) {
+    flush_local(addr);
+
+    // AP を起動するまでは、自分の TLB を消すだけでよい
+    let count = cpu::count();
+    if count <= 1 {
+        return;
+    }
+
+    let _lock = loop {
+        if let Some(lock) = LOCK.try_lock() {
+            break lock;
+        }
+        handle_shootdown();
+        spin_loop_hint();
+    };
+
+    let all = u64::max_value() >> (64 - count);
+    let targets = all & !(1 << cpu::id()) & !UNRESPONSIVE.load(Ordering::SeqCst);
+    if targets == 0 {
+        return;
+    }
+    ADDRESS.store(addr, Ordering::SeqCst);
+    PENDING.store(targets, Ordering::SeqCst);
+    ipi::send(
+        Destination::AllButSelf,
+        InterruptIndex::TlbShootdown.as_u8(),
+    );
+
+    // 割り込みが無効かもしれないので、tick ではなく I/O の遅延で時間を測る
+    for _ in 0..TIMEOUT_MICROS {
+        if PENDING.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        power::wait_a_moment();
+    }
+    let stuck = PENDING.swap(0, Ordering::SeqCst);
+    if stuck != 0 {
+        UNRESPONSIVE.fetch_or(stuck, Ordering::SeqCst);
+        log::error!("CPUs {:#x} did not respond to TLB shootdown", stuck);
+    }
+}
+
+/// 現在の CPU 宛ての shootdown の要求があれば、TLB を消す。
+pub fn handle_shootdown() {
+    let bit = 1 << cpu::id();
+    if UNRESPONSIVE.load(Ordering::SeqCst) & bit != 0 {
+        // 応答しない間の shootdown を取りこぼしているので、全て消す。
+        // 先にビットを落とすので、これ以降の shootdown は待ってもらえる
+        UNRESPONSIVE.fetch_and(!bit, Ordering::SeqCst);
+        tlb::flush_all();
+    }
+    if PENDING.load(Ordering::SeqCst) & bit != 0 {
+        flush_local(ADDRESS.load(Ordering::SeqCst));
+        PENDING.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
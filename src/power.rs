@@ -0,0 +1,199 @@
+//! ## Power
+//!
+//! ACPI を使って電源を切ったり、再起動したりする。
+//!
+//! ### Shutdown
+//!
+//! ACPI では、電源を切ることを「S5 (soft off) スリープ状態に入る」という。
+//! スリープ状態に入るには、PM1a（と、あれば PM1b）control register に
+//! sleep type (SLP_TYP) と SLP_EN ビットを書き込む。
+//! S5 の sleep type はマシンごとに異なり、DSDT の `\_S5` オブジェクトに書かれている。
+//! （`acpi::aml` モジュールを参照）
+//!
+//! PM1 control register を使うには、ACPI モードになっている必要がある。
+//! まだなっていなければ、FADT の SMI command port に ACPI enable の値を書き込んで切り替える。
+//!
+//! ### Reboot
+//!
+//! 再起動の方法はいくつかあり、マシンによって使えるものが異なるので、順番に試す。
+//!
+//! 1. FADT の reset register に reset value を書き込む（ACPI 2.0 以降）。
+//! 2. 8042 キーボードコントローラに CPU のリセットを要求する（`0xFE` コマンド）。
+//! 3. IDT を空にしてから例外を発生させ、Triple Fault を起こす。
+//!    Triple Fault が起きると、CPU はリセットされる。
+//!    （`interrupts` モジュールの Double Fault の説明を参照）
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/Shutdown
+//! - https://wiki.osdev.org/Reboot
+
+use crate::{
+    acpi::{self, address_space, AcpiError, Fadt, GenericAddress},
+    memory, println,
+};
+use x86_64::{
+    instructions::{interrupts, port::Port, tables},
+    structures::DescriptorTablePointer,
+    PhysAddr,
+};
+
+/// PM1 control register の SCI_EN ビット。ACPI モードなら立っている。
+const SCI_EN: u16 = 1 << 0;
+/// PM1 control register の SLP_TYP の位置。
+const SLP_TYP_SHIFT: u16 = 10;
+/// PM1 control register の SLP_EN ビット。
+const SLP_EN: u16 = 1 << 13;
+
+/// 8042 のステータスレジスタ（読み込み）とコマンドレジスタ（書き込み）。
+const PS2_COMMAND_PORT: u16 = 0x64;
+/// 8042 の入力バッファが一杯であることを表すステータスビット。
+const PS2_INPUT_BUFFER_FULL: u8 = 1 << 1;
+/// CPU のリセットラインをパルスさせるコマンド。
+const PS2_PULSE_RESET: u8 = 0xfe;
+
+/// 電源を切る。
+///
+/// 失敗した場合は、割り込みを無効にして CPU を止める。
+pub fn shutdown() -> ! {
+    if let Err(err) = acpi_shutdown() {
+        log::error!("ACPI shutdown failed : {:?}", err);
+    }
+    println!("It is now safe to turn off your computer.");
+    halt()
+}
+
+/// 再起動する。
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    if let Ok(fadt) = acpi::fadt() {
+        if let Some(register) = fadt.reset_register {
+            write_reset_register(register, fadt.reset_value);
+            wait();
+        }
+    }
+
+    pulse_8042_reset();
+    wait();
+
+    triple_fault()
+}
+
+fn acpi_shutdown() -> Result<(), AcpiError> {
+    let fadt = acpi::fadt()?;
+    let sleep_type = acpi::s5_sleep_type()?;
+
+    enable_acpi_mode(&fadt);
+    interrupts::disable();
+
+    let mut pm1a_control = Port::<u16>::new(fadt.pm1a_control_block as u16);
+    unsafe {
+        pm1a_control.write((u16::from(sleep_type.a) << SLP_TYP_SHIFT) | SLP_EN);
+    }
+    if fadt.pm1b_control_block != 0 {
+        let mut pm1b_control = Port::<u16>::new(fadt.pm1b_control_block as u16);
+        unsafe {
+            pm1b_control.write((u16::from(sleep_type.b) << SLP_TYP_SHIFT) | SLP_EN);
+        }
+    }
+
+    // 電源が切れていれば、ここには戻ってこない
+    wait();
+    Ok(())
+}
+
+/// まだ ACPI モードになっていなければ切り替える。
+fn enable_acpi_mode(fadt: &Fadt) {
+    let mut pm1a_control = Port::<u16>::new(fadt.pm1a_control_block as u16);
+    if unsafe { pm1a_control.read() } & SCI_EN != 0 {
+        return;
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        // ACPI モードへの切り替えに対応していない
+        return;
+    }
+
+    let mut smi_command = Port::<u8>::new(fadt.smi_command_port as u16);
+    unsafe { smi_command.write(fadt.acpi_enable) };
+    // 切り替わるまで待つ。いつまでも切り替わらなければ諦める
+    for _ in 0..1000 {
+        if unsafe { pm1a_control.read() } & SCI_EN != 0 {
+            return;
+        }
+        wait_a_moment();
+    }
+}
+
+fn write_reset_register(register: GenericAddress, value: u8) {
+    let address = register.address;
+    match register.address_space {
+        address_space::SYSTEM_IO => unsafe {
+            Port::<u8>::new(address as u16).write(value);
+        },
+        address_space::SYSTEM_MEMORY => {
+            if let Ok(virt) = memory::map_mmio(PhysAddr::new(address), 1) {
+                unsafe { virt.as_mut_ptr::<u8>().write_volatile(value) };
+            }
+        }
+        address_space::PCI_CONFIGURATION => {
+            // bus 0 のデバイスの configuration space 。
+            // アドレスの bit 32-47 がデバイス、bit 16-31 が function 、bit 0-15 がオフセット。
+            let device = ((address >> 32) & 0xffff) as u32;
+            let function = ((address >> 16) & 0xffff) as u32;
+            let offset = (address & 0xffff) as u32;
+            let config_address = 0x8000_0000 | (device << 11) | (function << 8) | (offset & 0xfc);
+            unsafe {
+                Port::<u32>::new(0xcf8).write(config_address);
+                Port::<u8>::new(0xcfc + (offset & 0b11) as u16).write(value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn pulse_8042_reset() {
+    let mut command = Port::<u8>::new(PS2_COMMAND_PORT);
+    unsafe {
+        // 入力バッファが空くまで待つ
+        for _ in 0..1000 {
+            if command.read() & PS2_INPUT_BUFFER_FULL == 0 {
+                break;
+            }
+            wait_a_moment();
+        }
+        command.write(PS2_PULSE_RESET);
+    }
+}
+
+/// IDT を空にして Triple Fault を起こし、CPU をリセットする。
+pub fn triple_fault() -> ! {
+    let empty_idt = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        tables::lidt(&empty_idt);
+    }
+    // IDT が空なので、どの例外のハンドラも呼び出せず Triple Fault になる
+    x86_64::instructions::interrupts::int3();
+    halt()
+}
+
+/// 割り込みを無効にして CPU を止める。
+fn halt() -> ! {
+    interrupts::disable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// 電源の操作が反映されるまでしばらく待つ。
+fn wait() {
+    for _ in 0..100_000 {
+        wait_a_moment();
+    }
+}
+
+/// 未使用のポート `0x80` に書き込んで、1µs 程度待つ。
+///
+/// 割り込みを無効にしていると tick が進まないので、I/O の遅延を使う。
+pub(crate) fn wait_a_moment() {
+    unsafe { Port::<u8>::new(0x80).write(0) };
+}
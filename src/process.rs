@@ -0,0 +1,225 @@
+//! ## Process
+//!
+//! プロセスは user mode のプログラムを実行する単位で、次のものを持つ。
+//!
+//! - アドレス空間（`memory::address_space`）。kernel の領域は全てのプロセスで共有する。
+//! - file descriptor の表（`file` モジュール）。
+//! - プログラムを実行するスレッド。
+//! - 終了コードと親プロセス。
+//!
+//! ### 生成と終了
+//!
+//! 1. `spawn` でプログラムをロードし、ring 3 で実行するスレッドを作る。
+//!    kernel のスレッドから作ったプロセスには親がない。
+//! 2. プロセスのスレッドは `exit` で終了コードを設定して終了する。
+//!    全てのスレッドが終了すると、プロセスは終了した状態になる。
+//!    どのスレッドも `exit` せずに終了した場合、終了コードは `EXIT_KILLED` になる。
+//!    user mode で例外を起こしたスレッドは、`fault_exit_code` で終了する。
+//!    （`interrupts::exceptions` を参照）
+//! 3. 親が `wait` で終了コードを受け取ると、プロセスは表から取り除かれ、
+//!    アドレス空間にマップされていたフレームが解放される。
+//!
+//! 親より先に `wait` されずに残った子は、親が取り除かれた時に親のない（kernel の子の）プロセスになる。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/Processes_and_Threads
+
+pub mod file;
+
+use self::file::FileTable;
+use crate::{
+    memory::address_space::AddressSpace,
+    thread::{self, JoinHandle, ThreadId},
+    user::{self, LoadError},
+};
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessId(u64);
+
+impl ProcessId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ProcessId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// `exit` を呼び出さずに終了したプロセスの終了コード。
+pub const EXIT_KILLED: i32 = -1;
+
+/// user mode で CPU 例外 `vector` が発生して終了したプロセスの終了コード。
+///
+/// `-128 - vector` なので、`EXIT_KILLED` とも区別できる。
+pub fn fault_exit_code(vector: u8) -> i32 {
+    -128 - i32::from(vector)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// 存在しないか、既に `wait` されたプロセス。
+    NoSuchProcess,
+    /// 現在のプロセスの子ではない。
+    NotChild,
+}
+
+pub struct Process {
+    id: ProcessId,
+    /// 親が取り除かれると `None` になる。
+    parent: Mutex<Option<ProcessId>>,
+    address_space: Arc<AddressSpace>,
+    files: Mutex<FileTable>,
+    /// まだ `wait` していないスレッド。
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    /// 最初に `exit` したスレッドの終了コード。
+    exit_code: Mutex<Option<i32>>,
+}
+
+impl Process {
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    pub fn parent(&self) -> Option<ProcessId> {
+        *self.parent.lock()
+    }
+
+    pub fn address_space(&self) -> &Arc<AddressSpace> {
+        &self.address_space
+    }
+
+    pub fn files(&self) -> MutexGuard<FileTable> {
+        self.files.lock()
+    }
+}
+
+lazy_static! {
+    /// `wait` されていない全てのプロセス。
+    static ref PROCESSES: Mutex<BTreeMap<ProcessId, Arc<Process>>> = Mutex::new(BTreeMap::new());
+    /// プロセスのスレッドが、どのプロセスに属しているか。
+    static ref THREADS: Mutex<BTreeMap<ThreadId, ProcessId>> = Mutex::new(BTreeMap::new());
+}
+
+/// ELF の実行ファイル `program` をロードし、新しいプロセスとして実行する。
+///
+/// 新しいプロセスは現在のプロセスの子になる。kernel のスレッドから呼び出した場合は親がない。
+/// `args` と `env` は `user::load` を参照。
+pub fn spawn(program: &[u8], args: &[&str], env: &[&str]) -> Result<ProcessId, LoadError> {
+    let program = user::load(program, args, env)?;
+    let process = Arc::new(Process {
+        id: ProcessId::new(),
+        parent: Mutex::new(current_id()),
+        address_space: Arc::new(program.address_space),
+        files: Mutex::new(FileTable::new()),
+        threads: Mutex::new(Vec::new()),
+        exit_code: Mutex::new(None),
+    });
+    let id = process.id;
+    PROCESSES.lock().insert(id, process.clone());
+
+    let (entry, stack_top) = (program.entry, program.stack_top);
+    let handle = thread::spawn_in(process.address_space.clone(), move || {
+        // user mode に入る前に登録するので、システムコールからは必ずプロセスが見つかる
+        THREADS.lock().insert(thread::current(), id);
+        unsafe { user::enter_user_mode(entry, stack_top) }
+    });
+    process.threads.lock().push(handle);
+
+    Ok(id)
+}
+
+/// 現在のスレッドが属するプロセス。kernel のスレッドなら `None` 。
+pub fn current() -> Option<Arc<Process>> {
+    let id = current_id()?;
+    PROCESSES.lock().get(&id).cloned()
+}
+
+pub fn current_id() -> Option<ProcessId> {
+    THREADS.lock().get(&thread::current()).copied()
+}
+
+/// `id` のプロセスを返す。`wait` された後は `None` 。
+pub fn get(id: ProcessId) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&id).cloned()
+}
+
+/// `id` のプロセスの子。
+pub fn children(id: Option<ProcessId>) -> Vec<ProcessId> {
+    PROCESSES
+        .lock()
+        .values()
+        .filter(|process| process.parent() == id)
+        .map(|process| process.id)
+        .collect()
+}
+
+/// 現在のプロセスの終了コードを `code` にし、現在のスレッドを終了する。
+///
+/// 既に他のスレッドが `exit` していた場合、終了コードは変更しない。
+/// プロセスの他のスレッドは終了しない。
+pub fn exit(code: i32) -> ! {
+    if let Some(process) = current() {
+        process.exit_code.lock().get_or_insert(code);
+    }
+    thread::exit()
+}
+
+/// 現在のプロセスの子 `id` の全てのスレッドが終了するまで待ち、終了コードを返す。
+/// どのスレッドも `exit` していなければ `EXIT_KILLED` を返す。
+///
+/// 他に `Process` への参照が残っていなければ、戻った時には
+/// `id` のアドレス空間にマップされていたフレームは全て解放されている。
+pub fn wait(id: ProcessId) -> Result<i32, ProcessError> {
+    let process = get(id).ok_or(ProcessError::NoSuchProcess)?;
+    if process.parent() != current_id() {
+        return Err(ProcessError::NotChild);
+    }
+
+    // `JoinHandle::wait` は終了したスレッドを scheduler から取り除くので、
+    // スレッドが持っていたアドレス空間への参照もなくなる
+    loop {
+        let handle = process.threads.lock().pop();
+        match handle {
+            Some(handle) => {
+                handle.wait();
+            }
+            None => break,
+        }
+    }
+
+    {
+        let mut processes = PROCESSES.lock();
+        if processes.remove(&id).is_none() {
+            // 他のスレッドが先に `wait` した
+            return Err(ProcessError::NoSuchProcess);
+        }
+        for child in processes.values() {
+            let mut parent = child.parent.lock();
+            if *parent == Some(id) {
+                *parent = None;
+            }
+        }
+    }
+    {
+        let mut threads = THREADS.lock();
+        let finished: Vec<ThreadId> = threads
+            .iter()
+            .filter(|(_, process_id)| **process_id == id)
+            .map(|(thread_id, _)| *thread_id)
+            .collect();
+        for thread_id in finished {
+            threads.remove(&thread_id);
+        }
+    }
+
+    let exit_code = process.exit_code.lock().unwrap_or(EXIT_KILLED);
+    // 他に `Arc<Process>` を持っているものがなければ、ここでアドレス空間が解放される
+    drop(process);
+    Ok(exit_code)
+}
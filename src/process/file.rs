@@ -0,0 +1,93 @@
+//! ## File Descriptor
+//!
+//! プロセスごとの file descriptor の表。
+//! 今のところ開けるのは画面と serial だけで、プロセスは次の表で始まる。
+//!
+//! | fd | file     |
+//! |----|----------|
+//! | 0  | なし     |
+//! | 1  | `Console`|
+//! | 2  | `Serial` |
+
+use crate::{print, serial_print};
+
+/// 1つのプロセスが開ける file の最大数。
+pub const MAX_FILES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum File {
+    /// VGA の画面。
+    Console,
+    /// serial port 。
+    Serial,
+}
+
+impl File {
+    pub fn write(self, s: &str) {
+        match self {
+            File::Console => print!("{}", s),
+            File::Serial => serial_print!("{}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileTable {
+    files: [Option<File>; MAX_FILES],
+}
+
+impl FileTable {
+    /// 1 に `Console`、2 に `Serial` を開いた表を作る。
+    pub fn new() -> FileTable {
+        let mut files = [None; MAX_FILES];
+        files[1] = Some(File::Console);
+        files[2] = Some(File::Serial);
+        FileTable { files }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<File> {
+        self.files.get(fd).and_then(|file| *file)
+    }
+
+    /// 空いている一番小さい fd に `file` を開き、その fd を返す。
+    /// 空きがなければ `None` を返す。
+    pub fn open(&mut self, file: File) -> Option<usize> {
+        let fd = self.files.iter().position(Option::is_none)?;
+        self.files[fd] = Some(file);
+        Some(fd)
+    }
+
+    /// `fd` を閉じ、開いていた file を返す。
+    pub fn close(&mut self, fd: usize) -> Option<File> {
+        self.files.get_mut(fd).and_then(Option::take)
+    }
+}
+
+impl Default for FileTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_file_table() {
+        serial_print!("test_file_table... ");
+        let mut files = FileTable::new();
+        assert_eq!(files.get(0), None);
+        assert_eq!(files.get(1), Some(File::Console));
+        assert_eq!(files.get(2), Some(File::Serial));
+        assert_eq!(files.get(MAX_FILES), None);
+
+        assert_eq!(files.open(File::Serial), Some(0));
+        assert_eq!(files.open(File::Serial), Some(3));
+        assert_eq!(files.close(1), Some(File::Console));
+        assert_eq!(files.close(1), None);
+        assert_eq!(files.open(File::Console), Some(1));
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,172 @@
+//! ## SMP
+//!
+//! 電源投入時には BSP（Bootstrap Processor）だけが動いていて、
+//! 他の CPU（AP : Application Processor）は INIT 状態で待っている。
+//! AP は BSP の local APIC から IPI を送って起動する。
+//!
+//! ### INIT-SIPI-SIPI
+//!
+//! 1. INIT IPI を送り、AP をリセットする。10ms 待つ。
+//! 2. Startup IPI (SIPI) を送る。AP は real mode で
+//!    `vector << 12` の物理アドレスから実行を始める。
+//! 3. AP が起動しなければ、もう一度 SIPI を送る。
+//!
+//! AP は `trampoline` を通って long mode に移り、`ap_main` を呼び出す。
+//! `ap_main` は自分の `PerCpu`（GDT, TSS, GS base）をロードし、
+//! IDT と local APIC を設定してから `AP_STARTED` を立てる。
+//! BSP はそれを待ってから次の AP を起動する。
+//! trampoline の引数を AP ごとに書き換えるので、AP は1つずつ起動する必要がある。
+//!
+//! 起動する AP は ACPI の MADT の processor の一覧から選ぶ。
+//!
+//! ### AP で動くもの
+//!
+//! 今のところ、AP は起動した後 `hlt` で待ち続け、IPI が来た時だけ動く。
+//! スレッドは BSP でのみ実行され、タイマーの割り込みも BSP にだけ配送される。
+//!
+//! - `ipi` : 他の CPU に割り込みを送る。
+//! - `call` : `run_on_cpu` で他の CPU にクロージャを実行させる。
+//! - `memory::tlb` : マップを解除した時に、他の CPU の TLB を消す。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/SMP
+//! - Intel SDM Vol. 3A, 8.4 "Multiple-Processor (MP) Initialization"
+
+pub mod call;
+pub mod ipi;
+mod trampoline;
+
+pub use self::call::run_on_cpu;
+
+use self::trampoline::Trampoline;
+use crate::{
+    acpi::{self, AcpiError},
+    cpu::{self, PerCpu},
+    interrupts::{self, apic},
+    thread::stack::Stack,
+};
+use core::{
+    mem,
+    sync::atomic::{spin_loop_hint, AtomicBool, Ordering},
+};
+use x86_64::structures::paging::mapper::MapToError;
+
+/// INIT IPI を送ってから SIPI を送るまでに待つタイマーの割り込みの回数。
+const INIT_DELAY_TICKS: u64 = 1;
+/// 1回目の SIPI の後、2回目を送るまでに待つタイマーの割り込みの回数。
+const SIPI_RETRY_TICKS: u64 = 1;
+/// 2回目の SIPI の後、AP の起動を待つタイマーの割り込みの回数。
+const STARTUP_TIMEOUT_TICKS: u64 = 100;
+
+/// 起動中の AP が `ap_main` まで到達したか。
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum SmpError {
+    /// APIC が有効になっていない。
+    ApicDisabled,
+    Acpi(AcpiError),
+    /// trampoline やスタックをマップできなかった。
+    Map(MapToError),
+    /// trampoline を置くための 1 MiB 未満のフレームがない。
+    NoLowMemory,
+    /// kernel の level 4 table が 4 GiB 以上のアドレスにあり、trampoline から使えない。
+    PageTableTooHigh,
+}
+
+impl From<AcpiError> for SmpError {
+    fn from(err: AcpiError) -> Self {
+        SmpError::Acpi(err)
+    }
+}
+
+impl From<MapToError> for SmpError {
+    fn from(err: MapToError) -> Self {
+        SmpError::Map(err)
+    }
+}
+
+/// MADT に書かれている全ての AP を起動し、起動している CPU の数を返す。
+///
+/// `init_apic` の後に、割り込みが有効な状態で一度だけ呼び出すこと。
+/// 起動しなかった AP は無視する。
+pub fn init() -> Result<usize, SmpError> {
+    if !apic::is_enabled() {
+        return Err(SmpError::ApicDisabled);
+    }
+    let madt = acpi::madt()?;
+
+    let bsp_id = apic::local::id();
+    cpu::current().set_apic_id(bsp_id);
+
+    let trampoline = Trampoline::install()?;
+    let processors = madt
+        .processors
+        .iter()
+        .filter(|processor| processor.enabled && processor.apic_id != bsp_id);
+    for processor in processors {
+        if cpu::count() >= cpu::MAX_CPUS {
+            break;
+        }
+
+        let percpu = cpu::new_ap()?;
+        // AP は停止しないので、スタックは解放しない
+        let stack = Stack::allocate()?;
+        trampoline.set_params(stack.top(), ap_main, percpu);
+        mem::forget(stack);
+
+        if start_ap(processor.apic_id, trampoline.vector()) {
+            cpu::register(percpu);
+        } else {
+            log::warn!("CPU (APIC ID {}) did not start", processor.apic_id);
+        }
+    }
+
+    Ok(cpu::count())
+}
+
+/// INIT-SIPI-SIPI で AP を起動し、`ap_main` に到達するのを待つ。
+fn start_ap(apic_id: u32, vector: u8) -> bool {
+    use apic::local::{icr, send_ipi};
+
+    AP_STARTED.store(false, Ordering::SeqCst);
+    let started = || AP_STARTED.load(Ordering::SeqCst);
+
+    send_ipi(apic_id, icr::INIT | icr::ASSERT);
+    wait_until(INIT_DELAY_TICKS, || false);
+
+    let sipi = icr::STARTUP | icr::ASSERT | u32::from(vector);
+    send_ipi(apic_id, sipi);
+    if wait_until(SIPI_RETRY_TICKS, started) {
+        return true;
+    }
+    send_ipi(apic_id, sipi);
+    wait_until(STARTUP_TIMEOUT_TICKS, started)
+}
+
+/// タイマーの割り込みが `ticks` 回発生するまで、`condition` が満たされるのを待つ。
+fn wait_until(ticks: u64, condition: impl Fn() -> bool) -> bool {
+    // 次の割り込みまでの時間は 1 周期より短いかもしれないので、1 回多く待つ
+    let deadline = interrupts::ticks() + ticks + 1;
+    while interrupts::ticks() < deadline {
+        if condition() {
+            return true;
+        }
+        spin_loop_hint();
+    }
+    condition()
+}
+
+/// AP が trampoline から呼び出される関数。
+extern "C" fn ap_main(percpu: *const PerCpu) -> ! {
+    let percpu: &'static PerCpu = unsafe { &*percpu };
+    percpu.load();
+    interrupts::init_idt();
+    crate::syscall::init();
+    apic::local::enable();
+    percpu.set_apic_id(apic::local::id());
+
+    AP_STARTED.store(true, Ordering::SeqCst);
+    x86_64::instructions::interrupts::enable();
+    crate::hlt_loop();
+}
@@ -0,0 +1,111 @@
+//! ## Cross-CPU Function Call
+//!
+//! `run_on_cpu` は、クロージャを他の CPU で実行し、その結果を返す。
+//!
+//! 1. 呼び出し側は、クロージャと結果を置く場所（`Call`）を自分のスタックに作り、
+//!    そのアドレスを `REQUESTS` に登録してから、相手の CPU に IPI を送る。
+//! 2. 相手の CPU は割り込みハンドラの中で自分宛ての要求を取り出して実行し、
+//!    結果を書き込んでから `done` を立てる。
+//! 3. 呼び出し側は `done` が立つまで待つ。
+//!
+//! 呼び出し側は実行が終わるまで戻らないので、クロージャはスタック上のデータを借用できる。
+//!
+//! 待っている間は自分宛ての要求と TLB shootdown も処理する。
+//! 割り込みを無効にした状態で2つの CPU が互いに `run_on_cpu` を呼び出しても、
+//! 待っている間に他の CPU が `memory::tlb::flush` を呼び出しても、デッドロックしない。
+
+use super::ipi::{self, Destination};
+use crate::{cpu, interrupts::InterruptIndex, memory::tlb, sync::IrqSafeMutex};
+use alloc::vec::Vec;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, Ordering};
+use lazy_static::lazy_static;
+
+/// 他の CPU で実行する関数の要求。
+struct Request {
+    /// 実行する CPU の番号。
+    cpu: usize,
+    /// `data` を受け取って実行する関数。
+    call: unsafe fn(*mut ()),
+    /// 呼び出し側のスタックにある `Call` のアドレス。
+    data: *mut (),
+}
+
+// `data` は `run_on_cpu` が戻るまで有効で、`Call` は `Send` なクロージャと結果しか持たない
+unsafe impl Send for Request {}
+
+lazy_static! {
+    static ref REQUESTS: IrqSafeMutex<Vec<Request>> = IrqSafeMutex::new(Vec::new());
+}
+
+/// 呼び出し側のスタックに置く、クロージャと結果。
+struct Call<F, R> {
+    func: Option<F>,
+    result: Option<R>,
+    done: AtomicBool,
+}
+
+/// `data` を `Call<F, R>` として実行する。
+///
+/// ## Safety
+/// `data` は有効な `Call<F, R>` を指している必要がある。
+unsafe fn run<F, R>(data: *mut ())
+where
+    F: FnOnce() -> R,
+{
+    let call = data as *mut Call<F, R>;
+    let func = (*call).func.take().expect("function is already called");
+    (*call).result = Some(func());
+    (*call).done.store(true, Ordering::Release);
+}
+
+/// 番号が `cpu` の CPU で `f` を実行し、その結果を返す。
+///
+/// `f` は相手の CPU の割り込みハンドラの中で、割り込みが無効な状態で実行されるので、
+/// 長い時間ブロックしてはいけない。
+/// `cpu` が現在の CPU であれば、その場で実行する。
+/// 存在しない CPU を指定すると panic する。
+pub fn run_on_cpu<F, R>(cpu: usize, f: F) -> R
+where
+    F: FnOnce() -> R + Send,
+    R: Send,
+{
+    assert!(cpu < cpu::count(), "CPU {} is not online", cpu);
+    if cpu == cpu::id() {
+        return f();
+    }
+
+    let mut call = Call {
+        func: Some(f),
+        result: None,
+        done: AtomicBool::new(false),
+    };
+    let data = &mut call as *mut Call<F, R>;
+    REQUESTS.lock().push(Request {
+        cpu,
+        call: run::<F, R>,
+        data: data as *mut (),
+    });
+    ipi::send(Destination::Cpu(cpu), InterruptIndex::CallFunction.as_u8());
+
+    while !unsafe { (*data).done.load(Ordering::Acquire) } {
+        handle_calls();
+        tlb::handle_shootdown();
+        spin_loop_hint();
+    }
+    unsafe { (*data).result.take() }.expect("function did not return")
+}
+
+/// 現在の CPU 宛ての要求を、登録された順に全て実行する。
+pub fn handle_calls() {
+    let current = cpu::id();
+    loop {
+        let request = {
+            let mut requests = REQUESTS.lock();
+            match requests.iter().position(|request| request.cpu == current) {
+                Some(index) => requests.remove(index),
+                None => return,
+            }
+        };
+        unsafe { (request.call)(request.data) };
+    }
+}
@@ -0,0 +1,49 @@
+//! ## IPI
+//!
+//! Inter-Processor Interrupt (IPI) は、local APIC から他の CPU に送る割り込みである。
+//! 送り先の CPU では、通常の割り込みと同じように IDT のハンドラが呼び出される。
+//!
+//! 送り先は APIC ID で1つの CPU を指定するか、
+//! destination shorthand で「自分以外の全て」「全て」を指定する。
+//! ここでは CPU を APIC ID ではなく `cpu` モジュールの番号で指定する。
+
+use crate::{cpu, interrupts::apic::local};
+
+/// IPI の送り先。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    /// 番号が一致する CPU 。
+    Cpu(usize),
+    /// 自分以外の全ての CPU 。
+    AllButSelf,
+    /// 自分を含む全ての CPU 。
+    All,
+}
+
+/// `destination` に `vector` の割り込みを送る。
+///
+/// APIC が有効になっている必要がある。
+/// `Destination::Cpu` で存在しない CPU を指定すると panic する。
+pub fn send(destination: Destination, vector: u8) {
+    let vector = u32::from(vector);
+    match destination {
+        Destination::Cpu(index) => {
+            let apic_id = cpu::get(index)
+                .and_then(|cpu| cpu.apic_id())
+                .unwrap_or_else(|| panic!("CPU {} is not online", index));
+            local::send_ipi(apic_id, local::icr::ASSERT | vector);
+        }
+        Destination::AllButSelf => {
+            local::send_ipi(
+                0,
+                local::icr::ALL_EXCLUDING_SELF | local::icr::ASSERT | vector,
+            );
+        }
+        Destination::All => {
+            local::send_ipi(
+                0,
+                local::icr::ALL_INCLUDING_SELF | local::icr::ASSERT | vector,
+            );
+        }
+    }
+}
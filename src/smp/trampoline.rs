@@ -0,0 +1,222 @@
+//! ## Trampoline
+//!
+//! INIT-SIPI-SIPI で起動された AP は、real mode で
+//! `vector << 12` の物理アドレスから実行を始める。
+//! trampoline はそこから protected mode を経由して long mode に移り、
+//! kernel の page table とスタックに切り替えてから Rust の関数を呼び出す。
+//!
+//! 1. real mode : trampoline 内の GDT をロードして protected mode に移る。
+//!    CS は `vector << 8` なので、ラベルのアドレスは `CS << 4` からの相対位置で計算する。
+//! 2. protected mode : PAE を有効にし、kernel の level 4 table を CR3 にロードする。
+//!    `IA32_EFER` で long mode と NX ビットを有効にしてから paging を有効にする。
+//! 3. long mode : 引数（`Params`）に書かれたスタックに切り替え、entry を呼び出す。
+//!
+//! paging を有効にした直後は trampoline の物理アドレスで実行を続けるので、
+//! trampoline のページは identity map しておく必要がある。
+//! また、CR3 は protected mode で 32 bit の値として書き込むので、
+//! kernel の level 4 table は 4 GiB 未満にある必要がある。
+//!
+//! trampoline のコードは位置に依存しないように書いてあり、
+//! BSP が `Params` を書き込んでから 1 MiB 未満のフレームにコピーして使う。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/SMP
+//! - Intel SDM Vol. 3A, 9.8 "Initialization and Mode Switching in IA-32e Mode"
+
+use super::SmpError;
+use crate::{cpu::PerCpu, memory};
+use core::{mem, ptr};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{Page, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+global_asm!(
+    r#"
+.pushsection .rodata.ap_trampoline, "a"
+.global ap_trampoline_start
+.global ap_trampoline_params
+.global ap_trampoline_end
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    // ebx = trampoline の物理アドレス
+    xor %ebx, %ebx
+    mov %ax, %bx
+    shl $4, %ebx
+
+    lea (ap_gdt - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (ap_gdt_pointer - ap_trampoline_start + 2)
+    lgdtl (ap_gdt_pointer - ap_trampoline_start)
+
+    lea (ap_protected_mode - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (ap_protected_mode_pointer - ap_trampoline_start)
+
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl *(ap_protected_mode_pointer - ap_trampoline_start)
+
+.code32
+ap_protected_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+
+    // PAE
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+
+    mov (ap_trampoline_params - ap_trampoline_start)(%ebx), %eax
+    mov %eax, %cr3
+
+    // IA32_EFER : LME と NXE
+    mov $0xc0000080, %ecx
+    rdmsr
+    or $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    // PG と WP
+    mov %cr0, %eax
+    or $((1 << 31) | (1 << 16)), %eax
+    mov %eax, %cr0
+
+    lea (ap_long_mode - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (ap_long_mode_pointer - ap_trampoline_start)(%ebx)
+    ljmp *(ap_long_mode_pointer - ap_trampoline_start)(%ebx)
+
+.code64
+ap_long_mode:
+    // trampoline の GDT のセグメントを指したままにしないように、null にしておく。
+    // SS は kernel の GDT をロードする時に設定し直す
+    xor %eax, %eax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov %ax, %fs
+    mov %ax, %gs
+
+    // mode を切り替えた後は上位 32 bit が不定なので、0 拡張する
+    mov %ebx, %ebx
+    mov (ap_trampoline_params - ap_trampoline_start + 8)(%rbx), %rsp
+    mov (ap_trampoline_params - ap_trampoline_start + 16)(%rbx), %rax
+    mov (ap_trampoline_params - ap_trampoline_start + 24)(%rbx), %rdi
+    call *%rax
+    ud2
+
+.balign 8
+ap_gdt:
+    .quad 0
+    // 0x08 : 32-bit code
+    .quad 0x00cf9a000000ffff
+    // 0x10 : 32-bit data
+    .quad 0x00cf92000000ffff
+    // 0x18 : 64-bit code
+    .quad 0x00af9a000000ffff
+ap_gdt_end:
+
+ap_gdt_pointer:
+    .word ap_gdt_end - ap_gdt - 1
+    .long 0
+
+ap_protected_mode_pointer:
+    .long 0
+    .word 0x08
+
+ap_long_mode_pointer:
+    .long 0
+    .word 0x18
+
+.balign 8
+ap_trampoline_params:
+    .quad 0
+    .quad 0
+    .quad 0
+    .quad 0
+ap_trampoline_end:
+
+.code64
+.popsection
+"#
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_params: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// AP の entry 。`PerCpu` を受け取って、戻らない。
+pub type Entry = extern "C" fn(*const PerCpu) -> !;
+
+/// trampoline に渡す引数。`ap_trampoline_params` と同じレイアウト。
+#[repr(C)]
+struct Params {
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    percpu: u64,
+}
+
+/// 1 MiB 未満のフレームにコピーされた trampoline 。
+pub struct Trampoline {
+    frame: PhysFrame,
+}
+
+impl Trampoline {
+    /// trampoline を 1 MiB 未満のフレームにコピーし、identity map する。
+    pub fn install() -> Result<Self, SmpError> {
+        let (level_4_table, _) = Cr3::read();
+        if level_4_table.start_address().as_u64() > u64::from(u32::max_value()) {
+            return Err(SmpError::PageTableTooHigh);
+        }
+
+        let frame =
+            memory::with(|memory| memory.allocate_low_frame()).ok_or(SmpError::NoLowMemory)?;
+        let addr = frame.start_address();
+
+        let code = unsafe { &ap_trampoline_start as *const u8 };
+        let len = unsafe { &ap_trampoline_end as *const u8 as usize - code as usize };
+        unsafe {
+            ptr::copy_nonoverlapping(code, memory::phys_to_virt(addr).as_mut_ptr(), len);
+        }
+
+        // bootloader が既に identity map している場合もある
+        let page = Page::containing_address(VirtAddr::new(addr.as_u64()));
+        if memory::translate(page.start_address()) != Some(addr) {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            unsafe { memory::map_to(page, frame, flags)? };
+        }
+
+        Ok(Trampoline { frame })
+    }
+
+    /// SIPI で送るベクタ。trampoline のページ番号。
+    pub fn vector(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    /// 次に起動する AP のスタックと entry を書き込む。
+    pub fn set_params(&self, stack_top: VirtAddr, entry: Entry, percpu: &'static PerCpu) {
+        let params = Params {
+            cr3: Cr3::read().0.start_address().as_u64(),
+            stack_top: stack_top.as_u64(),
+            entry: entry as usize as u64,
+            percpu: percpu as *const PerCpu as u64,
+        };
+        unsafe {
+            let offset = &ap_trampoline_params as *const u8 as u64
+                - &ap_trampoline_start as *const u8 as u64;
+            let addr = PhysAddr::new(self.frame.start_address().as_u64() + offset);
+            debug_assert_eq!(offset as usize % mem::align_of::<Params>(), 0);
+            ptr::write_volatile(memory::phys_to_virt(addr).as_mut_ptr(), params);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! ## 割り込みに対して安全な lock
+//!
+//! `spin::Mutex` を割り込みが有効な状態で lock すると、lock を保持している間に
+//! 割り込みが発生し、そのハンドラが同じ lock を取得しようとした場合にデッドロックする。
+//! ハンドラは lock が外れるのを待ち続けるが、lock を保持しているコードは
+//! ハンドラから戻るまで実行されないからである。
+//!
+//! `IrqSafeMutex` は lock している間だけ割り込みを無効にすることで、これを防ぐ。
+//! lock を外す時は、割り込みを lock する前の状態に戻す。
+//! そのため、割り込みが無効な状態（例えば割り込みハンドラの中）で lock しても、
+//! 勝手に割り込みが有効になることはない。
+//!
+//! 割り込みが無効な間は `thread` のスレッドも切り替えられないので、
+//! lock を保持しているスレッドが切り替えられて他のスレッドが待たされることもない。
+//! その代わり、lock を長時間保持すると割り込みの処理が遅れるので注意すること。
+
+use core::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+pub struct IrqSafeMutex<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSafeMutex {
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// 割り込みを無効にしてから lock する。
+    pub fn lock(&self) -> IrqSafeMutexGuard<T> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            were_enabled,
+        }
+    }
+
+    /// lock できなければすぐに `None` を返す。
+    ///
+    /// panic handler などで、lock を保持したまま止まったかもしれないコードの
+    /// lock を待たないようにするために使う。
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<T>> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSafeMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                were_enabled,
+            }),
+            None => {
+                if were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+pub struct IrqSafeMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    /// lock する前に割り込みが有効だったか。
+    were_enabled: bool,
+}
+
+impl<'a, T> Deref for IrqSafeMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqSafeMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for IrqSafeMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // 割り込みを有効にする前に lock を外す
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        if self.were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    #[test_case]
+    fn test_irq_safe_mutex_restores_interrupts() {
+        serial_print!("test_irq_safe_mutex_restores_interrupts... ");
+        let mutex = IrqSafeMutex::new(0);
+
+        assert!(interrupts::are_enabled());
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(!interrupts::are_enabled());
+            // lock している間は他から lock できない
+            assert!(mutex.try_lock().is_none());
+            assert!(!interrupts::are_enabled());
+        }
+        assert!(interrupts::are_enabled());
+
+        // 割り込みが無効な状態で lock しても、有効にはならない
+        interrupts::without_interrupts(|| {
+            *mutex.lock() += 1;
+            assert!(!interrupts::are_enabled());
+        });
+        assert!(interrupts::are_enabled());
+        assert_eq!(*mutex.lock(), 2);
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,274 @@
+//! ## System Call
+//!
+//! user mode (ring 3) のプログラムが kernel の機能を使うための入口。
+//!
+//! ### syscall / sysret
+//!
+//! `syscall` 命令は、MSR に設定したアドレスに ring 0 でジャンプする。
+//! 割り込みと違ってスタックは切り替わらず、戻り先の RIP を RCX に、RFLAGS を R11 に入れるだけなので、
+//! 入口（`entry` モジュール）でスタックを kernel のものに切り替える必要がある。
+//! `sysretq` 命令は RCX と R11 から RIP と RFLAGS を戻して ring 3 に戻る。
+//!
+//! | MSR     | 内容                                                           |
+//! |---------|----------------------------------------------------------------|
+//! | `STAR`  | `syscall` でロードする kernel の CS/SS と、`sysret` でロードする user の CS/SS |
+//! | `LSTAR` | `syscall` でジャンプするアドレス                               |
+//! | `FMASK` | `syscall` で RFLAGS から落とすビット（割り込みを無効にする）   |
+//!
+//! `int 0x80` でも同じシステムコールを呼び出せる。デバッグ用の遅い経路である。
+//!
+//! ### 呼び出し規約
+//!
+//! Linux の x86_64 と同じ。
+//! RAX にシステムコールの番号、RDI, RSI, RDX, R10, R8, R9 に引数を入れる。
+//! 戻り値は RAX に入る。`-4095..0` の値はエラー（`Error` の値の符号を反転したもの）を表す。
+//! RCX と R11 は壊れる。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/SYSCALL
+//! - Intel SDM Vol. 3A, 5.8.8 "Fast System Calls in 64-Bit Mode"
+
+mod entry;
+mod handlers;
+
+pub(crate) use self::entry::int80_handler;
+
+use crate::{
+    cpu, memory, process,
+    sync::IrqSafeMutex,
+    thread::{self, ThreadId},
+};
+use alloc::collections::BTreeMap;
+use core::{convert::TryFrom, slice};
+use lazy_static::lazy_static;
+use x86_64::{
+    instructions::interrupts, registers::model_specific::Msr, structures::paging::Page, VirtAddr,
+};
+
+/// `int 0x80` のベクタ。
+pub const INT80_VECTOR: usize = 0x80;
+
+/// システムコールの番号。
+pub mod number {
+    /// `write(fd, buf, len) -> len` : file descriptor `fd` に書き込む。（`process::file` を参照）
+    pub const WRITE: u64 = 0;
+    /// `exit(code) -> !` : プロセスの終了コードを設定し、現在のスレッドを終了する。
+    pub const EXIT: u64 = 1;
+    /// `yield() -> 0` : 他のスレッドに CPU を譲る。
+    pub const YIELD: u64 = 2;
+    /// `time() -> ticks` : 起動してからのタイマー割り込みの回数を返す。
+    pub const TIME: u64 = 3;
+    /// `sleep(ticks) -> 0` : `ticks` 回タイマー割り込みが発生するまで止まる。
+    pub const SLEEP: u64 = 4;
+}
+
+/// 番号の上限。
+const MAX_SYSCALLS: usize = 16;
+
+/// `IA32_EFER` MSR と、その System Call Extensions ビット。
+const IA32_EFER: u32 = 0xc000_0080;
+const EFER_SCE: u64 = 1;
+const IA32_STAR: u32 = 0xc000_0081;
+const IA32_LSTAR: u32 = 0xc000_0082;
+const IA32_FMASK: u32 = 0xc000_0084;
+
+/// `syscall` で RFLAGS から落とすビット。(TF, IF, DF, AC)
+const RFLAGS_MASK: u64 = 1 << 8 | 1 << 9 | 1 << 10 | 1 << 18;
+
+/// user mode のアドレスの上限。これ以上は non-canonical か kernel の領域。
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// エラーを表す戻り値の符号を反転した値の上限。`-4095..0` がエラーになる。
+const MAX_ERROR_CODE: u64 = 4095;
+
+/// システムコールのエラー。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// 存在しないシステムコール。
+    NoSuchSyscall,
+    /// 引数が不正。
+    InvalidArgument,
+    /// user mode からアクセスできないアドレス。
+    BadAddress,
+    /// 存在しない file descriptor 。
+    BadDescriptor,
+    /// エラーの範囲にあるが、どれにも当てはまらない値。符号を反転したものを持つ。
+    Unknown(u64),
+}
+
+impl Error {
+    /// 符号を反転する前の値。
+    fn code(self) -> u64 {
+        match self {
+            Error::NoSuchSyscall => 1,
+            Error::InvalidArgument => 2,
+            Error::BadAddress => 3,
+            Error::BadDescriptor => 4,
+            Error::Unknown(code) => code,
+        }
+    }
+
+    /// RAX に入れる値に変換する。
+    fn encode(self) -> u64 {
+        self.code().wrapping_neg()
+    }
+
+    /// システムコールの戻り値をエラーとそれ以外に分ける。
+    pub fn decode(value: u64) -> Result<u64, Error> {
+        match value.wrapping_neg() {
+            1 => Err(Error::NoSuchSyscall),
+            2 => Err(Error::InvalidArgument),
+            3 => Err(Error::BadAddress),
+            4 => Err(Error::BadDescriptor),
+            code @ 5..=MAX_ERROR_CODE => Err(Error::Unknown(code)),
+            _ => Ok(value),
+        }
+    }
+}
+
+/// システムコールの番号と引数のレジスタ。entry で push した順に並んでいる。
+#[derive(Debug)]
+#[repr(C)]
+pub struct Registers {
+    pub number: u64,
+    /// RDI, RSI, RDX, R10, R8, R9
+    pub args: Args,
+}
+
+/// システムコールの引数。`get` で型に変換して取り出す。
+#[derive(Debug)]
+#[repr(C)]
+pub struct Args([u64; 6]);
+
+impl Args {
+    /// `index` 番目の引数を `T` として取り出す。
+    pub fn get<T: FromArg>(&self, index: usize) -> Result<T, Error> {
+        T::from_arg(self.0[index])
+    }
+
+    /// `ptr` 番目と `len` 番目の引数を、user mode のバッファのアドレスと長さとして取り出す。
+    ///
+    /// バッファの全てのページが user mode からアクセスできることを確認する。
+    pub fn user_bytes(&self, ptr: usize, len: usize) -> Result<&[u8], Error> {
+        let start: u64 = self.get(ptr)?;
+        let len: u64 = self.get(len)?;
+        if len == 0 {
+            return Ok(&[]);
+        }
+        let end = start.checked_add(len).ok_or(Error::BadAddress)?;
+        if end > USER_END {
+            return Err(Error::BadAddress);
+        }
+        let mut pages = Page::range_inclusive(
+            Page::containing_address(VirtAddr::new(start)),
+            Page::containing_address(VirtAddr::new(end - 1)),
+        );
+        if !pages.all(memory::is_user_accessible) {
+            return Err(Error::BadAddress);
+        }
+        Ok(unsafe { slice::from_raw_parts(start as *const u8, len as usize) })
+    }
+}
+
+/// レジスタの値から変換できる引数の型。
+pub trait FromArg: Sized {
+    fn from_arg(value: u64) -> Result<Self, Error>;
+}
+
+impl FromArg for u64 {
+    fn from_arg(value: u64) -> Result<Self, Error> {
+        Ok(value)
+    }
+}
+
+impl FromArg for usize {
+    fn from_arg(value: u64) -> Result<Self, Error> {
+        Ok(value as usize)
+    }
+}
+
+impl FromArg for i32 {
+    fn from_arg(value: u64) -> Result<Self, Error> {
+        i32::try_from(value as i64).map_err(|_| Error::InvalidArgument)
+    }
+}
+
+/// システムコールの実装。
+type Handler = fn(&Args) -> Result<u64, Error>;
+
+lazy_static! {
+    /// 番号ごとのシステムコールの実装。
+    static ref TABLE: [Option<Handler>; MAX_SYSCALLS] = {
+        let mut table: [Option<Handler>; MAX_SYSCALLS] = [None; MAX_SYSCALLS];
+        table[number::WRITE as usize] = Some(handlers::write);
+        table[number::EXIT as usize] = Some(handlers::exit);
+        table[number::YIELD as usize] = Some(handlers::yield_now);
+        table[number::TIME as usize] = Some(handlers::time);
+        table[number::SLEEP as usize] = Some(handlers::sleep);
+        table
+    };
+
+    /// プロセスに属さないスレッドが `exit` した時の終了コード。
+    static ref EXIT_CODES: IrqSafeMutex<BTreeMap<ThreadId, i32>> =
+        IrqSafeMutex::new(BTreeMap::new());
+}
+
+/// 現在の CPU で `syscall` 命令を使えるようにする。
+///
+/// GDT をロードした後に、CPU ごとに呼び出すこと。
+pub fn init() {
+    let selectors = cpu::current().gdt().selectors();
+    // sysret は user data を base + 8、user code を base + 16 として扱う
+    let sysret_base = u64::from(selectors.user_data_selector.0) - 8;
+    let syscall_base = u64::from(selectors.code_selector.0);
+    debug_assert_eq!(
+        selectors.user_code_selector.0,
+        selectors.user_data_selector.0 + 8
+    );
+
+    unsafe {
+        let mut efer = Msr::new(IA32_EFER);
+        let value = efer.read();
+        efer.write(value | EFER_SCE);
+        Msr::new(IA32_STAR).write(sysret_base << 48 | syscall_base << 32);
+        Msr::new(IA32_LSTAR).write(entry::syscall_entry_address());
+        Msr::new(IA32_FMASK).write(RFLAGS_MASK);
+    }
+}
+
+/// プロセスに属さないスレッド（`user::enter_user_mode` を直接呼び出したもの）が
+/// `exit` や `exit_current` で終了した時の終了コードを取り出す。
+/// プロセスの終了コードは `process::wait` で受け取る。
+///
+/// 取り出すまで残り続ける。
+pub fn exit_code(id: ThreadId) -> Option<i32> {
+    EXIT_CODES.lock().remove(&id)
+}
+
+/// 現在のスレッドの終了コードを `code` にして終了する。
+///
+/// プロセスに属していれば `process::exit` と同じ。
+/// 属していなければ、`exit_code` で取り出せるように記録しておく。
+pub(crate) fn exit_current(code: i32) -> ! {
+    if process::current_id().is_none() {
+        EXIT_CODES.lock().insert(thread::current(), code);
+    }
+    process::exit(code)
+}
+
+/// システムコールを実行し、RAX に入れる値を返す。
+///
+/// entry から割り込みが無効な状態で呼び出される。
+/// 実行中は割り込みを有効にしておくので、スレッドが切り替わることもある。
+fn dispatch(registers: &Registers) -> u64 {
+    interrupts::enable();
+    let handler = TABLE
+        .get(registers.number as usize)
+        .and_then(|handler| *handler);
+    let result = match handler {
+        Some(handler) => handler(&registers.args),
+        None => Err(Error::NoSuchSyscall),
+    };
+    interrupts::disable();
+    result.unwrap_or_else(Error::encode)
+}
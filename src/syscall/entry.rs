@@ -0,0 +1,128 @@
+//! ## Entry
+//!
+//! `syscall` 命令と `int 0x80` の入口。
+//!
+//! どちらも引数のレジスタを `Registers` の順に push し、そのアドレスを渡して
+//! Rust の関数を呼び出す。戻ってきたら RAX 以外のレジスタを元に戻して ring 3 に戻る。
+//! RBX, RBP, R12..R15 は呼び出された Rust の関数が保存する。
+//!
+//! どちらも最初に `swapgs` で GS base を `PerCpu` に切り替え、ring 3 に戻る直前に元に戻す。
+//! （`cpu` モジュールを参照）
+//!
+//! `syscall` ではスタックが切り替わらないので、user mode のスタックポインタを
+//! `%gs:8` に退避してから、`%gs:16` の kernel のスタックに切り替える。
+//! 割り込みは `FMASK` で無効になっているので、退避した値を push し終えるまで
+//! 他の `syscall` に上書きされることはない。
+//!
+//! `int 0x80` は割り込みなので、CPU が TSS の RSP0 に切り替えてくれる。
+//! kernel から呼び出されることもあるので、CS の RPL が 3 の時だけ `swapgs` する。
+
+use super::{dispatch, Registers, USER_END};
+use crate::process;
+use core::mem;
+use x86_64::structures::idt::HandlerFunc;
+
+global_asm!(
+    r#"
+.global atomix_syscall_entry
+atomix_syscall_entry:
+    swapgs
+    mov %rsp, %gs:8
+    mov %gs:16, %rsp
+    pushq %gs:8
+    push %rcx
+    push %r11
+    push %r9
+    push %r8
+    push %r10
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rax
+    mov %rsp, %rdi
+    call atomix_syscall_handler
+    // user mode のスタックに戻す前に割り込みを無効にする
+    cli
+    add $8, %rsp
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %r10
+    pop %r8
+    pop %r9
+    pop %r11
+    pop %rcx
+    pop %rsp
+    swapgs
+    sysretq
+
+.global atomix_int80_entry
+atomix_int80_entry:
+    testb $3, 8(%rsp)
+    jz 1f
+    swapgs
+1:
+    push %r9
+    push %r8
+    push %r10
+    push %rdx
+    push %rsi
+    push %rdi
+    push %rax
+    mov %rsp, %rdi
+    call atomix_int80_handler
+    add $8, %rsp
+    pop %rdi
+    pop %rsi
+    pop %rdx
+    pop %r10
+    pop %r8
+    pop %r9
+    testb $3, 8(%rsp)
+    jz 2f
+    cli
+    swapgs
+2:
+    iretq
+"#
+);
+
+extern "C" {
+    fn atomix_syscall_entry();
+    fn atomix_int80_entry();
+}
+
+/// `syscall` の入口で push する値。
+#[repr(C)]
+struct SyscallFrame {
+    registers: Registers,
+    rflags: u64,
+    rip: u64,
+    rsp: u64,
+}
+
+/// `LSTAR` に設定する `syscall` の入口のアドレス。
+pub(super) fn syscall_entry_address() -> u64 {
+    atomix_syscall_entry as usize as u64
+}
+
+/// IDT に登録する `int 0x80` のハンドラ。
+pub(crate) fn int80_handler() -> HandlerFunc {
+    // アセンブリで書いた入口を、`x86-interrupt` の関数として登録する
+    unsafe { mem::transmute(atomix_int80_entry as unsafe extern "C" fn()) }
+}
+
+#[no_mangle]
+extern "C" fn atomix_syscall_handler(frame: &SyscallFrame) -> u64 {
+    // non-canonical な RIP に sysret すると、ring 0 のまま user のスタックで
+    // General Protection Fault が発生してしまうので、先にスレッドを終了する
+    if frame.rip >= USER_END {
+        super::exit_current(process::EXIT_KILLED);
+    }
+    dispatch(&frame.registers)
+}
+
+#[no_mangle]
+extern "C" fn atomix_int80_handler(registers: &Registers) -> u64 {
+    dispatch(registers)
+}
@@ -0,0 +1,42 @@
+//! 各システムコールの実装。番号と引数は `number` モジュールを参照。
+
+use super::{Args, Error};
+use crate::{
+    interrupts,
+    process::{self, file::FileTable},
+    thread,
+};
+use core::str;
+
+pub(super) fn write(args: &Args) -> Result<u64, Error> {
+    let fd: usize = args.get(0)?;
+    let bytes = args.user_bytes(1, 2)?;
+    let s = str::from_utf8(bytes).map_err(|_| Error::InvalidArgument)?;
+    // プロセスに属さないスレッドは、最初の状態の表を使う
+    let file = match process::current() {
+        Some(process) => process.files().get(fd),
+        None => FileTable::new().get(fd),
+    };
+    file.ok_or(Error::BadDescriptor)?.write(s);
+    Ok(bytes.len() as u64)
+}
+
+pub(super) fn exit(args: &Args) -> Result<u64, Error> {
+    let code: i32 = args.get(0)?;
+    super::exit_current(code);
+}
+
+pub(super) fn yield_now(_args: &Args) -> Result<u64, Error> {
+    thread::yield_now();
+    Ok(0)
+}
+
+pub(super) fn time(_args: &Args) -> Result<u64, Error> {
+    Ok(interrupts::ticks())
+}
+
+pub(super) fn sleep(args: &Args) -> Result<u64, Error> {
+    let ticks: u64 = args.get(0)?;
+    thread::sleep(ticks);
+    Ok(0)
+}
@@ -0,0 +1,64 @@
+//! ## Async/Await
+//!
+//! kernel のタスクを協調的マルチタスク（cooperative multitasking）で実行する。
+//! 各タスクは `Future` として表され、executor がそれを poll する。
+//! `Future` は処理を進められなくなると `Poll::Pending` を返して自発的に CPU を手放すので、
+//! タスクごとにスタックを用意したり、レジスタを退避したりする必要がない。
+//!
+//! ### Waker
+//!
+//! `Pending` を返したタスクを無駄に poll し続けないために、
+//! `Future` は poll された時に渡された `Waker` を保存しておき、
+//! 処理を進められるようになった時（例えばキーボード割り込みが発生した時）に
+//! `Waker::wake` を呼び出す。
+//! executor は wake されたタスクだけを poll する。
+//!
+//! ### 参照
+//! - https://os.phil-opp.com/async-await/
+
+pub mod executor;
+
+use alloc::boxed::Box;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+/// executor が実行するタスク。
+///
+/// `Future` は poll されている間に移動してはいけない（自己参照を含むかもしれない）ので、
+/// heap 上に `Pin` しておく。
+/// また、タスクごとに異なる `Future` の型を同じように扱えるよう、trait object にする。
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
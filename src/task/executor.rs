@@ -0,0 +1,198 @@
+//! ## Executor
+//!
+//! タスクを保持し、wake されたタスクを poll する。
+//!
+//! wake されたタスクの ID は `task_queue` に積まれる。
+//! `Waker::wake` は割り込みハンドラから呼び出されることがあるので、
+//! `task_queue` には lock を使わない `ArrayQueue` を使う。
+//! （lock を使うと、executor が lock を保持している間に割り込みが発生した場合に
+//! デッドロックしてしまう）
+//!
+//! 同じタスクが何度 wake されても、`task_queue` に積むのは1回だけにする。
+//! タスクごとに「キューに積まれている」ことを表す `queued` フラグを持ち、
+//! false から true に変えた時だけ積む。executor はタスクを取り出したら、poll する前に
+//! フラグを false に戻す。poll の途中で wake された場合は、もう一度積まれる。
+//! こうすると `task_queue` に同時に入るのはタスクの数までなので、
+//! タスクの数を `TASK_QUEUE_SIZE` までに制限しておけば wake が失われることはない。
+//!
+//! 実行できるタスクがない時は `hlt` 命令で次の割り込みまで CPU を休ませる。
+
+use super::{Task, TaskId};
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::{
+    mem,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
+};
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+/// `task_queue` の容量。同時に存在できるタスクの最大数になる。
+const TASK_QUEUE_SIZE: usize = 100;
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    /// タスクごとに `Waker` を作り直さないようにキャッシュしておく。
+    waker_cache: BTreeMap<TaskId, CachedWaker>,
+}
+
+/// `TaskWaker` と、それから作った `Waker` 。
+///
+/// `Waker` からは `TaskWaker` を取り出せないので、`queued` を操作するために両方持っておく。
+struct CachedWaker {
+    task_waker: Arc<TaskWaker>,
+    waker: Waker,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    /// タスクを追加する。
+    ///
+    /// ## Panics
+    /// すでに `TASK_QUEUE_SIZE` 個のタスクがある場合は panic する。
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        assert!(self.tasks.len() < TASK_QUEUE_SIZE, "too many tasks");
+        if self.tasks.insert(task_id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        let task_waker = TaskWaker::new(task_id, self.task_queue.clone());
+        let waker = TaskWaker::new_waker(task_waker.clone());
+        task_waker.wake_task();
+        self.waker_cache
+            .insert(task_id, CachedWaker { task_waker, waker });
+    }
+
+    /// 全てのタスクを実行し続ける。
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// 実行できるタスクがなくなるまで実行する。
+    ///
+    /// 全てのタスクが完了した場合は `true` を返す。
+    pub fn run_until_idle(&mut self) -> bool {
+        self.run_ready_tasks();
+        self.tasks.is_empty()
+    }
+
+    fn run_ready_tasks(&mut self) {
+        // `self` を分割して借用する
+        let Self {
+            tasks,
+            task_queue,
+            waker_cache,
+        } = self;
+
+        while let Ok(task_id) = task_queue.pop() {
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                // すでに完了したタスク
+                None => continue,
+            };
+            let cached = &waker_cache[&task_id];
+            // poll している間に wake されたら、もう一度積まれるようにする
+            cached.task_waker.queued.store(false, Ordering::SeqCst);
+            let mut context = Context::from_waker(&cached.waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {
+                    // 残っている `Waker` が、完了したタスクを積まないようにする
+                    cached.task_waker.queued.store(true, Ordering::SeqCst);
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // キューが空であることを確認してから hlt するまでの間に割り込みが発生すると、
+        // wake されたタスクがあるのに次の割り込みまで眠ってしまう。
+        // そのため、確認する前に割り込みを無効にし、
+        // `sti; hlt` で割り込みの有効化と hlt をアトミックに行う。
+        interrupts::disable();
+        if self.task_queue.is_empty() {
+            crate::enable_interrupts_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+/// タスクを wake するための `Waker` の中身。
+///
+/// wake されたら、タスクの ID を `task_queue` に積む。
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    /// タスクの ID が `task_queue` に積まれているか。
+    queued: AtomicBool,
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+            queued: AtomicBool::new(false),
+        })
+    }
+
+    fn new_waker(waker: Arc<TaskWaker>) -> Waker {
+        unsafe { Waker::from_raw(raw_waker(waker)) }
+    }
+
+    /// まだ積まれていなければ、タスクの ID を `task_queue` に積む。
+    ///
+    /// 割り込みハンドラからも呼び出されるので、lock を取ったり panic したりしない。
+    /// 積まれているのはタスクごとに1つまでで、タスクの数は `Executor::spawn` が
+    /// `TASK_QUEUE_SIZE` までに制限しているので、`push` は失敗しない。
+    fn wake_task(&self) {
+        if !self.queued.swap(true, Ordering::SeqCst) {
+            let _ = self.task_queue.push(self.task_id);
+        }
+    }
+}
+
+/// `Waker` は `RawWaker` と、その操作をまとめた `RawWakerVTable` から作る。
+/// `RawWaker` のデータポインタには `Arc<TaskWaker>` を raw pointer にしたものを使う。
+fn raw_waker(waker: Arc<TaskWaker>) -> RawWaker {
+    RawWaker::new(Arc::into_raw(waker) as *const (), &VTABLE)
+}
+
+static VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake_waker, wake_waker_by_ref, drop_waker);
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    let waker = Arc::from_raw(data as *const TaskWaker);
+    let cloned = waker.clone();
+    // 元の参照カウントは `Waker` が持っているので減らさない
+    mem::forget(waker);
+    raw_waker(cloned)
+}
+
+unsafe fn wake_waker(data: *const ()) {
+    let waker = Arc::from_raw(data as *const TaskWaker);
+    waker.wake_task();
+}
+
+unsafe fn wake_waker_by_ref(data: *const ()) {
+    let waker = &*(data as *const TaskWaker);
+    waker.wake_task();
+}
+
+unsafe fn drop_waker(data: *const ()) {
+    mem::drop(Arc::from_raw(data as *const TaskWaker));
+}
@@ -1,7 +1,7 @@
 #[macro_use]
 pub mod serial;
 
-use core::panic::PanicInfo;
+use core::{fmt, panic::PanicInfo};
 
 pub fn test_runner(tests: &[&dyn Fn()]) {
     serial_println!("Running {} tests", tests.len());
@@ -16,11 +16,56 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}\n", crate::backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
 
     loop {}
 }
 
+/// panic のメッセージが `expected` から始まっていれば成功として QEMU を終了する。
+///
+/// 例外ハンドラが正しく呼ばれたかを確認するテストの panic handler で使う。
+pub fn expect_panic_message(info: &PanicInfo, expected: &str) -> ! {
+    let matched = info.message().map_or(false, |message| {
+        let mut prefix = PrefixMatcher {
+            expected: expected.as_bytes(),
+            matched: true,
+        };
+        fmt::write(&mut prefix, *message).is_ok() && prefix.matched && prefix.expected.is_empty()
+    });
+
+    if matched {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Expected: {}", expected);
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    loop {}
+}
+
+/// 書き込まれた文字列の先頭が `expected` と一致するかを調べる。
+///
+/// 文字の途中で区切られても panic しないように、バイト列で比べる。
+struct PrefixMatcher<'a> {
+    expected: &'a [u8],
+    matched: bool,
+}
+
+impl<'a> fmt::Write for PrefixMatcher<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(self.expected.len());
+        if s.as_bytes()[..len] != self.expected[..len] {
+            self.matched = false;
+        }
+        self.expected = &self.expected[len..];
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
@@ -36,3 +81,24 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
         port.write(exit_code as u32);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fmt::Write;
+
+    #[test_case]
+    fn test_prefix_matcher_multibyte() {
+        serial_print!("test_prefix_matcher_multibyte... ");
+        let mut prefix = PrefixMatcher {
+            expected: "éx".as_bytes(),
+            matched: true,
+        };
+        // "é" の途中で区切られるが、panic せずに不一致になる
+        prefix.write_str("a").unwrap();
+        prefix.write_str("bc").unwrap();
+        assert!(!prefix.matched);
+        assert!(prefix.expected.is_empty());
+        serial_println!("[ok]");
+    }
+}
@@ -1,12 +1,13 @@
+use crate::sync::IrqSafeMutex;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    /// 割り込みハンドラからも print できるように、`IrqSafeMutex` を使う。
+    pub static ref SERIAL1: IrqSafeMutex<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSafeMutex::new(serial_port)
     };
 }
 
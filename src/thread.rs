@@ -0,0 +1,157 @@
+//! ## Kernel Threads
+//!
+//! `task` モジュールの協調的マルチタスクと違い、スレッドは
+//! タイマー割り込みによって強制的に切り替えられる（preemptive multitasking）。
+//! そのため、スレッドが自発的に CPU を手放さなくても、他のスレッドが実行される。
+//!
+//! 各スレッドは自分専用のスタックを持つ。
+//! スレッドの切り替え（context switch）では、現在のスレッドのレジスタを
+//! スタックに退避し、スタックポインタを次のスレッドのものに付け替える。
+//! 詳しくは `context` モジュールを参照。
+//!
+//! ### Scheduler
+//!
+//! 実行可能なスレッドを run queue に並べ、先頭から順番に実行する（round-robin）。
+//! タイマー割り込みのたびに、実行中のスレッドを run queue の末尾に戻して
+//! 次のスレッドに切り替える。
+//! 詳しくは `scheduler` モジュールを参照。
+//!
+//! ### 起動時のスレッド
+//!
+//! `kernel_main` を実行しているスレッド（boot thread）も1つのスレッドとして扱う。
+//! boot thread は bootloader が用意したスタックをそのまま使う。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/Kernel_Multitasking
+//! - https://wiki.osdev.org/Context_Switching
+
+mod context;
+mod scheduler;
+pub mod stack;
+
+pub(crate) use self::scheduler::on_tick;
+
+use self::{scheduler::Thread, stack::Stack};
+use crate::memory::address_space::AddressSpace;
+use alloc::{boxed::Box, sync::Arc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// boot thread の ID 。
+    const BOOT: ThreadId = ThreadId(0);
+
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// 新しいスレッドを作成し、`f` を実行する。
+///
+/// 作成したスレッドは run queue の末尾に追加され、順番が来たら実行される。
+/// スタックを割り当てられなかった場合は panic する。
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_thread(f, None)
+}
+
+/// `address_space` で `f` を実行する新しいスレッドを作成する。
+///
+/// `f` は kernel の領域で実行されるが、スレッドが実行されている間は
+/// `address_space` の user の領域が見える。
+/// user mode のプログラムを実行するのに使う。
+pub fn spawn_in<F, T>(address_space: Arc<AddressSpace>, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_thread(f, Some(address_space))
+}
+
+fn spawn_thread<F, T>(f: F, address_space: Option<Arc<AddressSpace>>) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    // 終了したスレッドのスタックをここで解放しておく
+    scheduler::reap();
+
+    let id = ThreadId::new();
+    let result = Arc::new(Mutex::new(None));
+    let entry = {
+        let result = result.clone();
+        Box::new(move || {
+            *result.lock() = Some(f());
+        })
+    };
+    let stack = Stack::allocate().expect("failed to allocate thread stack");
+    scheduler::add(Thread::new(id, stack, entry, address_space));
+
+    JoinHandle { id, result }
+}
+
+/// 現在のスレッドの ID を返す。
+pub fn current() -> ThreadId {
+    scheduler::current()
+}
+
+/// 他の実行可能なスレッドに CPU を譲る。
+///
+/// 他に実行可能なスレッドがなければ、すぐに戻る。
+pub fn yield_now() {
+    scheduler::yield_now();
+}
+
+/// 現在のスレッドを終了する。
+///
+/// `spawn` に渡した関数は最後まで実行されないので、`JoinHandle::join` は panic する。
+/// 終了を待つには `JoinHandle::wait` を使う。
+pub fn exit() -> ! {
+    scheduler::exit()
+}
+
+/// 少なくとも `ticks` 回タイマー割り込みが発生するまで、現在のスレッドを止める。
+///
+/// 止まっている間は他のスレッドが実行される。
+pub fn sleep(ticks: u64) {
+    scheduler::sleep_until(crate::interrupts::ticks() + ticks);
+}
+
+/// スレッドの終了を待つためのハンドル。
+///
+/// `join` せずに drop した場合、スレッドはそのまま実行され続ける。
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// スレッドが終了するまで待ち、`spawn` に渡した関数の戻り値を返す。
+    pub fn join(self) -> T {
+        self.wait().expect("thread exited without result")
+    }
+
+    /// スレッドが終了するまで待つ。
+    ///
+    /// `exit` で終了した場合は `None` を返す。
+    pub fn wait(self) -> Option<T> {
+        scheduler::join(self.id);
+        scheduler::reap();
+        self.result.lock().take()
+    }
+}
@@ -0,0 +1,90 @@
+//! ## Context Switch
+//!
+//! スレッドを切り替えるには、現在のスレッドのレジスタを保存し、
+//! 次のスレッドのレジスタを復元する必要がある。
+//!
+//! context switch は通常の関数呼び出しとして行うので、
+//! caller-saved なレジスタは呼び出し側のコンパイラがすでに保存している。
+//! そのため、ここで保存する必要があるのは callee-saved なレジスタ
+//! （`rbx`, `rbp`, `r12`〜`r15`）とスタックポインタだけである。
+//! （caller-saved / callee-saved については `interrupts` モジュールを参照）
+//!
+//! callee-saved なレジスタは現在のスタックに push し、
+//! その後のスタックポインタだけをスレッドごとに記録しておく。
+//! 次のスレッドのスタックポインタに付け替えてからレジスタを pop し、
+//! `ret` すると、次のスレッドが `switch` を呼び出した場所に戻る。
+//!
+//! ### 新しいスレッド
+//!
+//! 新しいスレッドのスタックには、`switch` が pop するレジスタの初期値と、
+//! `ret` の戻り先としてスレッドの開始関数のアドレスを積んでおく。
+//! こうすると、初めて切り替えられた時に開始関数から実行が始まる。
+//!
+//! ### RFLAGS
+//!
+//! RFLAGS は保存しない。`switch` は常に割り込みが無効な状態で呼び出され、
+//! 戻った後に呼び出し側が割り込みの状態を元に戻す。
+
+use x86_64::VirtAddr;
+
+global_asm!(
+    "
+    .global atomix_switch_context
+    atomix_switch_context:
+        pushq %rbp
+        pushq %rbx
+        pushq %r12
+        pushq %r13
+        pushq %r14
+        pushq %r15
+        movq %rsp, (%rdi)
+        movq %rsi, %rsp
+        popq %r15
+        popq %r14
+        popq %r13
+        popq %r12
+        popq %rbx
+        popq %rbp
+        retq
+    "
+);
+
+extern "C" {
+    fn atomix_switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+/// 現在のスタックポインタを `old_rsp` に保存し、`new_rsp` のスレッドに切り替える。
+///
+/// 保存したスレッドに切り替えられると、この関数から戻る。
+///
+/// ## Safety
+/// 割り込みが無効な状態で呼び出すこと。
+/// `new_rsp` は `switch` で保存されたものか、`init_stack` で作られたものである必要がある。
+pub unsafe fn switch(old_rsp: *mut u64, new_rsp: u64) {
+    atomix_switch_context(old_rsp, new_rsp);
+}
+
+/// 新しいスレッドのスタックを初期化し、`switch` に渡すスタックポインタを返す。
+///
+/// 切り替えられると `entry` が実行される。
+///
+/// ## Safety
+/// `stack_top` は書き込み可能なスタックの末尾を指している必要がある。
+pub unsafe fn init_stack(stack_top: VirtAddr, entry: extern "C" fn() -> !) -> u64 {
+    let mut rsp = stack_top.as_u64() & !0xf;
+    let mut push = |value: u64| {
+        rsp -= 8;
+        (rsp as *mut u64).write(value);
+    };
+
+    // `entry` の戻り先。`entry` は戻らないので使われない。
+    // 関数の先頭では `rsp + 8` が 16 の倍数であることが期待されるので、
+    // その調整も兼ねている。
+    push(0);
+    push(entry as u64);
+    // rbp, rbx, r12〜r15 の初期値
+    for _ in 0..6 {
+        push(0);
+    }
+    rsp
+}
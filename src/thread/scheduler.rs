@@ -0,0 +1,392 @@
+//! ## Scheduler
+//!
+//! 実行可能なスレッドの ID を run queue に並べ、先頭から順番に実行する。
+//! タイマー割り込みのたびに `on_tick` が呼び出され、
+//! 実行中のスレッドを run queue の末尾に戻して次のスレッドに切り替える。
+//!
+//! ### 割り込みと lock
+//!
+//! `SCHEDULER` の lock はタイマー割り込みのハンドラからも取得するので、
+//! lock を保持している間に割り込みが発生するとデッドロックしてしまう。
+//! そのため、lock は必ず割り込みを無効にしてから取得する。
+//!
+//! また、割り込みのハンドラの中で heap を使うと、割り込まれたスレッドが
+//! アロケータの lock を保持していた場合にデッドロックするので、
+//! `on_tick` と `schedule` では heap の割り当ても解放も行わない。
+//! run queue を固定長の配列にしているのはそのためである。
+//! 終了したスレッドのスタックなどは、`reap` で通常のスレッドの文脈から解放する。
+//!
+//! ### アドレス空間
+//!
+//! user mode のプログラムを実行するスレッドは自分のアドレス空間を持つ。
+//! 切り替える時に CR3 も切り替え先のスレッドのもの（kernel のスレッドなら kernel のもの）にする。
+//!
+//! ### 実行できるスレッドがない時
+//!
+//! 全てのスレッドが sleep や join で止まっている時は、
+//! `hlt` 命令で次の割り込みまで CPU を休ませる。
+
+use super::{context, stack::Stack, ThreadId};
+use crate::memory::address_space::{self, AddressSpace};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// 同時に存在できるスレッドの最大数。
+pub const MAX_THREADS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum State {
+    /// 実行中。
+    Running,
+    /// run queue で順番を待っている。
+    Ready,
+    /// tick が `until` になるまで止まっている。
+    Sleeping { until: u64 },
+    /// 指定したスレッドの終了を待っている。
+    Joining(ThreadId),
+    /// 終了した。
+    Dead,
+}
+
+pub(super) struct Thread {
+    id: ThreadId,
+    state: State,
+    /// 切り替えられた時点のスタックポインタ。
+    rsp: u64,
+    /// boot thread は bootloader が用意したスタックを使うので `None` 。
+    stack: Option<Stack>,
+    /// スレッドで実行する関数。実行を始める時に取り出す。
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// user mode のプログラムのアドレス空間。kernel のスレッドは `None` 。
+    address_space: Option<Arc<AddressSpace>>,
+}
+
+impl Thread {
+    pub(super) fn new(
+        id: ThreadId,
+        stack: Stack,
+        entry: Box<dyn FnOnce() + Send>,
+        address_space: Option<Arc<AddressSpace>>,
+    ) -> Thread {
+        let rsp = unsafe { context::init_stack(stack.top(), thread_start) };
+        Thread {
+            id,
+            state: State::Ready,
+            rsp,
+            stack: Some(stack),
+            entry: Some(entry),
+            address_space,
+        }
+    }
+
+    fn boot() -> Thread {
+        Thread {
+            id: ThreadId::BOOT,
+            state: State::Running,
+            // 最初に切り替えられた時に保存される
+            rsp: 0,
+            stack: None,
+            entry: None,
+            address_space: None,
+        }
+    }
+}
+
+/// 実行可能なスレッドの ID を並べた固定長のリングバッファ。
+struct RunQueue {
+    ids: [ThreadId; MAX_THREADS],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        RunQueue {
+            ids: [ThreadId::BOOT; MAX_THREADS],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, id: ThreadId) {
+        assert!(self.len < MAX_THREADS, "run queue is full");
+        self.ids[(self.head + self.len) % MAX_THREADS] = id;
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<ThreadId> {
+        if self.len == 0 {
+            return None;
+        }
+        let id = self.ids[self.head];
+        self.head = (self.head + 1) % MAX_THREADS;
+        self.len -= 1;
+        Some(id)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+struct Scheduler {
+    /// `rsp` のアドレスが変わらないように、`Box` に入れておく。
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    run_queue: RunQueue,
+    current: ThreadId,
+}
+
+lazy_static! {
+    static ref SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+        threads: BTreeMap::new(),
+        run_queue: RunQueue::new(),
+        current: ThreadId::BOOT,
+    });
+}
+
+/// 割り込みを無効にして `SCHEDULER` を借用し、`f` を実行する。
+fn with_scheduler<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut Scheduler) -> R,
+{
+    interrupts::without_interrupts(|| f(&mut *SCHEDULER.lock()))
+}
+
+impl Scheduler {
+    /// 最初のスレッドを作成する時に、boot thread を登録する。
+    /// heap を使うので、`Scheduler` の作成時には登録できない。
+    fn register_boot_thread(&mut self) {
+        if self.threads.is_empty() {
+            self.threads
+                .insert(ThreadId::BOOT, Box::new(Thread::boot()));
+        }
+    }
+
+    /// 現在のスレッドが実行を続けられるか。
+    ///
+    /// まだスレッドが作成されていない場合は、boot thread が実行中であるとみなす。
+    fn current_is_running(&self) -> bool {
+        self.threads
+            .get(&self.current)
+            .map_or(true, |thread| thread.state == State::Running)
+    }
+
+    fn current_mut(&mut self) -> &mut Thread {
+        let current = self.current;
+        self.threads
+            .get_mut(&current)
+            .expect("current thread is not registered")
+    }
+
+    fn make_ready(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            thread.state = State::Ready;
+            self.run_queue.push(id);
+        }
+    }
+
+    fn is_finished(&self, id: ThreadId) -> bool {
+        self.threads
+            .get(&id)
+            .map_or(true, |thread| thread.state == State::Dead)
+    }
+
+    /// 条件を満たした止まっているスレッドを run queue に追加する。
+    fn wake_threads<P>(&mut self, predicate: P)
+    where
+        P: Fn(State) -> bool,
+    {
+        let Self {
+            threads, run_queue, ..
+        } = self;
+        for thread in threads.values_mut() {
+            if predicate(thread.state) {
+                thread.state = State::Ready;
+                run_queue.push(thread.id);
+            }
+        }
+    }
+
+    /// `next` に切り替える準備をし、`context::switch` に渡すスタックポインタを返す。
+    ///
+    /// 現在のスレッドが `Running` のままなら run queue の末尾に戻す。
+    /// `next` が現在のスレッドなら `None` を返す。
+    fn prepare_switch(&mut self, next: ThreadId) -> Option<(*mut u64, u64)> {
+        let current = self.current;
+        if next == current {
+            self.current_mut().state = State::Running;
+            return None;
+        }
+
+        if self.current_mut().state == State::Running {
+            self.make_ready(current);
+        }
+        let old_rsp = &mut self.current_mut().rsp as *mut u64;
+
+        let next_thread = self
+            .threads
+            .get_mut(&next)
+            .expect("scheduled thread is not registered");
+        next_thread.state = State::Running;
+        let new_rsp = next_thread.rsp;
+        // user mode から戻ってきた時は、切り替え先のスレッドのスタックを使う
+        unsafe {
+            crate::cpu::current().set_kernel_stack(next_thread.stack.as_ref().map(Stack::top));
+            address_space::activate(next_thread.address_space.as_ref().map(|space| &**space));
+        }
+        self.current = next;
+
+        Some((old_rsp, new_rsp))
+    }
+
+    /// 終了したスレッドを取り除く。
+    fn take_dead(&mut self) -> Vec<Box<Thread>> {
+        let current = self.current;
+        let dead: Vec<ThreadId> = self
+            .threads
+            .values()
+            .filter(|thread| thread.state == State::Dead && thread.id != current)
+            .map(|thread| thread.id)
+            .collect();
+        dead.into_iter()
+            .filter_map(|id| self.threads.remove(&id))
+            .collect()
+    }
+}
+
+/// 次に実行するスレッドに切り替える。
+///
+/// 現在のスレッドの状態は呼び出し側が先に変更しておく。
+/// 切り替えられたスレッドが再び実行されると、この関数から戻る。
+///
+/// 割り込みが無効な状態で呼び出すこと。
+fn schedule() {
+    loop {
+        let mut scheduler = SCHEDULER.lock();
+        let next = match scheduler.run_queue.pop() {
+            Some(next) => next,
+            None if scheduler.current_is_running() => return,
+            None => {
+                drop(scheduler);
+                // 割り込みでどれかのスレッドが起こされるまで待つ
+                crate::enable_interrupts_and_hlt();
+                interrupts::disable();
+                continue;
+            }
+        };
+
+        if let Some((old_rsp, new_rsp)) = scheduler.prepare_switch(next) {
+            // 切り替える前に lock を外す。割り込みは無効なので、
+            // 他のスレッドが `old_rsp` や `new_rsp` を変更することはない。
+            drop(scheduler);
+            unsafe {
+                context::switch(old_rsp, new_rsp);
+            }
+        }
+        return;
+    }
+}
+
+/// 新しいスレッドの開始関数。
+///
+/// `schedule` から切り替えられてくるので、割り込みは無効になっている。
+extern "C" fn thread_start() -> ! {
+    let entry = SCHEDULER
+        .lock()
+        .current_mut()
+        .entry
+        .take()
+        .expect("thread has no entry");
+    interrupts::enable();
+
+    entry();
+
+    exit()
+}
+
+/// 現在のスレッドを終了する。
+pub(super) fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current;
+        scheduler.current_mut().state = State::Dead;
+        scheduler.wake_threads(|state| state == State::Joining(current));
+    }
+    schedule();
+    unreachable!("dead thread was scheduled");
+}
+
+/// タイマー割り込みのたびに呼び出される。
+///
+/// sleep しているスレッドを起こし、他に実行可能なスレッドがあれば切り替える。
+pub(crate) fn on_tick(now: u64) {
+    let preempt = {
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.threads.is_empty() {
+            // まだスレッドが作成されていない
+            return;
+        }
+        scheduler.wake_threads(|state| match state {
+            State::Sleeping { until } => until <= now,
+            _ => false,
+        });
+        // 現在のスレッドが止まっている場合は、`schedule` の中で hlt している
+        scheduler.current_is_running() && !scheduler.run_queue.is_empty()
+    };
+    if preempt {
+        schedule();
+    }
+}
+
+pub(super) fn add(thread: Thread) {
+    with_scheduler(|scheduler| {
+        assert!(scheduler.threads.len() < MAX_THREADS, "too many threads");
+        scheduler.register_boot_thread();
+        let id = thread.id;
+        scheduler.threads.insert(id, Box::new(thread));
+        scheduler.run_queue.push(id);
+    });
+}
+
+pub(super) fn current() -> ThreadId {
+    with_scheduler(|scheduler| scheduler.current)
+}
+
+pub(super) fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+pub(super) fn sleep_until(until: u64) {
+    interrupts::without_interrupts(|| {
+        SCHEDULER.lock().register_boot_thread();
+        // 起こされた時点で tick が `until` に達していなければ、もう一度眠る
+        while crate::interrupts::ticks() < until {
+            SCHEDULER.lock().current_mut().state = State::Sleeping { until };
+            schedule();
+        }
+    });
+}
+
+pub(super) fn join(id: ThreadId) {
+    interrupts::without_interrupts(|| loop {
+        {
+            let mut scheduler = SCHEDULER.lock();
+            if scheduler.is_finished(id) {
+                break;
+            }
+            scheduler.current_mut().state = State::Joining(id);
+        }
+        schedule();
+    });
+}
+
+/// 終了したスレッドのスタックなどを解放する。
+///
+/// スタックの解放では `memory` の lock を取得するので、割り込みが有効な状態で行う。
+pub(super) fn reap() {
+    let dead = with_scheduler(Scheduler::take_dead);
+    drop(dead);
+}
@@ -0,0 +1,100 @@
+//! ## Thread Stack
+//!
+//! スレッドのスタックは、`STACKS_START` 以降の仮想アドレスに
+//! `SLOT_PAGES` ページずつの区画（slot）を並べて割り当てる。
+//!
+//! 各 slot の先頭1ページはマップしないでおく（guard page）。
+//! スタックは上位アドレスから下位アドレスに向かって伸びるので、
+//! スタックを使い切ると guard page にアクセスして Page Fault が発生する。
+//! guard page がないと、隣のスタックを黙って壊してしまう。
+//!
+//! スタックを使い切った状態では Page Fault のハンドラを呼び出すための
+//! stack frame も push できないので、実際には Double Fault になる。
+//! （`interrupts` モジュールの Double Fault の説明を参照）
+//!
+//! 解放された slot は再利用する。
+
+use crate::memory;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+/// スレッドのスタックを割り当てる仮想アドレスの先頭。
+/// heap などの他の領域と重ならない適当なアドレスを選んでいる。
+pub const STACKS_START: u64 = 0x_6666_0000_0000;
+/// 1つのスタックのページ数。(64 KiB)
+pub const STACK_PAGES: u64 = 16;
+/// guard page を含めた1つの slot のページ数。
+const SLOT_PAGES: u64 = STACK_PAGES + 1;
+
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// 解放された slot の番号。
+    static ref FREE_SLOTS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+}
+
+/// スレッドのスタック。drop するとページのマップを解除してフレームを解放する。
+#[derive(Debug)]
+pub struct Stack {
+    slot: u64,
+}
+
+impl Stack {
+    /// 新しいスタックを割り当てる。
+    pub fn allocate() -> Result<Stack, MapToError> {
+        let slot = FREE_SLOTS
+            .lock()
+            .pop()
+            .unwrap_or_else(|| NEXT_SLOT.fetch_add(1, Ordering::Relaxed));
+        // 途中で失敗した場合は、drop でマップ済みのページが解放される
+        let stack = Stack { slot };
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        for page in stack.pages() {
+            memory::map_new(page, flags)?;
+        }
+        Ok(stack)
+    }
+
+    /// guard page 。マップされていない。
+    pub fn guard_page(&self) -> Page {
+        let addr = STACKS_START + self.slot * SLOT_PAGES * Size4KiB::SIZE;
+        Page::containing_address(VirtAddr::new(addr))
+    }
+
+    /// スタックとして使えるページ。
+    pub fn pages(&self) -> impl Iterator<Item = Page> {
+        let start = self.guard_page() + 1;
+        Page::range(start, start + STACK_PAGES)
+    }
+
+    /// スタックの末尾（最初のスタックポインタ）。
+    pub fn top(&self) -> VirtAddr {
+        (self.guard_page() + SLOT_PAGES).start_address()
+    }
+
+    /// スタックの先頭。これより下は guard page になる。
+    pub fn bottom(&self) -> VirtAddr {
+        (self.guard_page() + 1).start_address()
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        for page in self.pages() {
+            memory::with(|memory| {
+                // 割り当てに失敗したスタックでは、マップされていないページもある
+                if let Ok(frame) = memory.unmap(page) {
+                    memory.deallocate_frame(frame);
+                }
+            });
+        }
+        FREE_SLOTS.lock().push(self.slot);
+    }
+}
@@ -0,0 +1,87 @@
+//! ## User Mode
+//!
+//! x86_64 の特権レベルは ring 0 から ring 3 まであり、
+//! kernel は ring 0、user のプログラムは ring 3 で動かす。
+//! ring 3 では特権命令（`hlt` や `cli` など）を実行できず、
+//! `USER_ACCESSIBLE` が立っていないページにもアクセスできない。
+//!
+//! ### ring 3 に入る
+//!
+//! ring 0 から ring 3 に移る命令は `iretq`（と `sysretq`）しかない。
+//! 割り込みから戻る時と同じ stack frame（SS, RSP, RFLAGS, CS, RIP）を自分で push し、
+//! CS と SS に RPL 3 の user セグメントを指定して `iretq` を実行すると、
+//! CPU は ring 3 で RIP から実行を始める。
+//!
+//! ### ring 0 に戻る
+//!
+//! ring 3 で割り込みや例外が発生すると、CPU は TSS の RSP0 のスタックに切り替えて
+//! ring 0 のハンドラを呼び出す。（`gdt::tss` モジュールを参照）
+//!
+//! ### プログラムを実行する
+//!
+//! `load` は ELF の実行ファイルを新しいアドレス空間にロードする。（`loader` モジュールを参照）
+//! ロードしたプログラムは `process::spawn` でプロセスとして実行する。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/Getting_to_Ring_3
+//! - Intel SDM Vol. 3A, 6.12.1 "Exception- or Interrupt-Handler Procedures"
+
+pub mod elf;
+mod loader;
+
+pub use self::loader::{load, LoadError, Program};
+
+use crate::cpu;
+use x86_64::VirtAddr;
+
+/// ring 3 に入る時の RFLAGS 。割り込みを有効にする。(bit 1 は常に 1)
+const USER_RFLAGS: u64 = 0x202;
+
+/// ring 3 に移り、`stack_top` をスタックとして `entry` から実行を始める。
+///
+/// kernel の値が見えないように、汎用レジスタは全て 0 にする。
+/// `iretq` の直前に `swapgs` して、user mode の GS base（0）に切り替える。（`cpu` モジュールを参照）
+///
+/// ## Safety
+/// `entry` と `stack_top` のページは `USER_ACCESSIBLE` でマップされている必要がある。
+/// 現在のスタックには戻ってこないので、drop されるべき値を持ったまま呼び出さないこと。
+pub unsafe fn enter_user_mode(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    let selectors = cpu::current().gdt().selectors();
+    let code_selector = u64::from(selectors.user_code_selector.0);
+    let data_selector = u64::from(selectors.user_data_selector.0);
+
+    asm!("
+        mov %ax, %ds
+        mov %ax, %es
+        push %rax
+        push $0
+        push $1
+        push $2
+        push $3
+        xor %eax, %eax
+        xor %ebx, %ebx
+        xor %ecx, %ecx
+        xor %edx, %edx
+        xor %esi, %esi
+        xor %edi, %edi
+        xor %ebp, %ebp
+        xor %r8d, %r8d
+        xor %r9d, %r9d
+        xor %r10d, %r10d
+        xor %r11d, %r11d
+        xor %r12d, %r12d
+        xor %r13d, %r13d
+        xor %r14d, %r14d
+        xor %r15d, %r15d
+        cli
+        swapgs
+        iretq
+        "
+        :
+        : "r"(stack_top.as_u64()), "r"(USER_RFLAGS), "r"(code_selector), "r"(entry.as_u64()),
+          "{rax}"(data_selector)
+        : "memory"
+        : "volatile"
+    );
+    unreachable!("iretq returned");
+}
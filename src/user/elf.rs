@@ -0,0 +1,239 @@
+//! ## ELF
+//!
+//! ELF64 の実行ファイルのヘッダを読む。
+//!
+//! 実行に必要なのは ELF header と program header だけである。
+//! section header（シンボルやデバッグ情報）は読まない。
+//! 静的にリンクされた x86_64 の実行ファイル（`ET_EXEC`）だけをサポートする。
+//!
+//! データは `include_bytes!` で埋め込んだものでもよいように、
+//! アラインメントを仮定せずにバイト列から値を組み立てる。
+//!
+//! ### 参照
+//! - https://wiki.osdev.org/ELF
+//! - System V ABI, "Object Files"
+
+use core::convert::TryInto;
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_X86_64: u16 = 62;
+
+/// ELF header の大きさ。
+const HEADER_SIZE: usize = 64;
+/// program header の大きさ。
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// `PT_LOAD` : メモリにロードするセグメント。
+pub const PT_LOAD: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// ヘッダを読むのに必要な長さがない。
+    TooShort,
+    /// 先頭が `\x7fELF` ではない。
+    BadMagic,
+    /// 64-bit の little endian ではない。
+    UnsupportedClass,
+    /// ELF のバージョンが 1 ではない。
+    BadVersion,
+    /// 実行ファイル（`ET_EXEC`）ではない。
+    NotExecutable,
+    /// x86_64 用ではない。
+    UnsupportedMachine,
+    /// program header の大きさや位置が不正。
+    BadProgramHeader,
+    /// セグメントの大きさや位置が不正。
+    BadSegment,
+}
+
+/// セグメントの権限を表す `ProgramHeader::flags` のビット。
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+/// program header 。
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    /// `PF_X`, `PF_W`, `PF_R` の組み合わせ。
+    pub flags: u32,
+    /// ファイル中のデータの位置。
+    pub offset: u64,
+    /// ロードする仮想アドレス。
+    pub vaddr: u64,
+    /// ファイル中のデータの大きさ。
+    pub file_size: u64,
+    /// メモリ上の大きさ。`file_size` より後ろ（BSS）は 0 で埋める。
+    pub mem_size: u64,
+}
+
+/// 検証済みの ELF の実行ファイル。
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    program_header_offset: usize,
+    program_header_count: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// ヘッダと program header を検証する。
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != CLASS_64 || data[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::UnsupportedClass);
+        }
+        if data[6] != VERSION_CURRENT || read_u32(data, 20) != u32::from(VERSION_CURRENT) {
+            return Err(ElfError::BadVersion);
+        }
+        if read_u16(data, 16) != TYPE_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18) != MACHINE_X86_64 {
+            return Err(ElfError::UnsupportedMachine);
+        }
+
+        let program_header_offset = read_u64(data, 32) as usize;
+        let program_header_size = usize::from(read_u16(data, 54));
+        let program_header_count = usize::from(read_u16(data, 56));
+        let end = program_header_count
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(program_header_offset));
+        match end {
+            Some(end) if end <= data.len() && program_header_size == PROGRAM_HEADER_SIZE => {}
+            _ => return Err(ElfError::BadProgramHeader),
+        }
+
+        let elf = Elf {
+            data,
+            entry: read_u64(data, 24),
+            program_header_offset,
+            program_header_count,
+        };
+        for header in elf.program_headers() {
+            let file_end = header.offset.checked_add(header.file_size);
+            let valid = header.file_size <= header.mem_size
+                && file_end.map_or(false, |end| end <= data.len() as u64)
+                && header.vaddr.checked_add(header.mem_size).is_some();
+            if !valid {
+                return Err(ElfError::BadSegment);
+            }
+        }
+        Ok(elf)
+    }
+
+    /// 実行を始めるアドレス。
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let offset = self.program_header_offset;
+        (0..self.program_header_count).map(move |i| {
+            let base = offset + i * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                kind: read_u32(data, base),
+                flags: read_u32(data, base + 4),
+                offset: read_u64(data, base + 8),
+                vaddr: read_u64(data, base + 16),
+                file_size: read_u64(data, base + 32),
+                mem_size: read_u64(data, base + 40),
+            }
+        })
+    }
+
+    /// `PT_LOAD` のセグメント。
+    pub fn load_segments(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        self.program_headers()
+            .filter(|header| header.kind == PT_LOAD)
+    }
+
+    /// セグメントのファイル中のデータ。
+    pub fn segment_data(&self, header: &ProgramHeader) -> &'a [u8] {
+        let start = header.offset as usize;
+        &self.data[start..start + header.file_size as usize]
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{serial_print, serial_println};
+
+    /// ヘッダだけの ELF を作る。
+    fn header() -> [u8; HEADER_SIZE] {
+        let mut data = [0; HEADER_SIZE];
+        data[0..4].copy_from_slice(&MAGIC);
+        data[4] = CLASS_64;
+        data[5] = DATA_LITTLE_ENDIAN;
+        data[6] = VERSION_CURRENT;
+        data[16..18].copy_from_slice(&TYPE_EXEC.to_le_bytes());
+        data[18..20].copy_from_slice(&MACHINE_X86_64.to_le_bytes());
+        data[20..24].copy_from_slice(&1u32.to_le_bytes());
+        data[24..32].copy_from_slice(&0x1234u64.to_le_bytes());
+        data[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        data
+    }
+
+    #[test_case]
+    fn test_parse_header() {
+        serial_print!("test_parse_header... ");
+        let data = header();
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.entry(), 0x1234);
+        assert_eq!(elf.program_headers().count(), 0);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_parse_errors() {
+        serial_print!("test_parse_errors... ");
+        let data = header();
+        assert_eq!(Elf::parse(&data[..32]).err(), Some(ElfError::TooShort));
+
+        let mut bad = data;
+        bad[0] = 0;
+        assert_eq!(Elf::parse(&bad).err(), Some(ElfError::BadMagic));
+
+        let mut bad = data;
+        bad[4] = 1;
+        assert_eq!(Elf::parse(&bad).err(), Some(ElfError::UnsupportedClass));
+
+        let mut bad = data;
+        bad[16] = 3;
+        assert_eq!(Elf::parse(&bad).err(), Some(ElfError::NotExecutable));
+
+        let mut bad = data;
+        bad[18] = 3;
+        assert_eq!(Elf::parse(&bad).err(), Some(ElfError::UnsupportedMachine));
+
+        // program header がファイルの外にある
+        let mut bad = data;
+        bad[32] = HEADER_SIZE as u8;
+        bad[56] = 1;
+        assert_eq!(Elf::parse(&bad).err(), Some(ElfError::BadProgramHeader));
+        serial_println!("[ok]");
+    }
+}
@@ -0,0 +1,249 @@
+//! ## Loader
+//!
+//! ELF の実行ファイルを新しいアドレス空間にロードし、スタックを用意する。
+//! ring 3 で実行するスレッドは `process::spawn` が作る。
+//!
+//! ### セグメント
+//!
+//! `PT_LOAD` のセグメントをページ単位でマップし、ファイル中のデータをコピーする。
+//! マップするフレームは 0 で埋めてあるので、BSS（`file_size` より後ろ）は 0 になる。
+//! ページの権限はセグメントのフラグに合わせ、`PF_W` がなければ書き込み禁止、
+//! `PF_X` がなければ実行禁止（`NO_EXECUTE`）にする。
+//! 2つのセグメントが同じページを共有している場合はロードできない。
+//!
+//! ### スタック
+//!
+//! System V ABI に従って、スタックに次のように並べる。
+//!
+//! | アドレス | 内容                               |
+//! |----------|------------------------------------|
+//! | RSP      | argc                               |
+//! | RSP + 8  | argv[0] .. argv[argc - 1], NULL    |
+//! |          | envp[0] .. , NULL                  |
+//! |          | auxv の (type, value) .. , AT_NULL |
+//! |          | argv と envp の文字列              |
+//!
+//! RSP は 16 バイトに揃える。
+//!
+//! ### 参照
+//! - System V ABI AMD64 Architecture Processor Supplement, 3.4 "Process Initialization"
+
+use super::elf::{self, Elf, ElfError, ProgramHeader};
+use crate::memory::address_space::{self, AddressSpace, USER_END, USER_START};
+use alloc::vec::Vec;
+use core::ptr;
+use x86_64::{
+    structures::paging::{mapper::MapToError, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+/// スタックの一番上のアドレス。
+const STACK_TOP: u64 = USER_END;
+/// スタックのページ数。(64 KiB)
+const STACK_PAGES: u64 = 16;
+
+/// auxv の種類。
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Elf(ElfError),
+    Map(MapToError),
+    /// セグメントかエントリーポイントが `USER_START..USER_END` の外にある。
+    OutOfRange,
+    /// 引数と環境変数がスタックに収まらない。
+    ArgumentsTooLong,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> Self {
+        LoadError::Elf(err)
+    }
+}
+
+impl From<MapToError> for LoadError {
+    fn from(err: MapToError) -> Self {
+        LoadError::Map(err)
+    }
+}
+
+/// ロードしたプログラム。`enter_user_mode(entry, stack_top)` で実行を始める。
+#[derive(Debug)]
+pub struct Program {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    pub stack_top: VirtAddr,
+}
+
+/// ELF の実行ファイル `program` を新しいアドレス空間にロードする。
+///
+/// `args` と `env` はスタックにコピーし、`argv` と `envp` として渡す。
+pub fn load(program: &[u8], args: &[&str], env: &[&str]) -> Result<Program, LoadError> {
+    let elf = Elf::parse(program)?;
+    if !is_user_range(elf.entry(), 1) {
+        return Err(LoadError::OutOfRange);
+    }
+
+    // 途中で失敗した場合は、drop でマップしたフレームが解放される
+    let mut address_space = AddressSpace::new()?;
+    for header in elf.load_segments() {
+        load_segment(&mut address_space, &elf, &header)?;
+    }
+    let stack_top = setup_stack(&mut address_space, elf.entry(), args, env)?;
+
+    Ok(Program {
+        address_space,
+        entry: VirtAddr::new(elf.entry()),
+        stack_top,
+    })
+}
+
+/// `start` から `len` バイトが `USER_START..USER_END` に収まっているか。
+fn is_user_range(start: u64, len: u64) -> bool {
+    start >= USER_START && start.checked_add(len).map_or(false, |end| end <= USER_END)
+}
+
+/// セグメントをマップし、ファイル中のデータをコピーする。
+fn load_segment(
+    space: &mut AddressSpace,
+    elf: &Elf,
+    header: &ProgramHeader,
+) -> Result<(), LoadError> {
+    if header.mem_size == 0 {
+        return Ok(());
+    }
+    if !is_user_range(header.vaddr, header.mem_size) {
+        return Err(LoadError::OutOfRange);
+    }
+
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if header.flags & elf::PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if header.flags & elf::PF_X == 0 {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    let data = elf.segment_data(header);
+    let file_end = header.vaddr + header.file_size;
+    let first_page = Page::containing_address(VirtAddr::new(header.vaddr));
+    let last_page = Page::containing_address(VirtAddr::new(header.vaddr + header.mem_size - 1));
+    for page in Page::range_inclusive(first_page, last_page) {
+        let frame = space.map_new(page, flags)?;
+
+        // このページに含まれる部分だけをコピーする
+        let page_start = page.start_address().as_u64();
+        let copy_start = page_start.max(header.vaddr);
+        let copy_end = (page_start + Size4KiB::SIZE).min(file_end);
+        if copy_start < copy_end {
+            let src =
+                &data[(copy_start - header.vaddr) as usize..(copy_end - header.vaddr) as usize];
+            let dst = address_space::frame_to_virt(frame) + (copy_start - page_start);
+            unsafe {
+                ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr(), src.len());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// スタックをマップして argc, argv, envp, auxv を置き、RSP の初期値を返す。
+fn setup_stack(
+    space: &mut AddressSpace,
+    entry: u64,
+    args: &[&str],
+    env: &[&str],
+) -> Result<VirtAddr, LoadError> {
+    let bottom = STACK_TOP - STACK_PAGES * Size4KiB::SIZE;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    let first_page = Page::containing_address(VirtAddr::new(bottom));
+    let frames = (0..STACK_PAGES)
+        .map(|i| space.map_new(first_page + i, flags))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut stack = StackWriter {
+        frames,
+        bottom,
+        sp: STACK_TOP,
+    };
+
+    let arg_ptrs = args
+        .iter()
+        .map(|arg| stack.push_str(arg))
+        .collect::<Result<Vec<_>, _>>()?;
+    let env_ptrs = env
+        .iter()
+        .map(|var| stack.push_str(var))
+        .collect::<Result<Vec<_>, _>>()?;
+    let auxv = [(AT_PAGESZ, Size4KiB::SIZE), (AT_ENTRY, entry), (AT_NULL, 0)];
+
+    // argc から auxv までを積んだ後の RSP が 16 バイトに揃うようにする
+    stack.align_down(16);
+    let words = 1 + (args.len() + 1) + (env.len() + 1) + auxv.len() * 2;
+    if words % 2 == 1 {
+        stack.push_u64(0)?;
+    }
+
+    for &(kind, value) in auxv.iter().rev() {
+        stack.push_u64(value)?;
+        stack.push_u64(kind)?;
+    }
+    stack.push_u64(0)?;
+    for &ptr in env_ptrs.iter().rev() {
+        stack.push_u64(ptr)?;
+    }
+    stack.push_u64(0)?;
+    for &ptr in arg_ptrs.iter().rev() {
+        stack.push_u64(ptr)?;
+    }
+    let sp = stack.push_u64(args.len() as u64)?;
+    debug_assert_eq!(sp % 16, 0);
+
+    Ok(VirtAddr::new(sp))
+}
+
+/// 有効でないアドレス空間のスタックに、上から値を積む。
+///
+/// `frames` は `bottom` から順にマップしたフレーム。
+struct StackWriter {
+    frames: Vec<PhysFrame>,
+    bottom: u64,
+    sp: u64,
+}
+
+impl StackWriter {
+    /// `bytes` を積み、その先頭の（user の）アドレスを返す。
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<u64, LoadError> {
+        let sp = self
+            .sp
+            .checked_sub(bytes.len() as u64)
+            .filter(|&sp| sp >= self.bottom)
+            .ok_or(LoadError::ArgumentsTooLong)?;
+        for (i, &byte) in bytes.iter().enumerate() {
+            let offset = sp + i as u64 - self.bottom;
+            let frame = self.frames[(offset / Size4KiB::SIZE) as usize];
+            let addr = address_space::frame_to_virt(frame) + offset % Size4KiB::SIZE;
+            unsafe { addr.as_mut_ptr::<u8>().write(byte) };
+        }
+        self.sp = sp;
+        Ok(sp)
+    }
+
+    fn push_u64(&mut self, value: u64) -> Result<u64, LoadError> {
+        self.push_bytes(&value.to_le_bytes())
+    }
+
+    /// NUL 終端した文字列を積む。
+    fn push_str(&mut self, s: &str) -> Result<u64, LoadError> {
+        self.push_bytes(&[0])?;
+        self.push_bytes(s.as_bytes())
+    }
+
+    fn align_down(&mut self, align: u64) {
+        self.sp &= !(align - 1);
+    }
+}
@@ -1,6 +1,37 @@
+//! ## VGA Text Mode
+//!
+//! 物理アドレス `0xb8000` にある 80x25 の text buffer に文字を書き込んで画面に表示する。
+//! 各文字は ASCII コードと色の 2 バイトで表される。
+//!
+//! ### Scrollback
+//!
+//! 画面に表示している行も含めて、最近の `SCROLLBACK_LINES` 行を ring buffer に残しておく。
+//! 文字は ring buffer に書き込み、画面を表示している時だけ text buffer にも書き込む。
+//! `scroll_up` で過去の行を表示でき、何か出力すると最新の画面に戻る。
+//! `clear` は画面を空にするが、それまでの行は scrollback に残る。
+//!
+//! ### 制御文字
+//!
+//! - `\n` : 次の行の先頭に移る。
+//! - `\r` : 行の先頭に戻る。
+//! - `\t` : 次の `TAB_WIDTH` の倍数の列まで空白を書き込む。
+//! - `\x08` (backspace) : 1文字戻って消す。行の先頭より前には戻らない。
+//!
+//! ### カーソル
+//!
+//! ハードウェアのカーソルは CRTC（CRT controller）のレジスタで動かす。
+//! port `0x3D4` にレジスタの番号を書き込み、port `0x3D5` で値を読み書きする。
+//! カーソルの位置（`row * 80 + column`）の上位バイトは `0x0E` 、下位バイトは `0x0F` に書き込む。
+//! 過去の行を表示している間は、画面の外に動かして隠す。
+//!
+//! ### 参照
+//! - https://os.phil-opp.com/vga-text-mode/
+//! - https://wiki.osdev.org/Text_Mode_Cursor
+
+use crate::sync::IrqSafeMutex;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
+use x86_64::instructions::port::Port;
 
 #[macro_export]
 macro_rules! print {
@@ -13,28 +44,89 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
-lazy_static! {
-    static ref WRITER: Mutex<Writer> =
-        Mutex::new(Writer::new(ColorCode::new(Color::Yellow, Color::Black)));
-}
+/// 画面に表示している行も含めて、残しておく行の数。
+const SCROLLBACK_LINES: usize = 500;
+/// タブで揃える列の間隔。
+const TAB_WIDTH: usize = 8;
+
+/// CRTC のレジスタの番号を書き込む port 。
+const CRTC_ADDRESS_PORT: u16 = 0x3d4;
+/// CRTC のレジスタの値を読み書きする port 。
+const CRTC_DATA_PORT: u16 = 0x3d5;
+/// カーソルの位置の上位バイトのレジスタ。
+const CRTC_CURSOR_HIGH: u8 = 0x0e;
+/// カーソルの位置の下位バイトのレジスタ。
+const CRTC_CURSOR_LOW: u8 = 0x0f;
+
+/// 割り込みハンドラからも print できるように、`IrqSafeMutex` を使う。
+///
+/// scrollback が大きく、`lazy_static` で作るとスタックに載ってしまうので、const で初期化する。
+static WRITER: IrqSafeMutex<Writer> =
+    IrqSafeMutex::new(Writer::new(ColorCode::new(Color::Yellow, Color::Black)));
 
 /// Write a string to the VGA buffer.
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
     use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+    let mut writer = WRITER.lock();
+    writer.write_fmt(args).unwrap();
+    writer.update_cursor();
+}
+
+/// 画面を空にし、カーソルを左上に動かす。画面にあった行は scrollback に残る。
+pub fn clear() {
+    let mut writer = WRITER.lock();
+    writer.clear();
+    writer.update_cursor();
+}
+
+/// `lines` 行前の出力を表示する。残っている行より前には戻らない。
+pub fn scroll_up(lines: usize) {
+    let mut writer = WRITER.lock();
+    writer.sync();
+    writer.view = (writer.view + lines).min(writer.history);
+    writer.render();
+    writer.update_cursor();
+}
+
+/// `lines` 行後の出力を表示する。最新の画面より後には進まない。
+pub fn scroll_down(lines: usize) {
+    let mut writer = WRITER.lock();
+    writer.sync();
+    writer.view = writer.view.saturating_sub(lines);
+    writer.render();
+    writer.update_cursor();
 }
 
 struct Writer {
+    /// 出力した行の ring buffer 。最新の `BUF_HEIGHT` 行が画面に対応する。
+    lines: [[ScreenChar; BUF_WIDTH]; SCROLLBACK_LINES],
+    /// 画面の一番下の行の `lines` のインデックス。
+    bottom: usize,
+    /// 画面より前に残っている行の数。
+    history: usize,
+    /// 何行前を表示しているか。0 なら最新の画面を表示している。
+    view: usize,
+    /// カーソルの行。
+    row: usize,
+    /// カーソルの列。行の末尾まで書くと `BUF_WIDTH` になり、次の文字で改行する。
     column_position: usize,
     color_code: ColorCode,
+    /// bootloader などが書いた画面の内容を `lines` に読み込んだか。
+    synced: bool,
 }
 
 impl core::fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.sync();
+        if self.view != 0 {
+            // 過去の行を表示していれば、最新の画面に戻る
+            self.view = 0;
+            self.render();
+        }
         for byte in s.bytes() {
             match byte {
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                0x20..=0x7e | b'\n' | b'\r' | b'\t' | 0x08 => self.write_byte(byte),
                 _ => self.write_byte(0xfe),
             }
         }
@@ -43,61 +135,165 @@ impl core::fmt::Write for Writer {
 }
 
 impl Writer {
-    fn new(color_code: ColorCode) -> Writer {
+    const fn new(color_code: ColorCode) -> Writer {
         Writer {
+            lines: [[ScreenChar::NULL; BUF_WIDTH]; SCROLLBACK_LINES],
+            bottom: BUF_HEIGHT - 1,
+            history: 0,
+            view: 0,
+            // 起動時の画面の続きに書けるように、一番下の行から始める
+            row: BUF_HEIGHT - 1,
             column_position: 0,
             color_code,
+            synced: false,
         }
     }
 
+    /// 初めて使う時に、画面に表示されている内容を `lines` に読み込む。
+    fn sync(&mut self) {
+        if self.synced {
+            return;
+        }
+        let buffer = VGA_BUFFER.lock();
+        for row in 0..BUF_HEIGHT {
+            let line = self.line_index(row);
+            for col in 0..BUF_WIDTH {
+                self.lines[line][col] = buffer[row][col].read();
+            }
+        }
+        self.synced = true;
+    }
+
     fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            b'\r' => self.column_position = 0,
+            b'\t' => {
+                if self.column_position >= BUF_WIDTH {
+                    self.new_line();
+                }
+                let next = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column_position < next.min(BUF_WIDTH) {
+                    self.put(b' ');
+                }
+            }
+            0x08 => {
+                if self.column_position > 0 {
+                    self.column_position = self.column_position.min(BUF_WIDTH) - 1;
+                    let col = self.column_position;
+                    self.set_char(self.row, col, self.blank());
+                }
+            }
             byte => {
                 if self.column_position >= BUF_WIDTH {
                     self.new_line();
                 }
-                let row = BUF_HEIGHT - 1;
-                let col = self.column_position;
-                let screen_char = ScreenChar {
-                    ascii_char: byte,
-                    color_code: self.color_code,
-                };
-                write_char(screen_char, row, col);
-
-                self.column_position += 1;
+                self.put(byte);
             }
         }
     }
 
+    /// カーソルの位置に `byte` を書き込み、カーソルを進める。
+    fn put(&mut self, byte: u8) {
+        let screen_char = ScreenChar {
+            ascii_char: byte,
+            color_code: self.color_code,
+        };
+        self.set_char(self.row, self.column_position, screen_char);
+        self.column_position += 1;
+    }
+
     fn new_line(&mut self) {
-        for row in 1..BUF_HEIGHT {
+        self.column_position = 0;
+        if self.row < BUF_HEIGHT - 1 {
+            self.row += 1;
+        } else {
+            self.push_lines(1);
+            self.render();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.sync();
+        // カーソルより下の行は空なので、カーソルの行までを scrollback に送る
+        self.push_lines(self.row + 1);
+        self.row = 0;
+        self.column_position = 0;
+        self.view = 0;
+        self.render();
+    }
+
+    /// 画面を `count` 行分スクロールさせ、下に空の行を追加する。
+    fn push_lines(&mut self, count: usize) {
+        for _ in 0..count {
+            self.bottom = (self.bottom + 1) % SCROLLBACK_LINES;
+            self.lines[self.bottom] = [self.blank(); BUF_WIDTH];
+        }
+        self.history = (self.history + count).min(SCROLLBACK_LINES - BUF_HEIGHT);
+    }
+
+    /// 画面の `row` 行目に対応する `lines` のインデックス。
+    fn line_index(&self, row: usize) -> usize {
+        (self.bottom + SCROLLBACK_LINES - (BUF_HEIGHT - 1 - row)) % SCROLLBACK_LINES
+    }
+
+    /// 画面の `row` 行目の `col` 列目の文字を書き換える。
+    fn set_char(&mut self, row: usize, col: usize, screen_char: ScreenChar) {
+        let line = self.line_index(row);
+        self.lines[line][col] = screen_char;
+        if self.view == 0 {
+            write_char(screen_char, row, col);
+        }
+    }
+
+    /// `view` 行前の内容を text buffer に書き込む。
+    fn render(&self) {
+        let mut buffer = VGA_BUFFER.lock();
+        for row in 0..BUF_HEIGHT {
+            let line = (self.line_index(row) + SCROLLBACK_LINES - self.view) % SCROLLBACK_LINES;
             for col in 0..BUF_WIDTH {
-                let c = read_char(row, col);
-                write_char(c, row - 1, col);
+                buffer[row][col].write(self.lines[line][col]);
             }
         }
-        self.clear_row(BUF_HEIGHT - 1);
-        self.column_position = 0;
     }
 
-    fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
+    /// ハードウェアのカーソルを動かす。
+    fn update_cursor(&self) {
+        let position = if self.view == 0 {
+            self.row * BUF_WIDTH + self.column_position.min(BUF_WIDTH - 1)
+        } else {
+            // 画面の外に動かすと表示されない
+            BUF_HEIGHT * BUF_WIDTH
+        };
+        set_cursor(position as u16);
+    }
+
+    fn blank(&self) -> ScreenChar {
+        ScreenChar {
             ascii_char: b' ',
             color_code: self.color_code,
-        };
-        for col in 0..BUF_WIDTH {
-            write_char(blank, row, col);
         }
     }
 }
 
+/// CRTC のカーソルの位置を `position` にする。
+fn set_cursor(position: u16) {
+    let mut address = Port::<u8>::new(CRTC_ADDRESS_PORT);
+    let mut data = Port::<u8>::new(CRTC_DATA_PORT);
+    unsafe {
+        address.write(CRTC_CURSOR_HIGH);
+        data.write((position >> 8) as u8);
+        address.write(CRTC_CURSOR_LOW);
+        data.write(position as u8);
+    }
+}
+
 const BUF_HEIGHT: usize = 25;
 const BUF_WIDTH: usize = 80;
 
 lazy_static! {
-    static ref VGA_BUFFER: Mutex<&'static mut [[Volatile<ScreenChar>; BUF_WIDTH]; BUF_HEIGHT]> =
-        Mutex::new(unsafe { &mut *(0xb8000 as *mut _) });
+    static ref VGA_BUFFER: IrqSafeMutex<&'static mut [[Volatile<ScreenChar>; BUF_WIDTH]; BUF_HEIGHT]> =
+        IrqSafeMutex::new(unsafe { &mut *(0xb8000 as *mut _) });
 }
 
 /// Write a character to the VGA buffer.
@@ -117,6 +313,14 @@ pub struct ScreenChar {
     color_code: ColorCode,
 }
 
+impl ScreenChar {
+    /// まだ何も書き込まれていないことを表す値。画面には何も表示されない。
+    const NULL: ScreenChar = ScreenChar {
+        ascii_char: 0,
+        color_code: ColorCode(0),
+    };
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct ColorCode(u8);
@@ -144,7 +348,7 @@ pub enum Color {
 }
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((foreground as u8) | (background as u8) << 4)
     }
 }
@@ -154,6 +358,28 @@ mod tests {
     use super::*;
     use crate::{serial_print, serial_println};
 
+    /// 画面の `row` 行目の先頭 `len` 文字。
+    fn read_row(row: usize, len: usize) -> [u8; BUF_WIDTH] {
+        let mut chars = [0; BUF_WIDTH];
+        for (col, c) in chars.iter_mut().enumerate().take(len) {
+            *c = read_char(row, col).ascii_char;
+        }
+        chars
+    }
+
+    /// CRTC からカーソルの位置を読む。
+    fn cursor() -> u16 {
+        let mut address = Port::<u8>::new(CRTC_ADDRESS_PORT);
+        let mut data = Port::<u8>::new(CRTC_DATA_PORT);
+        unsafe {
+            address.write(CRTC_CURSOR_HIGH);
+            let high = data.read();
+            address.write(CRTC_CURSOR_LOW);
+            let low = data.read();
+            u16::from(high) << 8 | u16::from(low)
+        }
+    }
+
     #[test_case]
     fn test_println_simple() {
         serial_print!("test_println_simple... ");
@@ -183,4 +409,61 @@ mod tests {
 
         serial_println!("[ok]");
     }
+
+    #[test_case]
+    fn test_clear_and_cursor() {
+        serial_print!("test_clear_and_cursor... ");
+        clear();
+        assert_eq!(cursor(), 0);
+        assert_eq!(&read_row(BUF_HEIGHT - 1, 4)[..4], b"    ");
+        print!("abc");
+        assert_eq!(&read_row(0, 3)[..3], b"abc");
+        assert_eq!(cursor(), 3);
+        println!();
+        assert_eq!(cursor(), BUF_WIDTH as u16);
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_control_characters() {
+        serial_print!("test_control_characters... ");
+        clear();
+        print!("ab\tc\rX\x08Y");
+        assert_eq!(&read_row(0, 9)[..9], b"Yb      c");
+        assert_eq!(cursor(), 1);
+        // 行の先頭より前には戻らない
+        print!("\r\x08\x08Z");
+        assert_eq!(&read_row(0, 2)[..2], b"Zb");
+        serial_println!("[ok]");
+    }
+
+    #[test_case]
+    fn test_scrollback() {
+        serial_print!("test_scrollback... ");
+        clear();
+        for i in 0..100 {
+            println!("line {:02}", i);
+        }
+        assert_eq!(&read_row(BUF_HEIGHT - 2, 7)[..7], b"line 99");
+
+        scroll_up(30);
+        assert_eq!(&read_row(BUF_HEIGHT - 2, 7)[..7], b"line 69");
+        assert_eq!(cursor(), (BUF_HEIGHT * BUF_WIDTH) as u16);
+        scroll_down(10);
+        assert_eq!(&read_row(BUF_HEIGHT - 2, 7)[..7], b"line 79");
+
+        // 何か出力すると最新の画面に戻る
+        print!("x");
+        assert_eq!(&read_row(BUF_HEIGHT - 2, 7)[..7], b"line 99");
+        assert_eq!(&read_row(BUF_HEIGHT - 1, 1)[..1], b"x");
+
+        // 残っている行より前には戻らない
+        scroll_up(SCROLLBACK_LINES * 2);
+        scroll_down(0);
+        assert!(WRITER.lock().view <= SCROLLBACK_LINES - BUF_HEIGHT);
+        scroll_down(SCROLLBACK_LINES * 2);
+        assert_eq!(&read_row(BUF_HEIGHT - 2, 7)[..7], b"line 99");
+        println!();
+        serial_println!("[ok]");
+    }
 }
@@ -0,0 +1,190 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(atomix::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use atomix::{acpi, memory, serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    atomix::init();
+    atomix::memory::init(boot_info);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+    acpi::init().expect("ACPI initialization failed");
+
+    test_main();
+    atomix::hlt_loop();
+}
+
+fn has_table(signature: &[u8; 4]) -> bool {
+    acpi::tables()
+        .unwrap()
+        .addresses
+        .iter()
+        .any(|&addr| &acpi::header(addr).signature == signature)
+}
+
+#[test_case]
+fn tables() {
+    serial_print!("tables... ");
+    // QEMU のデフォルトの ACPI テーブル
+    assert!(has_table(b"FACP"));
+    assert!(has_table(b"APIC"));
+    assert!(has_table(b"HPET"));
+    // 全てのテーブルの checksum が正しい
+    for &addr in &acpi::tables().unwrap().addresses {
+        let signature = acpi::header(addr).signature;
+        assert_eq!(acpi::find_table(&signature), Ok(addr));
+    }
+    assert_eq!(
+        acpi::find_table(b"NONE"),
+        Err(acpi::AcpiError::TableNotFound(*b"NONE"))
+    );
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn madt() {
+    serial_print!("madt... ");
+    let madt = acpi::madt().unwrap();
+    assert_eq!(madt.local_apic_address.as_u64(), 0xfee0_0000);
+    assert!(madt.flags & acpi::madt::PCAT_COMPAT != 0);
+    assert!(madt.processors.iter().any(|processor| processor.enabled));
+
+    assert_eq!(madt.io_apics.len(), 1);
+    assert_eq!(madt.io_apics[0].address.as_u64(), 0xfec0_0000);
+    assert_eq!(madt.io_apics[0].gsi_base, 0);
+
+    // QEMU では PIT の IRQ 0 が GSI 2 に接続されている
+    let (gsi, _, _) = madt.isa_irq(0);
+    assert_eq!(gsi, 2);
+    // override がない IRQ はそのまま
+    let (gsi, _, _) = madt.isa_irq(1);
+    assert_eq!(gsi, 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn fadt() {
+    serial_print!("fadt... ");
+    let fadt = acpi::fadt().unwrap();
+    assert_ne!(fadt.dsdt.as_u64(), 0);
+    assert_eq!(&acpi::header(fadt.dsdt).signature, b"DSDT");
+    assert_ne!(fadt.pm1a_control_block, 0);
+    assert_eq!(fadt.sci_interrupt, 9);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn s5_sleep_type() {
+    serial_print!("s5_sleep_type... ");
+    // QEMU の DSDT には `\_S5` が定義されている
+    let sleep_type = acpi::s5_sleep_type().unwrap();
+    // SLP_TYP は 3 bit
+    assert!(sleep_type.a < 8);
+    assert!(sleep_type.b < 8);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn hpet() {
+    serial_print!("hpet... ");
+    let hpet = acpi::hpet().unwrap();
+    let base = hpet.base_address;
+    let address = base.address;
+    assert_eq!(base.address_space, acpi::address_space::SYSTEM_MEMORY);
+    assert_eq!(address, 0xfed0_0000);
+    assert!(hpet.comparators >= 3);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn mcfg() {
+    serial_print!("mcfg... ");
+    // i440FX には PCI Express がないので MCFG はない。q35 ならある。
+    match acpi::mcfg() {
+        Ok(mcfg) => assert!(mcfg
+            .entries
+            .iter()
+            .all(|entry| entry.start_bus <= entry.end_bus)),
+        Err(err) => assert_eq!(err, acpi::AcpiError::TableNotFound(*b"MCFG")),
+    }
+    serial_println!("[ok]");
+}
+
+/// 偽の RSDP やテーブルを置くための領域。ページをまたがないように揃えておく。
+#[repr(align(64))]
+struct Buffer([u8; 64]);
+
+impl Buffer {
+    fn new() -> Box<Buffer> {
+        Box::new(Buffer([0; 64]))
+    }
+
+    fn phys_addr(&self) -> PhysAddr {
+        memory::translate(VirtAddr::from_ptr(&self.0)).unwrap()
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// `0..len` の合計が 0 になるように `self.0[at]` を設定する。
+    fn fix_checksum(&mut self, at: usize, len: usize) {
+        self.0[at] = 0;
+        let sum = self.0[..len]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        self.0[at] = sum.wrapping_neg();
+    }
+}
+
+#[test_case]
+fn oversized_lengths() {
+    serial_print!("oversized_lengths... ");
+    // revision 2 の RSDP の `length` が大きすぎる
+    let mut rsdp = Buffer::new();
+    rsdp.0[..8].copy_from_slice(b"RSD PTR ");
+    rsdp.0[15] = 2;
+    rsdp.write_u32(20, u32::max_value());
+    rsdp.fix_checksum(8, 20);
+    assert_eq!(
+        acpi::init_from_rsdp(rsdp.phys_addr()),
+        Err(acpi::AcpiError::InvalidLength(*b"RSDP"))
+    );
+
+    // RSDT の `length` が大きすぎる
+    let mut rsdt = Buffer::new();
+    rsdt.0[..4].copy_from_slice(b"RSDT");
+    rsdt.write_u32(4, u32::max_value());
+    let mut rsdp = Buffer::new();
+    rsdp.0[..8].copy_from_slice(b"RSD PTR ");
+    rsdp.write_u32(16, rsdt.phys_addr().as_u64() as u32);
+    rsdp.fix_checksum(8, 20);
+    assert_eq!(
+        acpi::init_from_rsdp(rsdp.phys_addr()),
+        Err(acpi::AcpiError::InvalidLength(*b"RSDT"))
+    );
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn dump() {
+    serial_print!("dump... ");
+    serial_println!();
+    acpi::dump();
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
@@ -0,0 +1,172 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(atomix::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use atomix::{
+    interrupts::{ticks, timer::TickStream},
+    serial_print, serial_println,
+    task::{executor::Executor, Task},
+};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    cell::{Cell, RefCell},
+    future::Future,
+    panic::PanicInfo,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use futures_util::stream::StreamExt;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    atomix::init();
+    atomix::memory::init(boot_info);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    atomix::hlt_loop();
+}
+
+async fn async_number() -> u32 {
+    42
+}
+
+#[test_case]
+fn simple_task() {
+    serial_print!("simple_task... ");
+    let result = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+
+    let r = result.clone();
+    executor.spawn(Task::new(async move {
+        r.set(async_number().await);
+    }));
+
+    assert!(executor.run_until_idle());
+    assert_eq!(result.get(), 42);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn many_tasks() {
+    serial_print!("many_tasks... ");
+    let count = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+
+    for _ in 0..50 {
+        let c = count.clone();
+        executor.spawn(Task::new(async move {
+            c.set(c.get() + async_number().await);
+        }));
+    }
+
+    assert!(executor.run_until_idle());
+    assert_eq!(count.get(), 50 * 42);
+    serial_println!("[ok]");
+}
+
+/// 最初に poll された時に自分を `count` 回 wake し、`other` も wake して `Pending` を返す。
+struct WakeSelf {
+    count: usize,
+    other: Rc<RefCell<Option<Waker>>>,
+    polled: bool,
+}
+
+impl Future for WakeSelf {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.polled {
+            return Poll::Ready(());
+        }
+        self.polled = true;
+        for _ in 0..self.count {
+            context.waker().wake_by_ref();
+        }
+        self.other.borrow_mut().take().unwrap().wake();
+        Poll::Pending
+    }
+}
+
+/// `waker` を保存して、2回目に poll されるまで `Pending` を返す。
+struct WaitForWake {
+    waker: Rc<RefCell<Option<Waker>>>,
+    polled: bool,
+}
+
+impl Future for WaitForWake {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.polled {
+            return Poll::Ready(());
+        }
+        self.polled = true;
+        *self.waker.borrow_mut() = Some(context.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn repeated_wakes() {
+    serial_print!("repeated_wakes... ");
+    let waker = Rc::new(RefCell::new(None));
+    let done = Rc::new(Cell::new(false));
+    let mut executor = Executor::new();
+
+    let (w, d) = (waker.clone(), done.clone());
+    executor.spawn(Task::new(async move {
+        WaitForWake {
+            waker: w,
+            polled: false,
+        }
+        .await;
+        d.set(true);
+    }));
+    let w = waker.clone();
+    executor.spawn(Task::new(async move {
+        // キューの容量より多く wake しても、他のタスクの wake が失われない
+        WakeSelf {
+            count: 1000,
+            other: w,
+            polled: false,
+        }
+        .await;
+    }));
+
+    assert!(executor.run_until_idle());
+    assert!(done.get());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn tick_stream() {
+    serial_print!("tick_stream... ");
+    let start = ticks();
+    let mut executor = Executor::new();
+
+    executor.spawn(Task::new(async {
+        let mut stream = TickStream::new();
+        for _ in 0..3 {
+            stream.next().await;
+        }
+    }));
+
+    // タスクが完了するまで、割り込みを待ちながら executor を回す
+    while !executor.run_until_idle() {
+        x86_64::instructions::hlt();
+    }
+    assert!(ticks() >= start + 3);
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
@@ -0,0 +1,92 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(atomix::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use atomix::{
+    backtrace::{symbols, Backtrace},
+    serial_print, serial_println,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    atomix::init();
+    atomix::memory::init(boot_info);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    atomix::hlt_loop();
+}
+
+#[inline(never)]
+fn outer() -> Backtrace {
+    middle()
+}
+
+#[inline(never)]
+fn middle() -> Backtrace {
+    inner()
+}
+
+#[inline(never)]
+fn inner() -> Backtrace {
+    Backtrace::capture()
+}
+
+/// `address` を含む関数の名前。
+fn function_name(address: u64) -> &'static str {
+    symbols::kernel()
+        .and_then(|table| table.lookup(address - 1))
+        .map_or("??", |symbol| symbol.name)
+}
+
+#[test_case]
+fn symbol_table_is_embedded() {
+    serial_print!("symbol_table_is_embedded... ");
+    let table = symbols::kernel().expect("symbol table is not embedded");
+    assert!(!table.is_empty());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn walks_frame_pointers() {
+    serial_print!("walks_frame_pointers... ");
+    let backtrace = outer();
+    let addresses = backtrace.addresses();
+    assert!(addresses.len() >= 4);
+    assert!(function_name(addresses[0]).ends_with("inner"));
+    assert!(function_name(addresses[1]).ends_with("middle"));
+    assert!(function_name(addresses[2]).ends_with("outer"));
+    assert!(function_name(addresses[3]).ends_with("walks_frame_pointers"));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn stops_at_invalid_frame() {
+    serial_print!("stops_at_invalid_frame... ");
+    // マップされていないアドレスや、アラインされていないアドレスは辿らない
+    let backtrace = unsafe { Backtrace::walk(0x_dead_0000_0000) };
+    assert!(backtrace.addresses().is_empty());
+    let backtrace = unsafe { Backtrace::walk(0x1003) };
+    assert!(backtrace.addresses().is_empty());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn stops_at_non_text_address() {
+    serial_print!("stops_at_non_text_address... ");
+    // 戻りアドレスの位置に error code のような値がある frame は辿らない
+    let frame: [u64; 2] = [0, 0x0e];
+    let backtrace = unsafe { Backtrace::walk(frame.as_ptr() as u64) };
+    assert!(backtrace.addresses().is_empty());
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(atomix::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use atomix::{
+    memory::{
+        self,
+        address_space::{self, AddressSpace, USER_START},
+    },
+    serial_print, serial_println,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::paging::{Page, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    atomix::init();
+    atomix::memory::init(boot_info);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    atomix::hlt_loop();
+}
+
+/// 割り当てられているフレームの数と、共有されているフレームの数。
+fn frame_counts() -> (usize, usize) {
+    memory::with(|memory| {
+        (
+            memory.frame_allocator.allocated_frames(),
+            memory.ref_counts.shared_frames(),
+        )
+    })
+}
+
+fn ref_count(frame: PhysFrame) -> usize {
+    memory::with(|memory| memory.ref_counts.get(frame))
+}
+
+fn user_page(offset: u64) -> Page {
+    Page::containing_address(VirtAddr::new(USER_START + offset))
+}
+
+#[test_case]
+fn independent_writes() {
+    serial_print!("independent_writes... ");
+    let baseline = frame_counts();
+    let page = user_page(0x1000_0000);
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+    let mut parent = AddressSpace::new().unwrap();
+    let frame = parent.map_new(page, flags).unwrap();
+    unsafe {
+        address_space::activate(Some(&parent));
+        ptr.write_volatile(1);
+
+        let child = parent.clone_cow().unwrap();
+        assert_eq!(ref_count(frame), 2);
+
+        // 共有しているので、親が書き込むと新しいフレームにコピーされる
+        ptr.write_volatile(2);
+        assert_eq!(ref_count(frame), 1);
+
+        address_space::activate(Some(&child));
+        assert_eq!(ptr.read_volatile(), 1);
+        // 子のフレームはもう共有されていないので、コピーせずに書き込める
+        let (allocated, _) = frame_counts();
+        ptr.write_volatile(3);
+        assert_eq!(frame_counts().0, allocated);
+        assert_eq!(ptr.read_volatile(), 3);
+
+        address_space::activate(Some(&parent));
+        assert_eq!(ptr.read_volatile(), 2);
+
+        address_space::activate(None);
+        drop(child);
+    }
+    drop(parent);
+    assert_eq!(frame_counts(), baseline);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn ref_counts_return_to_baseline() {
+    serial_print!("ref_counts_return_to_baseline... ");
+    let baseline = frame_counts();
+    let user = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    let mut parent = AddressSpace::new().unwrap();
+    let writable = parent
+        .map_new(user_page(0), user | PageTableFlags::WRITABLE)
+        .unwrap();
+    let read_only = parent.map_new(user_page(0x1000), user).unwrap();
+
+    let first = parent.clone_cow().unwrap();
+    let second = first.clone_cow().unwrap();
+    assert_eq!(ref_count(writable), 3);
+    assert_eq!(ref_count(read_only), 3);
+    assert_eq!(frame_counts().1, baseline.1 + 2);
+
+    drop(first);
+    assert_eq!(ref_count(writable), 2);
+    drop(parent);
+    assert_eq!(ref_count(read_only), 1);
+    assert_eq!(frame_counts().1, baseline.1);
+
+    drop(second);
+    assert_eq!(frame_counts(), baseline);
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
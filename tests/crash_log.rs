@@ -0,0 +1,61 @@
+#![no_std]
+#![no_main]
+
+use atomix::{
+    logger::persistent,
+    power, serial_print, serial_println,
+    test_utils::{exit_qemu, QemuExitCode},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+/// 1回目の起動で書き込み、再起動した後に探す行。
+const MARKER: &str = "crash_log: about to triple fault";
+/// logger を通さずに書き込む行。
+const RECORDED: &str = "crash_log: recorded directly";
+
+entry_point!(main);
+
+/// 1回目の起動では、ログを書いてから Triple Fault を起こす。
+/// QEMU が再起動すると同じテストがもう一度起動するので、前回のログを調べる。
+fn main(boot_info: &'static BootInfo) -> ! {
+    atomix::init();
+    atomix::memory::init(boot_info);
+    persistent::init(&boot_info.memory_map);
+
+    let (found_marker, found_recorded) = persistent::previous_boot(|previous| match previous {
+        Some(previous) => (
+            previous.lines().any(|line| line.ends_with(MARKER)),
+            previous.lines().any(|line| line == RECORDED),
+        ),
+        None => (false, false),
+    });
+
+    if found_marker && found_recorded {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+        atomix::hlt_loop();
+    } else if persistent::previous_boot(|previous| previous.is_some()) {
+        // 再起動したのにログが見つからない。もう一度 Triple Fault を起こすと終わらないので失敗にする
+        serial_println!("[failed]");
+        serial_println!("log of the previous boot is missing");
+        persistent::print_previous(20);
+        exit_qemu(QemuExitCode::Failed);
+        atomix::hlt_loop();
+    } else if !persistent::is_enabled() {
+        serial_println!("[failed]");
+        serial_println!("crash log is disabled");
+        exit_qemu(QemuExitCode::Failed);
+        atomix::hlt_loop();
+    }
+
+    serial_print!("crash_log... ");
+    log::info!("{}", MARKER);
+    persistent::record(format_args!("{}", RECORDED));
+    power::triple_fault();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
@@ -0,0 +1,26 @@
+#![no_std]
+#![no_main]
+#![feature(core_intrinsics)]
+
+use atomix::serial_print;
+use core::panic::PanicInfo;
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    serial_print!("divide_error... ");
+
+    atomix::init();
+
+    // `/` 演算子はゼロ除算を事前にチェックして panic してしまうので、
+    // チェックなしの除算命令を直接実行させる。
+    let zero = unsafe { core::ptr::read_volatile(&0u64) };
+    let result = unsafe { core::intrinsics::unchecked_div(42u64, zero) };
+    serial_print!("{} ", result);
+
+    panic!("divide_error was not triggered");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::expect_panic_message(info, "EXCEPTION : DIVIDE ERROR");
+}
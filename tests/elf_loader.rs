@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(atomix::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use atomix::{
+    process, serial_print, serial_println,
+    user::{self, elf::ElfError, LoadError},
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+/// `tests/programs/hello.S` をビルドしたもの。
+static HELLO: &[u8] = include_bytes!("programs/hello.elf");
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    atomix::init();
+    atomix::memory::init(boot_info);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    atomix::hlt_loop();
+}
+
+#[test_case]
+fn run_hello() {
+    serial_print!("run_hello... ");
+    let id = process::spawn(HELLO, &["hello", "world "], &["A=1"]).unwrap();
+    // 100 + argc * 10 + envc
+    assert_eq!(process::wait(id), Ok(121));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn run_twice() {
+    serial_print!("run_twice... ");
+    // 2つのプログラムが同じアドレスにロードされても、別のアドレス空間なので干渉しない
+    let first = process::spawn(HELLO, &["hello", "first "], &[]).unwrap();
+    let second = process::spawn(HELLO, &["hello", "second "], &["A=1", "B=2"]).unwrap();
+    assert_eq!(process::wait(first), Ok(120));
+    assert_eq!(process::wait(second), Ok(122));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn reject_invalid() {
+    serial_print!("reject_invalid... ");
+    match user::load(&HELLO[..32], &[], &[]) {
+        Err(LoadError::Elf(ElfError::TooShort)) => {}
+        Err(e) => panic!("unexpected error: {:?}", e),
+        Ok(_) => panic!("truncated ELF was loaded"),
+    }
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
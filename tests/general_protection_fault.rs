@@ -0,0 +1,25 @@
+#![no_std]
+#![no_main]
+
+use atomix::serial_print;
+use core::panic::PanicInfo;
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    serial_print!("general_protection_fault... ");
+
+    atomix::init();
+
+    // non-canonical なアドレスへのアクセスは Page Fault ではなく
+    // General Protection Fault になる。
+    unsafe {
+        core::ptr::write_volatile(0xdead_0000_0000_0000 as *mut u64, 42);
+    }
+
+    panic!("general_protection_fault was not triggered");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::expect_panic_message(info, "EXCEPTION : GENERAL PROTECTION FAULT");
+}
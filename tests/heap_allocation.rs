@@ -0,0 +1,145 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(atomix::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use atomix::{allocator::HEAP_SIZE, serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    atomix::init();
+    atomix::memory::init(boot_info);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    atomix::hlt_loop();
+}
+
+#[test_case]
+fn simple_allocation() {
+    serial_print!("simple_allocation... ");
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn large_vec() {
+    serial_print!("large_vec... ");
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn large_allocation() {
+    serial_print!("large_allocation... ");
+    // heap の半分を一度に割り当てる
+    let size = HEAP_SIZE / 2;
+    let mut vec = Vec::<u8>::with_capacity(size);
+    vec.resize(size, 0xab);
+    assert!(vec.iter().all(|b| *b == 0xab));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn many_boxes() {
+    serial_print!("many_boxes... ");
+    // 解放された領域が再利用されなければ heap を使い切ってしまう
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    serial_println!("[ok]");
+}
+
+// bump allocator は長く生きる割り当てがあると領域を再利用できないので、このテストは通らない
+#[cfg(not(feature = "bump_allocator"))]
+#[test_case]
+fn many_boxes_long_lived() {
+    serial_print!("many_boxes_long_lived... ");
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn many_small_allocations() {
+    serial_print!("many_small_allocations... ");
+    let mut boxes = Vec::new();
+    for i in 0..1000 {
+        boxes.push(Box::new(i as u8));
+    }
+    for (i, b) in boxes.iter().enumerate() {
+        assert_eq!(**b, i as u8);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn reuse_after_free() {
+    serial_print!("reuse_after_free... ");
+    // 解放した領域が結合されていれば、同じ大きさの領域を何度でも割り当てられる
+    for _ in 0..16 {
+        let vec = Vec::<u8>::with_capacity(HEAP_SIZE / 2);
+        assert_eq!(vec.capacity(), HEAP_SIZE / 2);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn collections() {
+    serial_print!("collections... ");
+    let mut map = BTreeMap::new();
+    for i in 0..100 {
+        let mut s = String::from("value");
+        s.push_str(if i % 2 == 0 { "-even" } else { "-odd" });
+        map.insert(i, s);
+    }
+    assert_eq!(map.len(), 100);
+    assert_eq!(map[&42], "value-even");
+    assert_eq!(map[&7], "value-odd");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn usage_stats() {
+    serial_print!("usage_stats... ");
+    let before = atomix::allocator::stats();
+    let vec = Vec::<u8>::with_capacity(4096);
+    let during = atomix::allocator::stats();
+    assert_eq!(during.in_use, before.in_use + vec.capacity());
+    assert_eq!(during.live_allocations, before.live_allocations + 1);
+    assert!(during.high_water_mark >= during.in_use);
+    drop(vec);
+    let after = atomix::allocator::stats();
+    atomix::allocator::print_stats();
+    assert_eq!(after.in_use, before.in_use);
+    assert_eq!(
+        after.high_water_mark,
+        during.high_water_mark.max(before.high_water_mark)
+    );
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
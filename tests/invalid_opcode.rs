@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+#![feature(core_intrinsics)]
+
+use atomix::serial_print;
+use core::panic::PanicInfo;
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    serial_print!("invalid_opcode... ");
+
+    atomix::init();
+
+    // `abort` は `ud2` 命令にコンパイルされる。
+    unsafe { core::intrinsics::abort() }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::expect_panic_message(info, "EXCEPTION : INVALID OPCODE");
+}
@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(atomix::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use atomix::{interrupts::timer, println, serial_print, serial_println};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    atomix::init();
+    test_main();
+    atomix::hlt_loop();
+}
+
+/// hook が呼び出された回数。
+static HOOK_CALLS: AtomicU64 = AtomicU64::new(0);
+
+fn print_from_timer(tick: u64) {
+    HOOK_CALLS.fetch_add(1, Ordering::Relaxed);
+    println!("timer interrupt : {}", tick);
+    serial_print!(".");
+}
+
+#[test_case]
+fn print_from_interrupt_handler() {
+    serial_print!("print_from_interrupt_handler... ");
+    timer::set_hook(print_from_timer);
+
+    // main loop が lock を保持している間にも割り込みが発生するように、
+    // hook が十分な回数呼び出されるまで print し続ける。
+    // spin::Mutex ならどこかでデッドロックする。
+    let mut i = 0u64;
+    while HOOK_CALLS.load(Ordering::Relaxed) < 20 {
+        println!("main loop : {}", i);
+        serial_print!("");
+        i += 1;
+    }
+
+    timer::clear_hook();
+    serial_println!(" [ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
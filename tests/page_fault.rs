@@ -0,0 +1,54 @@
+#![no_std]
+#![no_main]
+
+use atomix::{
+    interrupts::page_fault,
+    serial_print, serial_println,
+    test_utils::{exit_qemu, QemuExitCode},
+};
+use core::panic::PanicInfo;
+use x86_64::{structures::idt::PageFaultErrorCode, VirtAddr};
+
+const FAULT_ADDR: u64 = 0xdead_beef_0000;
+
+#[no_mangle] // don't mangle the name of this function
+pub extern "C" fn _start() -> ! {
+    serial_print!("page_fault... ");
+
+    atomix::init();
+    page_fault::set_hook(check_fault);
+
+    // マップされていないアドレスへの書き込み
+    unsafe {
+        *(FAULT_ADDR as *mut u64) = 42;
+    }
+
+    serial_println!("[failed]");
+    serial_println!("page fault hook was not called");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+fn check_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if addr != VirtAddr::new(FAULT_ADDR) {
+        serial_println!("[failed]");
+        serial_println!("unexpected address : {:?}", addr);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    if !error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        || error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        serial_println!("[failed]");
+        serial_println!("unexpected error code : {:?}", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info);
+}
@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(atomix::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use atomix::{
+    interrupts::exceptions,
+    memory,
+    process::{self, ProcessError},
+    serial_print, serial_println,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+/// `tests/programs/hello.S` をビルドしたもの。
+/// argv[1] を serial に書き出し、100 + argc * 10 + envc で exit する。
+static HELLO: &[u8] = include_bytes!("programs/hello.elf");
+/// `tests/programs/fault.S` をビルドしたもの。argv[1] で選んだ例外を起こす。
+static FAULT: &[u8] = include_bytes!("programs/fault.elf");
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    atomix::init();
+    atomix::memory::init(boot_info);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    atomix::hlt_loop();
+}
+
+fn allocated_frames() -> usize {
+    memory::with(|memory| memory.frame_allocator.allocated_frames())
+}
+
+#[test_case]
+fn spawn_and_wait() {
+    serial_print!("spawn_and_wait... ");
+    let id = process::spawn(HELLO, &["hello", ""], &["A=1"]).unwrap();
+    let process = process::get(id).unwrap();
+    assert_eq!(process.parent(), None);
+    assert_eq!(process.files().get(1), Some(process::file::File::Console));
+    drop(process);
+
+    assert_eq!(process::wait(id), Ok(121));
+    assert!(process::get(id).is_none());
+    assert_eq!(process::wait(id), Err(ProcessError::NoSuchProcess));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn children_of_kernel() {
+    serial_print!("children_of_kernel... ");
+    assert_eq!(process::current_id(), None);
+    let first = process::spawn(HELLO, &["hello", ""], &[]).unwrap();
+    let second = process::spawn(HELLO, &["hello", ""], &[]).unwrap();
+    assert_ne!(first, second);
+    let children = process::children(None);
+    assert!(children.contains(&first) && children.contains(&second));
+
+    // 終了する順番と関係なく wait できる
+    assert_eq!(process::wait(second), Ok(120));
+    assert_eq!(process::wait(first), Ok(120));
+    assert!(process::children(None).is_empty());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn fault_terminates_process() {
+    serial_print!("fault_terminates_process... ");
+    let cases = [
+        ("d", exceptions::DIVIDE_ERROR),
+        ("g", exceptions::GENERAL_PROTECTION_FAULT),
+        ("p", exceptions::PAGE_FAULT),
+    ];
+    for &(arg, exception) in cases.iter() {
+        let id = process::spawn(FAULT, &["fault", arg], &[]).unwrap();
+        assert_eq!(
+            process::wait(id),
+            Ok(process::fault_exit_code(exception.vector))
+        );
+    }
+    // kernel は動き続けていて、他のプロセスも実行できる
+    let id = process::spawn(HELLO, &["hello", ""], &[]).unwrap();
+    assert_eq!(process::wait(id), Ok(120));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn no_frame_leak() {
+    serial_print!("no_frame_leak... ");
+    let run = || {
+        let id = process::spawn(HELLO, &["hello", ""], &[]).unwrap();
+        assert_eq!(process::wait(id), Ok(120));
+    };
+
+    // スレッドのスタックの page table は再利用されるので、先に作っておく
+    run();
+    let before = allocated_frames();
+    for _ in 0..1000 {
+        run();
+    }
+    assert_eq!(allocated_frames(), before);
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
@@ -0,0 +1,141 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(atomix::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use atomix::{
+    acpi, cpu,
+    gdt::tss,
+    memory, serial_print, serial_println,
+    smp::{self, run_on_cpu},
+};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    atomix::init();
+    atomix::memory::init(boot_info);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+    atomix::init_apic();
+    smp::init().expect("SMP initialization failed");
+
+    test_main();
+    atomix::hlt_loop();
+}
+
+#[test_case]
+fn all_cpus_started() {
+    serial_print!("all_cpus_started... ");
+    // テストは QEMU の `-smp 4` で実行する
+    let madt = acpi::madt().unwrap();
+    let enabled = madt.processors.iter().filter(|p| p.enabled).count();
+    assert_eq!(cpu::count(), enabled);
+    assert!(cpu::count() > 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn bsp_is_first() {
+    serial_print!("bsp_is_first... ");
+    assert_eq!(cpu::id(), 0);
+    assert_eq!(
+        cpu::current().apic_id(),
+        Some(atomix::interrupts::apic::local::id())
+    );
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn per_cpu_state() {
+    serial_print!("per_cpu_state... ");
+    for (index, percpu) in cpu::iter().enumerate() {
+        assert_eq!(percpu.index(), index);
+        // AP は起動時に自分の APIC ID を書き込む
+        let apic_id = percpu.apic_id().expect("APIC ID is not set");
+        let same_id = cpu::iter().filter(|other| other.apic_id() == Some(apic_id));
+        assert_eq!(same_id.count(), 1);
+        // GDT と TSS は CPU ごとに別々
+        for other in cpu::iter().skip(index + 1) {
+            assert!(!core::ptr::eq(percpu.gdt(), other.gdt()));
+            assert!(!core::ptr::eq(percpu.tss(), other.tss()));
+            assert_ne!(
+                percpu.tss().interrupt_stack_table[tss::DOUBLE_FAULT_IST_INDEX as usize],
+                other.tss().interrupt_stack_table[tss::DOUBLE_FAULT_IST_INDEX as usize]
+            );
+        }
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn run_on_each_cpu() {
+    serial_print!("run_on_each_cpu... ");
+    for index in 0..cpu::count() {
+        let (id, apic_id) = run_on_cpu(index, || (cpu::id(), cpu::current().apic_id()));
+        assert_eq!(id, index);
+        assert_eq!(apic_id, cpu::get(index).unwrap().apic_id());
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn run_on_cpu_borrows() {
+    serial_print!("run_on_cpu_borrows... ");
+    // 呼び出し側のスタックにあるデータを借用できる
+    let counter = AtomicUsize::new(0);
+    for index in 0..cpu::count() {
+        let value = run_on_cpu(index, || counter.fetch_add(1, Ordering::SeqCst));
+        assert_eq!(value, index);
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), cpu::count());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn tlb_shootdown() {
+    serial_print!("tlb_shootdown... ");
+    let page = Page::containing_address(VirtAddr::new(0x_5555_0000_0000));
+    let addr = page.start_address().as_u64();
+    let ptr = addr as *mut u64;
+    // 生ポインタは `Send` ではないので、アドレスを渡す
+    let read_on = |index| run_on_cpu(index, || unsafe { (addr as *const u64).read_volatile() });
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    memory::map_new(page, flags).unwrap();
+    unsafe { ptr.write_volatile(1) };
+    // 全ての CPU の TLB に変換をキャッシュさせる
+    for index in 0..cpu::count() {
+        assert_eq!(read_on(index), 1);
+    }
+
+    // 別のフレームにマップし直す。古い変換が残っていると 1 が読める
+    let old_frame = memory::unmap(page).unwrap();
+    let new_frame = memory::map_new(page, flags).unwrap();
+    assert_ne!(old_frame, new_frame);
+    unsafe { ptr.write_volatile(2) };
+    for index in 0..cpu::count() {
+        assert_eq!(read_on(index), 2);
+    }
+
+    let frame = memory::unmap(page).unwrap();
+    memory::with(|memory| {
+        memory.deallocate_frame(old_frame);
+        memory.deallocate_frame(frame);
+    });
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
@@ -0,0 +1,265 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks, global_asm)]
+#![test_runner(atomix::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use atomix::{
+    memory, serial_print, serial_println,
+    syscall::{self, Error},
+    thread, user,
+};
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, ptr};
+use x86_64::{
+    structures::paging::{Page, PageTableFlags},
+    VirtAddr,
+};
+
+/// user mode のコードとスタックを置くアドレス。
+const USER_CODE: u64 = 0x_3333_0000_0000;
+const USER_STACK: u64 = 0x_3333_0001_0000;
+/// GS を書き換えるプログラムのコードとスタックを置くアドレス。
+const GS_USER_CODE: u64 = 0x_3333_0002_0000;
+const GS_USER_STACK: u64 = 0x_3333_0003_0000;
+
+// システムコールを順に呼び出し、戻り値を `results` に書き込んでから exit(7) する。
+// コピーして実行するので、位置に依存しないように書く。
+global_asm!(
+    r#"
+.section .rodata.user_program, "a"
+.global user_program_start
+.global user_program_end
+.global user_program_results
+user_program_start:
+    lea results(%rip), %rbx
+    // write(1, message, len)
+    mov $0, %eax
+    mov $1, %edi
+    lea message(%rip), %rsi
+    mov $(message_end - message), %edx
+    syscall
+    mov %rax, 0(%rbx)
+    // write(99, message, len)
+    mov $0, %eax
+    mov $99, %edi
+    lea message(%rip), %rsi
+    mov $(message_end - message), %edx
+    syscall
+    mov %rax, 8(%rbx)
+    // time()
+    mov $3, %eax
+    syscall
+    mov %rax, 16(%rbx)
+    // sleep(2)
+    mov $4, %eax
+    mov $2, %edi
+    syscall
+    // int 0x80 で time()
+    mov $3, %eax
+    int $0x80
+    mov %rax, 24(%rbx)
+    // yield()
+    mov $2, %eax
+    syscall
+    // 存在しないシステムコール
+    mov $1000, %eax
+    syscall
+    mov %rax, 32(%rbx)
+    // write(2, 0, 1) : マップされていないアドレス
+    mov $0, %eax
+    mov $2, %edi
+    mov $0, %esi
+    mov $1, %edx
+    syscall
+    mov %rax, 40(%rbx)
+    // exit(7)
+    mov $1, %eax
+    mov $7, %edi
+    syscall
+    ud2
+message:
+    .ascii "hello from user mode\n"
+message_end:
+    .align 8
+results:
+user_program_results:
+    .quad 0, 0, 0, 0, 0, 0
+user_program_end:
+"#
+);
+
+// GS に user のセレクタを読み込み直してから、しばらく ring 3 で回って timer 割り込みを受け、
+// `syscall` と `int 0x80` で time() を呼び出す。戻り値を `gs_results` に書き込んでから exit(9) する。
+// kernel が user mode の GS base を使ってしまうと、ここで落ちる。
+global_asm!(
+    r#"
+.section .rodata.gs_program, "a"
+.global gs_program_start
+.global gs_program_end
+.global gs_program_results
+gs_program_start:
+    lea gs_results(%rip), %rbx
+    mov $0x1b, %ax
+    mov %ax, %gs
+    mov $20000000, %ecx
+1:
+    dec %ecx
+    jnz 1b
+    // time()
+    mov $3, %eax
+    syscall
+    mov %rax, 0(%rbx)
+    // int 0x80 で time()
+    mov $3, %eax
+    int $0x80
+    mov %rax, 8(%rbx)
+    // exit(9)
+    mov $1, %eax
+    mov $9, %edi
+    syscall
+    ud2
+    .align 8
+gs_results:
+gs_program_results:
+    .quad 0, 0
+gs_program_end:
+"#
+);
+
+extern "C" {
+    static user_program_start: u8;
+    static user_program_end: u8;
+    static user_program_results: u8;
+    static gs_program_start: u8;
+    static gs_program_end: u8;
+    static gs_program_results: u8;
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    atomix::init();
+    atomix::memory::init(boot_info);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    atomix::hlt_loop();
+}
+
+/// `start..end` のプログラムを `code` にコピーし、`stack` をスタックとして user mode で実行する。
+/// 終了するまで待ち、exit code と `results` のコピー先のアドレスを返す。
+fn run_program(
+    start: *const u8,
+    end: *const u8,
+    results: *const u8,
+    code: u64,
+    stack: u64,
+) -> (Option<i32>, usize) {
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let code_page = Page::containing_address(VirtAddr::new(code));
+    let stack_page = Page::containing_address(VirtAddr::new(stack));
+    memory::map_new(code_page, flags).unwrap();
+    memory::map_new(stack_page, flags).unwrap();
+
+    let len = end as usize - start as usize;
+    assert!(len <= 4096);
+    unsafe {
+        ptr::copy_nonoverlapping(start, code as *mut u8, len);
+    }
+
+    let handle = thread::spawn(move || unsafe {
+        user::enter_user_mode(
+            code_page.start_address(),
+            stack_page.start_address() + 4096u64,
+        )
+    });
+    let id = handle.id();
+    assert!(handle.wait().is_none());
+    (
+        syscall::exit_code(id),
+        code as usize + (results as usize - start as usize),
+    )
+}
+
+#[test_case]
+fn syscalls_from_user_mode() {
+    serial_print!("syscalls_from_user_mode... ");
+
+    let (exit_code, results) = unsafe {
+        run_program(
+            &user_program_start,
+            &user_program_end,
+            &user_program_results,
+            USER_CODE,
+            USER_STACK,
+        )
+    };
+    assert_eq!(exit_code, Some(7));
+
+    let results = unsafe { &*(results as *const [u64; 6]) };
+    let message_len = "hello from user mode\n".len() as u64;
+    assert_eq!(Error::decode(results[0]), Ok(message_len));
+    assert_eq!(Error::decode(results[1]), Err(Error::BadDescriptor));
+    let before = Error::decode(results[2]).unwrap();
+    let after = Error::decode(results[3]).unwrap();
+    assert!(after >= before + 2);
+    assert_eq!(Error::decode(results[4]), Err(Error::NoSuchSyscall));
+    assert_eq!(Error::decode(results[5]), Err(Error::BadAddress));
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn syscalls_after_reloading_gs() {
+    serial_print!("syscalls_after_reloading_gs... ");
+
+    let (exit_code, results) = unsafe {
+        run_program(
+            &gs_program_start,
+            &gs_program_end,
+            &gs_program_results,
+            GS_USER_CODE,
+            GS_USER_STACK,
+        )
+    };
+    assert_eq!(exit_code, Some(9));
+
+    let results = unsafe { &*(results as *const [u64; 2]) };
+    let before = Error::decode(results[0]).unwrap();
+    let after = Error::decode(results[1]).unwrap();
+    assert!(after >= before);
+
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn error_encoding() {
+    serial_print!("error_encoding... ");
+    assert_eq!(Error::decode(0), Ok(0));
+    assert_eq!(Error::decode(42), Ok(42));
+    assert_eq!(
+        Error::decode(2u64.wrapping_neg()),
+        Err(Error::InvalidArgument)
+    );
+    // エラーの範囲にある値は、知らないものでもエラーになる
+    assert_eq!(
+        Error::decode(100u64.wrapping_neg()),
+        Err(Error::Unknown(100))
+    );
+    assert_eq!(
+        Error::decode(4095u64.wrapping_neg()),
+        Err(Error::Unknown(4095))
+    );
+    assert_eq!(
+        Error::decode(4096u64.wrapping_neg()),
+        Ok(4096u64.wrapping_neg())
+    );
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
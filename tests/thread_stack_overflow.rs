@@ -0,0 +1,36 @@
+#![no_std]
+#![no_main]
+
+use atomix::{serial_print, thread};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use volatile::Volatile;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("thread_stack_overflow... ");
+
+    atomix::init();
+    atomix::memory::init(boot_info);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+
+    // スタックを使い切ると guard page にアクセスする。
+    // Page Fault のハンドラを呼び出すための stack frame も push できないので、
+    // Double Fault になる。
+    thread::spawn(stack_overflow).join();
+
+    panic!("stack overflow was not detected");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    // 末尾再帰の最適化でループにされないようにする
+    Volatile::new(0).read();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::expect_panic_message(info, "EXCEPTION : DOUBLE FAULT");
+}
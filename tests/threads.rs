@@ -0,0 +1,147 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(atomix::test_utils::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use atomix::{interrupts::ticks, serial_print, serial_println, thread};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{spin_loop_hint, AtomicBool, AtomicU64, Ordering},
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    atomix::init();
+    atomix::memory::init(boot_info);
+    atomix::allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    atomix::hlt_loop();
+}
+
+#[test_case]
+fn spawn_and_join() {
+    serial_print!("spawn_and_join... ");
+    let handle = thread::spawn(|| 6 * 7);
+    assert_ne!(handle.id(), thread::current());
+    assert_eq!(handle.join(), 42);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn yield_now() {
+    serial_print!("yield_now... ");
+    static DONE: AtomicBool = AtomicBool::new(false);
+    let handle = thread::spawn(|| DONE.store(true, Ordering::SeqCst));
+    while !DONE.load(Ordering::SeqCst) {
+        thread::yield_now();
+    }
+    handle.join();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn preemption() {
+    serial_print!("preemption... ");
+    // どちらも yield しないので、タイマー割り込みで切り替えられなければ終わらない
+    static PING: AtomicBool = AtomicBool::new(false);
+    static PONG: AtomicBool = AtomicBool::new(false);
+    let handle = thread::spawn(|| {
+        while !PING.load(Ordering::SeqCst) {
+            spin_loop_hint();
+        }
+        PONG.store(true, Ordering::SeqCst);
+    });
+    PING.store(true, Ordering::SeqCst);
+    while !PONG.load(Ordering::SeqCst) {
+        spin_loop_hint();
+    }
+    handle.join();
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn sleep() {
+    serial_print!("sleep... ");
+    let start = ticks();
+    thread::sleep(3);
+    assert!(ticks() >= start + 3);
+
+    let handle = thread::spawn(|| {
+        thread::sleep(2);
+        ticks()
+    });
+    assert!(handle.join() >= start + 5);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn fairness() {
+    serial_print!("fairness... ");
+    const THREADS: usize = 3;
+    static COUNTERS: [AtomicU64; THREADS] =
+        [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+    // 各スレッドが 10 回程度実行されるだけの時間、カウンタを回し続ける
+    let deadline = ticks() + 10 * THREADS as u64;
+    let handles: Vec<_> = (0..THREADS)
+        .map(|i| {
+            thread::spawn(move || {
+                while ticks() < deadline {
+                    COUNTERS[i].fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+
+    let counts: Vec<u64> = COUNTERS
+        .iter()
+        .map(|counter| counter.load(Ordering::Relaxed))
+        .collect();
+    let min = *counts.iter().min().unwrap();
+    let max = *counts.iter().max().unwrap();
+    // round-robin なら、どのスレッドもほぼ同じ回数だけ実行される
+    assert!(min > 0, "a thread was starved: {:?}", counts);
+    assert!(min * 2 >= max, "unfair scheduling: {:?}", counts);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn many_threads() {
+    serial_print!("many_threads... ");
+    // スタックが再利用されなければ、そのうちメモリを使い切る
+    for _ in 0..10 {
+        let handles: Vec<_> = (0..16u64).map(|i| thread::spawn(move || i * i)).collect();
+        let sum: u64 = handles.into_iter().map(|handle| handle.join()).sum();
+        assert_eq!(sum, (0..16).map(|i| i * i).sum());
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn stacks_are_freed() {
+    serial_print!("stacks_are_freed... ");
+    let allocated_frames =
+        || atomix::memory::with(|memory| memory.frame_allocator.allocated_frames());
+    // 最初のスレッドでは page table のフレームも割り当てられるので、一度作っておく
+    thread::spawn(|| ()).join();
+
+    let before = allocated_frames();
+    thread::spawn(|| ()).join();
+    assert_eq!(allocated_frames(), before);
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+use atomix::{
+    interrupts::page_fault,
+    memory, serial_print, serial_println,
+    test_utils::{exit_qemu, QemuExitCode},
+    user,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{Page, PageTableFlags},
+    },
+    VirtAddr,
+};
+
+/// user mode のコードとスタックを置くアドレス。
+const USER_CODE: u64 = 0x_2222_0000_0000;
+const USER_STACK: u64 = 0x_2222_0001_0000;
+
+/// user mode から読もうとする kernel のデータ。
+static SECRET: u64 = 42;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("user_mode... ");
+
+    atomix::init();
+    atomix::memory::init(boot_info);
+
+    let flags =
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let code_page = Page::containing_address(VirtAddr::new(USER_CODE));
+    let stack_page = Page::containing_address(VirtAddr::new(USER_STACK));
+    memory::map_new(code_page, flags).unwrap();
+    memory::map_new(stack_page, flags).unwrap();
+
+    // mov rax, [SECRET]
+    // jmp $
+    let mut code = [0x48, 0xa1, 0, 0, 0, 0, 0, 0, 0, 0, 0xeb, 0xfe];
+    let secret = &SECRET as *const u64 as u64;
+    code[2..10].copy_from_slice(&secret.to_le_bytes());
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), USER_CODE as *mut u8, code.len());
+    }
+
+    // ring 3 から kernel のページを読むと Page Fault で ring 0 に戻ってくる
+    page_fault::set_hook(user_page_fault);
+    unsafe {
+        user::enter_user_mode(
+            code_page.start_address(),
+            stack_page.start_address() + 4096u64,
+        )
+    }
+}
+
+fn user_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let expected = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::PROTECTION_VIOLATION;
+    if addr == VirtAddr::from_ptr(&SECRET) && error_code == expected {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Unexpected page fault at {:?} : {:?}", addr, error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    atomix::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    atomix::test_utils::test_panic_handler(info)
+}
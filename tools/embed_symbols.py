@@ -0,0 +1,147 @@
+#!/usr/bin/env python3
+"""kernel の ELF の `.symtab` から関数の表を作り、`.kernel_symbols` section に書き込む。
+
+表のフォーマットは `src/backtrace/symbols.rs` を参照。
+section の大きさは変えずに中身だけを上書きするので、他のアドレスはずれない。
+何度実行しても同じ結果になる。
+
+使い方: embed_symbols.py <kernel の ELF>
+"""
+
+import re
+import struct
+import sys
+
+SECTION = b".kernel_symbols"
+MAGIC = b"SYMTAB01"
+EMPTY_MAGIC = b"NOSYMTAB"
+HEADER_SIZE = 16
+ENTRY_SIZE = 16
+
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+# legacy の mangling で使われるエスケープ
+ESCAPES = {
+    "$SP$": "@",
+    "$BP$": "*",
+    "$RF$": "&",
+    "$LT$": "<",
+    "$GT$": ">",
+    "$LP$": "(",
+    "$RP$": ")",
+    "$C$": ",",
+}
+
+
+def demangle(name):
+    """`_ZN...E` の形の Rust の名前を `a::b::c` にする。それ以外はそのまま返す。"""
+    match = re.match(r"^_?_ZN(.*)E$", name)
+    if not match:
+        return name
+    rest = match.group(1)
+    parts = []
+    while rest:
+        length = re.match(r"^\d+", rest)
+        if not length:
+            return name
+        start = length.end()
+        end = start + int(length.group())
+        parts.append(rest[start:end])
+        rest = rest[end:]
+    # 最後の要素はハッシュ
+    if parts and re.match(r"^h[0-9a-f]{16}$", parts[-1]):
+        parts.pop()
+    return "::".join(demangle_part(part) for part in parts)
+
+
+def demangle_part(part):
+    if part.startswith("_$"):
+        part = part[1:]
+    for escape, char in ESCAPES.items():
+        part = part.replace(escape, char)
+    part = re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), part)
+    return part.replace("..", "::")
+
+
+def read_sections(elf):
+    if elf[:4] != b"\x7fELF" or elf[4] != 2 or elf[5] != 1:
+        sys.exit("not a 64-bit little endian ELF file")
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+    sections = []
+    for i in range(shnum):
+        fields = struct.unpack_from("<IIQQQQIIQQ", elf, shoff + i * shentsize)
+        sections.append(
+            {
+                "name": fields[0],
+                "type": fields[1],
+                "offset": fields[4],
+                "size": fields[5],
+                "link": fields[6],
+                "entsize": fields[9],
+            }
+        )
+    names = sections[shstrndx]
+    for section in sections:
+        start = names["offset"] + section["name"]
+        section["name"] = elf[start : elf.index(b"\0", start)]
+    return sections
+
+
+def functions(elf, sections):
+    symtab = next((s for s in sections if s["type"] == SHT_SYMTAB), None)
+    if symtab is None:
+        sys.exit("no .symtab in the kernel (stripped?)")
+    strtab = sections[symtab["link"]]
+    symbols = {}
+    for offset in range(symtab["offset"], symtab["offset"] + symtab["size"], 24):
+        name, info, _, shndx, value, size = struct.unpack_from("<IBBHQQ", elf, offset)
+        if info & 0xF != STT_FUNC or shndx == 0 or size == 0:
+            continue
+        start = strtab["offset"] + name
+        name = elf[start : elf.index(b"\0", start)].decode("utf-8", "replace")
+        # 同じアドレスに複数の名前がある場合は最初のものを使う
+        symbols.setdefault(value, (size, demangle(name)))
+    return sorted(symbols.items())
+
+
+def build_table(symbols):
+    names = bytearray()
+    entries = bytearray()
+    for address, (size, name) in symbols:
+        entries += struct.pack("<QII", address, min(size, 0xFFFFFFFF), len(names))
+        names += name.encode("utf-8") + b"\0"
+    header = MAGIC + struct.pack("<II", len(symbols), HEADER_SIZE + len(entries))
+    return header + entries + names
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: embed_symbols.py <kernel ELF>")
+    path = sys.argv[1]
+    with open(path, "rb") as file:
+        elf = bytearray(file.read())
+
+    sections = read_sections(elf)
+    section = next((s for s in sections if s["name"] == SECTION), None)
+    if section is None:
+        sys.exit("no {} section in {}".format(SECTION.decode(), path))
+    start, size = section["offset"], section["size"]
+    if elf[start : start + 8] not in (MAGIC, EMPTY_MAGIC):
+        sys.exit("unexpected contents in {}".format(SECTION.decode()))
+
+    table = build_table(functions(elf, sections))
+    if len(table) > size:
+        sys.exit(
+            "symbol table is too large ({} > {} bytes), increase CAPACITY".format(
+                len(table), size
+            )
+        )
+    elf[start : start + size] = table + bytes(size - len(table))
+    with open(path, "wb") as file:
+        file.write(elf)
+
+
+if __name__ == "__main__":
+    main()
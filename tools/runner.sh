@@ -0,0 +1,6 @@
+#!/bin/sh
+# cargo の runner 。kernel にシンボルの表を埋め込んでから bootimage runner に渡す。
+# （`src/backtrace/symbols.rs` を参照）
+set -e
+python3 "$(dirname "$0")/embed_symbols.py" "$1"
+exec bootimage runner "$@"